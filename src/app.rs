@@ -2,21 +2,31 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use camino::Utf8PathBuf;
-use clap::{Args, Parser, Subcommand};
+use camino::{Utf8Path, Utf8PathBuf};
+use clap::{error::ErrorKind, Args, CommandFactory, Parser, Subcommand};
 
-use crate::autounattend::WindowsVersion;
+use crate::autounattend::{
+    generate::UnattendPlatform, ElementPath, UnattendInsertion,
+    UnattendSetting, WindowsVersion,
+};
+use crate::checksum::{InputFile, Sha256Digest};
+use crate::steps::ImageAlignment;
+
+#[cfg(target_os = "linux")]
+use crate::linux::vm_profile::VmProfile;
 
 #[derive(Parser)]
 pub struct App {
-    /// The directory in which to store temporary files.
+    /// The directory in which to store temporary files. Required by commands
+    /// that build images.
     #[arg(long)]
-    pub work_dir: Utf8PathBuf,
+    pub work_dir: Option<Utf8PathBuf>,
 
     /// The path to the tool's output disk image (i.e. the generated all-in-one
-    /// installation disk or guest disk image).
+    /// installation disk or guest disk image). Required by commands that build
+    /// images.
     #[arg(long)]
-    pub output_image: Utf8PathBuf,
+    pub output_image: Option<Utf8PathBuf>,
 
     /// Forces the tool to run in an interactive or non-interactive mode. If not
     /// set, the tool infers whether to run interactively from whether it is
@@ -24,10 +34,58 @@ pub struct App {
     #[arg(long, default_value = Option::None)]
     pub interactive: Option<bool>,
 
+    /// The directory in which to store command logs. Each run writes its logs
+    /// to a new timestamped subdirectory. Defaults to `<work-dir>/logs`.
+    #[arg(long)]
+    pub log_dir: Option<Utf8PathBuf>,
+
+    /// The number of runs' logs to keep in the log directory, including the
+    /// current run's. Older runs' logs are deleted when a new run starts.
+    #[arg(long, default_value_t = 10,
+          value_parser = clap::value_parser!(u32).range(1..))]
+    pub keep_logs: u32,
+
     #[command(subcommand)]
     pub command: Command,
 }
 
+impl App {
+    /// Returns the working directory, exiting with a usage error if one wasn't
+    /// supplied.
+    pub fn work_dir(&self) -> &Utf8Path {
+        self.work_dir
+            .as_deref()
+            .unwrap_or_else(|| missing_argument("--work-dir"))
+    }
+
+    /// Returns the directory in which to create per-run log directories.
+    pub fn log_dir(&self) -> Utf8PathBuf {
+        match &self.log_dir {
+            Some(dir) => dir.clone(),
+            None => self.work_dir().join("logs"),
+        }
+    }
+
+    /// Returns the output image path, exiting with a usage error if one wasn't
+    /// supplied.
+    pub fn output_image(&self) -> &Utf8Path {
+        self.output_image
+            .as_deref()
+            .unwrap_or_else(|| missing_argument("--output-image"))
+    }
+}
+
+/// Exits with a usage error indicating that the selected command requires
+/// `arg`.
+fn missing_argument(arg: &str) -> ! {
+    App::command()
+        .error(
+            ErrorKind::MissingRequiredArgument,
+            format!("the selected command requires {arg}"),
+        )
+        .exit()
+}
+
 #[derive(Subcommand)]
 pub enum Command {
     /// Builds from a set of source files an installation disk suitable for use
@@ -35,7 +93,7 @@ pub enum Command {
     #[cfg(target_os = "illumos")]
     BuildInstallationDisk {
         #[command(flatten)]
-        sources: ImageSources,
+        sources: Box<ImageSources>,
     },
 
     /// Creates a disk image containing a generalized Windows installation
@@ -61,7 +119,7 @@ pub enum Command {
 
         #[cfg(target_os = "linux")]
         #[cfg_attr(target_os = "linux", command(flatten))]
-        sources: ImageSources,
+        sources: Box<ImageSources>,
 
         /// The path to the OVMF bootrom to supply to QEMU for use as a guest
         /// firmware image.
@@ -73,10 +131,257 @@ pub enum Command {
         #[cfg(target_os = "linux")]
         #[cfg_attr(target_os = "linux", arg(long, default_value_t = false))]
         vga_console: bool,
+
+        /// After building the image, boots it to check that it works (as the
+        /// verify-image command does).
+        #[cfg(target_os = "linux")]
+        #[cfg_attr(target_os = "linux", arg(long, default_value_t = false))]
+        verify: bool,
+
+        #[cfg(target_os = "linux")]
+        #[cfg_attr(target_os = "linux", command(flatten))]
+        verify_options: VerifyOptions,
+
+        #[cfg(target_os = "linux")]
+        #[cfg_attr(target_os = "linux", command(flatten))]
+        qemu: QemuOptions,
+
+        /// After trimming the output image, deallocates the zero-filled blocks
+        /// in it so that it takes up only as much storage as its data does.
+        #[arg(long, default_value_t = false)]
+        punch_holes: bool,
+
+        /// The multiple to which to round up the size of the trimmed output
+        /// image, in bytes or with a K, M, or G suffix (e.g. 1GiB). Must be a
+        /// multiple of the image's sector size.
+        #[arg(long, default_value_t = ImageAlignment::default())]
+        align: ImageAlignment,
+    },
+
+    /// Boots the image at --output-image in QEMU with a NoCloud configuration
+    /// drive and checks that cloudbase-init applied the drive's hostname, user,
+    /// and SSH key before powering the VM off. The image itself isn't
+    /// modified.
+    #[cfg(target_os = "linux")]
+    VerifyImage {
+        /// The path to the OVMF bootrom to supply to QEMU for use as a guest
+        /// firmware image.
+        #[arg(long)]
+        ovmf_path: Utf8PathBuf,
+
+        /// Displays a graphical console for the verification VM.
+        #[arg(long, default_value_t = false)]
+        vga_console: bool,
+
+        #[command(flatten)]
+        options: VerifyOptions,
+
+        #[command(flatten)]
+        qemu: QemuOptions,
+    },
+
+    /// Checks the answer files in an unattend directory (Autounattend.xml and
+    /// specialize-unattend.xml) for mistakes that would otherwise only surface
+    /// as errors during Windows Setup, such as misspelled pass or component
+    /// names, duplicate Order or keyValue entries in lists, and partition IDs
+    /// that don't refer to partitions the answer file creates.
+    ValidateUnattend {
+        /// The path to the directory containing the answer files to check.
+        #[arg(long)]
+        unattend_dir: Utf8PathBuf,
+    },
+
+    /// Generates Autounattend.xml and specialize-unattend.xml from a set of
+    /// high-level options instead of editing the template answer files. The
+    /// other files in the template unattend directories (e.g. prep.cmd and
+    /// OxidePrepBaseImage.ps1) aren't generated and must be copied alongside
+    /// the generated answer files.
+    GenerateUnattend {
+        #[command(flatten)]
+        options: UnattendOptions,
     },
+
+    /// Writes a NoCloud configuration drive: a FAT disk image labeled `cidata`
+    /// containing the `meta-data` and `user-data` files cloudbase-init reads
+    /// when an image first boots. Attaching this drive to a VM running an image
+    /// reproduces the configuration an Oxide rack supplies to its instances.
+    MakeConfigDrive {
+        #[command(flatten)]
+        options: ConfigDriveOptions,
+    },
+
+    /// Inspects a finished guest image without booting it: reads its GUID
+    /// partition table, identifies the partitions Windows Setup created, checks
+    /// the OS partition's NTFS volume and the EFI system partition's boot
+    /// loader, and flags images that were never shrunk. Exits with an error if
+    /// it finds any problems.
+    Inspect {
+        /// The path to the image to inspect.
+        image: Utf8PathBuf,
+    },
+
+    /// Uploads the image at --output-image to an Oxide rack: creates a disk in
+    /// the importing state, writes the image's data to it (skipping blocks
+    /// that are all zeroes), finalizes the disk with a snapshot, and creates an
+    /// image from the snapshot.
+    Upload {
+        #[command(flatten)]
+        options: UploadOptions,
+    },
+}
+
+#[derive(Args, Clone)]
+pub struct UploadOptions {
+    /// The URL of the Oxide API to upload to, e.g.
+    /// `https://oxide.sys.example.com`.
+    #[arg(long, env = "OXIDE_HOST")]
+    pub oxide_host: String,
+
+    /// The API token with which to authenticate. Prefer setting this with the
+    /// environment variable, which other users of the host can't see.
+    #[arg(long, env = "OXIDE_TOKEN", hide_env_values = true)]
+    pub oxide_token: String,
+
+    /// The name or ID of the project in which to create the image.
+    #[arg(long)]
+    pub project: String,
+
+    /// The name of the image to create. The disk and snapshot from which the
+    /// image is created get the same name.
+    #[arg(long)]
+    pub image_name: String,
+
+    /// The description of the image to create. Defaults to the description in
+    /// the image's `.image.json` file, or else to the Windows version.
+    #[arg(long)]
+    pub image_description: Option<String>,
+
+    /// The version of Windows installed in the image. This determines the OS
+    /// and version recorded in the image's metadata. Required unless the
+    /// image's `.image.json` file records them.
+    #[arg(long, value_enum)]
+    pub windows_version: Option<WindowsVersion>,
 }
 
 #[derive(Args, Clone)]
+pub struct ConfigDriveOptions {
+    /// The path to which to write the configuration drive's disk image. An
+    /// existing file at this path is replaced.
+    #[arg(long)]
+    pub output: Utf8PathBuf,
+
+    /// The hostname the guest should set.
+    #[arg(long)]
+    pub hostname: String,
+
+    /// A file containing SSH public keys, one per line, to authorize for the
+    /// guest's default user. May be specified multiple times.
+    #[arg(long = "ssh-key", value_name = "FILE")]
+    pub ssh_keys: Vec<Utf8PathBuf>,
+
+    /// A file whose contents to supply to the guest as user data, e.g. a
+    /// PowerShell script that begins with `#ps1_sysnative` or a
+    /// `#cloud-config` document. If not set, the user data is empty.
+    #[arg(long)]
+    pub user_data: Option<Utf8PathBuf>,
+
+    /// The instance ID to supply to the guest. cloudbase-init only applies a
+    /// drive's configuration once per instance ID. If not set, a new ID is
+    /// generated from the hostname and the current time.
+    #[arg(long)]
+    pub instance_id: Option<String>,
+}
+
+#[derive(Args, Clone)]
+pub struct UnattendOptions {
+    /// The directory into which to write the answer files. Existing answer
+    /// files in this directory are replaced.
+    #[arg(long)]
+    pub output_dir: Utf8PathBuf,
+
+    /// The platform whose image-building commands will use the answer files.
+    /// This determines how the answer files locate the installation image,
+    /// drivers, and setup scripts.
+    #[arg(long, value_enum)]
+    pub platform: UnattendPlatform,
+
+    /// The locale to use during setup and in the installed system.
+    #[arg(long, default_value = "en-US")]
+    pub locale: String,
+
+    /// The index of the image in install.wim to install. This selects the
+    /// edition of Windows to install when the installation media contains
+    /// multiple editions.
+    #[arg(long, default_value_t = 2)]
+    pub image_index: u32,
+
+    /// The Windows version whose virtio drivers should be installed. Only used
+    /// with `--platform linux`; the illumos installation disk contains only the
+    /// drivers for the version it was built for.
+    #[arg(long, value_enum, default_value_t = WindowsVersion::Server2022)]
+    pub windows_version: WindowsVersion,
+
+    /// A product key to enter during setup.
+    #[arg(long)]
+    pub product_key: Option<String>,
+
+    /// The time zone to set in the installed system, e.g. "UTC" or "Pacific
+    /// Standard Time".
+    #[arg(long)]
+    pub time_zone: Option<String>,
+
+    /// The computer name to set in the installed system. If not set, Windows
+    /// generates a random name.
+    #[arg(long)]
+    pub computer_name: Option<String>,
+}
+
+/// Options that control how an image is verified by booting it.
+#[derive(Args, Clone)]
+pub struct VerifyOptions {
+    /// The hostname to supply to the verification VM in its configuration
+    /// drive.
+    #[arg(long, default_value = "wimsy-verify")]
+    pub verify_hostname: String,
+
+    /// The user cloudbase-init is expected to create in the verification VM.
+    #[arg(long, default_value = "oxide")]
+    pub verify_user: String,
+
+    /// An SSH public key file to supply to the verification VM. If not set, a
+    /// new key is generated in the work directory.
+    #[arg(long)]
+    pub verify_ssh_public_key: Option<Utf8PathBuf>,
+
+    /// The number of minutes to wait for cloudbase-init to finish configuring
+    /// the verification VM.
+    #[arg(long, default_value_t = 30)]
+    pub verify_timeout_minutes: u64,
+}
+
+/// Options that control how QEMU runs the VMs that install and verify images.
+#[cfg(target_os = "linux")]
+#[derive(Args, Clone)]
+pub struct QemuOptions {
+    /// The QEMU system emulator to run, as a path or a name to look up on
+    /// PATH.
+    #[arg(long, default_value = "qemu-system-x86_64")]
+    pub qemu_binary: String,
+
+    /// If KVM isn't available, runs VMs with QEMU's TCG software emulator
+    /// instead of failing. TCG is many times slower than KVM: installing
+    /// Windows can take hours.
+    #[arg(long, default_value_t = false)]
+    pub allow_tcg: bool,
+
+    /// The virtual hardware to give VMs. `oxide` mirrors the devices and PCI
+    /// layout of an Oxide instance, so that images have drivers for exactly
+    /// the hardware the rack presents.
+    #[arg(long, value_enum, default_value_t = VmProfile::Legacy)]
+    pub vm_profile: VmProfile,
+}
+
+#[derive(Args, Clone, serde::Serialize)]
 pub struct ImageSources {
     /// The path to the Windows setup ISO to use for this operation.
     #[arg(long)]
@@ -88,8 +393,9 @@ pub struct ImageSources {
     ///
     /// - Top-level directories named `viostor` and `NetKVM`
     ///
-    /// - Within each of these directories, subdirectories named `2k16`, `2k19`,
-    ///   and `2k22`
+    /// - Within each of these directories, subdirectories named for each
+    ///   supported Windows version (`2k16`, `2k19`, `2k22`, `2k25`, `w10`, and
+    ///   `w11`)
     ///
     /// - Within each of these directories, an `amd64` subdirectory, which
     ///   contains `.cat`, `.inf`, and `.sys` files (i.e. the driver collateral
@@ -102,6 +408,55 @@ pub struct ImageSources {
     #[arg(long)]
     pub unattend_dir: Utf8PathBuf,
 
+    /// An optional directory whose contents (including subdirectories) are
+    /// delivered to the guest alongside prep.cmd and the other unattend files,
+    /// e.g. to supply monitoring agents or certificates. OxidePrepBaseImage.ps1
+    /// runs any `*.ps1` scripts in a top-level `hooks` subdirectory, in name
+    /// order, before it generalizes the image. Files in this directory may not
+    /// have the same names as the files the tool supplies.
+    #[arg(long)]
+    pub extra_files: Option<Utf8PathBuf>,
+
+    /// An optional path to a cloudbase-init installer to deliver to the guest.
+    /// If supplied, OxidePrepBaseImage.ps1 installs this package instead of
+    /// downloading it.
+    #[arg(long)]
+    pub cloudbase_init_msi: Option<Utf8PathBuf>,
+
+    /// An optional path to a Win32-OpenSSH release archive (OpenSSH-Win64.zip)
+    /// to deliver to the guest. If supplied, OxidePrepBaseImage.ps1 installs
+    /// OpenSSH from this archive instead of from Windows Update or GitHub. If
+    /// this and --cloudbase-init-msi are both supplied, the guest doesn't need
+    /// internet access.
+    #[arg(long)]
+    pub openssh_zip: Option<Utf8PathBuf>,
+
+    /// The expected SHA-256 digest of the file passed to --windows-iso. The
+    /// tool computes the digests of all its input files before running and
+    /// refuses to run if any of them doesn't match its expected digest.
+    #[arg(long, value_name = "DIGEST")]
+    pub windows_iso_sha256: Option<Sha256Digest>,
+
+    /// The expected SHA-256 digest of the file passed to --virtio-iso.
+    #[arg(long, value_name = "DIGEST")]
+    pub virtio_iso_sha256: Option<Sha256Digest>,
+
+    /// The expected SHA-256 digest of the file passed to
+    /// --cloudbase-init-msi.
+    #[arg(long, value_name = "DIGEST")]
+    pub cloudbase_init_msi_sha256: Option<Sha256Digest>,
+
+    /// The expected SHA-256 digest of the file passed to --openssh-zip.
+    #[arg(long, value_name = "DIGEST")]
+    pub openssh_zip_sha256: Option<Sha256Digest>,
+
+    /// A file in the format `sha256sum` produces (e.g. a SHA256SUMS file)
+    /// listing the expected digests of input files. Inputs are matched to
+    /// entries by file name; digests passed with options like
+    /// --windows-iso-sha256 take precedence over entries in this file.
+    #[arg(long)]
+    pub sha256sums: Option<Utf8PathBuf>,
+
     /// An optional image index to write into the Microsoft-Windows-Setup
     /// component's ImageInstall/OSImage/InstallFrom elements in
     /// Autounattend.xml. This index determines the edition of Windows that will
@@ -112,12 +467,93 @@ pub struct ImageSources {
     #[arg(long)]
     pub unattend_image_index: Option<u32>,
 
-    /// An optional Windows Server version that specifies the driver
-    /// installation paths to specify in Autounattend.xml. If set, this
-    /// substitutes the appropriate versioned directory name ("2k16", "2k19", or
-    /// "2k22") into the DriverPaths specified in the template Autounattend.xml
-    /// specified by --unattend-dir. If not specified, the existing driver paths
-    /// in that Autounattend.xml are used.
+    /// An optional Windows version that specifies the driver installation
+    /// paths to specify in Autounattend.xml. If set, this substitutes the
+    /// appropriate versioned directory name ("2k16", "2k19", "2k22", "2k25",
+    /// "w10", or "w11") for any versioned directory name in the DriverPaths
+    /// specified in the template Autounattend.xml specified by --unattend-dir.
+    /// If not specified, the existing driver paths in that Autounattend.xml are
+    /// used.
     #[arg(long, value_enum)]
     pub windows_version: Option<WindowsVersion>,
+
+    /// Sets the text of an element in Autounattend.xml, adding the element
+    /// (and any missing parents) if it doesn't exist. Settings have the form
+    /// PATH=VALUE, where PATH names a settings pass, a component in that pass,
+    /// and the path to the element within the component, e.g.
+    /// `windowsPE/Microsoft-Windows-Setup/UserData/ProductKey/Key=XXXXX`.
+    /// Elements in a path may be followed by attribute predicates to select
+    /// among elements with the same name, e.g.
+    /// `PathAndCredentials[@keyValue='1']`. Paths that begin with `/` are
+    /// instead treated as absolute paths from the root `unattend` element. May
+    /// be specified multiple times. It is an error for a setting not to match
+    /// any element.
+    #[arg(long = "set", value_name = "PATH=VALUE")]
+    pub unattend_set: Vec<UnattendSetting>,
+
+    /// Removes the elements at PATH, and all of their children, from
+    /// Autounattend.xml. Paths have the same form as in --set. May be specified
+    /// multiple times. It is an error for a path not to match any element.
+    #[arg(long = "unset", value_name = "PATH")]
+    pub unattend_unset: Vec<ElementPath>,
+
+    /// Adds an XML fragment as the last child of every element at PATH in
+    /// Autounattend.xml, e.g.
+    /// `windowsPE/Microsoft-Windows-Setup/UserData=<ProductKey><Key>XXXXX</Key></ProductKey>`.
+    /// Paths have the same form as in --set. The fragment must be written on a
+    /// single line and may use the `wcm` prefix (e.g. in `wcm:action="add"`)
+    /// without declaring it. May be specified multiple times. It is an error
+    /// for a path not to match any element.
+    #[arg(long = "insert", value_name = "PATH=XML")]
+    pub unattend_insert: Vec<UnattendInsertion>,
+}
+
+impl ImageSources {
+    /// Returns the input files whose digests should be computed and checked
+    /// before running a script.
+    pub fn input_files(&self) -> Vec<InputFile> {
+        let mut inputs = vec![
+            InputFile {
+                label: "Windows ISO",
+                path: self.windows_iso.clone(),
+                expected: self.windows_iso_sha256.clone(),
+            },
+            InputFile {
+                label: "virtio driver ISO",
+                path: self.virtio_iso.clone(),
+                expected: self.virtio_iso_sha256.clone(),
+            },
+        ];
+
+        if let Some(path) = &self.cloudbase_init_msi {
+            inputs.push(InputFile {
+                label: "cloudbase-init installer",
+                path: path.clone(),
+                expected: self.cloudbase_init_msi_sha256.clone(),
+            });
+        }
+
+        if let Some(path) = &self.openssh_zip {
+            inputs.push(InputFile {
+                label: "OpenSSH archive",
+                path: path.clone(),
+                expected: self.openssh_zip_sha256.clone(),
+            });
+        }
+
+        inputs
+    }
+
+    /// Returns the path supplied for each offline package, paired with the
+    /// name of the script context variable that holds it (see
+    /// `OFFLINE_PACKAGES`).
+    pub fn offline_packages(&self) -> Vec<(&'static str, &Utf8PathBuf)> {
+        [
+            ("cloudbase_init_msi", &self.cloudbase_init_msi),
+            ("openssh_zip", &self.openssh_zip),
+        ]
+        .into_iter()
+        .filter_map(|(var, path)| path.as_ref().map(|path| (var, path)))
+        .collect()
+    }
 }
@@ -0,0 +1,777 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Functions for generating complete answer files (Autounattend.xml and
+//! specialize-unattend.xml) from a typed description of the installation,
+//! rather than by editing a template.
+
+use std::{fs::File, io::BufWriter};
+
+use anyhow::{Context, Result};
+use camino::Utf8Path;
+
+use super::{
+    InstallerVolumeGuids, WindowsVersion, COMPONENT_ATTRIBUTES,
+    UNATTEND_NAMESPACE, WCM_NAMESPACE,
+};
+use crate::app::UnattendOptions;
+
+/// The namespace answer file components declare for `xsi:` attributes.
+const XSI_NAMESPACE: &str = "http://www.w3.org/2001/XMLSchema-instance";
+
+/// The platform on which an answer file will be used. This determines where
+/// Windows Setup looks for installation media, drivers, and scripts.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum UnattendPlatform {
+    /// A setup VM on a Linux host, with the Windows, virtio driver, and unattend
+    /// disks attached as separate CD-ROM drives.
+    Linux,
+
+    /// An all-in-one installation disk built by `build-installation-disk`.
+    Illumos,
+}
+
+/// The size of a partition Windows Setup should create.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PartitionSize {
+    /// A fixed size in megabytes.
+    Megabytes(u64),
+
+    /// Fill the rest of the disk.
+    Extend,
+}
+
+/// The kind of partition to create.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PartitionType {
+    Primary,
+    Efi,
+    Msr,
+}
+
+impl std::fmt::Display for PartitionType {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            PartitionType::Primary => write!(f, "Primary"),
+            PartitionType::Efi => write!(f, "EFI"),
+            PartitionType::Msr => write!(f, "MSR"),
+        }
+    }
+}
+
+/// A partition to create on the installation target disk, and how to format
+/// it afterwards.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Partition {
+    pub size: PartitionSize,
+    pub kind: PartitionType,
+    pub format: Option<String>,
+    pub letter: Option<char>,
+    pub label: Option<String>,
+    pub type_id: Option<String>,
+}
+
+impl Partition {
+    fn new(size: PartitionSize, kind: PartitionType) -> Self {
+        Self {
+            size,
+            kind,
+            format: None,
+            letter: None,
+            label: None,
+            type_id: None,
+        }
+    }
+}
+
+/// The partition layout of the disk Windows is installed to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DiskLayout {
+    /// The index of the disk to wipe and partition.
+    pub disk_id: u32,
+
+    /// The partitions to create, in order. Partition IDs start at 1.
+    pub partitions: Vec<Partition>,
+
+    /// The ID of the partition to install Windows into.
+    pub install_partition: u32,
+}
+
+impl Default for DiskLayout {
+    /// The standard UEFI layout: a recovery partition, an EFI system
+    /// partition, an MSR partition, and an OS partition filling the rest of
+    /// the disk.
+    fn default() -> Self {
+        Self {
+            disk_id: 0,
+            partitions: vec![
+                Partition {
+                    format: Some("NTFS".to_string()),
+                    label: Some("Recovery".to_string()),
+                    type_id: Some(
+                        "DE94BBA4-06D1-4D40-A16A-BFD50179D6AC".to_string(),
+                    ),
+                    ..Partition::new(
+                        PartitionSize::Megabytes(500),
+                        PartitionType::Primary,
+                    )
+                },
+                Partition {
+                    format: Some("FAT32".to_string()),
+                    label: Some("System".to_string()),
+                    ..Partition::new(
+                        PartitionSize::Megabytes(100),
+                        PartitionType::Efi,
+                    )
+                },
+                Partition::new(
+                    PartitionSize::Megabytes(128),
+                    PartitionType::Msr,
+                ),
+                Partition {
+                    format: Some("NTFS".to_string()),
+                    letter: Some('C'),
+                    label: Some("OS".to_string()),
+                    ..Partition::new(
+                        PartitionSize::Extend,
+                        PartitionType::Primary,
+                    )
+                },
+            ],
+            install_partition: 4,
+        }
+    }
+}
+
+/// The product key settings to put in the Microsoft-Windows-Setup component.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ProductKey {
+    /// The key to install, if any.
+    pub key: Option<String>,
+
+    /// When Setup should show the product key page ("Always", "OnError", or
+    /// "Never").
+    pub will_show_ui: String,
+}
+
+/// A description of the answer files to generate.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UnattendConfig {
+    /// The locale to use for Setup and the installed system, e.g. `en-US`.
+    pub locale: String,
+
+    /// The index of the image in `install.wim` to install.
+    pub image_index: u32,
+
+    /// The path to `install.wim`, if Setup shouldn't look for it on the
+    /// installation media.
+    pub install_from: Option<String>,
+
+    pub disk: DiskLayout,
+
+    /// The paths Windows should search for drivers during offline servicing.
+    pub driver_paths: Vec<String>,
+
+    pub product_key: Option<ProductKey>,
+
+    /// The command to run in audit mode to prepare the base image.
+    pub audit_command: String,
+
+    /// The time zone to set during the specialize pass, e.g. `UTC`.
+    pub time_zone: Option<String>,
+
+    /// The computer name to set during the specialize pass.
+    pub computer_name: Option<String>,
+}
+
+/// The commands the specialize pass runs on a generalized image's first boot,
+/// as (command, description, WillReboot) tuples.
+const SPECIALIZE_COMMANDS: &[(&str, &str, &str)] = &[
+    (
+        "net user administrator /active:no",
+        "Disable built-in Administrator account.",
+        "Never",
+    ),
+    (
+        "sc.exe config cloudbase-init start= auto",
+        "Re-enable cloudbase-init service.",
+        "Never",
+    ),
+    (
+        "cmd.exe /c \"\"C:\\Program Files\\Cloudbase Solutions\\Cloudbase-Init\\\
+        Python\\Scripts\\cloudbase-init.exe\" --config-file \"C:\\Program Files\\\
+        Cloudbase Solutions\\Cloudbase-Init\\conf\\cloudbase-init-unattend.conf\" \
+        && exit 1 || exit 2\"",
+        "Run Cloudbase-Init on first boot.",
+        "OnRequest",
+    ),
+];
+
+/// The settings in the OOBE element of the oobeSystem pass's
+/// Microsoft-Windows-Shell-Setup component.
+const OOBE_SETTINGS: &[(&str, &str)] = &[
+    ("HideEULAPage", "true"),
+    ("HideOEMRegistrationScreen", "true"),
+    ("HideOnlineAccountScreens", "true"),
+    ("HideLocalAccountScreen", "true"),
+    ("NetworkLocation", "Work"),
+    ("ProtectYourPC", "1"),
+];
+
+impl UnattendConfig {
+    /// The configuration used by `create-guest-disk-image` on Linux, which
+    /// attaches the virtio driver and unattend disks as CD-ROM drives whose
+    /// letters aren't known in advance.
+    pub fn linux(version: WindowsVersion) -> Self {
+        let drivers = ["NetKVM", "viostor"];
+        let driver_paths = ['D', 'E', 'F']
+            .iter()
+            .flat_map(|drive| {
+                drivers.iter().map(move |driver| {
+                    format!(
+                        "{drive}:\\{driver}\\{}\\amd64",
+                        version.as_driver_path_component()
+                    )
+                })
+            })
+            .collect();
+
+        Self {
+            locale: "en-US".to_string(),
+            image_index: 2,
+            install_from: None,
+            disk: DiskLayout::default(),
+            driver_paths,
+            product_key: Some(ProductKey {
+                key: None,
+                will_show_ui: "Never".to_string(),
+            }),
+            audit_command: "cmd /c \"FOR %i IN (D E F) DO IF EXIST \
+                %i:\\prep.cmd cmd.exe /c %i:\\prep.cmd %i\""
+                .to_string(),
+            time_zone: None,
+            computer_name: None,
+        }
+    }
+
+    /// The configuration used by `build-installation-disk` on illumos, which
+    /// refers to the installer disk's partitions by volume GUID.
+    pub fn illumos(guids: &InstallerVolumeGuids) -> Self {
+        Self {
+            locale: "en-US".to_string(),
+            image_index: 2,
+            install_from: Some(format!(
+                "\\\\?\\Volume{{{}}}\\install.wim",
+                guids.image
+            )),
+            disk: DiskLayout::default(),
+            driver_paths: vec![format!(
+                "\\\\?\\Volume{{{}}}\\virtio-drivers\\",
+                guids.setup
+            )],
+            product_key: None,
+            audit_command: format!(
+                "cmd.exe /c \"\\\\.\\Volume{{{}}}\\prep.cmd\"",
+                guids.setup
+            ),
+            time_zone: None,
+            computer_name: None,
+        }
+    }
+
+    /// Builds a configuration from the options passed to the
+    /// `generate-unattend` command.
+    pub fn from_options(options: &UnattendOptions) -> Self {
+        let mut config = match options.platform {
+            UnattendPlatform::Linux => Self::linux(options.windows_version),
+            UnattendPlatform::Illumos => {
+                Self::illumos(&default_illumos_guids())
+            }
+        };
+
+        config.locale = options.locale.clone();
+        config.image_index = options.image_index;
+        config.time_zone = options.time_zone.clone();
+        config.computer_name = options.computer_name.clone();
+        if let Some(key) = &options.product_key {
+            config.product_key = Some(ProductKey {
+                key: Some(key.clone()),
+                will_show_ui: "OnError".to_string(),
+            });
+        }
+
+        config
+    }
+
+    /// Writes an Autounattend.xml for this configuration to `output`.
+    pub fn write_autounattend<W: std::io::Write>(
+        &self,
+        output: W,
+    ) -> Result<()> {
+        let mut w = AnswerFileWriter::new(output);
+        w.start("unattend")?;
+
+        w.start_settings("windowsPE")?;
+        w.start_component("Microsoft-Windows-International-Core-WinPE")?;
+        w.start("SetupUILanguage")?;
+        w.text_element("UILanguage", &self.locale)?;
+        w.end()?;
+        w.locale_elements(&self.locale)?;
+        w.end()?;
+
+        w.start_component("Microsoft-Windows-Setup")?;
+        self.write_disk_configuration(&mut w)?;
+        self.write_image_install(&mut w)?;
+        w.start("UserData")?;
+        w.text_element("AcceptEula", "true")?;
+        if let Some(product_key) = &self.product_key {
+            w.start("ProductKey")?;
+            if let Some(key) = &product_key.key {
+                w.text_element("Key", key)?;
+            }
+            w.text_element("WillShowUI", &product_key.will_show_ui)?;
+            w.end()?;
+        }
+        w.end()?;
+        w.end()?;
+        w.end()?;
+
+        w.start_settings("offlineServicing")?;
+        w.start_component("Microsoft-Windows-PnpCustomizationsNonWinPE")?;
+        w.start("DriverPaths")?;
+        for (key, path) in self.driver_paths.iter().enumerate() {
+            w.start_list_item(
+                "PathAndCredentials",
+                Some(&(key + 1).to_string()),
+            )?;
+            w.text_element("Path", path)?;
+            w.end()?;
+        }
+        w.end()?;
+        w.end()?;
+        w.end()?;
+
+        w.start_settings("oobeSystem")?;
+        w.start_component("Microsoft-Windows-Deployment")?;
+        w.start("Reseal")?;
+        w.text_element("Mode", "Audit")?;
+        w.end()?;
+        w.end()?;
+        w.end()?;
+
+        w.start_settings("auditUser")?;
+        w.start_component("Microsoft-Windows-Deployment")?;
+        w.start("RunSynchronous")?;
+        w.start_list_item("RunSynchronousCommand", None)?;
+        w.text_element("Description", "Prepare Oxide base image")?;
+        w.text_element("Order", "1")?;
+        w.text_element("Path", &self.audit_command)?;
+        w.text_element("WillReboot", "OnRequest")?;
+        w.end()?;
+        w.end()?;
+        w.end()?;
+        w.end()?;
+
+        w.end()?;
+        Ok(())
+    }
+
+    /// Writes a specialize-unattend.xml for this configuration to `output`.
+    pub fn write_specialize_unattend<W: std::io::Write>(
+        &self,
+        output: W,
+    ) -> Result<()> {
+        let mut w = AnswerFileWriter::new(output);
+        w.start("unattend")?;
+
+        w.start_settings("generalize")?;
+        w.start_component("Microsoft-Windows-PnpSysprep")?;
+        w.text_element("PersistAllDeviceInstalls", "false")?;
+        w.end()?;
+        w.end()?;
+
+        w.start_settings("specialize")?;
+        w.start_component("Microsoft-Windows-Deployment")?;
+        w.start("RunSynchronous")?;
+        for (order, (path, description, will_reboot)) in
+            SPECIALIZE_COMMANDS.iter().enumerate()
+        {
+            w.start_list_item("RunSynchronousCommand", None)?;
+            w.text_element("Order", &(order + 1).to_string())?;
+            w.text_element("Path", path)?;
+            w.text_element("Description", description)?;
+            w.text_element("WillReboot", will_reboot)?;
+            w.end()?;
+        }
+        w.end()?;
+        w.end()?;
+
+        if self.computer_name.is_some() || self.time_zone.is_some() {
+            w.start_component("Microsoft-Windows-Shell-Setup")?;
+            if let Some(name) = &self.computer_name {
+                w.text_element("ComputerName", name)?;
+            }
+            if let Some(time_zone) = &self.time_zone {
+                w.text_element("TimeZone", time_zone)?;
+            }
+            w.end()?;
+        }
+        w.end()?;
+
+        w.start_settings("oobeSystem")?;
+        w.start_component("Microsoft-Windows-International-Core")?;
+        w.locale_elements(&self.locale)?;
+        w.end()?;
+        w.start_component("Microsoft-Windows-Shell-Setup")?;
+        w.start("OOBE")?;
+        for (name, value) in OOBE_SETTINGS {
+            w.text_element(name, value)?;
+        }
+        w.end()?;
+        w.end()?;
+        w.end()?;
+
+        w.end()?;
+        Ok(())
+    }
+
+    /// Writes Autounattend.xml and specialize-unattend.xml for this
+    /// configuration into `dir`, replacing any existing files.
+    pub fn write_to_dir(&self, dir: &Utf8Path) -> Result<()> {
+        let path = dir.join("Autounattend.xml");
+        let file =
+            File::create(&path).with_context(|| format!("creating {path}"))?;
+        self.write_autounattend(BufWriter::new(file))
+            .with_context(|| format!("writing {path}"))?;
+
+        let path = dir.join("specialize-unattend.xml");
+        let file =
+            File::create(&path).with_context(|| format!("creating {path}"))?;
+        self.write_specialize_unattend(BufWriter::new(file))
+            .with_context(|| format!("writing {path}"))?;
+
+        Ok(())
+    }
+
+    fn write_disk_configuration<W: std::io::Write>(
+        &self,
+        w: &mut AnswerFileWriter<W>,
+    ) -> Result<()> {
+        w.start("DiskConfiguration")?;
+        w.start_list_item("Disk", None)?;
+
+        w.start("CreatePartitions")?;
+        for (order, partition) in self.disk.partitions.iter().enumerate() {
+            w.start_list_item("CreatePartition", None)?;
+            w.text_element("Order", &(order + 1).to_string())?;
+            match partition.size {
+                PartitionSize::Megabytes(size) => {
+                    w.text_element("Size", &size.to_string())?
+                }
+                PartitionSize::Extend => w.text_element("Extend", "true")?,
+            }
+            w.text_element("Type", &partition.kind.to_string())?;
+            w.end()?;
+        }
+        w.end()?;
+
+        w.start("ModifyPartitions")?;
+        for (order, partition) in self.disk.partitions.iter().enumerate() {
+            let id = (order + 1).to_string();
+            w.start_list_item("ModifyPartition", None)?;
+            w.text_element("Order", &id)?;
+            w.text_element("PartitionID", &id)?;
+            if let Some(format) = &partition.format {
+                w.text_element("Format", format)?;
+            }
+            if let Some(letter) = partition.letter {
+                w.text_element("Letter", &letter.to_string())?;
+            }
+            if let Some(label) = &partition.label {
+                w.text_element("Label", label)?;
+            }
+            if let Some(type_id) = &partition.type_id {
+                w.text_element("TypeID", type_id)?;
+            }
+            w.end()?;
+        }
+        w.end()?;
+
+        w.text_element("DiskID", &self.disk.disk_id.to_string())?;
+        w.text_element("WillWipeDisk", "true")?;
+        w.end()?;
+        w.end()?;
+        Ok(())
+    }
+
+    fn write_image_install<W: std::io::Write>(
+        &self,
+        w: &mut AnswerFileWriter<W>,
+    ) -> Result<()> {
+        w.start("ImageInstall")?;
+        w.start("OSImage")?;
+        w.start("InstallFrom")?;
+        if let Some(path) = &self.install_from {
+            w.text_element("Path", path)?;
+        }
+        w.start_list_item("MetaData", None)?;
+        w.text_element("Key", "/IMAGE/INDEX")?;
+        w.text_element("Value", &self.image_index.to_string())?;
+        w.end()?;
+        w.end()?;
+        w.start("InstallTo")?;
+        w.text_element("DiskID", &self.disk.disk_id.to_string())?;
+        w.text_element(
+            "PartitionID",
+            &self.disk.install_partition.to_string(),
+        )?;
+        w.end()?;
+        w.end()?;
+        w.end()?;
+        Ok(())
+    }
+}
+
+/// Returns the volume GUIDs the illumos installer disk uses by default (i.e.
+/// the ones in the shipped `illumos/prep.cmd`).
+pub fn default_illumos_guids() -> InstallerVolumeGuids {
+    InstallerVolumeGuids {
+        setup: "569CBD84-352D-44D9-B92D-BF25B852925B".to_string(),
+        image: "A94E24F7-92C9-405C-82AA-9A1B45BA180C".to_string(),
+    }
+}
+
+/// Generates answer files from the options passed to the `generate-unattend`
+/// command.
+pub fn run_generate_unattend(options: &UnattendOptions) -> Result<()> {
+    let config = UnattendConfig::from_options(options);
+    std::fs::create_dir_all(&options.output_dir)
+        .with_context(|| format!("creating {}", options.output_dir))?;
+
+    config.write_to_dir(&options.output_dir)?;
+    for file in crate::validate::VALIDATED_FILES {
+        println!("Wrote {}", options.output_dir.join(file));
+    }
+
+    Ok(())
+}
+
+/// A thin wrapper around an indenting `xml::EventWriter` with helpers for the
+/// element shapes that appear in answer files.
+struct AnswerFileWriter<W: std::io::Write> {
+    inner: xml::EventWriter<W>,
+    depth: usize,
+}
+
+impl<W: std::io::Write> AnswerFileWriter<W> {
+    fn new(sink: W) -> Self {
+        let inner = xml::EventWriter::new_with_config(
+            sink,
+            xml::EmitterConfig::new()
+                .perform_indent(true)
+                .indent_string("    ")
+                .autopad_comments(false),
+        );
+
+        Self { inner, depth: 0 }
+    }
+
+    fn write<'a, E: Into<xml::writer::XmlEvent<'a>>>(
+        &mut self,
+        event: E,
+    ) -> Result<()> {
+        self.inner.write(event)?;
+        Ok(())
+    }
+
+    fn start(&mut self, name: &str) -> Result<()> {
+        let mut start = xml::writer::XmlEvent::start_element(name);
+        if self.depth == 0 {
+            start = start.default_ns(UNATTEND_NAMESPACE);
+        }
+
+        self.depth += 1;
+        self.write(start)
+    }
+
+    fn start_settings(&mut self, pass: &str) -> Result<()> {
+        self.depth += 1;
+        self.write(
+            xml::writer::XmlEvent::start_element("settings").attr("pass", pass),
+        )
+    }
+
+    fn start_component(&mut self, name: &str) -> Result<()> {
+        let mut start = xml::writer::XmlEvent::start_element("component")
+            .attr("name", name);
+        for (name, value) in COMPONENT_ATTRIBUTES {
+            start = start.attr(*name, value);
+        }
+
+        self.depth += 1;
+        self.write(start.ns("wcm", WCM_NAMESPACE).ns("xsi", XSI_NAMESPACE))
+    }
+
+    /// Starts an element that's added to a list of settings, e.g. a
+    /// `RunSynchronousCommand` with `wcm:action="add"`.
+    fn start_list_item(&mut self, name: &str, key: Option<&str>) -> Result<()> {
+        let mut start = xml::writer::XmlEvent::start_element(name)
+            .attr("wcm:action", "add");
+        if let Some(key) = key {
+            start = start.attr("wcm:keyValue", key);
+        }
+
+        self.depth += 1;
+        self.write(start)
+    }
+
+    fn end(&mut self) -> Result<()> {
+        self.depth -= 1;
+        self.write(xml::writer::XmlEvent::end_element())
+    }
+
+    fn text_element(&mut self, name: &str, value: &str) -> Result<()> {
+        self.write(xml::writer::XmlEvent::start_element(name))?;
+        self.write(xml::writer::XmlEvent::characters(value))?;
+        self.write(xml::writer::XmlEvent::end_element())
+    }
+
+    /// Writes the locale settings shared by the International-Core components.
+    fn locale_elements(&mut self, locale: &str) -> Result<()> {
+        for name in ["InputLocale", "SystemLocale", "UILanguage", "UserLocale"]
+        {
+            self.text_element(name, locale)?;
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const ILLUMOS_UNATTEND: &str =
+        include_str!("../../illumos/Autounattend.xml");
+    const LINUX_UNATTEND: &str =
+        include_str!("../../unattend/Autounattend.xml");
+    const SPECIALIZE_UNATTEND: &str =
+        include_str!("../../unattend/specialize-unattend.xml");
+
+    /// Reduces a document to its elements, attributes, and text, ignoring
+    /// formatting, comments, namespace declarations, and attribute order.
+    fn normalize(doc: &str) -> Vec<String> {
+        use xml::reader::XmlEvent;
+
+        super::super::new_reader(doc.as_bytes())
+            .into_iter()
+            .filter_map(|event| match event.unwrap() {
+                XmlEvent::StartElement { name, attributes, .. } => {
+                    let mut attributes: Vec<String> = attributes
+                        .iter()
+                        .map(|a| {
+                            format!("{}={}", a.name.borrow().to_repr(), a.value)
+                        })
+                        .collect();
+                    attributes.sort();
+                    Some(format!(
+                        "<{} {}>",
+                        name.local_name,
+                        attributes.join(" ")
+                    ))
+                }
+                XmlEvent::EndElement { name } => {
+                    Some(format!("</{}>", name.local_name))
+                }
+                XmlEvent::Characters(text) => Some(text),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn autounattend(config: &UnattendConfig) -> String {
+        let mut output = Vec::new();
+        config.write_autounattend(&mut output).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    fn specialize_unattend(config: &UnattendConfig) -> String {
+        let mut output = Vec::new();
+        config.write_specialize_unattend(&mut output).unwrap();
+        String::from_utf8(output).unwrap()
+    }
+
+    #[test]
+    fn linux_config_matches_template() {
+        let config = UnattendConfig::linux(WindowsVersion::Server2022);
+        assert_eq!(
+            normalize(&autounattend(&config)),
+            normalize(LINUX_UNATTEND)
+        );
+        assert_eq!(
+            normalize(&specialize_unattend(&config)),
+            normalize(SPECIALIZE_UNATTEND)
+        );
+    }
+
+    #[test]
+    fn illumos_config_matches_template() {
+        let config = UnattendConfig::illumos(&default_illumos_guids());
+        assert_eq!(
+            normalize(&autounattend(&config)),
+            normalize(ILLUMOS_UNATTEND)
+        );
+
+        // The generated file should identify the installer's partitions the
+        // same way the template does.
+        assert_eq!(
+            super::super::find_installer_volume_guids(&autounattend(&config))
+                .unwrap(),
+            default_illumos_guids()
+        );
+    }
+
+    #[test]
+    fn generated_files_validate() {
+        let mut config = UnattendConfig::linux(WindowsVersion::Windows11);
+        config.time_zone = Some("UTC".to_string());
+        config.computer_name = Some("oxide-vm".to_string());
+        config.product_key = Some(ProductKey {
+            key: Some("12345-12345-12345-12345-12345".to_string()),
+            will_show_ui: "OnError".to_string(),
+        });
+
+        let autounattend = autounattend(&config);
+        assert!(autounattend.contains("D:\\viostor\\w11\\amd64"));
+        assert!(
+            autounattend.contains("<Key>12345-12345-12345-12345-12345</Key>")
+        );
+        assert_eq!(
+            crate::validate::validate_answer_file_contents(&autounattend),
+            crate::validate::Problems::default()
+        );
+
+        let specialize = specialize_unattend(&config);
+        assert!(specialize.contains("<ComputerName>oxide-vm</ComputerName>"));
+        assert!(specialize.contains("<TimeZone>UTC</TimeZone>"));
+        assert_eq!(
+            crate::validate::validate_answer_file_contents(&specialize),
+            crate::validate::Problems::default()
+        );
+    }
+
+    #[test]
+    fn custom_disk_layout() {
+        let mut config = UnattendConfig::linux(WindowsVersion::Server2022);
+        config.disk.partitions.remove(0);
+        config.disk.install_partition = 3;
+
+        let doc = autounattend(&config);
+        assert!(!doc.contains("Recovery"));
+        assert_eq!(
+            crate::validate::validate_answer_file_contents(&doc),
+            crate::validate::Problems::default()
+        );
+    }
+}
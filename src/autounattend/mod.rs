@@ -0,0 +1,1710 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Functions for reading and updating a template Autounattend.xml inline, e.g.
+//! to select specific Windows editions and virtio driver versions.
+
+use std::{
+    collections::BTreeSet,
+    fs::File,
+    io::{BufReader, BufWriter},
+    path::Path,
+};
+
+use anyhow::{Context, Result};
+use itertools::Itertools;
+
+pub mod generate;
+
+#[derive(
+    Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum, serde::Serialize,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum WindowsVersion {
+    Server2016,
+    Server2019,
+    Server2022,
+    Server2025,
+    Windows10,
+    Windows11,
+}
+
+impl std::fmt::Display for WindowsVersion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WindowsVersion::Server2016 => write!(f, "Windows Server 2016"),
+            WindowsVersion::Server2019 => write!(f, "Windows Server 2019"),
+            WindowsVersion::Server2022 => write!(f, "Windows Server 2022"),
+            WindowsVersion::Server2025 => write!(f, "Windows Server 2025"),
+            WindowsVersion::Windows10 => write!(f, "Windows 10"),
+            WindowsVersion::Windows11 => write!(f, "Windows 11"),
+        }
+    }
+}
+
+impl WindowsVersion {
+    /// Every supported Windows version, in the order in which they're declared.
+    pub const ALL: &'static [WindowsVersion] = &[
+        WindowsVersion::Server2016,
+        WindowsVersion::Server2019,
+        WindowsVersion::Server2022,
+        WindowsVersion::Server2025,
+        WindowsVersion::Windows10,
+        WindowsVersion::Windows11,
+    ];
+
+    /// Yields the name of the versioned directory that contains this version's
+    /// drivers on a Fedora-style virtio driver ISO.
+    pub fn as_driver_path_component(&self) -> &'static str {
+        match self {
+            WindowsVersion::Server2016 => "2k16",
+            WindowsVersion::Server2019 => "2k19",
+            WindowsVersion::Server2022 => "2k22",
+            WindowsVersion::Server2025 => "2k25",
+            WindowsVersion::Windows10 => "w10",
+            WindowsVersion::Windows11 => "w11",
+        }
+    }
+
+    /// Returns the operating system name to record in an Oxide image's
+    /// metadata for this version.
+    pub fn image_os(&self) -> &'static str {
+        match self {
+            WindowsVersion::Server2016
+            | WindowsVersion::Server2019
+            | WindowsVersion::Server2022
+            | WindowsVersion::Server2025 => "Windows Server",
+            WindowsVersion::Windows10 | WindowsVersion::Windows11 => "Windows",
+        }
+    }
+
+    /// Returns the version to record in an Oxide image's metadata for this
+    /// version.
+    pub fn image_version(&self) -> &'static str {
+        match self {
+            WindowsVersion::Server2016 => "2016",
+            WindowsVersion::Server2019 => "2019",
+            WindowsVersion::Server2022 => "2022",
+            WindowsVersion::Server2025 => "2025",
+            WindowsVersion::Windows10 => "10",
+            WindowsVersion::Windows11 => "11",
+        }
+    }
+
+    /// Returns the version whose driver directory is named `component`, if
+    /// there is one.
+    pub fn from_driver_path_component(component: &str) -> Option<Self> {
+        Self::ALL
+            .iter()
+            .copied()
+            .find(|v| v.as_driver_path_component() == component)
+    }
+
+    /// Returns the version named at the start of the WIM image name `name`
+    /// (e.g. "Windows Server 2022 SERVERSTANDARD"), if there is one.
+    pub fn from_image_name(name: &str) -> Option<Self> {
+        Self::ALL.iter().copied().find(|v| {
+            name.strip_prefix(&v.to_string())
+                .is_some_and(|rest| rest.is_empty() || rest.starts_with(' '))
+        })
+    }
+}
+
+// The general idea here is to stream in elements from an Autounattend.xml
+// looking for a hierarchy of tags that matches something the caller wants to
+// replace. For editions, this is buried in the Microsoft-Windows-Setup
+// component:
+//
+// <unattend>
+//   <settings pass="windowsPE">
+//     <component name="Microsoft-Windows-Setup">
+//       <ImageInstall>
+//         <OSImage>
+//           <InstallFrom>
+//             <MetaData wcm:action="add">
+//               <Key>/IMAGE/INDEX</Key>
+//               <Value>2</Value> <-- replace this
+//
+// For drivers the component of interest is
+// Microsoft-Windows-PnpCustomizationsNonWinPE:
+//
+// <unattend>
+//   <settings pass="offlineServicing">
+//     <component name="Microsoft-Windows-PnpCustomizationsNonWinPE">
+//       <DriverPaths>
+//         <PathAndCredentials wcm:action="add" wcm:keyValue="1">
+//           <Path>D:\NetKVM\2k22\amd64</Path> <-- substitute for 2k22
+//         </PathAndCredentials>
+//         <PathAndCredentials wcm:action="add" wcm:keyValue="2">
+//           <Path>D:\viostor\2k22\amd64</Path>
+//         </PathAndCredentials>
+//         <!-- etc -->
+//
+// Note that only the Linux version of the tool uses the latter construction;
+// the illumos scripts copy appropriately versioned drivers out of the driver
+// ISO and put them into the installer disk directly, so it needs to substitute
+// paths when deciding where to copy from instead of changing the answer file.
+
+// Callers can also supply their own rules using a compact path syntax. See
+// [`ElementPath`] for details.
+
+/// An attribute within the start of an element that must be present in order
+/// for the element to match a rule.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct MatchAttribute {
+    name: String,
+    value: String,
+}
+
+/// A matching rule for the start of an element.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct MatchElement {
+    name: String,
+    attributes: Vec<MatchAttribute>,
+}
+
+impl MatchElement {
+    fn new(name: &str) -> Self {
+        Self { name: name.to_owned(), attributes: Vec::new() }
+    }
+
+    fn with_attribute(mut self, name: &str, value: &str) -> Self {
+        self.attributes.push(MatchAttribute {
+            name: name.to_owned(),
+            value: value.to_owned(),
+        });
+        self
+    }
+
+    fn matches(
+        &self,
+        name: &xml::name::OwnedName,
+        attributes: &[xml::attribute::OwnedAttribute],
+    ) -> bool {
+        // If this element's local name matches the rule's name and each
+        // attribute in the rule has a matching attribute in the element, this
+        // is a match.
+        self.name == name.local_name
+            && self.attributes.iter().all(|attr| {
+                attributes.iter().any(|xml_attr| {
+                    attr.name == xml_attr.name.local_name
+                        && attr.value == xml_attr.value
+                })
+            })
+    }
+}
+
+impl std::fmt::Display for MatchElement {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.name)?;
+        for attr in &self.attributes {
+            let quote = if attr.value.contains('\'') { '"' } else { '\'' };
+            write!(f, "[@{}={quote}{}{quote}]", attr.name, attr.value)?;
+        }
+
+        Ok(())
+    }
+}
+
+/// A path from the root of an answer file to a set of elements in it.
+///
+/// Paths are written as `/`-separated lists of element names, each of which
+/// may be followed by any number of attribute predicates of the form
+/// `[@name='value']`. An element matches a path component if its local name
+/// matches the component's name and it has every attribute the component
+/// specifies (attributes are also compared by local name, so `wcm:action` is
+/// written as `@action`).
+///
+/// Paths that begin with `/` are absolute and must name every element from the
+/// root `unattend` element down. All other paths begin with the name of a
+/// configuration pass and the name of a component in that pass, e.g.
+///
+/// `windowsPE/Microsoft-Windows-Setup/UserData/ProductKey/Key`
+///
+/// is shorthand for
+///
+/// `/unattend/settings[@pass='windowsPE']/component[@name='Microsoft-Windows-Setup']/UserData/ProductKey/Key`
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ElementPath {
+    elements: Vec<MatchElement>,
+}
+
+impl ElementPath {
+    fn from_elements(elements: Vec<MatchElement>) -> Self {
+        Self { elements }
+    }
+
+    fn len(&self) -> usize {
+        self.elements.len()
+    }
+}
+
+/// Splits `s` at each occurrence of `delimiter` that isn't inside a predicate
+/// or a quoted string.
+fn split_outside_predicates(s: &str, delimiter: char) -> Vec<&str> {
+    let mut parts = Vec::new();
+    let mut in_predicate = false;
+    let mut quote = None;
+    let mut start = 0;
+    for (i, c) in s.char_indices() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (Some(_), _) => {}
+            (None, '\'' | '"') if in_predicate => quote = Some(c),
+            (None, '[') => in_predicate = true,
+            (None, ']') => in_predicate = false,
+            (None, c) if c == delimiter && !in_predicate => {
+                parts.push(&s[start..i]);
+                start = i + c.len_utf8();
+            }
+            _ => {}
+        }
+    }
+
+    parts.push(&s[start..]);
+    parts
+}
+
+/// Parses a single path component of the form `name[@attr='value']...`.
+fn parse_path_component(component: &str) -> Result<MatchElement> {
+    let (name, mut predicates) = match component.find('[') {
+        Some(idx) => component.split_at(idx),
+        None => (component, ""),
+    };
+
+    // Element names in the answer file schema may be namespace-qualified, but
+    // rules only ever compare local names.
+    let name = name.rsplit(':').next().unwrap_or(name);
+    if name.is_empty()
+        || !name
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.'))
+    {
+        anyhow::bail!("'{component}' does not begin with a valid element name");
+    }
+
+    let mut element = MatchElement::new(name);
+    while !predicates.is_empty() {
+        let Some((attr, rest)) =
+            predicates.strip_prefix("[@").and_then(|p| p.split_once('='))
+        else {
+            anyhow::bail!(
+                "malformed predicate '{predicates}' in '{component}' (expected \
+                [@name='value'])"
+            );
+        };
+
+        // Attribute values may themselves contain ']', so look for the end of
+        // the quoted value before looking for the end of the predicate.
+        let quote = rest.chars().next().filter(|c| matches!(c, '\'' | '"'));
+        let Some((value, rest)) = quote.and_then(|q| rest[1..].split_once(q))
+        else {
+            anyhow::bail!(
+                "predicate value in '{component}' must be quoted with ' or \""
+            );
+        };
+
+        let Some(rest) = rest.strip_prefix(']') else {
+            anyhow::bail!("unterminated predicate in '{component}'");
+        };
+
+        let attr = attr.rsplit(':').next().unwrap_or(attr);
+        if attr.is_empty() {
+            anyhow::bail!("predicate in '{component}' has no attribute name");
+        }
+
+        element = element.with_attribute(attr, value);
+        predicates = rest;
+    }
+
+    Ok(element)
+}
+
+impl std::str::FromStr for ElementPath {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let (absolute, path) = match s.strip_prefix('/') {
+            Some(path) => (true, path),
+            None => (false, s),
+        };
+
+        let mut components = split_outside_predicates(path, '/').into_iter();
+        let mut elements = Vec::new();
+        if !absolute {
+            let pass = components
+                .next()
+                .map(parse_path_component)
+                .transpose()?
+                .ok_or_else(|| anyhow::anyhow!("path is empty"))?;
+
+            elements.push(MatchElement::new("unattend"));
+            elements.push(MatchElement {
+                name: "settings".to_owned(),
+                attributes: std::iter::once(MatchAttribute {
+                    name: "pass".to_owned(),
+                    value: pass.name,
+                })
+                .chain(pass.attributes)
+                .collect(),
+            });
+
+            if let Some(component) = components.next() {
+                let component = parse_path_component(component)?;
+                elements.push(MatchElement {
+                    name: "component".to_owned(),
+                    attributes: std::iter::once(MatchAttribute {
+                        name: "name".to_owned(),
+                        value: component.name,
+                    })
+                    .chain(component.attributes)
+                    .collect(),
+                });
+            }
+        }
+
+        for component in components {
+            elements.push(parse_path_component(component)?);
+        }
+
+        if elements.is_empty() {
+            anyhow::bail!("path is empty");
+        }
+
+        Ok(Self { elements })
+    }
+}
+
+impl std::fmt::Display for ElementPath {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for element in &self.elements {
+            write!(f, "/{element}")?;
+        }
+
+        Ok(())
+    }
+}
+
+// Paths (and the settings and insertions below) are recorded in build
+// manifests in the same form in which they're passed on the command line.
+impl serde::Serialize for ElementPath {
+    fn serialize<S: serde::Serializer>(
+        &self,
+        serializer: S,
+    ) -> std::result::Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// A request to set the text of the element at `path` to `value`, parsed from
+/// an expression of the form `path=value`.
+#[derive(Clone, Debug)]
+pub struct UnattendSetting {
+    pub path: ElementPath,
+    pub value: String,
+}
+
+impl std::str::FromStr for UnattendSetting {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let parts = split_outside_predicates(s, '=');
+        if parts.len() < 2 {
+            anyhow::bail!("'{s}' is not of the form PATH=VALUE");
+        }
+
+        let path = parts[0];
+        let value = &s[path.len() + 1..];
+        if value.contains(['\n', '\r']) {
+            anyhow::bail!("values may not contain line breaks");
+        }
+
+        Ok(Self { path: path.parse()?, value: value.to_owned() })
+    }
+}
+
+impl std::fmt::Display for UnattendSetting {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}={}", self.path, self.value)
+    }
+}
+
+impl serde::Serialize for UnattendSetting {
+    fn serialize<S: serde::Serializer>(
+        &self,
+        serializer: S,
+    ) -> std::result::Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// A fragment of answer file XML containing one or more elements, along with
+/// the source text it was parsed from.
+#[derive(Clone, Debug)]
+pub struct UnattendFragment {
+    source: String,
+    events: Vec<xml::reader::XmlEvent>,
+}
+
+impl std::str::FromStr for UnattendFragment {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        // Fragments are parsed as though they appear inside a component, so
+        // they can use the answer file's default namespace and the `wcm`
+        // prefix without declaring them.
+        let wrapped = format!(
+            "<fragment xmlns=\"{UNATTEND_NAMESPACE}\" \
+            xmlns:wcm=\"{WCM_NAMESPACE}\">{s}</fragment>"
+        );
+
+        let mut events = Vec::new();
+        for e in new_reader(wrapped.as_bytes()) {
+            match e.with_context(|| format!("parsing XML fragment '{s}'"))? {
+                xml::reader::XmlEvent::StartDocument { .. }
+                | xml::reader::XmlEvent::EndDocument => {}
+                e => events.push(e),
+            }
+        }
+
+        // Drop the wrapper element.
+        events.remove(0);
+        events.pop();
+
+        if !events
+            .iter()
+            .any(|e| matches!(e, xml::reader::XmlEvent::StartElement { .. }))
+        {
+            anyhow::bail!("XML fragment '{s}' contains no elements");
+        }
+
+        Ok(Self { source: s.to_owned(), events })
+    }
+}
+
+impl std::fmt::Display for UnattendFragment {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.source)
+    }
+}
+
+/// A request to add `fragment` as the last child of every element at `path`,
+/// parsed from an expression of the form `path=fragment`.
+#[derive(Clone, Debug)]
+pub struct UnattendInsertion {
+    pub path: ElementPath,
+    pub fragment: UnattendFragment,
+}
+
+impl std::str::FromStr for UnattendInsertion {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let setting: UnattendSetting = s.parse().map_err(|_| {
+            anyhow::anyhow!("'{s}' is not of the form PATH=XML")
+        })?;
+
+        Ok(Self { path: setting.path, fragment: setting.value.parse()? })
+    }
+}
+
+impl std::fmt::Display for UnattendInsertion {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}={}", self.path, self.fragment)
+    }
+}
+
+impl serde::Serialize for UnattendInsertion {
+    fn serialize<S: serde::Serializer>(
+        &self,
+        serializer: S,
+    ) -> std::result::Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// The change a [`ReplacementRule`] makes to the elements it matches.
+enum ReplacementAction {
+    /// Replaces the text of each matching element using the supplied function.
+    /// Elements that don't exist are not created.
+    Replace(Box<dyn Fn(&str) -> String>),
+
+    /// Sets the text of each matching element to the supplied value, creating
+    /// the element (and any missing ancestors) if no element matches.
+    Set(String),
+
+    /// Removes each matching element and all of its children.
+    Delete,
+
+    /// Adds the supplied fragment as the last child of each matching element.
+    Insert(UnattendFragment),
+}
+
+/// A replacement rule, specifying a path to a set of elements and the action
+/// to take on each element that matches it.
+pub struct ReplacementRule {
+    path: ElementPath,
+    action: ReplacementAction,
+}
+
+impl ReplacementRule {
+    /// Creates a rule that sets the text of the elements at `path` to `value`,
+    /// creating them if they don't exist.
+    pub fn set_text(path: ElementPath, value: impl Into<String>) -> Self {
+        Self { path, action: ReplacementAction::Set(value.into()) }
+    }
+
+    /// Creates a rule that removes the elements at `path`.
+    pub fn delete(path: ElementPath) -> Self {
+        Self { path, action: ReplacementAction::Delete }
+    }
+
+    /// Creates a rule that adds `fragment` as the last child of each element
+    /// at `path`.
+    pub fn insert(path: ElementPath, fragment: UnattendFragment) -> Self {
+        Self { path, action: ReplacementAction::Insert(fragment) }
+    }
+
+    fn replace_with(
+        elements: Vec<MatchElement>,
+        replace_fn: impl Fn(&str) -> String + 'static,
+    ) -> Self {
+        Self {
+            path: ElementPath::from_elements(elements),
+            action: ReplacementAction::Replace(Box::new(replace_fn)),
+        }
+    }
+}
+
+impl From<UnattendSetting> for ReplacementRule {
+    fn from(value: UnattendSetting) -> Self {
+        Self::set_text(value.path, value.value)
+    }
+}
+
+impl From<UnattendInsertion> for ReplacementRule {
+    fn from(value: UnattendInsertion) -> Self {
+        Self::insert(value.path, value.fragment)
+    }
+}
+
+/// The default namespace for elements in an answer file.
+pub(crate) const UNATTEND_NAMESPACE: &str =
+    "urn:schemas-microsoft-com:unattend";
+
+/// The namespace that defines the attributes answer files use to manipulate
+/// lists of settings (e.g. `wcm:action` and `wcm:keyValue`).
+pub(crate) const WCM_NAMESPACE: &str =
+    "http://schemas.microsoft.com/WMIConfig/2002/State";
+
+/// The attributes Windows Setup expects every `component` element to have, and
+/// their values for the components this tool works with.
+const COMPONENT_ATTRIBUTES: &[(&str, &str)] = &[
+    ("processorArchitecture", "amd64"),
+    ("publicKeyToken", "31bf3856ad364e35"),
+    ("language", "neutral"),
+    ("versionScope", "nonSxS"),
+];
+
+/// Writes to `output` a chain of nested elements described by `elements`,
+/// giving the innermost element the text `value`.
+fn write_missing_elements<W: std::io::Write>(
+    output: &mut xml::EventWriter<W>,
+    elements: &[MatchElement],
+    value: &str,
+) -> Result<()> {
+    for element in elements {
+        let attribute_names: Vec<String> = element
+            .attributes
+            .iter()
+            .map(|attr| match attr.name.as_str() {
+                "action" | "keyValue" => format!("wcm:{}", attr.name),
+                name => name.to_owned(),
+            })
+            .collect();
+
+        let mut start =
+            xml::writer::XmlEvent::start_element(element.name.as_str());
+        for (name, attr) in attribute_names.iter().zip(&element.attributes) {
+            start = start.attr(name.as_str(), &attr.value);
+        }
+
+        if element.name == "component" {
+            for (name, value) in COMPONENT_ATTRIBUTES {
+                if !element.attributes.iter().any(|attr| attr.name == *name) {
+                    start = start.attr(*name, value);
+                }
+            }
+
+            start = start.ns("wcm", WCM_NAMESPACE);
+        }
+
+        output.write(start)?;
+    }
+
+    output.write(xml::writer::XmlEvent::characters(value))?;
+    for _ in elements {
+        output.write(xml::writer::XmlEvent::end_element())?;
+    }
+
+    Ok(())
+}
+
+/// The state of a single rule with respect to a single open element.
+#[derive(Clone, Copy, Default)]
+struct RuleState {
+    /// True if this element and all its ancestors match the first elements in
+    /// the rule's path.
+    on_path: bool,
+
+    /// True if this element matches the rule's entire path.
+    matched: bool,
+
+    /// True if this element or one of its descendants matched the rule's entire
+    /// path.
+    found: bool,
+
+    /// True if the rule has written replacement text into this element.
+    wrote_text: bool,
+}
+
+/// An element whose start has been read but whose end has not.
+struct OpenElement {
+    /// The whitespace that preceded the most recent child of this element,
+    /// used to indent any new children added to it.
+    child_whitespace: Option<String>,
+
+    /// The state of each rule in the updater with respect to this element.
+    rules: Vec<RuleState>,
+}
+
+/// Writes out and clears any `whitespace` that was held back while deciding
+/// whether to keep the element that follows it.
+fn flush_whitespace<W: std::io::Write>(
+    output: &mut xml::EventWriter<W>,
+    whitespace: &mut Option<String>,
+) -> Result<()> {
+    if let Some(ws) = whitespace.take() {
+        output.write(xml::writer::XmlEvent::characters(&ws))?;
+    }
+
+    Ok(())
+}
+
+/// Creates a reader for an answer file. Comments are preserved so that they
+/// survive being written back out.
+pub(crate) fn new_reader<R: std::io::Read>(source: R) -> xml::EventReader<R> {
+    xml::EventReader::new_with_config(
+        source,
+        xml::ParserConfig::new().ignore_comments(false),
+    )
+}
+
+/// Creates a writer for an answer file that reproduces comments from the input
+/// verbatim.
+fn new_writer<W: std::io::Write>(sink: W) -> xml::EventWriter<W> {
+    xml::EventWriter::new_with_config(
+        sink,
+        xml::EmitterConfig::new().autopad_comments(false),
+    )
+}
+
+fn replace_image_index(_: &str, new_index: u32) -> String {
+    new_index.to_string()
+}
+
+/// Replaces every component of `path` that names a versioned driver directory
+/// (e.g. `2k22` or `w11`) with the directory name for `version`.
+fn replace_version_in_driver_path(
+    path: &str,
+    version: WindowsVersion,
+) -> String {
+    path.split('\\')
+        .map(|component| {
+            if WindowsVersion::from_driver_path_component(component).is_some() {
+                version.as_driver_path_component()
+            } else {
+                component
+            }
+        })
+        .collect::<Vec<_>>()
+        .join("\\")
+}
+
+/// The volume GUIDs an answer file uses to find its files on an all-in-one
+/// installer disk (see the `build-installation-disk` command).
+#[derive(Debug, PartialEq, Eq)]
+pub struct InstallerVolumeGuids {
+    /// The GUID of the partition containing WinPE, the unattend scripts, and
+    /// the virtio drivers.
+    pub setup: String,
+
+    /// The GUID of the partition containing `install.wim`.
+    pub image: String,
+}
+
+/// Returns true if `s` is a GUID in the usual 8-4-4-4-12 hex digit format.
+fn is_guid(s: &str) -> bool {
+    let groups: Vec<&str> = s.split('-').collect();
+    groups.len() == 5
+        && groups.iter().zip([8, 4, 4, 4, 12]).all(|(g, len)| {
+            g.len() == len && g.chars().all(|c| c.is_ascii_hexdigit())
+        })
+}
+
+/// Returns the GUID of every `Volume{GUID}` path component in `text`, in
+/// upper case. The braces may be escaped with backticks, as they must be in
+/// PowerShell command lines.
+pub fn volume_guids_in(text: &str) -> Vec<String> {
+    text.match_indices("Volume")
+        .filter_map(|(idx, _)| {
+            let rest = &text[idx + "Volume".len()..];
+            let rest = rest.strip_prefix('`').unwrap_or(rest);
+            let rest = rest.strip_prefix('{')?;
+            let (guid, _) = rest.split_once('}')?;
+            let guid = guid.strip_suffix('`').unwrap_or(guid);
+            is_guid(guid).then(|| guid.to_ascii_uppercase())
+        })
+        .collect()
+}
+
+/// Reads the volume GUIDs an installer answer file refers to. The GUID in
+/// `ImageInstall/OSImage/InstallFrom/Path` identifies the image partition;
+/// every other volume path in the file must refer to the setup partition.
+pub fn find_installer_volume_guids(
+    contents: &str,
+) -> Result<InstallerVolumeGuids> {
+    use xml::reader::XmlEvent;
+
+    let mut open: Vec<String> = Vec::new();
+    let mut setup = BTreeSet::new();
+    let mut image = BTreeSet::new();
+    for e in new_reader(contents.as_bytes()) {
+        match e.context("reading volume GUIDs from Autounattend.xml")? {
+            XmlEvent::StartElement { name, .. } => open.push(name.local_name),
+            XmlEvent::EndElement { .. } => {
+                open.pop();
+            }
+            XmlEvent::Characters(data) => {
+                let guids = volume_guids_in(&data);
+                if open.ends_with(&["InstallFrom".into(), "Path".into()]) {
+                    image.extend(guids);
+                } else {
+                    setup.extend(guids);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    let exactly_one = |guids: BTreeSet<String>, what: &str| {
+        if guids.len() == 1 {
+            Ok(guids.into_iter().next().unwrap())
+        } else {
+            Err(anyhow::anyhow!(
+                "Autounattend.xml must refer to exactly one {what} volume, \
+                but refers to {} ({})",
+                guids.len(),
+                guids.iter().join(", ")
+            ))
+        }
+    };
+
+    let guids = InstallerVolumeGuids {
+        setup: exactly_one(setup, "setup")?,
+        image: exactly_one(image, "installation image (InstallFrom)")?,
+    };
+
+    if guids.setup == guids.image {
+        anyhow::bail!(
+            "Autounattend.xml uses volume {} for both the setup and \
+            installation image partitions",
+            guids.setup
+        );
+    }
+
+    Ok(guids)
+}
+
+/// Checks that every volume path in a `prep.cmd` script refers to the setup
+/// partition in `guids`.
+pub fn check_prep_cmd_volume_guids(
+    contents: &str,
+    guids: &InstallerVolumeGuids,
+) -> Result<()> {
+    let mismatched: BTreeSet<String> = volume_guids_in(contents)
+        .into_iter()
+        .filter(|guid| *guid != guids.setup)
+        .collect();
+
+    if !mismatched.is_empty() {
+        anyhow::bail!(
+            "prep.cmd and Autounattend.xml disagree about the setup \
+            partition's volume GUID:\n  Autounattend.xml: {}\n  prep.cmd:         \
+            {}",
+            guids.setup,
+            mismatched.iter().join(", ")
+        );
+    }
+
+    Ok(())
+}
+
+/// The settings in an answer file that determine what gets installed.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct AnswerFileSummary {
+    /// The image index in `ImageInstall/OSImage/InstallFrom/MetaData`, which
+    /// selects the Windows edition to install.
+    pub image_index: Option<u32>,
+
+    /// The Windows versions named by the versioned directories in the answer
+    /// file's driver paths, in the order in which they first appear.
+    pub driver_versions: Vec<WindowsVersion>,
+}
+
+/// Reads the image index and driver versions from the answer file in
+/// `contents`.
+pub fn summarize_answer_file(contents: &str) -> Result<AnswerFileSummary> {
+    use xml::reader::XmlEvent;
+
+    let mut summary = AnswerFileSummary::default();
+    let mut stack: Vec<String> = Vec::new();
+    let mut metadata_key = None;
+    for event in new_reader(contents.as_bytes()) {
+        match event.context("parsing answer file")? {
+            XmlEvent::StartElement { name, .. } => {
+                if name.local_name == "MetaData" {
+                    metadata_key = None;
+                }
+                stack.push(name.local_name);
+            }
+            XmlEvent::EndElement { .. } => {
+                stack.pop();
+            }
+            XmlEvent::Characters(text) => {
+                let parent = stack.len().checked_sub(2).map(|i| &stack[i]);
+                match (parent.map(String::as_str), stack.last()) {
+                    (Some("MetaData"), Some(elem)) if elem == "Key" => {
+                        metadata_key = Some(text.trim().to_owned());
+                    }
+                    (Some("MetaData"), Some(elem))
+                        if elem == "Value"
+                            && metadata_key.as_deref()
+                                == Some("/IMAGE/INDEX") =>
+                    {
+                        summary.image_index = text.trim().parse().ok();
+                    }
+                    (Some("PathAndCredentials"), Some(elem))
+                        if elem == "Path" =>
+                    {
+                        for version in text.split('\\').filter_map(
+                            WindowsVersion::from_driver_path_component,
+                        ) {
+                            if !summary.driver_versions.contains(&version) {
+                                summary.driver_versions.push(version);
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok(summary)
+}
+
+pub struct AutounattendUpdater {
+    rules: Vec<ReplacementRule>,
+}
+
+impl AutounattendUpdater {
+    pub fn new(
+        image_index: Option<u32>,
+        virtio_driver_version: Option<WindowsVersion>,
+    ) -> Self {
+        let mut rules = Vec::new();
+        if let Some(index) = image_index {
+            let elements = vec![
+                MatchElement::new("unattend"),
+                MatchElement::new("settings")
+                    .with_attribute("pass", "windowsPE"),
+                MatchElement::new("component")
+                    .with_attribute("name", "Microsoft-Windows-Setup"),
+                MatchElement::new("ImageInstall"),
+                MatchElement::new("OSImage"),
+                MatchElement::new("InstallFrom"),
+                MatchElement::new("MetaData").with_attribute("action", "add"),
+                MatchElement::new("Value"),
+            ];
+
+            rules.push(ReplacementRule::replace_with(elements, move |old| {
+                replace_image_index(old, index)
+            }));
+        }
+
+        if let Some(version) = virtio_driver_version {
+            let elements = vec![
+                MatchElement::new("unattend"),
+                MatchElement::new("settings")
+                    .with_attribute("pass", "offlineServicing"),
+                MatchElement::new("component").with_attribute(
+                    "name",
+                    "Microsoft-Windows-PnpCustomizationsNonWinPE",
+                ),
+                MatchElement::new("DriverPaths"),
+                MatchElement::new("PathAndCredentials")
+                    .with_attribute("action", "add"),
+                MatchElement::new("Path"),
+            ];
+
+            rules.push(ReplacementRule::replace_with(elements, move |path| {
+                replace_version_in_driver_path(path, version)
+            }));
+        }
+
+        Self { rules }
+    }
+
+    /// Adds `rule` to the set of rules this updater applies. Rules are applied
+    /// in the order in which they were added.
+    pub fn add_rule(&mut self, rule: ReplacementRule) {
+        self.rules.push(rule);
+    }
+
+    /// Applies this updater's rules to the answer file at `input`, writing the
+    /// result to `output`.
+    ///
+    /// Returns the total number of times any rule matched, or an error if any
+    /// rule matched no elements at all.
+    pub fn run(
+        &self,
+        input: impl AsRef<Path>,
+        output: impl AsRef<Path>,
+    ) -> Result<usize> {
+        let infile = File::open(input)?;
+        let reader = new_reader(BufReader::new(infile));
+        let outfile = File::create(output)?;
+        let writer = new_writer(BufWriter::new(outfile));
+
+        self.run_internal(reader, writer)
+    }
+
+    fn run_internal<R: std::io::Read, W: std::io::Write>(
+        &self,
+        input: xml::EventReader<R>,
+        mut output: xml::EventWriter<W>,
+    ) -> Result<usize> {
+        use xml::reader::XmlEvent;
+
+        let mut matches = vec![0; self.rules.len()];
+        let mut open: Vec<OpenElement> = Vec::new();
+
+        // Whitespace is held back until the next event is read so that it can
+        // be dropped along with a deleted element that follows it.
+        let mut whitespace: Option<String> = None;
+
+        // The number of elements within a deleted element that are still open.
+        // Events are discarded while this is nonzero.
+        let mut deleting = 0usize;
+
+        for e in input {
+            let e = e.context("updating Autounattend.xml")?;
+            if deleting > 0 {
+                match e {
+                    XmlEvent::StartElement { .. } => deleting += 1,
+                    XmlEvent::EndElement { .. } => deleting -= 1,
+                    _ => {}
+                }
+
+                continue;
+            }
+
+            match &e {
+                XmlEvent::Whitespace(ws) => {
+                    whitespace.get_or_insert_with(String::new).push_str(ws);
+                    continue;
+                }
+                XmlEvent::StartElement { name, attributes, .. } => {
+                    let depth = open.len();
+                    let states: Vec<RuleState> = self
+                        .rules
+                        .iter()
+                        .enumerate()
+                        .map(|(rule_index, rule)| {
+                            let parent_on_path = open
+                                .last()
+                                .is_none_or(|p| p.rules[rule_index].on_path);
+
+                            let on_path = parent_on_path
+                                && depth < rule.path.len()
+                                && rule.path.elements[depth]
+                                    .matches(name, attributes);
+
+                            let matched =
+                                on_path && depth + 1 == rule.path.len();
+
+                            RuleState {
+                                on_path,
+                                matched,
+                                found: matched,
+                                wrote_text: false,
+                            }
+                        })
+                        .collect();
+
+                    for (count, state) in matches.iter_mut().zip(&states) {
+                        if state.matched {
+                            *count += 1;
+                        }
+                    }
+
+                    let delete =
+                        self.rules.iter().zip(&states).any(|(rule, state)| {
+                            state.matched
+                                && matches!(
+                                    rule.action,
+                                    ReplacementAction::Delete
+                                )
+                        });
+
+                    if delete {
+                        // Drop the deleted element's indentation along with
+                        // the element itself, and let its ancestors know that
+                        // the rules it matched were satisfied.
+                        whitespace = None;
+                        if let Some(parent) = open.last_mut() {
+                            for (p, s) in parent.rules.iter_mut().zip(&states) {
+                                p.found |= s.found;
+                            }
+                        }
+
+                        deleting = 1;
+                        continue;
+                    }
+
+                    if let Some(parent) = open.last_mut() {
+                        parent.child_whitespace.clone_from(&whitespace);
+                    }
+
+                    open.push(OpenElement {
+                        child_whitespace: None,
+                        rules: states,
+                    });
+                }
+                XmlEvent::Characters(data) => {
+                    let Some(element) = open.last_mut() else {
+                        anyhow::bail!("found text outside the root element");
+                    };
+
+                    let mut new_data: Option<String> = None;
+                    for (rule, state) in
+                        self.rules.iter().zip(element.rules.iter_mut())
+                    {
+                        if !state.matched {
+                            continue;
+                        }
+
+                        // If multiple rules match the same element, apply them
+                        // in order, feeding the output of each rule into the
+                        // next.
+                        let old = new_data.as_deref().unwrap_or(data);
+                        new_data = Some(match &rule.action {
+                            ReplacementAction::Replace(replace_fn) => {
+                                replace_fn(old)
+                            }
+                            ReplacementAction::Set(value) => value.clone(),
+                            ReplacementAction::Insert(_) => continue,
+                            ReplacementAction::Delete => {
+                                unreachable!("deleted elements have no text")
+                            }
+                        });
+
+                        state.wrote_text = true;
+                    }
+
+                    if let Some(new_data) = new_data {
+                        flush_whitespace(&mut output, &mut whitespace)?;
+                        output.write(xml::writer::XmlEvent::characters(
+                            &new_data,
+                        ))?;
+                        continue;
+                    }
+                }
+                XmlEvent::EndElement { .. } => {
+                    let Some(mut element) = open.pop() else {
+                        anyhow::bail!("found unbalanced end element");
+                    };
+
+                    let depth = open.len();
+                    for ((rule, state), count) in self
+                        .rules
+                        .iter()
+                        .zip(element.rules.iter_mut())
+                        .zip(matches.iter_mut())
+                    {
+                        match &rule.action {
+                            ReplacementAction::Set(value)
+                                if state.matched && !state.wrote_text =>
+                            {
+                                // The element exists but is empty, so just
+                                // write the value into it.
+                                flush_whitespace(&mut output, &mut whitespace)?;
+                                output.write(
+                                    xml::writer::XmlEvent::characters(value),
+                                )?;
+                            }
+                            ReplacementAction::Set(value)
+                                if state.on_path && !state.found =>
+                            {
+                                // This is the deepest element on the rule's
+                                // path and nothing inside it matched, so add
+                                // the rest of the path as new children of this
+                                // element.
+                                if let Some(ws) = &element.child_whitespace {
+                                    output.write(
+                                        xml::writer::XmlEvent::characters(ws),
+                                    )?;
+                                }
+
+                                write_missing_elements(
+                                    &mut output,
+                                    &rule.path.elements[depth + 1..],
+                                    value,
+                                )?;
+
+                                state.found = true;
+                                *count += 1;
+                            }
+                            ReplacementAction::Insert(fragment)
+                                if state.matched =>
+                            {
+                                if let Some(ws) = &element.child_whitespace {
+                                    output.write(
+                                        xml::writer::XmlEvent::characters(ws),
+                                    )?;
+                                }
+
+                                for e in &fragment.events {
+                                    if let Some(writer_event) =
+                                        e.as_writer_event()
+                                    {
+                                        output.write(writer_event)?;
+                                    }
+                                }
+                            }
+                            _ => {}
+                        }
+                    }
+
+                    if let Some(parent) = open.last_mut() {
+                        for (p, s) in
+                            parent.rules.iter_mut().zip(&element.rules)
+                        {
+                            p.found |= s.found;
+                        }
+                    }
+                }
+                _ => {}
+            }
+
+            flush_whitespace(&mut output, &mut whitespace)?;
+            if let Some(writer_event) = e.as_writer_event() {
+                output.write(writer_event)?;
+            }
+        }
+
+        let unmatched: Vec<String> = self
+            .rules
+            .iter()
+            .zip(&matches)
+            .filter(|(_, count)| **count == 0)
+            .map(|(rule, _)| rule.path.to_string())
+            .collect();
+
+        if !unmatched.is_empty() {
+            anyhow::bail!(
+                "no elements in Autounattend.xml matched these paths: {}",
+                unmatched.join(", ")
+            );
+        }
+
+        Ok(matches.iter().sum())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const ILLUMOS_UNATTEND: &str =
+        include_str!("../../illumos/Autounattend.xml");
+    const LINUX_UNATTEND: &str =
+        include_str!("../../unattend/Autounattend.xml");
+    const SPECIALIZE_UNATTEND: &str =
+        include_str!("../../unattend/specialize-unattend.xml");
+    const ILLUMOS_PREP_CMD: &str = include_str!("../../illumos/prep.cmd");
+
+    #[test]
+    fn replace_illumos_unattend() {
+        let updater =
+            AutounattendUpdater::new(Some(1), Some(WindowsVersion::Server2016));
+
+        let reader = new_reader(ILLUMOS_UNATTEND.as_bytes());
+        let writer = new_writer(std::io::empty());
+
+        assert_eq!(updater.run_internal(reader, writer).unwrap(), 2);
+    }
+
+    #[test]
+    fn replace_linux_unattend() {
+        let updater =
+            AutounattendUpdater::new(Some(1), Some(WindowsVersion::Server2016));
+
+        let reader = new_reader(LINUX_UNATTEND.as_bytes());
+        let mut new: Vec<u8> = Vec::new();
+        let writer = new_writer(&mut new);
+
+        assert_eq!(updater.run_internal(reader, writer).unwrap(), 7);
+
+        let as_str = std::str::from_utf8(&new).unwrap();
+        assert!(LINUX_UNATTEND.contains("D:\\NetKVM\\2k22\\amd64"));
+        assert!(as_str.contains("D:\\NetKVM\\2k16\\amd64"));
+    }
+
+    #[test]
+    fn summarize_templates() {
+        assert_eq!(
+            summarize_answer_file(LINUX_UNATTEND).unwrap(),
+            AnswerFileSummary {
+                image_index: Some(2),
+                driver_versions: vec![WindowsVersion::Server2022],
+            }
+        );
+
+        // The illumos answer file's drivers aren't in versioned directories.
+        assert_eq!(
+            summarize_answer_file(ILLUMOS_UNATTEND).unwrap(),
+            AnswerFileSummary { image_index: Some(2), driver_versions: vec![] }
+        );
+    }
+
+    #[test]
+    fn replace_any_versioned_driver_path() {
+        for from in WindowsVersion::ALL {
+            for to in WindowsVersion::ALL {
+                let path = format!(
+                    "D:\\viostor\\{}\\amd64",
+                    from.as_driver_path_component()
+                );
+
+                assert_eq!(
+                    replace_version_in_driver_path(&path, *to),
+                    format!(
+                        "D:\\viostor\\{}\\amd64",
+                        to.as_driver_path_component()
+                    )
+                );
+            }
+        }
+
+        // Components that merely contain a version name are left alone.
+        assert_eq!(
+            replace_version_in_driver_path(
+                "D:\\w11drivers\\2k22x\\amd64",
+                WindowsVersion::Windows11
+            ),
+            "D:\\w11drivers\\2k22x\\amd64"
+        );
+    }
+
+    /// Runs `updater` over `input`, returning the number of matches and the
+    /// updated document.
+    fn run_updater(
+        updater: &AutounattendUpdater,
+        input: &str,
+    ) -> Result<(usize, String)> {
+        let reader = new_reader(input.as_bytes());
+        let mut new: Vec<u8> = Vec::new();
+        let writer = new_writer(&mut new);
+        let matches = updater.run_internal(reader, writer)?;
+        Ok((matches, String::from_utf8(new).unwrap()))
+    }
+
+    #[test]
+    fn parse_element_paths() {
+        let short: ElementPath =
+            "windowsPE/Microsoft-Windows-Setup/UserData/ProductKey/Key"
+                .parse()
+                .unwrap();
+
+        let long: ElementPath = "/unattend/settings[@pass='windowsPE']/\
+            component[@name=\"Microsoft-Windows-Setup\"]/UserData/ProductKey/Key"
+            .parse()
+            .unwrap();
+
+        assert_eq!(short, long);
+        assert_eq!(short.to_string().parse::<ElementPath>().unwrap(), short);
+
+        let with_predicates: ElementPath = "offlineServicing/\
+            Microsoft-Windows-PnpCustomizationsNonWinPE/DriverPaths/\
+            PathAndCredentials[@wcm:action='add'][@keyValue='a/b]c']/Path"
+            .parse()
+            .unwrap();
+
+        assert_eq!(with_predicates.len(), 6);
+        assert_eq!(
+            with_predicates.elements[4],
+            MatchElement::new("PathAndCredentials")
+                .with_attribute("action", "add")
+                .with_attribute("keyValue", "a/b]c")
+        );
+
+        for bad in [
+            "",
+            "/",
+            "windowsPE//Key",
+            "windowsPE/Setup[name='x']",
+            "windowsPE/Setup[@name=x]",
+            "windowsPE/Setup[@name='x'",
+            "windowsPE/Set up",
+        ] {
+            assert!(bad.parse::<ElementPath>().is_err(), "parsed '{bad}'");
+        }
+    }
+
+    #[test]
+    fn parse_settings() {
+        let setting: UnattendSetting =
+            "windowsPE/Microsoft-Windows-Setup/UserData/ProductKey/Key=A=B"
+                .parse()
+                .unwrap();
+
+        assert_eq!(setting.value, "A=B");
+        assert_eq!(setting.path.len(), 6);
+
+        let setting: UnattendSetting =
+            "/unattend/settings[@pass='specialize']=".parse().unwrap();
+        assert_eq!(setting.value, "");
+        assert_eq!(
+            setting.to_string().parse::<UnattendSetting>().unwrap().path,
+            setting.path
+        );
+
+        assert!("windowsPE/Microsoft-Windows-Setup[@name='a=b']"
+            .parse::<UnattendSetting>()
+            .is_err());
+    }
+
+    #[test]
+    fn set_existing_element_text() {
+        let mut updater = AutounattendUpdater::new(None, None);
+        updater.add_rule(
+            "windowsPE/Microsoft-Windows-International-Core-WinPE/UserLocale=\
+            de-DE"
+                .parse::<UnattendSetting>()
+                .unwrap()
+                .into(),
+        );
+
+        let (matches, output) = run_updater(&updater, LINUX_UNATTEND).unwrap();
+        assert_eq!(matches, 1);
+        assert!(output.contains("<UserLocale>de-DE</UserLocale>"));
+        assert!(output.contains("<SystemLocale>en-US</SystemLocale>"));
+    }
+
+    #[test]
+    fn set_inserts_missing_elements() {
+        let mut updater = AutounattendUpdater::new(None, None);
+        updater.add_rule(
+            "windowsPE/Microsoft-Windows-Setup/UserData/ProductKey/Key=XXXXX"
+                .parse::<UnattendSetting>()
+                .unwrap()
+                .into(),
+        );
+
+        // The Linux template has a ProductKey element with a commented-out
+        // Key, so only the Key should be added.
+        let (matches, output) = run_updater(&updater, LINUX_UNATTEND).unwrap();
+        assert_eq!(matches, 1);
+        assert!(output.contains(
+            "<WillShowUI>Never</WillShowUI>\n                    \
+            <Key>XXXXX</Key>\n                </ProductKey>"
+        ));
+
+        // The illumos template has no ProductKey at all.
+        let (matches, output) =
+            run_updater(&updater, ILLUMOS_UNATTEND).unwrap();
+        assert_eq!(matches, 1);
+        assert!(output.contains("<ProductKey><Key>XXXXX</Key></ProductKey>"));
+
+        // Running the same rule over the output should now replace the
+        // existing key instead of adding another one.
+        let (matches, again) = run_updater(&updater, &output).unwrap();
+        assert_eq!(matches, 1);
+        assert_eq!(again.matches("<Key>XXXXX</Key>").count(), 1);
+    }
+
+    #[test]
+    fn set_inserts_missing_component() {
+        let mut updater = AutounattendUpdater::new(None, None);
+        updater.add_rule(
+            "specialize/Microsoft-Windows-Shell-Setup/ComputerName=wimsy"
+                .parse::<UnattendSetting>()
+                .unwrap()
+                .into(),
+        );
+
+        let (matches, output) = run_updater(&updater, LINUX_UNATTEND).unwrap();
+        assert_eq!(matches, 1);
+        assert!(output.contains(
+            "<settings pass=\"specialize\">\
+            <component \
+            xmlns:wcm=\"http://schemas.microsoft.com/WMIConfig/2002/State\" \
+            name=\"Microsoft-Windows-Shell-Setup\" \
+            processorArchitecture=\"amd64\" \
+            publicKeyToken=\"31bf3856ad364e35\" language=\"neutral\" \
+            versionScope=\"nonSxS\">\
+            <ComputerName>wimsy</ComputerName></component></settings>"
+        ));
+    }
+
+    #[test]
+    fn delete_elements() {
+        let mut updater = AutounattendUpdater::new(None, None);
+        updater.add_rule(ReplacementRule::delete(
+            "offlineServicing/Microsoft-Windows-PnpCustomizationsNonWinPE/\
+            DriverPaths/PathAndCredentials[@keyValue='3']"
+                .parse()
+                .unwrap(),
+        ));
+
+        let (matches, output) = run_updater(&updater, LINUX_UNATTEND).unwrap();
+        assert_eq!(matches, 1);
+        assert!(!output.contains("E:\\NetKVM"));
+        assert!(output.contains("E:\\viostor"));
+        assert!(output.contains(
+            "</PathAndCredentials>\n                \
+            <PathAndCredentials wcm:action=\"add\" wcm:keyValue=\"4\">"
+        ));
+    }
+
+    #[test]
+    fn unmatched_rules_are_errors() {
+        let mut updater = AutounattendUpdater::new(None, None);
+        updater.add_rule(ReplacementRule::delete(
+            "windowsPE/Microsoft-Windows-Setup/UserData/ProductKey"
+                .parse()
+                .unwrap(),
+        ));
+
+        assert!(run_updater(&updater, LINUX_UNATTEND).is_ok());
+        let err = run_updater(&updater, ILLUMOS_UNATTEND).unwrap_err();
+        assert!(err.to_string().contains("ProductKey"));
+    }
+
+    /// Parses `doc` into a list of events, ignoring whitespace, so that two
+    /// documents can be compared regardless of how they're formatted.
+    fn significant_events(doc: &str) -> Vec<xml::reader::XmlEvent> {
+        new_reader(doc.as_bytes())
+            .into_iter()
+            .map(|e| e.unwrap())
+            .filter(|e| !matches!(e, xml::reader::XmlEvent::Whitespace(_)))
+            .collect()
+    }
+
+    #[test]
+    fn templates_round_trip() {
+        let updater = AutounattendUpdater::new(None, None);
+        for input in [ILLUMOS_UNATTEND, LINUX_UNATTEND, SPECIALIZE_UNATTEND] {
+            let (matches, output) = run_updater(&updater, input).unwrap();
+            assert_eq!(matches, 0);
+            assert_eq!(significant_events(&output), significant_events(input));
+        }
+    }
+
+    #[test]
+    fn insert_fragments() {
+        const PATH: &str = "offlineServicing/\
+            Microsoft-Windows-PnpCustomizationsNonWinPE/DriverPaths=\
+            <PathAndCredentials wcm:action=\"add\" wcm:keyValue=\"7\">\
+            <Path>G:\\drivers</Path></PathAndCredentials>";
+
+        let mut updater = AutounattendUpdater::new(None, None);
+        updater.add_rule(PATH.parse::<UnattendInsertion>().unwrap().into());
+
+        for input in [ILLUMOS_UNATTEND, LINUX_UNATTEND] {
+            let (matches, output) = run_updater(&updater, input).unwrap();
+            assert_eq!(matches, 1);
+            assert!(output.contains(
+                "</PathAndCredentials>\n                \
+                <PathAndCredentials wcm:action=\"add\" wcm:keyValue=\"7\">\
+                <Path>G:\\drivers</Path></PathAndCredentials>\n            \
+                </DriverPaths>"
+            ));
+
+            // The new entry should be visible to rules that run over the
+            // output.
+            let mut reader = AutounattendUpdater::new(None, None);
+            reader.add_rule(ReplacementRule::delete(
+                "offlineServicing/\
+                Microsoft-Windows-PnpCustomizationsNonWinPE/DriverPaths/\
+                PathAndCredentials[@action='add'][@keyValue='7']"
+                    .parse()
+                    .unwrap(),
+            ));
+
+            let (matches, restored) = run_updater(&reader, &output).unwrap();
+            assert_eq!(matches, 1);
+            assert_eq!(
+                restored,
+                run_updater(&AutounattendUpdater::new(None, None), input)
+                    .unwrap()
+                    .1
+            );
+        }
+    }
+
+    #[test]
+    fn insert_into_every_match() {
+        let mut updater = AutounattendUpdater::new(None, None);
+        updater.add_rule(
+            "offlineServicing/Microsoft-Windows-PnpCustomizationsNonWinPE/\
+            DriverPaths/PathAndCredentials=<Credentials><Domain>oxide\
+            </Domain></Credentials>"
+                .parse::<UnattendInsertion>()
+                .unwrap()
+                .into(),
+        );
+
+        let (matches, output) = run_updater(&updater, LINUX_UNATTEND).unwrap();
+        assert_eq!(matches, 6);
+        assert_eq!(output.matches("<Domain>oxide</Domain>").count(), 6);
+    }
+
+    #[test]
+    fn insert_component() {
+        let mut updater = AutounattendUpdater::new(None, None);
+        updater.add_rule(
+            "specialize=<component name=\"Microsoft-Windows-Shell-Setup\" \
+            processorArchitecture=\"amd64\" \
+            publicKeyToken=\"31bf3856ad364e35\" language=\"neutral\" \
+            versionScope=\"nonSxS\"><TimeZone>UTC</TimeZone></component>"
+                .parse::<UnattendInsertion>()
+                .unwrap()
+                .into(),
+        );
+
+        let (matches, output) =
+            run_updater(&updater, SPECIALIZE_UNATTEND).unwrap();
+        assert_eq!(matches, 1);
+
+        // The `wcm` namespace isn't in scope in a settings element, so the
+        // inserted component needs to declare it.
+        assert!(output.contains(
+            "<component xmlns:wcm=\"http://schemas.microsoft.com/WMIConfig/\
+            2002/State\" name=\"Microsoft-Windows-Shell-Setup\""
+        ));
+        assert!(output.contains(
+            "<TimeZone>UTC</TimeZone></component>\n  \
+            </settings>"
+        ));
+    }
+
+    #[test]
+    fn invalid_fragments() {
+        for bad in [
+            "windowsPE=",
+            "windowsPE=just text",
+            "windowsPE=<Unclosed>",
+            "windowsPE=<A></B>",
+            "windowsPE=<A>\n</A>",
+            "<A/>",
+        ] {
+            assert!(
+                bad.parse::<UnattendInsertion>().is_err(),
+                "parsed '{bad}'"
+            );
+        }
+    }
+
+    #[test]
+    fn illumos_volume_guids_agree() {
+        let guids = find_installer_volume_guids(ILLUMOS_UNATTEND).unwrap();
+        assert_eq!(
+            guids,
+            InstallerVolumeGuids {
+                setup: "569CBD84-352D-44D9-B92D-BF25B852925B".to_string(),
+                image: "A94E24F7-92C9-405C-82AA-9A1B45BA180C".to_string(),
+            }
+        );
+
+        assert_eq!(volume_guids_in(ILLUMOS_PREP_CMD).len(), 2);
+        check_prep_cmd_volume_guids(ILLUMOS_PREP_CMD, &guids).unwrap();
+    }
+
+    #[test]
+    fn mismatched_volume_guids() {
+        // The Linux answer file finds its files by drive letter, not volume.
+        assert!(find_installer_volume_guids(LINUX_UNATTEND).is_err());
+
+        // Pointing the driver path at a different volume leaves the answer
+        // file with two candidate setup volumes.
+        let two_setup_volumes = ILLUMOS_UNATTEND.replacen(
+            "569CBD84-352D-44D9-B92D-BF25B852925B",
+            "00000000-352D-44D9-B92D-BF25B852925B",
+            1,
+        );
+        let err = find_installer_volume_guids(&two_setup_volumes).unwrap_err();
+        assert!(err.to_string().contains("exactly one setup volume"));
+
+        // Changing every reference in the answer file should produce a diff
+        // against prep.cmd.
+        let moved = ILLUMOS_UNATTEND.replace(
+            "569CBD84-352D-44D9-B92D-BF25B852925B",
+            "569cbd84-0000-44d9-b92d-bf25b852925b",
+        );
+        let guids = find_installer_volume_guids(&moved).unwrap();
+        assert_eq!(guids.setup, "569CBD84-0000-44D9-B92D-BF25B852925B");
+        let err =
+            check_prep_cmd_volume_guids(ILLUMOS_PREP_CMD, &guids).unwrap_err();
+        assert!(err.to_string().contains(
+            "Autounattend.xml: 569CBD84-0000-44D9-B92D-BF25B852925B\n  \
+            prep.cmd:         569CBD84-352D-44D9-B92D-BF25B852925B"
+        ));
+    }
+
+    #[test]
+    fn replace_with_no_rules_is_noop() {
+        let updater = AutounattendUpdater::new(None, None);
+
+        for input in &[ILLUMOS_UNATTEND, LINUX_UNATTEND] {
+            let reader = new_reader(input.as_bytes());
+            let writer = new_writer(std::io::empty());
+            assert_eq!(updater.run_internal(reader, writer).unwrap(), 0);
+        }
+    }
+
+    #[test]
+    fn versions_from_image_names() {
+        for (name, version) in [
+            (
+                "Windows Server 2022 SERVERSTANDARD",
+                Some(WindowsVersion::Server2022),
+            ),
+            ("Windows Server 2016", Some(WindowsVersion::Server2016)),
+            ("Windows 10 Pro", Some(WindowsVersion::Windows10)),
+            ("Windows 11 Enterprise", Some(WindowsVersion::Windows11)),
+            ("Windows 100", None),
+            ("Windows Server 2012 R2 SERVERSTANDARD", None),
+        ] {
+            assert_eq!(
+                WindowsVersion::from_image_name(name),
+                version,
+                "{name}"
+            );
+        }
+    }
+}
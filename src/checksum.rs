@@ -0,0 +1,292 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Functions for computing SHA-256 digests of a script's input files and
+//! checking them against the digests the user expects.
+
+use std::{collections::HashMap, io::Read};
+
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::ui::{ProgressUnit, Ui};
+
+/// The size of the buffer used to stream files into the hasher.
+const HASH_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// A SHA-256 digest, stored as a lowercase hex string.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Sha256Digest(String);
+
+impl TryFrom<String> for Sha256Digest {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self> {
+        value.parse()
+    }
+}
+
+impl From<Sha256Digest> for String {
+    fn from(value: Sha256Digest) -> Self {
+        value.0
+    }
+}
+
+impl std::str::FromStr for Sha256Digest {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        if s.len() != 64 || !s.chars().all(|c| c.is_ascii_hexdigit()) {
+            anyhow::bail!(
+                "'{s}' is not a SHA-256 digest (expected 64 hex digits)"
+            );
+        }
+
+        Ok(Self(s.to_ascii_lowercase()))
+    }
+}
+
+impl std::fmt::Display for Sha256Digest {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// A file a script reads whose digest should be computed and, optionally,
+/// checked.
+pub struct InputFile {
+    /// A short description of the input, e.g. "Windows ISO".
+    pub label: &'static str,
+
+    pub path: Utf8PathBuf,
+
+    /// The digest the user supplied for this input on the command line, if
+    /// any.
+    pub expected: Option<Sha256Digest>,
+}
+
+/// An input file and the digest computed from its contents.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct HashedInput {
+    pub label: String,
+    pub path: Utf8PathBuf,
+    pub sha256: Sha256Digest,
+}
+
+/// Computes the SHA-256 digest of the file at `path`, reading it in chunks and
+/// reporting progress through `ui`.
+pub fn sha256_file(path: &Utf8Path, ui: &dyn Ui) -> Result<Sha256Digest> {
+    let mut file =
+        std::fs::File::open(path).with_context(|| format!("opening {path}"))?;
+    let len = file.metadata().with_context(|| format!("reading {path}"))?.len();
+
+    let mut hasher = Sha256::new();
+    let mut buf = vec![0u8; HASH_BUFFER_SIZE];
+    ui.start_progress(
+        &format!("computing SHA-256 digest of {path}"),
+        len,
+        ProgressUnit::Bytes,
+    );
+    let result = loop {
+        let read = match file.read(&mut buf) {
+            Ok(read) => read,
+            Err(e) => break Err(e).with_context(|| format!("reading {path}")),
+        };
+        if read == 0 {
+            break Ok(());
+        }
+
+        hasher.update(&buf[..read]);
+        ui.inc_progress(read as u64);
+    };
+
+    ui.finish_progress();
+    result?;
+
+    Ok(Sha256Digest(
+        hasher.finalize().iter().map(|b| format!("{b:02x}")).collect(),
+    ))
+}
+
+/// Parses the contents of a file in the format `sha256sum` produces, returning
+/// a map from file names (without any directory components) to their digests.
+pub fn parse_sha256sums(
+    contents: &str,
+) -> Result<HashMap<String, Sha256Digest>> {
+    let mut sums = HashMap::new();
+    for (number, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+
+        let (digest, name) =
+            line.split_once(char::is_whitespace).with_context(|| {
+                format!("line {}: expected DIGEST FILE", number + 1)
+            })?;
+
+        // `sha256sum` marks files hashed in binary mode with a leading `*`.
+        let name = name.trim_start();
+        let name = name.strip_prefix('*').unwrap_or(name);
+        let name = name.rsplit(['/', '\\']).next().unwrap_or(name);
+        let digest =
+            digest.parse().with_context(|| format!("line {}", number + 1))?;
+
+        sums.insert(name.to_owned(), digest);
+    }
+
+    Ok(sums)
+}
+
+/// Computes the digest of each of the `inputs` that exists and checks it
+/// against the expected digest for that input, which is either the one
+/// supplied with the input or the one listed for the input's file name in the
+/// `sha256sums` file. Returns the digests of the hashed inputs and a list of
+/// problems, including any mismatched digests.
+pub fn check_inputs(
+    inputs: &[InputFile],
+    sha256sums: Option<&Utf8Path>,
+    ui: &dyn Ui,
+) -> (Vec<HashedInput>, Vec<String>) {
+    let mut errors = Vec::new();
+    let sums = match sha256sums {
+        None => HashMap::new(),
+        Some(path) => match std::fs::read_to_string(path)
+            .with_context(|| format!("reading {path}"))
+            .and_then(|contents| parse_sha256sums(&contents))
+        {
+            Ok(sums) => sums,
+            Err(e) => {
+                errors.push(format!("invalid checksum file {path}: {e:#}"));
+                HashMap::new()
+            }
+        },
+    };
+
+    let mut hashed = Vec::new();
+    for input in inputs {
+        // Missing inputs are reported by the scripts' file checks.
+        if !input.path.is_file() {
+            continue;
+        }
+
+        let sha256 = match sha256_file(&input.path, ui) {
+            Ok(sha256) => sha256,
+            Err(e) => {
+                errors.push(format!("{e:#}"));
+                continue;
+            }
+        };
+
+        let expected = input
+            .expected
+            .as_ref()
+            .or_else(|| input.path.file_name().and_then(|name| sums.get(name)));
+
+        if let Some(expected) = expected {
+            if *expected != sha256 {
+                errors.push(format!(
+                    "{} '{}' has SHA-256 digest {sha256}, expected {expected}",
+                    input.label, input.path
+                ));
+            }
+        }
+
+        hashed.push(HashedInput {
+            label: input.label.to_owned(),
+            path: input.path.clone(),
+            sha256,
+        });
+    }
+
+    (hashed, errors)
+}
+
+/// Formats the digests of `inputs` in the format `sha256sum` produces.
+pub fn format_sha256sums(inputs: &[HashedInput]) -> String {
+    inputs
+        .iter()
+        .map(|input| format!("{}  {}\n", input.sha256, input.path))
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_support::{scratch_dir, NullUi};
+
+    const ABC_SHA256: &str =
+        "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad";
+
+    #[test]
+    fn parse_digests() {
+        assert_eq!(
+            ABC_SHA256.to_ascii_uppercase().parse::<Sha256Digest>().unwrap(),
+            ABC_SHA256.parse().unwrap()
+        );
+        assert!("abc".parse::<Sha256Digest>().is_err());
+        assert!(ABC_SHA256.replace('a', "g").parse::<Sha256Digest>().is_err());
+    }
+
+    #[test]
+    fn parse_sums_file() {
+        let sums = parse_sha256sums(&format!(
+            "# comment\n\n{ABC_SHA256}  windows.iso\n\
+            {ABC_SHA256} */srv/isos/virtio-win.iso\n"
+        ))
+        .unwrap();
+
+        assert_eq!(sums.len(), 2);
+        assert_eq!(sums["windows.iso"], ABC_SHA256.parse().unwrap());
+        assert_eq!(sums["virtio-win.iso"], ABC_SHA256.parse().unwrap());
+
+        assert!(parse_sha256sums("not-a-digest  windows.iso").is_err());
+        assert!(parse_sha256sums(ABC_SHA256).is_err());
+    }
+
+    #[test]
+    fn hash_and_check_inputs() {
+        let dir = scratch_dir("checksum");
+        let path = dir.join("abc.iso");
+        std::fs::write(&path, "abc").unwrap();
+
+        assert_eq!(
+            sha256_file(&path, &NullUi).unwrap(),
+            ABC_SHA256.parse().unwrap()
+        );
+
+        let input = |expected: Option<&str>| InputFile {
+            label: "test ISO",
+            path: path.clone(),
+            expected: expected.map(|e| e.parse().unwrap()),
+        };
+
+        let (hashed, errors) = check_inputs(&[input(None)], None, &NullUi);
+        assert!(errors.is_empty());
+        assert_eq!(
+            format_sha256sums(&hashed),
+            format!("{ABC_SHA256}  {path}\n")
+        );
+
+        let wrong = "0".repeat(64);
+        let (_, errors) = check_inputs(&[input(Some(&wrong))], None, &NullUi);
+        assert_eq!(errors.len(), 1);
+
+        // Digests in a sums file are matched by file name.
+        let sums = dir.join("SHA256SUMS");
+        std::fs::write(&sums, format!("{wrong}  abc.iso\n")).unwrap();
+        let (_, errors) = check_inputs(&[input(None)], Some(&sums), &NullUi);
+        assert_eq!(errors.len(), 1);
+
+        // Digests supplied directly take precedence over the sums file.
+        let (_, errors) =
+            check_inputs(&[input(Some(ABC_SHA256))], Some(&sums), &NullUi);
+        assert!(errors.is_empty());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,188 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Builds NoCloud configuration drives like the ones an Oxide rack supplies to
+//! its instances.
+//!
+//! A NoCloud drive is a FAT filesystem labeled `cidata` whose root directory
+//! contains a `meta-data` file (a YAML document with the instance's ID,
+//! hostname, and SSH public keys) and a `user-data` file (e.g. a script or a
+//! cloud-config document). cloudbase-init's `NoCloudConfigDriveService` reads
+//! these files from a virtual disk when the guest first boots.
+
+use anyhow::{Context as _, Result};
+use camino::Utf8Path;
+use sha2::{Digest, Sha256};
+
+use crate::{
+    app::ConfigDriveOptions,
+    vfat::{build_image, FatFile},
+};
+
+/// The volume label NoCloud data sources look for.
+const VOLUME_LABEL: &str = "cidata";
+
+/// The contents of a NoCloud configuration drive.
+pub struct ConfigDrive {
+    pub instance_id: String,
+    pub hostname: String,
+    pub public_keys: Vec<String>,
+    pub user_data: Vec<u8>,
+}
+
+impl ConfigDrive {
+    /// Returns the contents of the drive's `meta-data` file. Values are written
+    /// as JSON strings, which are also valid YAML strings, so that they needn't
+    /// be escaped by hand.
+    fn meta_data(&self) -> String {
+        let quote = |s: &str| serde_json::to_string(s).unwrap();
+        let mut meta_data = format!(
+            "instance-id: {}\nlocal-hostname: {}\n",
+            quote(&self.instance_id),
+            quote(&self.hostname)
+        );
+
+        if !self.public_keys.is_empty() {
+            meta_data.push_str("public-keys:\n");
+            for key in &self.public_keys {
+                meta_data.push_str(&format!("  - {}\n", quote(key)));
+            }
+        }
+
+        meta_data
+    }
+
+    /// Returns a raw disk image containing the drive's filesystem.
+    pub fn to_image(&self) -> Result<Vec<u8>> {
+        let meta_data = self.meta_data();
+
+        // Derive the volume serial number from the drive's contents so that
+        // the same configuration always produces the same image.
+        let digest = Sha256::new()
+            .chain_update(meta_data.as_bytes())
+            .chain_update(&self.user_data)
+            .finalize();
+        let serial = u32::from_le_bytes(digest[..4].try_into().unwrap());
+
+        build_image(
+            VOLUME_LABEL,
+            serial,
+            &[
+                FatFile { name: "meta-data", contents: meta_data.as_bytes() },
+                FatFile { name: "user-data", contents: &self.user_data },
+            ],
+        )
+    }
+
+    /// Writes the drive's disk image to `path`, replacing any existing file.
+    pub fn write_image(&self, path: &Utf8Path) -> Result<()> {
+        std::fs::write(path, self.to_image()?)
+            .with_context(|| format!("writing {path}"))
+    }
+}
+
+/// Returns an instance ID for a new drive for a guest named `hostname`. The ID
+/// includes the current time so that cloudbase-init treats every new drive as
+/// a new instance and reapplies its configuration.
+pub fn new_instance_id(hostname: &str) -> String {
+    let secs = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs();
+    format!("{hostname}-{secs}")
+}
+
+/// Reads the SSH public keys in the file at `path`, one per line, ignoring
+/// blank lines and comments.
+pub fn read_public_keys(path: &Utf8Path) -> Result<Vec<String>> {
+    let contents = std::fs::read_to_string(path)
+        .with_context(|| format!("reading SSH public keys from {path}"))?;
+    let keys: Vec<String> = contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_owned)
+        .collect();
+
+    if keys.is_empty() {
+        anyhow::bail!("{path} doesn't contain any SSH public keys");
+    }
+
+    Ok(keys)
+}
+
+/// Runs the make-config-drive command.
+pub fn run_make_config_drive(options: &ConfigDriveOptions) -> Result<()> {
+    let mut public_keys = Vec::new();
+    for path in &options.ssh_keys {
+        public_keys.extend(read_public_keys(path)?);
+    }
+
+    let user_data = match &options.user_data {
+        Some(path) => std::fs::read(path)
+            .with_context(|| format!("reading user data from {path}"))?,
+        None => Vec::new(),
+    };
+
+    let drive = ConfigDrive {
+        instance_id: options
+            .instance_id
+            .clone()
+            .unwrap_or_else(|| new_instance_id(&options.hostname)),
+        hostname: options.hostname.clone(),
+        public_keys,
+        user_data,
+    };
+
+    drive.write_image(&options.output)?;
+    println!("Wrote {}", options.output);
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn meta_data_quotes_values() {
+        let drive = ConfigDrive {
+            instance_id: "i-1".to_string(),
+            hostname: "host".to_string(),
+            public_keys: vec![
+                "ssh-ed25519 AAAA a: b".to_string(),
+                "ssh-rsa BBBB".to_string(),
+            ],
+            user_data: Vec::new(),
+        };
+
+        assert_eq!(
+            drive.meta_data(),
+            "instance-id: \"i-1\"\n\
+             local-hostname: \"host\"\n\
+             public-keys:\n  \
+               - \"ssh-ed25519 AAAA a: b\"\n  \
+               - \"ssh-rsa BBBB\"\n"
+        );
+
+        let drive = ConfigDrive { public_keys: Vec::new(), ..drive };
+        assert!(!drive.meta_data().contains("public-keys"));
+    }
+
+    #[test]
+    fn images_are_reproducible() {
+        let drive = ConfigDrive {
+            instance_id: "i-1".to_string(),
+            hostname: "host".to_string(),
+            public_keys: Vec::new(),
+            user_data: b"#ps1_sysnative\r\nhostname\r\n".to_vec(),
+        };
+
+        let image = drive.to_image().unwrap();
+        assert_eq!(image, drive.to_image().unwrap());
+        assert_eq!(&image[43..49], b"cidata");
+
+        let other = ConfigDrive { hostname: "other".to_string(), ..drive };
+        assert_ne!(image, other.to_image().unwrap());
+    }
+}
@@ -0,0 +1,450 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Reads GUID partition tables directly from disk images.
+
+use std::io::{Read, Seek, SeekFrom};
+
+use anyhow::{Context as _, Result};
+
+/// The signature at the start of a GPT header.
+const SIGNATURE: &[u8; 8] = b"EFI PART";
+
+/// The sector sizes to try when looking for the primary GPT header, which is
+/// always in the second sector.
+const SECTOR_SIZES: [u64; 2] = [512, 4096];
+
+/// The well-known partition type GUIDs of the partitions Windows Setup
+/// creates.
+pub const EFI_SYSTEM_PARTITION: &str = "C12A7328-F81F-11D2-BA4B-00A0C93EC93B";
+pub const MICROSOFT_RESERVED: &str = "E3C9E316-0B5C-4DB8-817D-F92DF00215AE";
+pub const BASIC_DATA: &str = "EBD0A0A2-B9E5-4433-87C0-68B6B72699C7";
+pub const WINDOWS_RECOVERY: &str = "DE94BBA4-06D1-4D40-A16A-BFD50179D6AC";
+
+/// A partition in a GUID partition table.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Partition {
+    /// The partition's 1-based index in the partition entry array.
+    pub number: u32,
+    pub type_guid: String,
+    pub unique_guid: String,
+    pub first_lba: u64,
+    pub last_lba: u64,
+    pub name: String,
+}
+
+impl Partition {
+    /// Returns the number of sectors in the partition.
+    pub fn sectors(&self) -> u64 {
+        self.last_lba - self.first_lba + 1
+    }
+}
+
+/// A disk's GUID partition table.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct PartitionTable {
+    pub sector_size: u64,
+    pub disk_guid: String,
+    pub first_usable_lba: u64,
+    pub last_usable_lba: u64,
+
+    /// The LBA at which the header says its backup is stored.
+    pub backup_lba: u64,
+
+    /// The number of entries in the partition entry array, and the size of
+    /// each entry in bytes.
+    pub entry_count: u32,
+    pub entry_size: u32,
+
+    /// The partitions in the table, ordered by number. Unused entries are
+    /// omitted.
+    pub partitions: Vec<Partition>,
+}
+
+impl PartitionTable {
+    /// Returns the last LBA used by any partition, or `None` if the table has
+    /// no partitions.
+    pub fn last_used_lba(&self) -> Option<u64> {
+        self.partitions.iter().map(|p| p.last_lba).max()
+    }
+
+    /// Returns the number of sectors the secondary GPT occupies at the end of
+    /// the disk: a copy of the partition entry array followed by the backup
+    /// header.
+    pub fn secondary_gpt_sectors(&self) -> u64 {
+        let entries_len =
+            u64::from(self.entry_count) * u64::from(self.entry_size);
+        entries_len.div_ceil(self.sector_size) + 1
+    }
+}
+
+/// A parsed GPT header.
+struct Header {
+    disk_guid: String,
+    current_lba: u64,
+    backup_lba: u64,
+    first_usable_lba: u64,
+    last_usable_lba: u64,
+    entries_lba: u64,
+    entry_count: u32,
+    entry_size: u32,
+    entries_crc: u32,
+}
+
+/// Reads the primary GUID partition table from `disk`.
+pub fn read_partition_table<R: Read + Seek>(
+    disk: &mut R,
+) -> Result<PartitionTable> {
+    for sector_size in SECTOR_SIZES {
+        let mut sector = vec![0u8; sector_size as usize];
+        disk.seek(SeekFrom::Start(sector_size))?;
+        if read_full(disk, &mut sector)? < SIGNATURE.len()
+            || &sector[..SIGNATURE.len()] != SIGNATURE
+        {
+            continue;
+        }
+
+        let header = parse_header(&sector).context("reading GPT header")?;
+        let partitions = read_entries(disk, &header, sector_size)?;
+        return Ok(PartitionTable {
+            sector_size,
+            disk_guid: header.disk_guid,
+            first_usable_lba: header.first_usable_lba,
+            last_usable_lba: header.last_usable_lba,
+            backup_lba: header.backup_lba,
+            entry_count: header.entry_count,
+            entry_size: header.entry_size,
+            partitions,
+        });
+    }
+
+    anyhow::bail!("disk doesn't have a GUID partition table")
+}
+
+/// Checks whether `disk` has a valid backup GPT header in its last sector that
+/// agrees with `table`.
+pub fn has_valid_backup<R: Read + Seek>(
+    disk: &mut R,
+    table: &PartitionTable,
+) -> Result<bool> {
+    let disk_size = disk.seek(SeekFrom::End(0))?;
+    if disk_size < table.sector_size {
+        return Ok(false);
+    }
+
+    let last_lba = disk_size / table.sector_size - 1;
+    if table.backup_lba != last_lba {
+        return Ok(false);
+    }
+
+    let mut sector = vec![0u8; table.sector_size as usize];
+    disk.seek(SeekFrom::Start(last_lba * table.sector_size))?;
+    disk.read_exact(&mut sector)?;
+    if &sector[..SIGNATURE.len()] != SIGNATURE {
+        return Ok(false);
+    }
+
+    let Ok(header) = parse_header(&sector) else {
+        return Ok(false);
+    };
+
+    if header.current_lba != last_lba || header.disk_guid != table.disk_guid {
+        return Ok(false);
+    }
+
+    Ok(read_entries(disk, &header, table.sector_size)
+        .is_ok_and(|partitions| partitions == table.partitions))
+}
+
+fn parse_header(sector: &[u8]) -> Result<Header> {
+    let header_size = u32_at(sector, 12) as usize;
+    if !(92..=sector.len()).contains(&header_size) {
+        anyhow::bail!("invalid GPT header size {header_size}");
+    }
+
+    // The header's checksum is computed with the checksum field zeroed.
+    let mut header = sector[..header_size].to_vec();
+    header[16..20].fill(0);
+    if crc32(&header) != u32_at(sector, 16) {
+        anyhow::bail!("GPT header checksum doesn't match");
+    }
+
+    let entry_size = u32_at(sector, 84);
+    if entry_size < 128 || !entry_size.is_multiple_of(8) {
+        anyhow::bail!("invalid GPT partition entry size {entry_size}");
+    }
+
+    Ok(Header {
+        current_lba: u64_at(sector, 24),
+        backup_lba: u64_at(sector, 32),
+        first_usable_lba: u64_at(sector, 40),
+        last_usable_lba: u64_at(sector, 48),
+        disk_guid: format_guid(&sector[56..72]),
+        entries_lba: u64_at(sector, 72),
+        entry_count: u32_at(sector, 80),
+        entry_size,
+        entries_crc: u32_at(sector, 88),
+    })
+}
+
+fn read_entries<R: Read + Seek>(
+    disk: &mut R,
+    header: &Header,
+    sector_size: u64,
+) -> Result<Vec<Partition>> {
+    let len = header.entry_count as usize * header.entry_size as usize;
+    if len > 1024 * 1024 {
+        anyhow::bail!("GPT partition entry array is implausibly large");
+    }
+
+    if header.first_usable_lba > header.last_usable_lba {
+        anyhow::bail!(
+            "GPT usable LBA range {}..={} is empty",
+            header.first_usable_lba,
+            header.last_usable_lba
+        );
+    }
+
+    let offset = header
+        .entries_lba
+        .checked_mul(sector_size)
+        .context("GPT partition entry array LBA is out of range")?;
+    let mut entries = vec![0u8; len];
+    disk.seek(SeekFrom::Start(offset))?;
+    disk.read_exact(&mut entries).context("reading GPT partition entries")?;
+    if crc32(&entries) != header.entries_crc {
+        anyhow::bail!("GPT partition entry array checksum doesn't match");
+    }
+
+    let mut partitions = Vec::new();
+    for (i, entry) in entries.chunks(header.entry_size as usize).enumerate() {
+        if entry[..16].iter().all(|b| *b == 0) {
+            continue;
+        }
+
+        let name: Vec<u16> = entry[56..128]
+            .chunks_exact(2)
+            .map(|c| u16::from_le_bytes([c[0], c[1]]))
+            .take_while(|c| *c != 0)
+            .collect();
+
+        // The checksum only shows that the entries weren't damaged after they
+        // were written, not that they describe a sensible layout.
+        let number = i as u32 + 1;
+        let (first_lba, last_lba) = (u64_at(entry, 32), u64_at(entry, 40));
+        let usable = header.first_usable_lba..=header.last_usable_lba;
+        if first_lba > last_lba
+            || !usable.contains(&first_lba)
+            || !usable.contains(&last_lba)
+        {
+            anyhow::bail!(
+                "GPT partition {number} spans LBAs {first_lba}..={last_lba}, \
+                which isn't within the usable range {}..={}",
+                header.first_usable_lba,
+                header.last_usable_lba
+            );
+        }
+
+        partitions.push(Partition {
+            number,
+            type_guid: format_guid(&entry[0..16]),
+            unique_guid: format_guid(&entry[16..32]),
+            first_lba,
+            last_lba,
+            name: String::from_utf16_lossy(&name),
+        });
+    }
+
+    Ok(partitions)
+}
+
+/// Reads into `buf` until it's full or the reader runs out of data, returning
+/// the number of bytes read.
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> Result<usize> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..])? {
+            0 => break,
+            n => read += n,
+        }
+    }
+
+    Ok(read)
+}
+
+fn u32_at(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+fn u64_at(buf: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+/// Formats a GUID stored in the mixed-endian form GPTs use.
+fn format_guid(bytes: &[u8]) -> String {
+    format!(
+        "{:08X}-{:04X}-{:04X}-{:02X}{:02X}-{}",
+        u32::from_le_bytes(bytes[0..4].try_into().unwrap()),
+        u16::from_le_bytes(bytes[4..6].try_into().unwrap()),
+        u16::from_le_bytes(bytes[6..8].try_into().unwrap()),
+        bytes[8],
+        bytes[9],
+        bytes[10..16].iter().map(|b| format!("{b:02X}")).collect::<String>()
+    )
+}
+
+/// Computes the CRC-32 (as used by GPT, zlib, etc.) of `data`.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in data {
+        crc ^= u32::from(*byte);
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xedb8_8320 & (crc & 1).wrapping_neg());
+        }
+    }
+
+    !crc
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+
+    /// Parses a GUID in its textual form into the mixed-endian form GPTs use.
+    fn guid_bytes(guid: &str) -> [u8; 16] {
+        let hex: String = guid.chars().filter(|c| *c != '-').collect();
+        let mut bytes = [0u8; 16];
+        for (i, byte) in bytes.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).unwrap();
+        }
+
+        bytes[0..4].reverse();
+        bytes[4..6].reverse();
+        bytes[6..8].reverse();
+        bytes
+    }
+
+    fn header(
+        current: u64,
+        backup: u64,
+        entries_lba: u64,
+        last_usable: u64,
+        entries_crc: u32,
+    ) -> Vec<u8> {
+        let mut header = vec![0u8; 92];
+        header[0..8].copy_from_slice(SIGNATURE);
+        header[8..12].copy_from_slice(&0x0001_0000u32.to_le_bytes());
+        header[12..16].copy_from_slice(&92u32.to_le_bytes());
+        header[24..32].copy_from_slice(&current.to_le_bytes());
+        header[32..40].copy_from_slice(&backup.to_le_bytes());
+        header[40..48].copy_from_slice(&34u64.to_le_bytes());
+        header[48..56].copy_from_slice(&last_usable.to_le_bytes());
+        header[56..72].copy_from_slice(&guid_bytes(
+            "2F0C5E3E-6C3B-4B86-9A3E-1B1D6D4E2E8B",
+        ));
+        header[72..80].copy_from_slice(&entries_lba.to_le_bytes());
+        header[80..84].copy_from_slice(&128u32.to_le_bytes());
+        header[84..88].copy_from_slice(&128u32.to_le_bytes());
+        header[88..92].copy_from_slice(&entries_crc.to_le_bytes());
+        let crc = crc32(&header);
+        header[16..20].copy_from_slice(&crc.to_le_bytes());
+        header
+    }
+
+    /// Builds a disk of `sectors` 512-byte sectors with primary and backup
+    /// GPTs describing `partitions`, given as (type GUID, first LBA, last LBA,
+    /// name) tuples.
+    pub(crate) fn build_disk(
+        sectors: u64,
+        partitions: &[(&str, u64, u64, &str)],
+    ) -> Vec<u8> {
+        let mut entries = vec![0u8; 128 * 128];
+        for (i, (type_guid, first, last, name)) in partitions.iter().enumerate()
+        {
+            let entry = &mut entries[i * 128..(i + 1) * 128];
+            entry[0..16].copy_from_slice(&guid_bytes(type_guid));
+            entry[16] = i as u8 + 1;
+            entry[32..40].copy_from_slice(&first.to_le_bytes());
+            entry[40..48].copy_from_slice(&last.to_le_bytes());
+            for (j, c) in name.encode_utf16().enumerate() {
+                entry[56 + j * 2..58 + j * 2].copy_from_slice(&c.to_le_bytes());
+            }
+        }
+
+        let entries_crc = crc32(&entries);
+        let last_lba = sectors - 1;
+        let mut disk = vec![0u8; sectors as usize * 512];
+        let primary = header(1, last_lba, 2, last_lba - 33, entries_crc);
+        disk[512..512 + primary.len()].copy_from_slice(&primary);
+        disk[1024..1024 + entries.len()].copy_from_slice(&entries);
+
+        let backup =
+            header(last_lba, 1, last_lba - 32, last_lba - 33, entries_crc);
+        let offset = last_lba as usize * 512;
+        disk[offset..offset + backup.len()].copy_from_slice(&backup);
+        let offset = (last_lba - 32) as usize * 512;
+        disk[offset..offset + entries.len()].copy_from_slice(&entries);
+        disk
+    }
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xcbf4_3926);
+    }
+
+    #[test]
+    fn read_table() {
+        let mut disk = std::io::Cursor::new(build_disk(
+            4096,
+            &[
+                (WINDOWS_RECOVERY, 2048, 2147, "Basic data partition"),
+                (EFI_SYSTEM_PARTITION, 2148, 2247, "EFI system partition"),
+                (BASIC_DATA, 2248, 4000, "Basic data partition"),
+            ],
+        ));
+
+        let table = read_partition_table(&mut disk).unwrap();
+        assert_eq!(table.sector_size, 512);
+        assert_eq!(table.disk_guid, "2F0C5E3E-6C3B-4B86-9A3E-1B1D6D4E2E8B");
+        assert_eq!(table.partitions.len(), 3);
+        assert_eq!(table.partitions[1].type_guid, EFI_SYSTEM_PARTITION);
+        assert_eq!(table.partitions[1].name, "EFI system partition");
+        assert_eq!(table.partitions[1].sectors(), 100);
+        assert_eq!(table.last_used_lba(), Some(4000));
+        assert_eq!(table.secondary_gpt_sectors(), 33);
+        assert!(has_valid_backup(&mut disk, &table).unwrap());
+
+        // Truncating the disk (as shrinking an image does) loses the backup.
+        let mut truncated = disk.into_inner();
+        truncated.truncate(4001 * 512);
+        let mut truncated = std::io::Cursor::new(truncated);
+        let table = read_partition_table(&mut truncated).unwrap();
+        assert!(!has_valid_backup(&mut truncated, &table).unwrap());
+    }
+
+    #[test]
+    fn reject_corrupt_tables() {
+        let mut disk = build_disk(4096, &[(BASIC_DATA, 2048, 4000, "")]);
+        disk[1024 + 40] ^= 1;
+        assert!(read_partition_table(&mut std::io::Cursor::new(&disk))
+            .unwrap_err()
+            .to_string()
+            .contains("checksum"));
+
+        assert!(read_partition_table(&mut std::io::Cursor::new(vec![
+            0u8;
+            8192
+        ]))
+        .is_err());
+
+        // Entries with valid checksums must still describe sensible ranges.
+        for (first, last) in [(3000, 2999), (20, 4000), (2048, 4095)] {
+            let disk = build_disk(4096, &[(BASIC_DATA, first, last, "")]);
+            let err = read_partition_table(&mut std::io::Cursor::new(&disk))
+                .unwrap_err()
+                .to_string();
+            assert!(err.contains("usable range"), "{err}");
+        }
+    }
+}
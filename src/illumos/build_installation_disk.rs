@@ -3,26 +3,38 @@
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
 use std::{
+    cell::RefCell,
     collections::HashMap,
     process::{Command, Stdio},
     str::FromStr,
 };
 
 use anyhow::{Context as _, Result};
-use camino::Utf8PathBuf;
+use camino::{Utf8Path, Utf8PathBuf};
 use colored::Colorize;
-use itertools::iproduct;
+use itertools::{iproduct, Itertools};
 
 use crate::{
     app::ImageSources,
+    autounattend::{
+        check_prep_cmd_volume_guids, find_installer_volume_guids,
+        InstallerVolumeGuids, ReplacementRule, UnattendInsertion,
+        UnattendSetting,
+    },
+    checksum::{check_inputs, HashedInput},
+    image_metadata::describe_installation,
+    manifest::{parse_driver_version, DriverVersion},
     runner::{Context, MissingPrerequisites, Script, ScriptStep},
     steps::get_gpt_partition_information,
     ui::Ui,
     util::{
-        check_executable_prerequisites, check_file_prerequisites,
-        run_command_check_status,
+        check_executable_prerequisites, check_extra_files_prerequisites,
+        check_file_prerequisites, copy_dir_contents, copy_file_with_progress,
+        run_7z_with_progress, run_command_capture_output,
+        run_command_check_status, run_command_with_stdin,
     },
-    UNATTEND_FILES,
+    validate::{validate_answer_file, validate_unattend_dir},
+    OFFLINE_PACKAGES, UNATTEND_FILES,
 };
 
 pub struct BuildInstallationDiskArgs {
@@ -33,12 +45,17 @@ pub struct BuildInstallationDiskArgs {
 
 pub struct BuildInstallationDiskScript {
     steps: Vec<ScriptStep>,
+    hashed_inputs: RefCell<Vec<HashedInput>>,
     args: BuildInstallationDiskArgs,
 }
 
 impl BuildInstallationDiskScript {
     pub(super) fn new(script_args: BuildInstallationDiskArgs) -> Self {
-        Self { steps: get_script(), args: script_args }
+        Self {
+            steps: get_script(),
+            hashed_inputs: RefCell::new(Vec::new()),
+            args: script_args,
+        }
     }
 }
 
@@ -72,6 +89,12 @@ impl Script for BuildInstallationDiskScript {
             "Unattend file directory".bold(),
             sources.unattend_dir
         )?;
+        if let Some(extra_files) = &sources.extra_files {
+            writeln!(w, "  {}: {}", "Extra files".bold(), extra_files)?;
+        }
+        for (_, package) in sources.offline_packages() {
+            writeln!(w, "  {}: {}", "Offline package".bold(), package)?;
+        }
 
         writeln!(w)?;
 
@@ -91,13 +114,25 @@ impl Script for BuildInstallationDiskScript {
             writeln!(w, "  Windows version defaulted to Server 2022")?;
         }
 
+        for setting in &sources.unattend_set {
+            writeln!(w, "  Will set in Autounattend.xml: {}", setting)?;
+        }
+
+        for path in &sources.unattend_unset {
+            writeln!(w, "  Will remove from Autounattend.xml: {}", path)?;
+        }
+
+        for insertion in &sources.unattend_insert {
+            writeln!(w, "  Will insert into Autounattend.xml: {}", insertion)?;
+        }
+
         writeln!(w)?;
         writeln!(w, "  {}: {}", "Output file".bold(), args.output_image)?;
 
         Ok(())
     }
 
-    fn check_prerequisites(&self) -> MissingPrerequisites {
+    fn check_prerequisites(&self, ui: &dyn Ui) -> MissingPrerequisites {
         let mut errors = Vec::new();
         let mut warnings = Vec::new();
         let mut files = vec![
@@ -107,6 +142,16 @@ impl Script for BuildInstallationDiskScript {
 
         errors.extend(check_file_prerequisites(&files));
 
+        // Verify the inputs' digests (and remember them so they can be recorded
+        // with the output) after making sure the inputs exist.
+        let (hashed, checksum_errors) = check_inputs(
+            &self.args.sources.input_files(),
+            self.args.sources.sha256sums.as_deref(),
+            ui,
+        );
+        errors.extend(checksum_errors);
+        *self.hashed_inputs.borrow_mut() = hashed;
+
         files.clear();
         for file in UNATTEND_FILES {
             let mut path = self.args.sources.unattend_dir.clone();
@@ -115,6 +160,48 @@ impl Script for BuildInstallationDiskScript {
         }
 
         warnings.extend(check_file_prerequisites(&files));
+
+        // Offline packages were requested explicitly, so they must exist.
+        files.clear();
+        for (_, package) in self.args.sources.offline_packages() {
+            files.push(package.clone());
+        }
+
+        errors.extend(check_file_prerequisites(&files));
+
+        // Answer files that are present should be free of obvious mistakes.
+        let problems = validate_unattend_dir(&self.args.sources.unattend_dir);
+        errors.extend(problems.errors);
+        warnings.extend(problems.warnings);
+
+        // Extra files are copied to the root of the WinPE partition, so they
+        // can't share names with the unattend files or the directories the
+        // other files are copied into.
+        if let Some(extra_files) = &self.args.sources.extra_files {
+            let mut reserved = UNATTEND_FILES.to_vec();
+            reserved.extend(OFFLINE_PACKAGES.iter().map(|(_, file)| *file));
+            reserved.extend(["cloudbase-init", "virtio-drivers"]);
+            errors.extend(check_extra_files_prerequisites(
+                extra_files,
+                &reserved,
+            ));
+        }
+
+        // The installer disk's partitions get the volume GUIDs that the answer
+        // file refers to, so make sure they can be determined up front. Rules
+        // that customize the answer file can change the GUIDs, so if there are
+        // any, the customized file is checked when the GUIDs are assigned
+        // instead.
+        let sources = &self.args.sources;
+        let customized = !sources.unattend_set.is_empty()
+            || !sources.unattend_unset.is_empty()
+            || !sources.unattend_insert.is_empty();
+        if !customized && sources.unattend_dir.join("Autounattend.xml").exists()
+        {
+            if let Err(e) = read_installer_volume_guids(&sources.unattend_dir) {
+                errors.push(format!("{e:#}"));
+            }
+        }
         errors.extend(check_executable_prerequisites(self.steps()));
 
         MissingPrerequisites::from_messages(errors, warnings)
@@ -150,6 +237,55 @@ impl Script for BuildInstallationDiskScript {
             ctx.insert("windows_version".to_string(), "2k22".to_string());
         }
 
+        if !sources.unattend_set.is_empty() {
+            ctx.insert(
+                "unattend_set".to_string(),
+                sources.unattend_set.iter().map(|s| s.to_string()).join("\n"),
+            );
+        }
+
+        if !sources.unattend_unset.is_empty() {
+            ctx.insert(
+                "unattend_unset".to_string(),
+                sources.unattend_unset.iter().map(|p| p.to_string()).join("\n"),
+            );
+        }
+
+        if !sources.unattend_insert.is_empty() {
+            ctx.insert(
+                "unattend_insert".to_string(),
+                sources
+                    .unattend_insert
+                    .iter()
+                    .map(|i| i.to_string())
+                    .join("\n"),
+            );
+        }
+
+        ctx.insert(
+            "hashed_inputs".to_string(),
+            serde_json::to_string(&*self.hashed_inputs.borrow()).unwrap(),
+        );
+
+        for (var, package) in sources.offline_packages() {
+            ctx.insert(var.to_string(), package.to_string());
+        }
+
+        if let Some(extra_files) = &sources.extra_files {
+            ctx.insert("extra_files".to_string(), extra_files.to_string());
+        }
+
+        ctx.insert(
+            "manifest_options".to_string(),
+            serde_json::json!({
+                "command": "build-installation-disk",
+                "work_dir": args.work_dir,
+                "output_image": args.output_image,
+                "sources": sources,
+            })
+            .to_string(),
+        );
+
         ctx
     }
 }
@@ -200,21 +336,45 @@ fn create_installer_disk_partitions(
     .map(|_| ())
 }
 
+/// Reads the volume GUIDs that the answer file in `unattend_dir` expects the
+/// installer disk's partitions to have, checking that `prep.cmd` (if present)
+/// agrees with it.
+fn read_installer_volume_guids(
+    unattend_dir: &Utf8Path,
+) -> Result<InstallerVolumeGuids> {
+    let autounattend =
+        std::fs::read_to_string(unattend_dir.join("Autounattend.xml"))
+            .context("reading Autounattend.xml")?;
+    let guids = find_installer_volume_guids(&autounattend)?;
+
+    let prep_cmd = unattend_dir.join("prep.cmd");
+    if prep_cmd.exists() {
+        let prep_cmd =
+            std::fs::read_to_string(&prep_cmd).context("reading prep.cmd")?;
+        check_prep_cmd_volume_guids(&prep_cmd, &guids)?;
+    }
+
+    Ok(guids)
+}
+
 fn set_installer_disk_partition_ids(
     ctx: &mut Context,
     ui: &dyn Ui,
 ) -> Result<()> {
-    // N.B. These partition GUIDs must match the GUIDs in
-    // Autounattend.xml.
-    const PARTITION_GUID_1: &str = "569CBD84-352D-44D9-B92D-BF25B852925B";
-    const PARTITION_GUID_2: &str = "A94E24F7-92C9-405C-82AA-9A1B45BA180C";
+    // The answer file and setup scripts find their files on the installer
+    // disk by volume GUID, so give the partitions the GUIDs they expect. By
+    // now `unattend_dir` is the working copy, so this reads the answer file
+    // as customized by any `--set`, `--unset`, or `--insert` rules.
+    let guids = read_installer_volume_guids(Utf8Path::new(
+        ctx.get_var("unattend_dir").unwrap(),
+    ))?;
 
     run_command_check_status(
         Command::new("sgdisk").args([
             "-u",
-            &format!("1:{PARTITION_GUID_1}"),
+            &format!("1:{}", guids.setup),
             "-u",
-            &format!("2:{PARTITION_GUID_2}"),
+            &format!("2:{}", guids.image),
             ctx.get_var("output_image").unwrap(),
         ]),
         ui,
@@ -226,7 +386,7 @@ fn mount_installer_disk_as_loopback_device(
     ctx: &mut Context,
     ui: &dyn Ui,
 ) -> Result<()> {
-    let repack_loop = run_command_check_status(
+    let repack_loop = run_command_capture_output(
         Command::new("pfexec").args([
             "lofiadm",
             "-l",
@@ -256,25 +416,25 @@ fn mount_installer_disk_as_loopback_device(
     );
     ctx.set_var("repack_loop_setup", format!("/dev/dsk/{}s0", block_device));
     ctx.set_var("repack_loop_image", format!("/dev/dsk/{}s1", block_device));
+    ctx.register_cleanup("loopback device", remove_loopback_device);
 
     Ok(())
 }
 
 fn create_winpe_fat32(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
     let yes_cmd = Command::new("yes").stdout(Stdio::piped()).spawn()?;
-    run_command_check_status(
-        Command::new("pfexec")
-            .args([
-                "mkfs",
-                "-F",
-                "pcfs",
-                "-o",
-                "fat=32",
-                &ctx.get_var("repack_loop_setup_raw").unwrap(),
-            ])
-            .stdin(Stdio::from(yes_cmd.stdout.ok_or(anyhow::anyhow!(
-                "failed to get stdout from 'yes' to pipe to 'mkfs'"
-            ))?)),
+    run_command_with_stdin(
+        Command::new("pfexec").args([
+            "mkfs",
+            "-F",
+            "pcfs",
+            "-o",
+            "fat=32",
+            &ctx.get_var("repack_loop_setup_raw").unwrap(),
+        ]),
+        Stdio::from(yes_cmd.stdout.ok_or(anyhow::anyhow!(
+            "failed to get stdout from 'yes' to pipe to 'mkfs'"
+        ))?),
         ui,
     )
     .map(|_| ())
@@ -300,6 +460,7 @@ fn mount_winpe_partition(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
     )?;
 
     ctx.set_var("setup_mount", setup_mount.to_string());
+    ctx.register_cleanup("WinPE partition mount", unmount_winpe_partition);
     Ok(())
 }
 
@@ -307,7 +468,7 @@ fn extract_setup_to_winpe_partition(
     ctx: &mut Context,
     ui: &dyn Ui,
 ) -> Result<()> {
-    run_command_check_status(
+    run_7z_with_progress(
         Command::new("7z").args([
             "x",
             "-x!sources/install.wim",
@@ -315,6 +476,7 @@ fn extract_setup_to_winpe_partition(
             &format!("-o{}", &ctx.get_var("setup_mount").unwrap()),
         ]),
         ui,
+        "extracting setup files",
     )
     .map(|_| ())
 }
@@ -353,13 +515,28 @@ fn copy_unattend_files_to_work_dir(
     Ok(())
 }
 
-fn customize_autounattend_xml(ctx: &mut Context, _ui: &dyn Ui) -> Result<()> {
-    let customizer = crate::autounattend::AutounattendUpdater::new(
+fn customize_autounattend_xml(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
+    let mut customizer = crate::autounattend::AutounattendUpdater::new(
         ctx.get_var("unattend_image_index")
             .map(|val| val.parse::<u32>().unwrap()),
         None,
     );
 
+    for setting in ctx.get_var("unattend_set").into_iter().flat_map(str::lines)
+    {
+        customizer.add_rule(setting.parse::<UnattendSetting>()?.into());
+    }
+
+    for path in ctx.get_var("unattend_unset").into_iter().flat_map(str::lines) {
+        customizer.add_rule(ReplacementRule::delete(path.parse()?));
+    }
+
+    for insertion in
+        ctx.get_var("unattend_insert").into_iter().flat_map(str::lines)
+    {
+        customizer.add_rule(insertion.parse::<UnattendInsertion>()?.into());
+    }
+
     let unattend_dir =
         Utf8PathBuf::from_str(ctx.get_var("unattend_dir").unwrap()).unwrap();
     let mut unattend_src = unattend_dir.clone();
@@ -373,6 +550,18 @@ fn customize_autounattend_xml(ctx: &mut Context, _ui: &dyn Ui) -> Result<()> {
         .run(&unattend_src, &unattend_dst)
         .context("customizing Autounattend.xml")?;
 
+    let problems = validate_answer_file(&unattend_dst);
+    if !problems.errors.is_empty() {
+        anyhow::bail!(
+            "customized Autounattend.xml is invalid:\n  {}",
+            problems.errors.join("\n  ")
+        );
+    }
+
+    for warning in &problems.warnings {
+        ui.set_substep(&format!("Warning: {warning}"));
+    }
+
     std::fs::remove_file(&unattend_src)
         .context("removing temporary Autounattend.xml")?;
 
@@ -434,6 +623,46 @@ fn copy_cloudbase_init_to_winpe_partition(
     Ok(())
 }
 
+fn copy_offline_packages_to_winpe_partition(
+    ctx: &mut Context,
+    ui: &dyn Ui,
+) -> Result<()> {
+    let setup_mount =
+        Utf8PathBuf::from_str(ctx.get_var("setup_mount").unwrap()).unwrap();
+
+    for (var, filename) in OFFLINE_PACKAGES {
+        let Some(package) = ctx.get_var(var) else {
+            continue;
+        };
+
+        copy_file_with_progress(
+            Utf8Path::new(package),
+            &setup_mount.join(filename),
+            ui,
+        )
+        .context("copying offline package to WinPE partition")?;
+    }
+
+    Ok(())
+}
+
+fn copy_extra_files_to_winpe_partition(
+    ctx: &mut Context,
+    ui: &dyn Ui,
+) -> Result<()> {
+    let Some(extra_files) = ctx.get_var("extra_files") else {
+        ui.set_substep("no extra files specified");
+        return Ok(());
+    };
+
+    copy_dir_contents(
+        Utf8Path::new(extra_files),
+        Utf8Path::new(ctx.get_var("setup_mount").unwrap()),
+        ui,
+    )
+    .context("copying extra files to WinPE partition")
+}
+
 fn copy_virtio_to_winpe_partition(
     ctx: &mut Context,
     ui: &dyn Ui,
@@ -457,13 +686,47 @@ fn copy_virtio_to_winpe_partition(
     Ok(())
 }
 
+fn read_virtio_driver_versions(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
+    let drivers_dir = Utf8Path::new(ctx.get_var("setup_mount").unwrap())
+        .join("virtio-drivers");
+    let windows_version = ctx.get_var("windows_version").unwrap().to_string();
+
+    let mut drivers = Vec::new();
+    for entry in drivers_dir
+        .read_dir_utf8()
+        .with_context(|| format!("reading {drivers_dir}"))?
+    {
+        let path = entry?.into_path();
+        if !path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("inf"))
+        {
+            continue;
+        }
+
+        ui.set_substep(&format!("reading {path}"));
+        let contents =
+            std::fs::read(&path).with_context(|| format!("reading {path}"))?;
+        if let Some(version) = parse_driver_version(&contents) {
+            drivers.push(DriverVersion {
+                driver: path.file_stem().unwrap().to_string(),
+                windows_version: windows_version.clone(),
+                version,
+            });
+        }
+    }
+
+    ctx.set_var("virtio_driver_versions", serde_json::to_string(&drivers)?);
+    Ok(())
+}
+
 fn unmount_winpe_partition(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
     let setup_mount = ctx.get_var("setup_mount").unwrap();
     run_command_check_status(
         Command::new("pfexec").args(["umount", setup_mount]),
         ui,
-    )
-    .map(|_| ())
+    )?;
+
+    ctx.release("WinPE partition mount");
+    Ok(())
 }
 
 fn get_wim_partition_parameters(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
@@ -526,11 +789,12 @@ fn mount_wim_partition(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
     .map(|_| ())?;
 
     ctx.set_var("image_mount", image_mount.to_string());
+    ctx.register_cleanup("WIM partition mount", unmount_wim_partition);
     Ok(())
 }
 
 fn copy_install_wim(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
-    run_command_check_status(
+    run_7z_with_progress(
         Command::new("7z").args([
             "e",
             "-i!sources/install.wim",
@@ -538,17 +802,39 @@ fn copy_install_wim(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
             &format!("-o{}", ctx.get_var("image_mount").unwrap()),
         ]),
         ui,
+        "extracting install.wim",
     )
     .map(|_| ())
 }
 
+fn read_windows_edition(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
+    let unattend_dir = Utf8Path::new(ctx.get_var("unattend_dir").unwrap());
+    let install_wim =
+        Utf8Path::new(ctx.get_var("image_mount").unwrap()).join("install.wim");
+    let Some(metadata) = describe_installation(
+        &unattend_dir.join("Autounattend.xml"),
+        ctx.get_var("windows_version"),
+        || crate::wim::read_file_images(&install_wim),
+        ui,
+    )?
+    else {
+        return Ok(());
+    };
+
+    ui.set_substep(&format!("disk will install {}", metadata.description));
+    ctx.set_var("image_metadata", serde_json::to_string(&metadata)?);
+    Ok(())
+}
+
 fn unmount_wim_partition(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
     run_command_check_status(
         Command::new("pfexec")
             .args(["umount", ctx.get_var("image_mount").unwrap()]),
         ui,
-    )
-    .map(|_| ())
+    )?;
+
+    ctx.release("WIM partition mount");
+    Ok(())
 }
 
 fn remove_loopback_device(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
@@ -562,8 +848,29 @@ fn remove_loopback_device(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
             ctx.get_var("repack_loop").unwrap(),
         ]),
         ui,
+    )?;
+
+    ctx.release("loopback device");
+    Ok(())
+}
+
+fn record_input_checksums(ctx: &mut Context, _ui: &dyn Ui) -> Result<()> {
+    crate::steps::record_input_checksums(
+        ctx.get_var("output_image").unwrap(),
+        ctx.get_var("hashed_inputs").unwrap(),
+    )
+}
+
+fn write_image_metadata(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
+    crate::steps::write_output_image_metadata(
+        ctx.get_var("output_image").unwrap(),
+        ctx.get_var("image_metadata"),
+        ui,
     )
-    .map(|_| ())
+}
+
+fn write_manifest(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
+    crate::manifest::write_manifest(ctx, ui)
 }
 
 fn get_script() -> Vec<ScriptStep> {
@@ -583,6 +890,14 @@ fn get_script() -> Vec<ScriptStep> {
             create_installer_disk_partitions,
             &["sgdisk"],
         ),
+        ScriptStep::new(
+            "copy unattend files to working directory",
+            copy_unattend_files_to_work_dir,
+        ),
+        ScriptStep::new(
+            "customizing Autounattend.xml",
+            customize_autounattend_xml,
+        ),
         ScriptStep::with_prereqs(
             "set partition IDs for partitions on installer disk",
             set_installer_disk_partition_ids,
@@ -602,14 +917,6 @@ fn get_script() -> Vec<ScriptStep> {
             extract_setup_to_winpe_partition,
             &["7z"],
         ),
-        ScriptStep::new(
-            "copy unattend files to working directory",
-            copy_unattend_files_to_work_dir,
-        ),
-        ScriptStep::new(
-            "customizing Autounattend.xml",
-            customize_autounattend_xml,
-        ),
         ScriptStep::new(
             "copying unattend scripts to WinPE partition",
             copy_unattend_to_winpe_partition,
@@ -618,11 +925,23 @@ fn get_script() -> Vec<ScriptStep> {
             "copying cloudbase-init scripts to WinPE partition",
             copy_cloudbase_init_to_winpe_partition,
         ),
+        ScriptStep::new(
+            "copying offline packages to WinPE partition",
+            copy_offline_packages_to_winpe_partition,
+        ),
+        ScriptStep::new(
+            "copying extra files to WinPE partition",
+            copy_extra_files_to_winpe_partition,
+        ),
         ScriptStep::with_prereqs(
             "copying virtio drivers to WinPE partition",
             copy_virtio_to_winpe_partition,
             &["7z"],
         ),
+        ScriptStep::new(
+            "reading virtio driver versions",
+            read_virtio_driver_versions,
+        ),
         ScriptStep::new("unmounting WinPE partition", unmount_winpe_partition),
         ScriptStep::with_prereqs(
             "reading partition parameters for WIM partition",
@@ -644,12 +963,25 @@ fn get_script() -> Vec<ScriptStep> {
             copy_install_wim,
             &["7z"],
         ),
+        ScriptStep::new(
+            "read Windows edition from install.wim",
+            read_windows_edition,
+        ),
         ScriptStep::new("unmounting image partition", unmount_wim_partition),
         ScriptStep::new("flushing changes to disk", |_ctx, ui| {
             let mut sync = Command::new("sync");
             run_command_check_status(&mut sync, ui).map(|_| ())
         }),
         ScriptStep::new("remove loopback device", remove_loopback_device),
+        ScriptStep::new(
+            "record checksums of input files",
+            record_input_checksums,
+        ),
+        ScriptStep::new(
+            "write installation metadata for installer disk",
+            write_image_metadata,
+        ),
+        ScriptStep::new("write build manifest", write_manifest),
     ];
 
     steps
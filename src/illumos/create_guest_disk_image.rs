@@ -2,19 +2,25 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use std::{os::unix::net::UnixStream, process::Command, str::FromStr};
+use std::{
+    os::unix::{net::UnixStream, process::CommandExt},
+    process::Command,
+    str::FromStr,
+};
 
 use crate::{
+    image_metadata::ImageMetadata,
     runner::{Context, MissingPrerequisites, Script, ScriptStep},
+    steps::ImageAlignment,
     ui::Ui,
     util::{
         check_executable_prerequisites, check_file_prerequisites,
-        run_command_check_status,
+        run_command_check_status, wait_for_child_or_interrupt,
     },
 };
 
 use anyhow::{Context as _, Result};
-use camino::Utf8PathBuf;
+use camino::{Utf8Path, Utf8PathBuf};
 use colored::Colorize;
 
 const VNIC_NAME: &str = "vnic0";
@@ -25,6 +31,12 @@ pub struct CreateGuestDiskImageArgs {
     pub vnic_link: String,
     pub installer_image: Utf8PathBuf,
     pub propolis_bootrom: Utf8PathBuf,
+
+    /// Whether to deallocate zero-filled blocks in the finished image.
+    pub punch_holes: bool,
+
+    /// The multiple to which to round up the size of the trimmed image.
+    pub align: ImageAlignment,
 }
 
 pub struct CreateGuestDiskImageScript {
@@ -34,7 +46,7 @@ pub struct CreateGuestDiskImageScript {
 
 impl CreateGuestDiskImageScript {
     pub(super) fn new(script_args: CreateGuestDiskImageArgs) -> Self {
-        Self { steps: get_script(), args: script_args }
+        Self { steps: get_script(script_args.punch_holes), args: script_args }
     }
 }
 
@@ -60,12 +72,21 @@ impl Script for CreateGuestDiskImageScript {
         writeln!(w, "  {}: {}", "VNIC name".bold(), VNIC_NAME)?;
         writeln!(w)?;
         writeln!(w, "  {}: {}", "Output file".bold(), args.output_image)?;
+        writeln!(
+            w,
+            "  Will round image size up to a multiple of {}",
+            args.align
+        )?;
+        if args.punch_holes {
+            writeln!(w, "  Will punch holes in zero-filled regions of image")?;
+        }
 
         Ok(())
     }
 
-    fn check_prerequisites(&self) -> MissingPrerequisites {
+    fn check_prerequisites(&self, _ui: &dyn Ui) -> MissingPrerequisites {
         let mut errors = Vec::new();
+        let mut warnings = Vec::new();
         let files = vec![
             self.args.installer_image.clone(),
             self.args.propolis_bootrom.clone(),
@@ -74,11 +95,32 @@ impl Script for CreateGuestDiskImageScript {
         errors.extend(check_file_prerequisites(&files));
         errors.extend(check_executable_prerequisites(self.steps()));
 
-        MissingPrerequisites::from_messages(errors, vec![])
+        // The installer disk's build describes the edition it installs. Disks
+        // built by older versions of wimsy don't have this description.
+        let metadata = ImageMetadata::path_for(&self.args.installer_image);
+        if self.args.installer_image.exists() && !metadata.exists() {
+            warnings.push(format!(
+                "{metadata} doesn't exist; the output image won't be \
+                described in an .image.json file"
+            ));
+        }
+
+        MissingPrerequisites::from_messages(errors, warnings)
     }
 
     fn initial_context(&self) -> std::collections::HashMap<String, String> {
         let args = &self.args;
+        let options = serde_json::json!({
+            "command": "create-guest-disk-image",
+            "work_dir": args.work_dir,
+            "output_image": args.output_image,
+            "vnic_link": args.vnic_link,
+            "installer_image": args.installer_image,
+            "propolis_bootrom": args.propolis_bootrom,
+            "punch_holes": args.punch_holes,
+                "align": args.align.to_string(),
+        });
+
         [
             ("work_dir".to_string(), args.work_dir.to_string()),
             ("vnic_link".to_string(), args.vnic_link.clone()),
@@ -86,12 +128,27 @@ impl Script for CreateGuestDiskImageScript {
             ("installer_image".to_string(), args.installer_image.to_string()),
             ("output_image".to_string(), args.output_image.to_string()),
             ("propolis_bootrom".to_string(), args.propolis_bootrom.to_string()),
+            ("align".to_string(), args.align.to_string()),
+            ("manifest_options".to_string(), options.to_string()),
         ]
         .into_iter()
         .collect()
     }
 }
 
+fn read_installer_metadata(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
+    let installer_image =
+        Utf8Path::new(ctx.get_var("installer_image").unwrap());
+    let Some(metadata) = ImageMetadata::read_for(installer_image)? else {
+        ui.set_substep("installer disk has no installation metadata");
+        return Ok(());
+    };
+
+    ui.set_substep(&format!("installing {}", metadata.description));
+    ctx.set_var("image_metadata", serde_json::to_string(&metadata)?);
+    Ok(())
+}
+
 fn create_vnic(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
     run_command_check_status(
         Command::new("pfexec").args([
@@ -103,8 +160,10 @@ fn create_vnic(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
             ctx.get_var("vnic_name").unwrap(),
         ]),
         ui,
-    )
-    .map(|_| ())
+    )?;
+
+    ctx.register_cleanup("installation VM VNIC", remove_vnic);
+    Ok(())
 }
 
 fn create_output_image(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
@@ -161,23 +220,21 @@ pci-path = "0.8.0"
 }
 
 fn run_propolis_standalone(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
-    let current_dir = std::env::current_dir().context(
-        "getting current directory before launching propolis-standalone",
-    )?;
-
     let work_dir =
         Utf8PathBuf::from_str(ctx.get_var("work_dir").unwrap()).unwrap();
 
-    std::env::set_current_dir(&work_dir).context(
-        "setting working directory before launching propolis-standalone",
-    )?;
-
-    let executable = "propolis-standalone";
+    // propolis-standalone creates its serial console socket in its working
+    // directory, so run it in the work directory.
     let mut propolis = Command::new("pfexec");
     propolis
-        .args([executable, ctx.get_var("vm_toml_path").unwrap()])
-        .stdout::<std::fs::File>(ui.child_stdout(executable)?)
-        .stderr::<std::fs::File>(ui.child_stderr(executable)?);
+        .args(["propolis-standalone", ctx.get_var("vm_toml_path").unwrap()])
+        .current_dir(&work_dir);
+    let mut logs = ui.child_logs(&propolis)?;
+    propolis.stdout(logs.stdout.try_clone()?).stderr(logs.stderr.try_clone()?);
+
+    // Put propolis-standalone in its own process group so that only wimsy
+    // receives a terminal Ctrl-C and can stop the VM itself.
+    propolis.process_group(0);
 
     ui.set_substep(&format!("Launching propolis-standalone: {:?}", propolis));
     let mut propolis =
@@ -215,17 +272,30 @@ fn run_propolis_standalone(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
         "Waiting for propolis-standalone to exit (this may take a while)",
     );
 
-    let status =
-        propolis.wait().context("waiting for propolis-standalone to exit")?;
+    let status = wait_for_child_or_interrupt(
+        &mut propolis,
+        std::time::Duration::from_secs(30),
+        |propolis| {
+            // propolis-standalone runs with elevated privileges, so signal it
+            // with them too. It stops the VM when it receives SIGINT.
+            run_command_check_status(
+                Command::new("pfexec").args([
+                    "kill",
+                    "-INT",
+                    &propolis.id().to_string(),
+                ]),
+                ui,
+            )
+            .map(|_| ())
+        },
+    )
+    .context("waiting for propolis-standalone to exit")?;
+    logs.record_exit_status(status)?;
 
     if !status.success() {
         anyhow::bail!("propolis-server exited with error {:?}", status);
     }
 
-    std::env::set_current_dir(current_dir).context(
-        "restoring working directory after running propolis-standalone",
-    )?;
-
     Ok(())
 }
 
@@ -246,6 +316,7 @@ fn shrink_output_image(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
         ctx.get_var("output_image").unwrap(),
         ctx.get_var("sector_size").unwrap(),
         ctx.get_var("last_sector").unwrap(),
+        ctx.get_var("align").unwrap(),
         ui,
     )
 }
@@ -262,12 +333,44 @@ fn remove_vnic(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
             ctx.get_var("vnic_name").unwrap(),
         ]),
         ui,
+    )?;
+
+    ctx.release("installation VM VNIC");
+    Ok(())
+}
+
+fn punch_holes(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
+    crate::steps::punch_holes_in_output_image(
+        ctx.get_var("output_image").unwrap(),
+        ui,
+    )
+}
+
+fn write_block_map(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
+    crate::steps::write_output_block_map(
+        ctx.get_var("output_image").unwrap(),
+        ui,
+    )
+}
+
+fn write_image_metadata(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
+    crate::steps::write_output_image_metadata(
+        ctx.get_var("output_image").unwrap(),
+        ctx.get_var("image_metadata"),
+        ui,
     )
-    .map(|_| ())
 }
 
-fn get_script() -> Vec<ScriptStep> {
-    vec![
+fn write_manifest(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
+    crate::manifest::write_manifest(ctx, ui)
+}
+
+fn get_script(punch_holes_in_image: bool) -> Vec<ScriptStep> {
+    let mut steps = vec![
+        ScriptStep::new(
+            "read installation metadata from installer disk",
+            read_installer_metadata,
+        ),
         ScriptStep::new("create VNIC for installation VM", create_vnic),
         ScriptStep::with_prereqs(
             "create output image",
@@ -280,10 +383,9 @@ fn get_script() -> Vec<ScriptStep> {
             run_propolis_standalone,
             &["propolis-standalone"],
         ),
-        ScriptStep::with_prereqs(
-            "get size of primary installation partition",
+        ScriptStep::new(
+            "find last sector used by output image partitions",
             get_partition_size,
-            &["sgdisk"],
         ),
         ScriptStep::with_prereqs(
             "trim unused sectors from output image",
@@ -295,6 +397,21 @@ fn get_script() -> Vec<ScriptStep> {
             repair_secondary_gpt,
             &["sgdisk"],
         ),
+    ];
+
+    if punch_holes_in_image {
+        steps.push(ScriptStep::new(
+            "punch holes in zero-filled regions of output image",
+            punch_holes,
+        ));
+    }
+
+    steps.extend([
+        ScriptStep::new("write block map of output image", write_block_map),
         ScriptStep::new("remove installation VM VNIC", remove_vnic),
-    ]
+        ScriptStep::new("write image metadata", write_image_metadata),
+        ScriptStep::new("write build manifest", write_manifest),
+    ]);
+
+    steps
 }
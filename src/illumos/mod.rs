@@ -37,23 +37,42 @@ pub fn get_script(app: &crate::app::App) -> Box<dyn Script> {
     match &app.command {
         Command::BuildInstallationDisk { sources } => Box::new(
             BuildInstallationDiskScript::new(BuildInstallationDiskArgs {
-                work_dir: app.work_dir.clone(),
-                output_image: app.output_image.clone(),
-                sources: sources.clone(),
+                work_dir: app.work_dir().to_owned(),
+                output_image: app.output_image().to_owned(),
+                sources: sources.as_ref().clone(),
             }),
         ),
         Command::CreateGuestDiskImage {
             vnic_link,
             installer_image,
             propolis_bootrom,
+            punch_holes,
+            align,
         } => Box::new(CreateGuestDiskImageScript::new(
             CreateGuestDiskImageArgs {
-                work_dir: app.work_dir.clone(),
-                output_image: app.output_image.clone(),
+                work_dir: app.work_dir().to_owned(),
+                output_image: app.output_image().to_owned(),
                 vnic_link: vnic_link.clone(),
                 installer_image: installer_image.clone(),
                 propolis_bootrom: propolis_bootrom.clone(),
+                punch_holes: *punch_holes,
+                align: *align,
             },
         )),
+        Command::ValidateUnattend { .. } => {
+            unreachable!("validate-unattend doesn't run a script")
+        }
+        Command::GenerateUnattend { .. } => {
+            unreachable!("generate-unattend doesn't run a script")
+        }
+        Command::MakeConfigDrive { .. } => {
+            unreachable!("make-config-drive doesn't run a script")
+        }
+        Command::Inspect { .. } => {
+            unreachable!("inspect doesn't run a script")
+        }
+        Command::Upload { .. } => {
+            unreachable!("upload's script isn't specific to an OS")
+        }
     }
 }
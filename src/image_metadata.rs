@@ -0,0 +1,261 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Describes the Windows installation in a finished image.
+//!
+//! Builds write this description to `<output image>.image.json` so that
+//! uploads (and any other catalog of images) can label an image with the OS,
+//! version, and edition that were actually installed instead of a
+//! hand-typed description.
+
+use anyhow::{Context as _, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use serde::{Deserialize, Serialize};
+
+use crate::{autounattend::WindowsVersion, ui::Ui, wim::WimImage};
+
+/// The metadata of a finished image.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct ImageMetadata {
+    /// The operating system, e.g. "Windows Server".
+    pub os: String,
+
+    /// The operating system's version, e.g. "2022".
+    pub version: String,
+
+    /// A description of the installed edition, e.g. "Windows Server 2022
+    /// Standard (Desktop Experience), build 10.0.20348.587".
+    pub description: String,
+
+    /// The index of the installed image in `install.wim`, if the answer file
+    /// selected one.
+    pub image_index: Option<u32>,
+
+    /// The description of the installed image from `install.wim`, if it could
+    /// be read.
+    pub edition: Option<WimImage>,
+}
+
+impl ImageMetadata {
+    /// Describes an installation of `version`, optionally of the edition
+    /// described by `edition`.
+    pub fn new(
+        version: WindowsVersion,
+        image_index: Option<u32>,
+        edition: Option<WimImage>,
+    ) -> Self {
+        let description = match &edition {
+            Some(edition) => match &edition.build {
+                Some(build) => {
+                    format!("{}, build {build}", edition.best_name())
+                }
+                None => edition.best_name().to_owned(),
+            },
+            None => version.to_string(),
+        };
+
+        Self {
+            os: version.image_os().to_owned(),
+            version: version.image_version().to_owned(),
+            description,
+            image_index,
+            edition,
+        }
+    }
+
+    /// Returns the path of the metadata file for the image at `image`.
+    pub fn path_for(image: &Utf8Path) -> Utf8PathBuf {
+        format!("{image}.image.json").into()
+    }
+
+    /// Reads the metadata for the image at `image`, if there is any.
+    pub fn read_for(image: &Utf8Path) -> Result<Option<Self>> {
+        let path = Self::path_for(image);
+        let contents = match std::fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                return Ok(None)
+            }
+            Err(e) => return Err(e).with_context(|| format!("reading {path}")),
+        };
+
+        serde_json::from_str(&contents)
+            .with_context(|| format!("parsing {path}"))
+            .map(Some)
+    }
+
+    /// Writes this metadata to the metadata file for the image at `image`.
+    pub fn write_for(&self, image: &Utf8Path) -> Result<()> {
+        let path = Self::path_for(image);
+        std::fs::write(&path, serde_json::to_string_pretty(self)? + "\n")
+            .with_context(|| format!("writing {path}"))
+    }
+}
+
+/// Describes the installation that Setup will perform with the answer file at
+/// `answer_file`. `read_images` reads the descriptions of the images on the
+/// installation media; the answer file's `/IMAGE/INDEX` selects one of them.
+///
+/// The Windows version comes from `windows_version` (a driver path component
+/// such as "2k22"), the answer file's driver paths, or the edition's name, in
+/// that order. Returns `None` if none of these determine the version.
+pub fn describe_installation(
+    answer_file: &Utf8Path,
+    windows_version: Option<&str>,
+    read_images: impl FnOnce() -> Result<Vec<WimImage>>,
+    ui: &dyn Ui,
+) -> Result<Option<ImageMetadata>> {
+    let summary = match std::fs::read_to_string(answer_file) {
+        Ok(contents) => crate::autounattend::summarize_answer_file(&contents)?,
+        Err(_) => Default::default(),
+    };
+
+    // Setup installs the image the answer file selects, so describe that one.
+    // If the media's install image can't be read, fall back to describing
+    // just the Windows version.
+    let edition = match summary.image_index {
+        Some(index) => match read_images() {
+            Ok(images) => {
+                Some(images.into_iter().find(|i| i.index == index).ok_or_else(
+                    || {
+                        anyhow::anyhow!(
+                            "answer file selects image {index}, but the \
+                            install image has no image with that index"
+                        )
+                    },
+                )?)
+            }
+            Err(e) => {
+                ui.set_substep(&format!("couldn't read editions: {e:#}"));
+                None
+            }
+        },
+        None => {
+            ui.set_substep("answer file doesn't select an image index");
+            None
+        }
+    };
+
+    let version = windows_version
+        .and_then(WindowsVersion::from_driver_path_component)
+        .or_else(|| summary.driver_versions.first().copied())
+        .or_else(|| {
+            edition
+                .as_ref()
+                .and_then(|e| WindowsVersion::from_image_name(&e.name))
+        });
+
+    let Some(version) = version else {
+        ui.set_substep("couldn't determine the installed Windows version");
+        return Ok(None);
+    };
+
+    Ok(Some(ImageMetadata::new(version, summary.image_index, edition)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_support::{scratch_dir, NullUi};
+
+    #[test]
+    fn describe_installed_edition() {
+        let edition = WimImage {
+            index: 2,
+            name: "Windows Server 2022 SERVERSTANDARD".to_string(),
+            display_name: Some(
+                "Windows Server 2022 Standard (Desktop Experience)".to_string(),
+            ),
+            build: Some("10.0.20348.587".to_string()),
+            ..Default::default()
+        };
+
+        let metadata = ImageMetadata::new(
+            WindowsVersion::Server2022,
+            Some(2),
+            Some(edition.clone()),
+        );
+        assert_eq!(metadata.os, "Windows Server");
+        assert_eq!(metadata.version, "2022");
+        assert_eq!(
+            metadata.description,
+            "Windows Server 2022 Standard (Desktop Experience), build \
+            10.0.20348.587"
+        );
+
+        // Older media don't have display names or service pack builds.
+        let edition =
+            WimImage { display_name: None, build: None, ..edition.clone() };
+        let metadata = ImageMetadata::new(
+            WindowsVersion::Server2022,
+            Some(2),
+            Some(edition),
+        );
+        assert_eq!(metadata.description, "Windows Server 2022 SERVERSTANDARD");
+
+        let metadata =
+            ImageMetadata::new(WindowsVersion::Windows11, None, None);
+        assert_eq!(metadata.os, "Windows");
+        assert_eq!(metadata.version, "11");
+        assert_eq!(metadata.description, "Windows 11");
+    }
+
+    #[test]
+    fn round_trip_through_sidecar() {
+        let dir = scratch_dir("metadata");
+        let image = dir.join("image.img");
+        assert_eq!(ImageMetadata::read_for(&image).unwrap(), None);
+
+        let metadata =
+            ImageMetadata::new(WindowsVersion::Server2019, None, None);
+        metadata.write_for(&image).unwrap();
+        assert_eq!(ImageMetadata::read_for(&image).unwrap(), Some(metadata));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn describe_installation_from_answer_file() {
+        let dir = scratch_dir("describe-installation");
+        let answer_file = dir.join("Autounattend.xml");
+        std::fs::write(
+            &answer_file,
+            include_str!("../unattend/Autounattend.xml"),
+        )
+        .unwrap();
+
+        let edition = WimImage {
+            index: 2,
+            name: "Windows Server 2019 SERVERSTANDARD".to_string(),
+            ..Default::default()
+        };
+
+        // The explicit version wins over the edition's name.
+        let images = || Ok(vec![edition.clone()]);
+        let metadata =
+            describe_installation(&answer_file, Some("2k22"), images, &NullUi)
+                .unwrap()
+                .unwrap();
+        assert_eq!(metadata.version, "2022");
+        assert_eq!(metadata.edition, Some(edition.clone()));
+
+        // Unreadable media leave just the version to describe.
+        let metadata = describe_installation(
+            &answer_file,
+            Some("2k19"),
+            || anyhow::bail!("no install image"),
+            &NullUi,
+        )
+        .unwrap()
+        .unwrap();
+        assert_eq!(metadata.edition, None);
+        assert_eq!(metadata.description, "Windows Server 2019");
+
+        // The selected index has to exist on the media.
+        let images = || Ok(vec![WimImage { index: 1, ..edition.clone() }]);
+        assert!(
+            describe_installation(&answer_file, None, images, &NullUi).is_err()
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
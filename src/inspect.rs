@@ -0,0 +1,613 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Inspects a finished guest image without booting it.
+//!
+//! The inspection reads the image's GUID partition table, identifies the
+//! partitions Windows Setup creates (the recovery, EFI system, Microsoft
+//! reserved, and OS partitions), and checks that the OS partition holds an NTFS
+//! volume that fits in it and that the EFI system partition holds a boot
+//! loader. It also catches images that were never shrunk, which is what
+//! happens when the guest never gets as far as running sysprep.
+
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+};
+
+use anyhow::{Context as _, Result};
+use camino::Utf8Path;
+use colored::Colorize;
+use indicatif::HumanBytes;
+
+use crate::{
+    gpt::{self, PartitionTable},
+    steps::BLANK_IMAGE_SIZE,
+    vfat::FatVolume,
+};
+
+/// The path of the default UEFI boot loader on the EFI system partition.
+const BOOT_LOADER_PATH: [&str; 3] = ["EFI", "Boot", "bootx64.efi"];
+
+/// The role a partition plays in a Windows installation, as determined by its
+/// type GUID.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PartitionRole {
+    Recovery,
+    Efi,
+    Msr,
+    Os,
+    Other,
+}
+
+impl PartitionRole {
+    fn from_type_guid(guid: &str) -> Self {
+        match guid {
+            gpt::WINDOWS_RECOVERY => Self::Recovery,
+            gpt::EFI_SYSTEM_PARTITION => Self::Efi,
+            gpt::MICROSOFT_RESERVED => Self::Msr,
+            gpt::BASIC_DATA => Self::Os,
+            _ => Self::Other,
+        }
+    }
+}
+
+impl std::fmt::Display for PartitionRole {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.pad(match self {
+            Self::Recovery => "Recovery",
+            Self::Efi => "EFI",
+            Self::Msr => "MSR",
+            Self::Os => "OS",
+            Self::Other => "Other",
+        })
+    }
+}
+
+/// The partition roles, in order, of the layout the default `Autounattend.xml`
+/// creates.
+const DEFAULT_LAYOUT: [PartitionRole; 4] = [
+    PartitionRole::Recovery,
+    PartitionRole::Efi,
+    PartitionRole::Msr,
+    PartitionRole::Os,
+];
+
+/// The parts of an NTFS boot sector that describe the volume's geometry.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NtfsVolume {
+    pub bytes_per_sector: u64,
+    pub cluster_size: u64,
+    pub total_sectors: u64,
+}
+
+impl NtfsVolume {
+    pub fn size(&self) -> u64 {
+        self.total_sectors.saturating_mul(self.bytes_per_sector)
+    }
+}
+
+/// Parses an NTFS boot sector, returning `None` if `sector` isn't one.
+fn parse_ntfs_boot_sector(sector: &[u8]) -> Option<NtfsVolume> {
+    if sector.len() < 512
+        || &sector[3..11] != b"NTFS    "
+        || sector[510..512] != [0x55, 0xaa]
+    {
+        return None;
+    }
+
+    let bytes_per_sector =
+        u64::from(u16::from_le_bytes([sector[11], sector[12]]));
+    if !bytes_per_sector.is_power_of_two() || bytes_per_sector < 256 {
+        return None;
+    }
+
+    // Values from 0xE0 up encode cluster sizes too large to express in
+    // sectors as a negative power of two (0xF4 is 2^12 bytes). NTFS never
+    // writes the values in between, so they mean the sector is corrupt.
+    let cluster_size = match sector[13] {
+        n @ 1..=0x80 => u64::from(n) * bytes_per_sector,
+        n @ 0xe0..=0xff => 1 << (256 - u32::from(n)),
+        _ => return None,
+    };
+
+    Some(NtfsVolume {
+        bytes_per_sector,
+        cluster_size,
+        total_sectors: u64::from_le_bytes(sector[40..48].try_into().unwrap()),
+    })
+}
+
+/// The filesystem found in a partition.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Filesystem {
+    Ntfs(NtfsVolume),
+
+    /// A FAT filesystem. `boot_loader_size` is the size of the default UEFI
+    /// boot loader, or `None` if the filesystem doesn't contain one.
+    Fat {
+        boot_loader_size: Option<u32>,
+    },
+    Unknown,
+}
+
+impl std::fmt::Display for Filesystem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.pad(match self {
+            Self::Ntfs(_) => "NTFS",
+            Self::Fat { .. } => "FAT",
+            Self::Unknown => "unknown",
+        })
+    }
+}
+
+/// A partition and what was found in it.
+pub struct InspectedPartition {
+    pub partition: gpt::Partition,
+    pub role: PartitionRole,
+    pub filesystem: Filesystem,
+}
+
+/// The results of inspecting an image.
+pub struct Inspection {
+    pub image_size: u64,
+
+    /// The amount of storage the image occupies, if it was read from a file.
+    pub allocated_size: Option<u64>,
+
+    pub table: PartitionTable,
+    pub backup_valid: bool,
+    pub partitions: Vec<InspectedPartition>,
+}
+
+impl Inspection {
+    /// Inspects the disk image in `disk`.
+    pub fn read<R: Read + Seek>(disk: &mut R) -> Result<Self> {
+        let image_size = disk.seek(SeekFrom::End(0))?;
+        let table = gpt::read_partition_table(disk)?;
+        let backup_valid = gpt::has_valid_backup(disk, &table)?;
+
+        let mut partitions = Vec::new();
+        for partition in &table.partitions {
+            let offset = partition
+                .first_lba
+                .checked_mul(table.sector_size)
+                .with_context(|| {
+                    format!("partition {} is out of range", partition.number)
+                })?;
+            let filesystem = detect_filesystem(disk, offset)?;
+            partitions.push(InspectedPartition {
+                partition: partition.clone(),
+                role: PartitionRole::from_type_guid(&partition.type_guid),
+                filesystem,
+            });
+        }
+
+        Ok(Self {
+            image_size,
+            allocated_size: None,
+            table,
+            backup_valid,
+            partitions,
+        })
+    }
+
+    /// Returns the OS partition: the NTFS basic data partition, or the last
+    /// basic data partition if none of them hold an NTFS volume.
+    fn os_partition(&self) -> Option<&InspectedPartition> {
+        let mut os =
+            self.partitions.iter().filter(|p| p.role == PartitionRole::Os);
+        os.clone()
+            .find(|p| matches!(p.filesystem, Filesystem::Ntfs(_)))
+            .or_else(|| os.next_back())
+    }
+
+    /// Returns whether the partitions are laid out as the default
+    /// `Autounattend.xml` lays them out.
+    pub fn has_default_layout(&self) -> bool {
+        self.partitions.len() == DEFAULT_LAYOUT.len()
+            && self.partitions.iter().zip(1..).zip(DEFAULT_LAYOUT).all(
+                |((p, number), role)| {
+                    p.partition.number == number && p.role == role
+                },
+            )
+    }
+
+    /// Returns descriptions of the problems that would keep the image from
+    /// booting or that suggest the build didn't finish.
+    pub fn problems(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        let sector_size = self.table.sector_size;
+
+        match self.os_partition() {
+            None => problems
+                .push("image has no OS (basic data) partition".to_string()),
+            Some(os) => match &os.filesystem {
+                Filesystem::Ntfs(volume) => {
+                    let partition_size =
+                        os.partition.sectors().saturating_mul(sector_size);
+                    if volume.size() > partition_size {
+                        problems.push(format!(
+                            "NTFS volume in partition {} ({}) is larger than \
+                            its partition ({})",
+                            os.partition.number,
+                            HumanBytes(volume.size()),
+                            HumanBytes(partition_size)
+                        ));
+                    }
+                }
+                _ => problems.push(format!(
+                    "OS partition {} doesn't contain an NTFS volume",
+                    os.partition.number
+                )),
+            },
+        }
+
+        match self.partitions.iter().find(|p| p.role == PartitionRole::Efi) {
+            None => {
+                problems.push("image has no EFI system partition".to_string())
+            }
+            Some(efi) => match &efi.filesystem {
+                Filesystem::Fat { boot_loader_size: Some(_) } => {}
+                Filesystem::Fat { boot_loader_size: None } => {
+                    problems.push(format!(
+                        "EFI system partition {} doesn't contain \\{}",
+                        efi.partition.number,
+                        BOOT_LOADER_PATH.join("\\")
+                    ))
+                }
+                _ => problems.push(format!(
+                    "EFI system partition {} doesn't contain a FAT filesystem",
+                    efi.partition.number
+                )),
+            },
+        }
+
+        // The OS partition fills the blank disk until OxidePrepBaseImage.ps1
+        // shrinks it just before running sysprep, so an OS partition that
+        // still reaches the end of the blank disk means the prep script never
+        // got that far.
+        let blank_last_lba = BLANK_IMAGE_SIZE / sector_size - 1;
+        let unshrunk = self.table.last_used_lba().is_some_and(|last| {
+            // Windows Setup leaves the end of the disk unused for the backup
+            // GPT and alignment, so allow a little slack.
+            last.saturating_add(2048) >= blank_last_lba
+        });
+        if unshrunk {
+            problems.push(format!(
+                "the last partition still fills the {} blank disk, so the \
+                guest never shrank its OS partition; this usually means \
+                OxidePrepBaseImage.ps1 failed before running sysprep",
+                HumanBytes(BLANK_IMAGE_SIZE)
+            ));
+        } else if self.image_size >= BLANK_IMAGE_SIZE {
+            problems.push(format!(
+                "image is still {}, the size of the blank disk; it wasn't \
+                trimmed after installation",
+                HumanBytes(self.image_size)
+            ));
+        }
+
+        if !self.backup_valid {
+            problems.push(
+                "the backup GPT at the end of the image is missing or doesn't \
+                match the primary GPT (repair it with `sgdisk -e`)"
+                    .to_string(),
+            );
+        }
+
+        problems
+    }
+
+    /// Prints a human-readable report of the inspection.
+    pub fn print(&self, w: &mut dyn std::io::Write) -> std::io::Result<()> {
+        writeln!(
+            w,
+            "{}: {} ({} bytes), {}-byte sectors",
+            "Image size".bold(),
+            HumanBytes(self.image_size),
+            self.image_size,
+            self.table.sector_size
+        )?;
+        if let Some(allocated_size) = self.allocated_size {
+            writeln!(
+                w,
+                "{}: {} ({} bytes)",
+                "Allocated size".bold(),
+                HumanBytes(allocated_size),
+                allocated_size
+            )?;
+        }
+
+        writeln!(
+            w,
+            "{}: GPT {}, backup {}",
+            "Partition table".bold(),
+            self.table.disk_guid,
+            if self.backup_valid { "valid" } else { "missing or invalid" }
+        )?;
+        writeln!(w)?;
+
+        writeln!(
+            w,
+            "   #  Role         First LBA      Last LBA        Size  FS       Name"
+        )?;
+        for p in &self.partitions {
+            writeln!(
+                w,
+                "  {:>2}  {:<8}  {:>12}  {:>12}  {:>10}  {:<7}  {}",
+                p.partition.number,
+                p.role,
+                p.partition.first_lba,
+                p.partition.last_lba,
+                HumanBytes(
+                    p.partition
+                        .sectors()
+                        .saturating_mul(self.table.sector_size),
+                )
+                .to_string(),
+                p.filesystem,
+                p.partition.name
+            )?;
+        }
+        let mut details = Vec::new();
+        if !self.has_default_layout() {
+            details.push(
+                "Note: the partitions differ from the default \
+                Autounattend.xml layout (Recovery, EFI, MSR, OS)"
+                    .to_string(),
+            );
+        }
+
+        if let Some(os) = self.os_partition() {
+            if let Filesystem::Ntfs(volume) = &os.filesystem {
+                details.push(format!(
+                    "OS volume: NTFS in partition {}, {}, {} clusters",
+                    os.partition.number,
+                    HumanBytes(volume.size()),
+                    HumanBytes(volume.cluster_size)
+                ));
+            }
+        }
+
+        for p in &self.partitions {
+            if let Filesystem::Fat { boot_loader_size: Some(size) } =
+                p.filesystem
+            {
+                details.push(format!(
+                    "Boot loader: \\{} in partition {} ({})",
+                    BOOT_LOADER_PATH.join("\\"),
+                    p.partition.number,
+                    HumanBytes(u64::from(size))
+                ));
+            }
+        }
+
+        if !details.is_empty() {
+            writeln!(w)?;
+            for detail in details {
+                writeln!(w, "  {detail}")?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Identifies the filesystem that starts `offset` bytes into `disk`.
+fn detect_filesystem<R: Read + Seek>(
+    disk: &mut R,
+    offset: u64,
+) -> Result<Filesystem> {
+    let mut sector = [0u8; 512];
+    disk.seek(SeekFrom::Start(offset))?;
+    if disk.read_exact(&mut sector).is_err() {
+        return Ok(Filesystem::Unknown);
+    }
+
+    if let Some(volume) = parse_ntfs_boot_sector(&sector) {
+        return Ok(Filesystem::Ntfs(volume));
+    }
+
+    let Ok(mut volume) = FatVolume::open(disk, offset) else {
+        return Ok(Filesystem::Unknown);
+    };
+
+    // A damaged directory just means the boot loader can't be found.
+    let boot_loader = volume.find(&BOOT_LOADER_PATH).ok().flatten();
+    Ok(Filesystem::Fat {
+        boot_loader_size: boot_loader.filter(|e| !e.is_dir).map(|e| e.size),
+    })
+}
+
+/// Runs the inspect command.
+pub fn run_inspect(image: &Utf8Path) -> Result<()> {
+    let mut file =
+        File::open(image).with_context(|| format!("opening {image}"))?;
+    let mut inspection = Inspection::read(&mut file)
+        .with_context(|| format!("inspecting {image}"))?;
+    inspection.allocated_size =
+        Some(crate::sparse::allocated_size(&file.metadata()?));
+
+    println!("Inspecting {image}:\n");
+    inspection.print(&mut std::io::stdout())?;
+    println!();
+
+    let problems = inspection.problems();
+    if problems.is_empty() {
+        println!("No problems found.");
+        return Ok(());
+    }
+
+    println!("{}", "Problems:".bold());
+    for problem in &problems {
+        println!("  {problem}");
+    }
+
+    println!();
+    anyhow::bail!("found {} problem(s) in {image}", problems.len())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{
+        gpt::test::build_disk,
+        vfat::{build_image, test::build_boot_image},
+    };
+
+    fn ntfs_boot_sector(total_sectors: u64) -> [u8; 512] {
+        let mut sector = [0u8; 512];
+        sector[3..11].copy_from_slice(b"NTFS    ");
+        sector[11..13].copy_from_slice(&512u16.to_le_bytes());
+        sector[13] = 8;
+        sector[40..48].copy_from_slice(&total_sectors.to_le_bytes());
+        sector[510..512].copy_from_slice(&[0x55, 0xaa]);
+        sector
+    }
+
+    #[test]
+    fn parse_ntfs_boot_sectors() {
+        let mut sector = ntfs_boot_sector(1000);
+        assert_eq!(
+            parse_ntfs_boot_sector(&sector),
+            Some(NtfsVolume {
+                bytes_per_sector: 512,
+                cluster_size: 4096,
+                total_sectors: 1000
+            })
+        );
+
+        // 0xF4 encodes 2^12-byte clusters.
+        sector[13] = 0xf4;
+        assert_eq!(parse_ntfs_boot_sector(&sector).unwrap().cluster_size, 4096);
+
+        // Corrupt cluster sizes are rejected rather than overflowing.
+        for byte in [0, 0x81, 0xc0, 0xdf] {
+            sector[13] = byte;
+            assert_eq!(parse_ntfs_boot_sector(&sector), None, "{byte:#x}");
+        }
+
+        sector[3] = b'X';
+        assert_eq!(parse_ntfs_boot_sector(&sector), None);
+    }
+
+    /// Builds a small image with the default layout, a FAT EFI system
+    /// partition (with a boot loader if `with_boot_loader` is set), and an
+    /// NTFS OS volume of `ntfs_sectors` sectors.
+    fn build_image_disk(with_boot_loader: bool, ntfs_sectors: u64) -> Vec<u8> {
+        let mut disk = build_disk(
+            8192,
+            &[
+                (gpt::WINDOWS_RECOVERY, 34, 99, "Basic data partition"),
+                (gpt::EFI_SYSTEM_PARTITION, 100, 2147, "EFI system partition"),
+                (gpt::MICROSOFT_RESERVED, 2148, 2199, "Microsoft reserved"),
+                (gpt::BASIC_DATA, 2200, 8000, "Basic data partition"),
+            ],
+        );
+
+        let esp = if with_boot_loader {
+            build_boot_image()
+        } else {
+            build_image("ESP", 0, &[]).unwrap()
+        };
+        disk[100 * 512..100 * 512 + esp.len()].copy_from_slice(&esp);
+
+        let ntfs = ntfs_boot_sector(ntfs_sectors);
+        disk[2200 * 512..2201 * 512].copy_from_slice(&ntfs);
+        disk
+    }
+
+    #[test]
+    fn inspect_images() {
+        let disk = build_image_disk(true, 5801);
+        let inspection =
+            Inspection::read(&mut std::io::Cursor::new(disk)).unwrap();
+        assert!(inspection.problems().is_empty());
+        assert_eq!(
+            inspection.partitions[1].filesystem,
+            Filesystem::Fat { boot_loader_size: Some(5) }
+        );
+
+        let disk = build_image_disk(false, 5801);
+        let inspection =
+            Inspection::read(&mut std::io::Cursor::new(disk)).unwrap();
+        assert!(inspection.has_default_layout());
+        assert!(inspection.backup_valid);
+        assert_eq!(
+            inspection
+                .partitions
+                .iter()
+                .map(|p| p.filesystem.to_string())
+                .collect::<Vec<_>>(),
+            ["unknown", "FAT", "unknown", "NTFS"]
+        );
+        assert_eq!(
+            inspection.problems(),
+            ["EFI system partition 2 doesn't contain \\EFI\\Boot\\bootx64.efi"]
+        );
+
+        // An NTFS volume that runs past the end of its partition is flagged.
+        let disk = build_image_disk(false, 5802);
+        let inspection =
+            Inspection::read(&mut std::io::Cursor::new(disk)).unwrap();
+        let problems = inspection.problems();
+        assert_eq!(problems.len(), 2);
+        assert!(problems[0].contains("larger than its partition"));
+
+        // So is a truncated image whose backup GPT is gone.
+        let mut disk = build_image_disk(false, 5801);
+        disk.truncate(8034 * 512);
+        let inspection =
+            Inspection::read(&mut std::io::Cursor::new(disk)).unwrap();
+        assert!(inspection.problems().iter().any(|p| p.contains("backup GPT")));
+
+        let mut report = Vec::new();
+        inspection.print(&mut report).unwrap();
+        let report = String::from_utf8(report).unwrap();
+        assert!(report.contains("OS volume: NTFS in partition 4"));
+    }
+
+    #[test]
+    fn flag_unshrunk_images() {
+        let last_lba = BLANK_IMAGE_SIZE / 512 - 1;
+        let os = gpt::Partition {
+            number: 1,
+            type_guid: gpt::BASIC_DATA.to_string(),
+            unique_guid: String::new(),
+            first_lba: 2048,
+            last_lba: last_lba - 33,
+            name: String::new(),
+        };
+
+        let inspection = Inspection {
+            image_size: BLANK_IMAGE_SIZE,
+            allocated_size: None,
+            table: PartitionTable {
+                sector_size: 512,
+                disk_guid: String::new(),
+                first_usable_lba: 34,
+                last_usable_lba: last_lba - 33,
+                backup_lba: last_lba,
+                entry_count: 128,
+                entry_size: 128,
+                partitions: vec![os.clone()],
+            },
+            backup_valid: true,
+            partitions: vec![InspectedPartition {
+                partition: os,
+                role: PartitionRole::Os,
+                filesystem: Filesystem::Ntfs(NtfsVolume {
+                    bytes_per_sector: 512,
+                    cluster_size: 4096,
+                    total_sectors: 1000,
+                }),
+            }],
+        };
+
+        assert!(!inspection.has_default_layout());
+        let problems = inspection.problems();
+        assert!(problems.iter().any(|p| p.contains("never shrank")));
+    }
+}
@@ -5,22 +5,38 @@
 //! Defines a script for building a Windows guest image on a Linux system using
 //! QEMU.
 
-use std::{collections::HashMap, io::Write, process::Command, str::FromStr};
+use std::{
+    cell::RefCell, collections::HashMap, io::Write,
+    os::unix::process::CommandExt, process::Command, str::FromStr,
+};
 
 use crate::{
-    app::ImageSources,
-    runner::{Context, MissingPrerequisites, Script, ScriptStep},
+    app::{ImageSources, QemuOptions, VerifyOptions},
+    autounattend::{
+        ReplacementRule, UnattendInsertion, UnattendSetting, WindowsVersion,
+    },
+    checksum::{check_inputs, HashedInput},
+    image_metadata::describe_installation,
+    manifest::{parse_driver_version, DriverVersion},
+    runner::{
+        interrupt_requested, Context, MissingPrerequisites, Script, ScriptStep,
+    },
+    steps::ImageAlignment,
     ui::Ui,
     util::{
-        check_executable_prerequisites, check_file_prerequisites,
-        run_command_check_status,
+        check_executable_prerequisites, check_extra_files_prerequisites,
+        check_file_prerequisites, copy_dir_contents, copy_file_with_progress,
+        run_command_capture_output, run_command_check_status,
+        wait_for_child_or_interrupt,
     },
-    UNATTEND_FILES,
+    validate::{validate_answer_file, validate_unattend_dir},
+    OFFLINE_PACKAGES, UNATTEND_FILES,
 };
 
 use anyhow::{Context as _, Result};
-use camino::Utf8PathBuf;
+use camino::{Utf8Path, Utf8PathBuf};
 use colored::Colorize;
+use itertools::Itertools;
 
 pub struct CreateGuestDiskImageArgs {
     pub work_dir: Utf8PathBuf,
@@ -28,16 +44,38 @@ pub struct CreateGuestDiskImageArgs {
     pub sources: ImageSources,
     pub ovmf_path: Utf8PathBuf,
     pub vga_console: bool,
+
+    /// If set, the options with which to verify the image after building it.
+    pub verify: Option<VerifyOptions>,
+
+    /// Whether to deallocate zero-filled blocks in the finished image.
+    pub punch_holes: bool,
+
+    /// The multiple to which to round up the size of the trimmed image.
+    pub align: ImageAlignment,
+
+    /// The QEMU binary and accelerators with which to run VMs.
+    pub qemu: QemuOptions,
 }
 
 pub struct CreateGuestDiskImageScript {
     steps: Vec<ScriptStep>,
+    hashed_inputs: RefCell<Vec<HashedInput>>,
     args: CreateGuestDiskImageArgs,
 }
 
 impl CreateGuestDiskImageScript {
     pub(super) fn new(script_args: CreateGuestDiskImageArgs) -> Self {
-        Self { steps: get_script(), args: script_args }
+        let mut steps = get_script(script_args.punch_holes);
+        if let Some(options) = &script_args.verify {
+            steps.extend(super::verify_image::verification_steps(options));
+        }
+
+        Self {
+            steps,
+            hashed_inputs: RefCell::new(Vec::new()),
+            args: script_args,
+        }
     }
 }
 
@@ -71,7 +109,21 @@ impl Script for CreateGuestDiskImageScript {
             "Unattend file directory".bold(),
             sources.unattend_dir
         )?;
+        if let Some(extra_files) = &sources.extra_files {
+            writeln!(w, "  {}: {}", "Extra files".bold(), extra_files)?;
+        }
+        for (_, package) in sources.offline_packages() {
+            writeln!(w, "  {}: {}", "Offline package".bold(), package)?;
+        }
         writeln!(w, "  {}: {}", "Guest bootrom".bold(), args.ovmf_path)?;
+        writeln!(w, "  {}: {}", "QEMU binary".bold(), args.qemu.qemu_binary)?;
+        writeln!(w, "  {}: {}", "VM profile".bold(), args.qemu.vm_profile)?;
+        if args.qemu.allow_tcg {
+            writeln!(
+                w,
+                "  Will use TCG software emulation if KVM is unavailable"
+            )?;
+        }
 
         writeln!(w)?;
 
@@ -94,13 +146,41 @@ impl Script for CreateGuestDiskImageScript {
             )?;
         }
 
+        for setting in &sources.unattend_set {
+            writeln!(w, "  Will set in Autounattend.xml: {}", setting)?;
+        }
+
+        for path in &sources.unattend_unset {
+            writeln!(w, "  Will remove from Autounattend.xml: {}", path)?;
+        }
+
+        for insertion in &sources.unattend_insert {
+            writeln!(w, "  Will insert into Autounattend.xml: {}", insertion)?;
+        }
+
         writeln!(w)?;
         writeln!(w, "  {}: {}", "Output file".bold(), args.output_image)?;
+        writeln!(
+            w,
+            "  Will round image size up to a multiple of {}",
+            args.align
+        )?;
+        if args.punch_holes {
+            writeln!(w, "  Will punch holes in zero-filled regions of image")?;
+        }
+
+        if let Some(options) = &args.verify {
+            writeln!(
+                w,
+                "  Will verify the image by booting it as {} with user {}",
+                options.verify_hostname, options.verify_user
+            )?;
+        }
 
         Ok(())
     }
 
-    fn check_prerequisites(&self) -> MissingPrerequisites {
+    fn check_prerequisites(&self, ui: &dyn Ui) -> MissingPrerequisites {
         let mut errors = Vec::new();
         let mut warnings = Vec::new();
         let mut files = vec![
@@ -112,6 +192,16 @@ impl Script for CreateGuestDiskImageScript {
         // The ISOs and bootrom are strictly required to proceed.
         errors.extend(check_file_prerequisites(&files));
 
+        // Verify the inputs' digests (and remember them so they can be recorded
+        // with the output) after making sure the inputs exist.
+        let (hashed, checksum_errors) = check_inputs(
+            &self.args.sources.input_files(),
+            self.args.sources.sha256sums.as_deref(),
+            ui,
+        );
+        errors.extend(checksum_errors);
+        *self.hashed_inputs.borrow_mut() = hashed;
+
         // The unattend files are generally desirable, but it's possible to run
         // without them. For example:
         //
@@ -129,6 +219,39 @@ impl Script for CreateGuestDiskImageScript {
 
         warnings.extend(check_file_prerequisites(&files));
 
+        // Offline packages were requested explicitly, so they must exist.
+        files.clear();
+        for (_, package) in self.args.sources.offline_packages() {
+            files.push(package.clone());
+        }
+
+        errors.extend(check_file_prerequisites(&files));
+
+        // Answer files that are present should be free of obvious mistakes.
+        let problems = validate_unattend_dir(&self.args.sources.unattend_dir);
+        errors.extend(problems.errors);
+        warnings.extend(problems.warnings);
+
+        if let Some(extra_files) = &self.args.sources.extra_files {
+            let mut reserved = UNATTEND_FILES.to_vec();
+            reserved.extend(OFFLINE_PACKAGES.iter().map(|(_, file)| *file));
+            errors.extend(check_extra_files_prerequisites(
+                extra_files,
+                &reserved,
+            ));
+        }
+
+        if let Some(options) = &self.args.verify {
+            errors.extend(super::verify_image::check_verify_prerequisites(
+                options,
+            ));
+        }
+
+        let (qemu_errors, qemu_warnings) =
+            super::qemu::check_qemu_prerequisites(&self.args.qemu);
+        errors.extend(qemu_errors);
+        warnings.extend(qemu_warnings);
+
         // All the relevant executables are required to proceed.
         errors.extend(check_executable_prerequisites(self.steps()));
 
@@ -148,10 +271,96 @@ impl Script for CreateGuestDiskImageScript {
         .into_iter()
         .collect();
 
+        if let Some(image_index) = args.sources.unattend_image_index {
+            ctx.insert(
+                "unattend_image_index".to_string(),
+                image_index.to_string(),
+            );
+        }
+
+        if let Some(windows_version) = args.sources.windows_version {
+            ctx.insert(
+                "windows_version".to_string(),
+                windows_version.as_driver_path_component().to_string(),
+            );
+        }
+
+        if !args.sources.unattend_set.is_empty() {
+            ctx.insert(
+                "unattend_set".to_string(),
+                args.sources
+                    .unattend_set
+                    .iter()
+                    .map(|s| s.to_string())
+                    .join("\n"),
+            );
+        }
+
+        if !args.sources.unattend_unset.is_empty() {
+            ctx.insert(
+                "unattend_unset".to_string(),
+                args.sources
+                    .unattend_unset
+                    .iter()
+                    .map(|p| p.to_string())
+                    .join("\n"),
+            );
+        }
+
+        if !args.sources.unattend_insert.is_empty() {
+            ctx.insert(
+                "unattend_insert".to_string(),
+                args.sources
+                    .unattend_insert
+                    .iter()
+                    .map(|i| i.to_string())
+                    .join("\n"),
+            );
+        }
+
+        if let Some(extra_files) = &args.sources.extra_files {
+            ctx.insert("extra_files".to_string(), extra_files.to_string());
+        }
+
+        for (var, package) in args.sources.offline_packages() {
+            ctx.insert(var.to_string(), package.to_string());
+        }
+
+        ctx.insert(
+            "hashed_inputs".to_string(),
+            serde_json::to_string(&*self.hashed_inputs.borrow()).unwrap(),
+        );
+
         if args.vga_console {
             ctx.insert("vga_console".to_string(), String::new());
         }
 
+        ctx.insert("align".to_string(), args.align.to_string());
+        super::qemu::insert_qemu_context(&args.qemu, &mut ctx);
+
+        if let Some(options) = &args.verify {
+            super::verify_image::insert_verification_context(options, &mut ctx);
+        }
+
+        ctx.insert(
+            "manifest_options".to_string(),
+            serde_json::json!({
+                "command": "create-guest-disk-image",
+                "work_dir": args.work_dir,
+                "output_image": args.output_image,
+                "sources": args.sources,
+                "ovmf_path": args.ovmf_path,
+                "vga_console": args.vga_console,
+                "verify": args.verify.is_some(),
+                "punch_holes": args.punch_holes,
+                "align": args.align.to_string(),
+                "qemu_binary": args.qemu.qemu_binary,
+                "allow_tcg": args.qemu.allow_tcg,
+                "vm_profile": args.qemu.vm_profile.to_string(),
+            })
+            .to_string(),
+        );
+
         ctx
     }
 }
@@ -186,6 +395,13 @@ fn copy_unattend_files_to_work_dir(
     let mut work_unattend =
         Utf8PathBuf::from_str(ctx.get_var("work_dir").unwrap()).unwrap();
     work_unattend.push("unattend");
+
+    // Start from an empty directory so that files left over from a previous
+    // run (e.g. old extra files) don't end up on the configuration ISO.
+    if work_unattend.exists() {
+        std::fs::remove_dir_all(&work_unattend)
+            .context("removing old unattend files from work directory")?;
+    }
     std::fs::create_dir_all(&work_unattend)
         .context("creating temporary directory for unattend files")?;
 
@@ -214,13 +430,66 @@ fn copy_unattend_files_to_work_dir(
     Ok(())
 }
 
-fn customize_autounattend_xml(ctx: &mut Context, _ui: &dyn Ui) -> Result<()> {
-    let customizer = crate::autounattend::AutounattendUpdater::new(
+fn copy_extra_files_to_work_dir(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
+    let Some(extra_files) = ctx.get_var("extra_files") else {
+        ui.set_substep("no extra files specified");
+        return Ok(());
+    };
+
+    copy_dir_contents(
+        Utf8Path::new(extra_files),
+        Utf8Path::new(ctx.get_var("unattend_dir").unwrap()),
+        ui,
+    )
+    .context("copying extra files to work directory")
+}
+
+fn copy_offline_packages_to_work_dir(
+    ctx: &mut Context,
+    ui: &dyn Ui,
+) -> Result<()> {
+    let unattend_dir =
+        Utf8PathBuf::from_str(ctx.get_var("unattend_dir").unwrap()).unwrap();
+
+    for (var, filename) in OFFLINE_PACKAGES {
+        let Some(package) = ctx.get_var(var) else {
+            continue;
+        };
+
+        copy_file_with_progress(
+            Utf8Path::new(package),
+            &unattend_dir.join(filename),
+            ui,
+        )?;
+    }
+
+    Ok(())
+}
+
+fn customize_autounattend_xml(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
+    let mut customizer = crate::autounattend::AutounattendUpdater::new(
         ctx.get_var("unattend_image_index")
             .map(|val| val.parse::<u32>().unwrap()),
-        None,
+        ctx.get_var("windows_version").map(|val| {
+            WindowsVersion::from_driver_path_component(val).unwrap()
+        }),
     );
 
+    for setting in ctx.get_var("unattend_set").into_iter().flat_map(str::lines)
+    {
+        customizer.add_rule(setting.parse::<UnattendSetting>()?.into());
+    }
+
+    for path in ctx.get_var("unattend_unset").into_iter().flat_map(str::lines) {
+        customizer.add_rule(ReplacementRule::delete(path.parse()?));
+    }
+
+    for insertion in
+        ctx.get_var("unattend_insert").into_iter().flat_map(str::lines)
+    {
+        customizer.add_rule(insertion.parse::<UnattendInsertion>()?.into());
+    }
+
     let unattend_dir =
         Utf8PathBuf::from_str(ctx.get_var("unattend_dir").unwrap()).unwrap();
     let mut unattend_src = unattend_dir.clone();
@@ -234,12 +503,104 @@ fn customize_autounattend_xml(ctx: &mut Context, _ui: &dyn Ui) -> Result<()> {
         .run(&unattend_src, &unattend_dst)
         .context("customizing Autounattend.xml")?;
 
+    let problems = validate_answer_file(&unattend_dst);
+    if !problems.errors.is_empty() {
+        anyhow::bail!(
+            "customized Autounattend.xml is invalid:\n  {}",
+            problems.errors.join("\n  ")
+        );
+    }
+
+    for warning in &problems.warnings {
+        ui.set_substep(&format!("Warning: {warning}"));
+    }
+
     std::fs::remove_file(&unattend_src)
         .context("removing temporary Autounattend.xml")?;
 
     Ok(())
 }
 
+fn read_virtio_driver_versions(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
+    // Setup loads drivers from the directories the customized answer file
+    // names, so only record the versions of the drivers in those directories.
+    let unattend_dir = Utf8Path::new(ctx.get_var("unattend_dir").unwrap());
+    let autounattend = unattend_dir.join("Autounattend.xml");
+    let Ok(contents) = std::fs::read_to_string(&autounattend) else {
+        ui.set_substep("no Autounattend.xml to read driver paths from");
+        return Ok(());
+    };
+
+    let windows_versions: Vec<String> =
+        crate::autounattend::summarize_answer_file(&contents)?
+            .driver_versions
+            .iter()
+            .map(|v| v.as_driver_path_component().to_string())
+            .collect();
+
+    let virtio_iso = ctx.get_var("virtio_iso").unwrap().to_string();
+    let listing = run_command_capture_output(
+        Command::new("isoinfo").args(["-J", "-f", "-i", &virtio_iso]),
+        ui,
+    )?;
+
+    // Driver INF files live at /<driver>/<version>/amd64/<driver>.inf.
+    let mut drivers = Vec::new();
+    for path in String::from_utf8_lossy(&listing.stdout).lines() {
+        let components: Vec<&str> = path.split('/').collect();
+        let [_, _, version, arch, inf] = components.as_slice() else {
+            continue;
+        };
+
+        let Some(windows_version) =
+            windows_versions.iter().find(|v| v.eq_ignore_ascii_case(version))
+        else {
+            continue;
+        };
+
+        if !arch.eq_ignore_ascii_case("amd64")
+            || !inf.to_ascii_lowercase().ends_with(".inf")
+        {
+            continue;
+        }
+
+        ui.set_substep(&format!("reading {path}"));
+        let contents = run_command_capture_output(
+            Command::new("isoinfo").args(["-J", "-i", &virtio_iso, "-x", path]),
+            ui,
+        )?;
+
+        if let Some(version) = parse_driver_version(&contents.stdout) {
+            drivers.push(DriverVersion {
+                driver: inf[..inf.len() - ".inf".len()].to_string(),
+                windows_version: windows_version.clone(),
+                version,
+            });
+        }
+    }
+
+    ctx.set_var("virtio_driver_versions", serde_json::to_string(&drivers)?);
+    Ok(())
+}
+
+fn read_windows_edition(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
+    let unattend_dir = Utf8Path::new(ctx.get_var("unattend_dir").unwrap());
+    let iso = Utf8Path::new(ctx.get_var("windows_iso").unwrap());
+    let Some(metadata) = describe_installation(
+        &unattend_dir.join("Autounattend.xml"),
+        ctx.get_var("windows_version"),
+        || crate::wim::read_iso_images(iso),
+        ui,
+    )?
+    else {
+        return Ok(());
+    };
+
+    ui.set_substep(&format!("installing {}", metadata.description));
+    ctx.set_var("image_metadata", serde_json::to_string(&metadata)?);
+    Ok(())
+}
+
 fn install_via_qemu(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
     // Launch a VM in QEMU with the installation target disk attached as an NVMe
     // drive and CD-ROM drives containing the Windows installation media, the
@@ -271,15 +632,21 @@ fn install_via_qemu(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
         ctx.get_var("unattend_iso").unwrap()
     );
 
-    let mut args = vec![
-        "-nodefaults",
-        "-enable-kvm",
-        "-M",
-        "pc",
+    let profile = super::qemu::vm_profile(ctx);
+    let machine_args = profile.machine_args();
+    let nic_arg = profile.nic("net0");
+    let install_disk_device = profile.boot_disk("drivec");
+    let windows_cd_device = profile.cdrom(0, "win-disk");
+    let virtio_cd_device = profile.cdrom(1, "virtio-disk");
+    let unattend_cd_device = profile.cdrom(2, "unattend-disk");
+
+    let accel = super::qemu::accelerator(ctx, ui)?;
+    let mut args = vec!["-nodefaults"];
+    args.extend(accel.qemu_args());
+    args.extend(machine_args.iter().map(String::as_str));
+    args.extend([
         "-m",
         "2048",
-        "-cpu",
-        "host,kvm=off,hv_relaxed,hv_spinlocks=0x1fff,hv_vapic,hv_time",
         "-smp",
         "2,sockets=1,cores=2",
         "-rtc",
@@ -289,22 +656,21 @@ fn install_via_qemu(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
         "-netdev",
         "user,id=net0",
         "-device",
-        "virtio-net-pci,netdev=net0",
+        &nic_arg,
         "-device",
-        "nvme,drive=drivec,serial=01de01de,physical_block_size=512,\
-                logical_block_size=512,discard_granularity=512,bootindex=1",
+        &install_disk_device,
         "-drive",
         &install_disk_arg,
         "-device",
-        "ide-cd,drive=win-disk,id=cd-disk0,unit=0,bus=ide.0,bootindex=2",
+        &windows_cd_device,
         "-drive",
         &windows_iso_arg,
         "-device",
-        "ide-cd,drive=virtio-disk,id=cd-disk1,unit=0,bus=ide.1",
+        &virtio_cd_device,
         "-drive",
         &virtio_iso_arg,
         "-device",
-        "ide-cd,drive=unattend-disk,id=cd-disk2,unit=1,bus=ide.0",
+        &unattend_cd_device,
         "-drive",
         &unattend_iso_arg,
         // prep.cmd, the wrapper script that executes OxidePrepBaseImage.ps1,
@@ -318,7 +684,7 @@ fn install_via_qemu(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
         // commands via TCP.
         "-monitor",
         "telnet:localhost:8888,server,nowait",
-    ];
+    ]);
 
     if ctx.get_var("vga_console").is_some() {
         args.extend_from_slice(&["-vga", "std", "-display", "gtk"]);
@@ -326,20 +692,80 @@ fn install_via_qemu(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
         args.extend_from_slice(&["-display", "none"]);
     }
 
-    let qemu = "qemu-system-x86_64";
-    let qemu = Command::new(qemu)
-        .args(&args)
-        .stdout::<std::fs::File>(ui.child_stdout(qemu)?)
-        .stderr::<std::fs::File>(ui.child_stderr(qemu)?)
+    let mut qemu = super::qemu::qemu_command(ctx);
+    qemu.args(&args);
+    let mut logs = ui.child_logs(&qemu)?;
+    // Run QEMU in its own process group so that a Ctrl-C in the terminal
+    // interrupts only wimsy, which then stops QEMU through its monitor.
+    let mut qemu = qemu
+        .stdout(logs.stdout.try_clone()?)
+        .stderr(logs.stderr.try_clone()?)
+        .process_group(0)
         .spawn()?;
 
+    let mut telnet = match press_enter_to_boot(ui) {
+        Ok(telnet) => telnet,
+        Err(e) => {
+            // Don't leave QEMU running if it can't be driven.
+            let _ = qemu.kill();
+            let _ = qemu.wait();
+            return Err(e);
+        }
+    };
+
+    ui.set_substep("waiting for guest to complete installation");
+    let status = wait_for_child_or_interrupt(
+        &mut qemu,
+        std::time::Duration::from_secs(10),
+        |_| {
+            // Ask QEMU to exit via its monitor. This stops the guest
+            // immediately, but lets QEMU shut down in an orderly way.
+            telnet.write_all(b"quit\n")?;
+            Ok(telnet.flush()?)
+        },
+    )?;
+
+    logs.record_exit_status(status)?;
+    if !status.success() {
+        anyhow::bail!("QEMU returned non-success exit code: {status}");
+    }
+
+    Ok(())
+}
+
+/// Connects to the QEMU monitor and simulates mashing the Enter key to get past
+/// the "Press any key to boot from CD or DVD" prompt and the Windows boot menu.
+/// Returns the connection to the monitor.
+fn press_enter_to_boot(ui: &dyn Ui) -> Result<std::net::TcpStream> {
+    let mut telnet = connect_to_monitor("127.0.0.1:8888", ui)?;
+
+    ui.set_substep("booting from installation media");
+    for _ in 0..20 {
+        if interrupt_requested() {
+            anyhow::bail!("interrupted by user");
+        }
+
+        telnet.write_all("sendkey ret\n".as_bytes())?;
+        telnet.flush()?;
+        std::thread::sleep(std::time::Duration::from_secs(1));
+    }
+
+    Ok(telnet)
+}
+
+/// Connects to the QEMU monitor listening at `addr`, retrying for a few seconds
+/// while QEMU starts up.
+pub(super) fn connect_to_monitor(
+    addr: &str,
+    ui: &dyn Ui,
+) -> Result<std::net::TcpStream> {
     ui.set_substep("connecting to QEMU's telnet control interface");
     let mut attempts = 0;
-    let mut telnet = loop {
-        match std::net::TcpStream::connect("127.0.0.1:8888") {
+    loop {
+        match std::net::TcpStream::connect(addr) {
             Ok(stream) => break Ok(stream),
             Err(_) => {
-                if attempts < 10 {
+                if attempts < 10 && !interrupt_requested() {
                     std::thread::sleep(std::time::Duration::from_secs(1));
                     attempts += 1;
                 } else {
@@ -349,23 +775,7 @@ fn install_via_qemu(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
                 }
             }
         }
-    }?;
-
-    // Simulate mashing the Enter key to get past the "Press any key to boot
-    // from CD or DVD" prompt and the Windows boot menu.
-    ui.set_substep("waiting for guest to complete installation");
-    for _ in 0..20 {
-        telnet.write_all("sendkey ret\n".as_bytes())?;
-        telnet.flush()?;
-        std::thread::sleep(std::time::Duration::from_secs(1));
-    }
-
-    let output = qemu.wait_with_output()?;
-    if !output.status.success() {
-        anyhow::bail!("QEMU returned non-success exit code: {:?}", output);
     }
-
-    Ok(())
 }
 
 fn get_partition_size(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
@@ -385,6 +795,7 @@ fn shrink_output_image(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
         ctx.get_var("output_image").unwrap(),
         ctx.get_var("sector_size").unwrap(),
         ctx.get_var("last_sector").unwrap(),
+        ctx.get_var("align").unwrap(),
         ui,
     )
 }
@@ -393,35 +804,83 @@ fn repair_secondary_gpt(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
     crate::steps::repair_secondary_gpt(ctx.get_var("output_image").unwrap(), ui)
 }
 
-fn get_script() -> Vec<ScriptStep> {
-    vec![
+fn record_input_checksums(ctx: &mut Context, _ui: &dyn Ui) -> Result<()> {
+    crate::steps::record_input_checksums(
+        ctx.get_var("output_image").unwrap(),
+        ctx.get_var("hashed_inputs").unwrap(),
+    )
+}
+
+fn punch_holes(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
+    crate::steps::punch_holes_in_output_image(
+        ctx.get_var("output_image").unwrap(),
+        ui,
+    )
+}
+
+fn write_block_map(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
+    crate::steps::write_output_block_map(
+        ctx.get_var("output_image").unwrap(),
+        ui,
+    )
+}
+
+fn write_image_metadata(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
+    crate::steps::write_output_image_metadata(
+        ctx.get_var("output_image").unwrap(),
+        ctx.get_var("image_metadata"),
+        ui,
+    )
+}
+
+fn write_manifest(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
+    crate::manifest::write_manifest(ctx, ui)
+}
+
+fn get_script(punch_holes_in_image: bool) -> Vec<ScriptStep> {
+    let mut steps = vec![
         ScriptStep::with_prereqs(
             "create output image",
             create_output_image,
             &["qemu-img"],
         ),
-        ScriptStep::with_prereqs(
-            "create guest configuration ISO",
-            create_config_iso,
-            &["genisoimage"],
-        ),
         ScriptStep::new(
             "copy unattend files to work directory",
             copy_unattend_files_to_work_dir,
         ),
+        ScriptStep::new(
+            "copy extra files to work directory",
+            copy_extra_files_to_work_dir,
+        ),
+        ScriptStep::new(
+            "copy offline packages to work directory",
+            copy_offline_packages_to_work_dir,
+        ),
         ScriptStep::new(
             "customize Autounattend.xml",
             customize_autounattend_xml,
         ),
         ScriptStep::with_prereqs(
+            "read virtio driver versions",
+            read_virtio_driver_versions,
+            &["isoinfo"],
+        ),
+        ScriptStep::new(
+            "read Windows edition from install image",
+            read_windows_edition,
+        ),
+        ScriptStep::with_prereqs(
+            "create guest configuration ISO",
+            create_config_iso,
+            &["genisoimage"],
+        ),
+        ScriptStep::new(
             "install Windows to output image using QEMU",
             install_via_qemu,
-            &["qemu-system-x86_64"],
         ),
-        ScriptStep::with_prereqs(
-            "get size of primary installation partition",
+        ScriptStep::new(
+            "find last sector used by output image partitions",
             get_partition_size,
-            &["sgdisk"],
         ),
         ScriptStep::with_prereqs(
             "trim unused sectors from output image",
@@ -433,5 +892,24 @@ fn get_script() -> Vec<ScriptStep> {
             repair_secondary_gpt,
             &["sgdisk"],
         ),
-    ]
+    ];
+
+    if punch_holes_in_image {
+        steps.push(ScriptStep::new(
+            "punch holes in zero-filled regions of output image",
+            punch_holes,
+        ));
+    }
+
+    steps.extend([
+        ScriptStep::new("write block map of output image", write_block_map),
+        ScriptStep::new(
+            "record checksums of input files",
+            record_input_checksums,
+        ),
+        ScriptStep::new("write image metadata", write_image_metadata),
+        ScriptStep::new("write build manifest", write_manifest),
+    ]);
+
+    steps
 }
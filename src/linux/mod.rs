@@ -6,24 +6,66 @@
 
 use crate::{app::Command, runner::Script};
 
-use self::create_guest_disk_image::{
-    CreateGuestDiskImageArgs, CreateGuestDiskImageScript,
+use self::{
+    create_guest_disk_image::{
+        CreateGuestDiskImageArgs, CreateGuestDiskImageScript,
+    },
+    verify_image::{VerifyImageArgs, VerifyImageScript},
 };
 
 mod create_guest_disk_image;
+mod qemu;
+mod verify_image;
+pub(crate) mod vm_profile;
 
 pub fn get_script(app: &crate::app::App) -> Box<dyn Script> {
     match &app.command {
-        Command::CreateGuestDiskImage { sources, ovmf_path, vga_console } => {
-            Box::new(CreateGuestDiskImageScript::new(
-                CreateGuestDiskImageArgs {
-                    sources: sources.clone(),
-                    work_dir: app.work_dir.clone(),
-                    output_image: app.output_image.clone(),
-                    ovmf_path: ovmf_path.clone(),
-                    vga_console: *vga_console,
-                },
-            ))
+        Command::CreateGuestDiskImage {
+            sources,
+            ovmf_path,
+            vga_console,
+            verify,
+            verify_options,
+            punch_holes,
+            align,
+            qemu,
+        } => Box::new(CreateGuestDiskImageScript::new(
+            CreateGuestDiskImageArgs {
+                sources: sources.as_ref().clone(),
+                work_dir: app.work_dir().to_owned(),
+                output_image: app.output_image().to_owned(),
+                ovmf_path: ovmf_path.clone(),
+                vga_console: *vga_console,
+                verify: verify.then(|| verify_options.clone()),
+                punch_holes: *punch_holes,
+                align: *align,
+                qemu: qemu.clone(),
+            },
+        )),
+        Command::VerifyImage { ovmf_path, vga_console, options, qemu } => {
+            Box::new(VerifyImageScript::new(VerifyImageArgs {
+                work_dir: app.work_dir().to_owned(),
+                output_image: app.output_image().to_owned(),
+                ovmf_path: ovmf_path.clone(),
+                vga_console: *vga_console,
+                options: options.clone(),
+                qemu: qemu.clone(),
+            }))
+        }
+        Command::ValidateUnattend { .. } => {
+            unreachable!("validate-unattend doesn't run a script")
+        }
+        Command::GenerateUnattend { .. } => {
+            unreachable!("generate-unattend doesn't run a script")
+        }
+        Command::MakeConfigDrive { .. } => {
+            unreachable!("make-config-drive doesn't run a script")
+        }
+        Command::Inspect { .. } => {
+            unreachable!("inspect doesn't run a script")
+        }
+        Command::Upload { .. } => {
+            unreachable!("upload's script isn't specific to an OS")
         }
     }
 }
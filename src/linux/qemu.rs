@@ -0,0 +1,189 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Finds the QEMU binary and chooses the accelerator with which the Linux
+//! scripts run their VMs.
+//!
+//! VMs run with KVM whenever `/dev/kvm` can be opened. Hosts without KVM (e.g.
+//! CI runners and nested VMs without nested virtualization) can run VMs with
+//! QEMU's TCG software emulator instead, but only if the user passes
+//! `--allow-tcg`, since TCG is slow enough that installing Windows can take
+//! hours.
+
+use std::collections::HashMap;
+
+use anyhow::Result;
+
+use crate::{app::QemuOptions, runner::Context, ui::Ui};
+
+use super::vm_profile::VmProfile;
+
+/// The path to the KVM device.
+const KVM_DEVICE: &str = "/dev/kvm";
+
+/// The CPU model to present to KVM guests. `kvm=off` hides the KVM signature
+/// from the guest; the `hv_` options enable the Hyper-V enlightenments Windows
+/// uses to run more efficiently in a VM.
+const KVM_CPU: &str =
+    "host,kvm=off,hv_relaxed,hv_spinlocks=0x1fff,hv_vapic,hv_time";
+
+/// The CPU model to present to TCG guests. TCG can't pass the host's CPU
+/// through, and its default model lacks instructions that Windows 11 and
+/// Windows Server 2025 require (they need an x86-64-v2 CPU), so add them to
+/// the baseline model explicitly.
+const TCG_CPU: &str = "qemu64,+ssse3,+sse4.1,+sse4.2,+popcnt,+cx16,+lahf_lm";
+
+/// The accelerator with which QEMU runs a VM.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(super) enum Accelerator {
+    Kvm,
+    Tcg,
+}
+
+impl Accelerator {
+    /// Returns the QEMU arguments that select this accelerator and a CPU model
+    /// it supports.
+    pub(super) fn qemu_args(&self) -> [&'static str; 4] {
+        match self {
+            Accelerator::Kvm => ["-accel", "kvm", "-cpu", KVM_CPU],
+            Accelerator::Tcg => ["-accel", "tcg,thread=multi", "-cpu", TCG_CPU],
+        }
+    }
+}
+
+/// Checks whether KVM is usable by this process. Returns a description of the
+/// problem if it isn't.
+fn check_kvm() -> Result<(), String> {
+    match std::fs::File::options().read(true).write(true).open(KVM_DEVICE) {
+        Ok(_) => Ok(()),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Err(format!(
+            "{KVM_DEVICE} doesn't exist (is virtualization enabled in the \
+            host's firmware, and is the kvm module loaded?)"
+        )),
+        Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+            Err(format!(
+                "can't open {KVM_DEVICE}: permission denied (add yourself to \
+                the kvm group with `sudo usermod -aG kvm $USER` and log in \
+                again)"
+            ))
+        }
+        Err(e) => Err(format!("can't open {KVM_DEVICE}: {e}")),
+    }
+}
+
+/// Chooses an accelerator given the result of checking for KVM.
+fn choose_accelerator(
+    kvm: Result<(), String>,
+    allow_tcg: bool,
+) -> Result<Accelerator, String> {
+    match kvm {
+        Ok(()) => Ok(Accelerator::Kvm),
+        Err(_) if allow_tcg => Ok(Accelerator::Tcg),
+        Err(e) => Err(format!(
+            "KVM is unavailable: {e}; pass --allow-tcg to use software \
+            emulation instead"
+        )),
+    }
+}
+
+/// Checks that the QEMU binary in `options` exists and that VMs can be run
+/// with an accelerator `options` allows. Returns a list of errors and a list
+/// of warnings.
+pub(super) fn check_qemu_prerequisites(
+    options: &QemuOptions,
+) -> (Vec<String>, Vec<String>) {
+    let mut errors = Vec::new();
+    let mut warnings = Vec::new();
+    if let Err(e) = which::which(&options.qemu_binary) {
+        errors.push(format!(
+            "QEMU binary '{}' not found (is it installed and on your PATH?): \
+            {e}",
+            options.qemu_binary
+        ));
+    }
+
+    let kvm = check_kvm();
+    let unavailable = kvm.as_ref().err().cloned();
+    match choose_accelerator(kvm, options.allow_tcg) {
+        Ok(Accelerator::Kvm) => {}
+        Ok(Accelerator::Tcg) => warnings.push(format!(
+            "KVM is unavailable: {}. VMs will run with TCG software emulation, \
+            which is MUCH slower than KVM; installing Windows can take hours.",
+            unavailable.unwrap()
+        )),
+        Err(e) => errors.push(e),
+    }
+
+    (errors, warnings)
+}
+
+/// Adds the context variables that [`qemu_command`], [`accelerator`], and
+/// [`vm_profile`] read to `ctx`.
+pub(super) fn insert_qemu_context(
+    options: &QemuOptions,
+    ctx: &mut HashMap<String, String>,
+) {
+    ctx.insert("qemu_binary".to_string(), options.qemu_binary.clone());
+    ctx.insert("vm_profile".to_string(), options.vm_profile.to_string());
+    if options.allow_tcg {
+        ctx.insert("allow_tcg".to_string(), String::new());
+    }
+}
+
+/// Returns a command that runs the QEMU binary the user selected.
+pub(super) fn qemu_command(ctx: &Context) -> std::process::Command {
+    std::process::Command::new(ctx.get_var("qemu_binary").unwrap())
+}
+
+/// Returns the profile describing the virtual hardware to give VMs.
+pub(super) fn vm_profile(ctx: &Context) -> VmProfile {
+    clap::ValueEnum::from_str(ctx.get_var("vm_profile").unwrap(), false)
+        .unwrap()
+}
+
+/// Chooses the accelerator with which to run a VM, checking again for KVM in
+/// case it changed since the script's prerequisites were checked.
+pub(super) fn accelerator(ctx: &Context, ui: &dyn Ui) -> Result<Accelerator> {
+    let accel =
+        choose_accelerator(check_kvm(), ctx.get_var("allow_tcg").is_some())
+            .map_err(anyhow::Error::msg)?;
+
+    if accel == Accelerator::Tcg {
+        ui.set_substep("KVM is unavailable; using TCG software emulation");
+    }
+
+    Ok(accel)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn tcg_only_when_allowed() {
+        let missing = || Err("no KVM".to_string());
+        assert_eq!(choose_accelerator(Ok(()), false), Ok(Accelerator::Kvm));
+        assert_eq!(choose_accelerator(Ok(()), true), Ok(Accelerator::Kvm));
+        assert_eq!(choose_accelerator(missing(), true), Ok(Accelerator::Tcg));
+
+        let err = choose_accelerator(missing(), false).unwrap_err();
+        assert!(err.contains("no KVM"), "{err}");
+        assert!(err.contains("--allow-tcg"), "{err}");
+    }
+
+    #[test]
+    fn missing_qemu_binary_is_an_error() {
+        let options = QemuOptions {
+            qemu_binary: "/nonexistent/qemu-system-x86_64".to_string(),
+            allow_tcg: true,
+            vm_profile: VmProfile::Legacy,
+        };
+
+        let (errors, _) = check_qemu_prerequisites(&options);
+        assert!(
+            errors.iter().any(|e| e.contains("/nonexistent/qemu")),
+            "{errors:?}"
+        );
+    }
+}
@@ -0,0 +1,626 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Defines a script that checks a finished guest image by booting it in QEMU.
+//!
+//! The script attaches a NoCloud configuration drive to the VM, just as an
+//! Oxide rack would, and waits for cloudbase-init to apply it. The drive's
+//! user-data is a PowerShell script that reports the guest's hostname, users,
+//! and authorized SSH keys. cloudbase-init logs the script's output to COM3,
+//! which QEMU writes to a file that this script reads to decide whether the
+//! image was configured correctly.
+
+use std::{
+    collections::HashMap,
+    io::Write,
+    net::TcpStream,
+    os::unix::process::CommandExt,
+    process::{Child, Command},
+    time::{Duration, Instant},
+};
+
+use crate::{
+    app::{QemuOptions, VerifyOptions},
+    config_drive::{new_instance_id, read_public_keys, ConfigDrive},
+    runner::{
+        interrupt_requested, Context, MissingPrerequisites, Script, ScriptStep,
+    },
+    ui::Ui,
+    util::{
+        check_executable_prerequisites, check_file_prerequisites,
+        run_command_check_status,
+    },
+};
+
+use anyhow::{Context as _, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use colored::Colorize;
+
+use super::create_guest_disk_image::connect_to_monitor;
+
+/// The address of the monitor of the QEMU instance that runs the verification
+/// VM. This differs from the installation VM's monitor so that the two can't
+/// be confused.
+const MONITOR_ADDR: &str = "127.0.0.1:8889";
+
+/// The prefix of the markers the user-data script writes to its output.
+const MARKER_PREFIX: &str = "wimsy-verify[";
+
+/// How long to wait for the guest to shut down after asking it to power off.
+const POWERDOWN_GRACE_PERIOD: Duration = Duration::from_secs(120);
+
+/// How long to wait for QEMU to exit after asking it to quit.
+const QUIT_GRACE_PERIOD: Duration = Duration::from_secs(10);
+
+pub struct VerifyImageArgs {
+    pub work_dir: Utf8PathBuf,
+    pub output_image: Utf8PathBuf,
+    pub ovmf_path: Utf8PathBuf,
+    pub vga_console: bool,
+    pub options: VerifyOptions,
+    pub qemu: QemuOptions,
+}
+
+pub struct VerifyImageScript {
+    steps: Vec<ScriptStep>,
+    args: VerifyImageArgs,
+}
+
+impl VerifyImageScript {
+    pub(super) fn new(script_args: VerifyImageArgs) -> Self {
+        Self {
+            steps: verification_steps(&script_args.options),
+            args: script_args,
+        }
+    }
+}
+
+impl Script for VerifyImageScript {
+    fn steps(&self) -> &[ScriptStep] {
+        self.steps.as_slice()
+    }
+
+    fn print_configuration(
+        &self,
+        mut w: Box<dyn std::io::Write>,
+    ) -> std::io::Result<()> {
+        writeln!(w, "Verifying a Windows image with these options:\n")?;
+
+        let args = &self.args;
+        let options = &args.options;
+        writeln!(w, "  {}: {}", "Working directory".bold(), args.work_dir)?;
+        writeln!(w, "  {}: {}", "Image".bold(), args.output_image)?;
+        writeln!(w, "  {}: {}", "Guest bootrom".bold(), args.ovmf_path)?;
+        writeln!(w, "  {}: {}", "QEMU binary".bold(), args.qemu.qemu_binary)?;
+        writeln!(w, "  {}: {}", "VM profile".bold(), args.qemu.vm_profile)?;
+        if args.qemu.allow_tcg {
+            writeln!(
+                w,
+                "  Will use TCG software emulation if KVM is unavailable"
+            )?;
+        }
+        writeln!(w)?;
+        writeln!(w, "  Expected hostname: {}", options.verify_hostname)?;
+        writeln!(w, "  Expected user: {}", options.verify_user)?;
+        match &options.verify_ssh_public_key {
+            Some(key) => writeln!(w, "  SSH public key: {}", key)?,
+            None => writeln!(w, "  Will generate a test SSH key")?,
+        }
+        writeln!(w, "  Timeout: {} minutes", options.verify_timeout_minutes)?;
+
+        Ok(())
+    }
+
+    fn check_prerequisites(&self, _ui: &dyn Ui) -> MissingPrerequisites {
+        let mut errors = check_file_prerequisites(&[
+            self.args.output_image.clone(),
+            self.args.ovmf_path.clone(),
+        ]);
+
+        errors.extend(check_verify_prerequisites(&self.args.options));
+        let (qemu_errors, warnings) =
+            super::qemu::check_qemu_prerequisites(&self.args.qemu);
+        errors.extend(qemu_errors);
+        errors.extend(check_executable_prerequisites(self.steps()));
+        MissingPrerequisites::from_messages(errors, warnings)
+    }
+
+    fn initial_context(&self) -> HashMap<String, String> {
+        let args = &self.args;
+        let mut ctx: HashMap<String, String> = [
+            ("work_dir".to_string(), args.work_dir.to_string()),
+            ("output_image".to_string(), args.output_image.to_string()),
+            ("ovmf_path".to_string(), args.ovmf_path.to_string()),
+        ]
+        .into_iter()
+        .collect();
+
+        if args.vga_console {
+            ctx.insert("vga_console".to_string(), String::new());
+        }
+
+        insert_verification_context(&args.options, &mut ctx);
+        super::qemu::insert_qemu_context(&args.qemu, &mut ctx);
+        ctx
+    }
+}
+
+/// Returns the steps that verify the image at the `output_image` context
+/// variable. Scripts that run these steps must also set the context variables
+/// set by [`insert_verification_context`] and
+/// [`super::qemu::insert_qemu_context`].
+pub(super) fn verification_steps(options: &VerifyOptions) -> Vec<ScriptStep> {
+    let mut steps = Vec::new();
+    if options.verify_ssh_public_key.is_none() {
+        steps.push(ScriptStep::with_prereqs(
+            "generate SSH key for verification VM",
+            generate_ssh_key,
+            &["ssh-keygen"],
+        ));
+    }
+
+    steps.push(ScriptStep::new(
+        "create verification configuration drive",
+        create_config_drive,
+    ));
+
+    steps.push(ScriptStep::new(
+        "boot image and verify guest configuration",
+        boot_and_verify,
+    ));
+
+    steps
+}
+
+/// Adds the context variables the verification steps need to `ctx`.
+pub(super) fn insert_verification_context(
+    options: &VerifyOptions,
+    ctx: &mut HashMap<String, String>,
+) {
+    ctx.insert("verify_hostname".to_string(), options.verify_hostname.clone());
+    ctx.insert("verify_user".to_string(), options.verify_user.clone());
+    ctx.insert(
+        "verify_timeout_secs".to_string(),
+        (options.verify_timeout_minutes * 60).to_string(),
+    );
+
+    if let Some(key) = &options.verify_ssh_public_key {
+        ctx.insert("verify_ssh_public_key".to_string(), key.to_string());
+    }
+}
+
+/// Checks that the files the verification options refer to exist.
+pub(super) fn check_verify_prerequisites(
+    options: &VerifyOptions,
+) -> Vec<String> {
+    match &options.verify_ssh_public_key {
+        Some(key) => check_file_prerequisites(std::slice::from_ref(key)),
+        None => Vec::new(),
+    }
+}
+
+fn generate_ssh_key(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
+    let key_path =
+        Utf8Path::new(ctx.get_var("work_dir").unwrap()).join("verify_key");
+    let public_key_path = key_path.with_extension("pub");
+
+    // ssh-keygen asks before overwriting an existing key, so remove any key
+    // left over from a previous run first.
+    for path in [&key_path, &public_key_path] {
+        if path.exists() {
+            std::fs::remove_file(path)
+                .with_context(|| format!("removing old key {path}"))?;
+        }
+    }
+
+    run_command_check_status(
+        Command::new("ssh-keygen").args([
+            "-q",
+            "-t",
+            "ed25519",
+            "-N",
+            "",
+            "-C",
+            "wimsy-verify",
+            "-f",
+            key_path.as_str(),
+        ]),
+        ui,
+    )?;
+
+    ctx.set_var("verify_ssh_public_key", public_key_path.into_string());
+    Ok(())
+}
+
+fn create_config_drive(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
+    let hostname = ctx.get_var("verify_hostname").unwrap();
+    let drive = ConfigDrive {
+        instance_id: new_instance_id(hostname),
+        hostname: hostname.to_owned(),
+        public_keys: read_public_keys(Utf8Path::new(
+            ctx.get_var("verify_ssh_public_key").unwrap(),
+        ))?,
+        user_data: user_data_script(ctx.get_var("verify_user").unwrap())
+            .into_bytes(),
+    };
+
+    let image = Utf8Path::new(ctx.get_var("work_dir").unwrap())
+        .join("verify-cidata.img");
+    ui.set_substep(&format!("writing {image}"));
+    drive.write_image(&image)?;
+    ctx.set_var("verify_config_drive", image.into_string());
+    Ok(())
+}
+
+fn boot_and_verify(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
+    let work_dir = Utf8Path::new(ctx.get_var("work_dir").unwrap());
+    let com1_log = work_dir.join("verify-com1.log");
+    let com3_log = work_dir.join("verify-com3.log");
+    for log in [&com1_log, &com3_log] {
+        if log.exists() {
+            std::fs::remove_file(log)
+                .with_context(|| format!("removing old log {log}"))?;
+        }
+    }
+
+    let pflash_arg = format!(
+        "if=pflash,format=raw,readonly=on,file={}",
+        ctx.get_var("ovmf_path").unwrap()
+    );
+
+    // Writes to the image go to a temporary overlay so that booting it doesn't
+    // change it (e.g. by generating a new machine identity).
+    let image_arg = format!(
+        "if=none,id=drivec,file={},format=raw,snapshot=on",
+        ctx.get_var("output_image").unwrap()
+    );
+
+    let config_drive_arg = format!(
+        "if=none,id=cidata,file={},format=raw",
+        ctx.get_var("verify_config_drive").unwrap()
+    );
+
+    let com1_arg = format!("file:{com1_log}");
+    let com3_arg = format!("file:{com3_log}");
+    let monitor_arg = format!("telnet:{MONITOR_ADDR},server,nowait");
+    let profile = super::qemu::vm_profile(ctx);
+    let machine_args = profile.machine_args();
+    let nic_arg = profile.nic("net0");
+    let image_device = profile.boot_disk("drivec");
+    let config_drive_device = profile.config_drive("cidata");
+
+    let accel = super::qemu::accelerator(ctx, ui)?;
+    let mut args = vec!["-nodefaults"];
+    args.extend(accel.qemu_args());
+    args.extend(machine_args.iter().map(String::as_str));
+    args.extend([
+        "-m",
+        "2048",
+        "-smp",
+        "2,sockets=1,cores=2",
+        "-rtc",
+        "base=localtime",
+        "-drive",
+        &pflash_arg,
+        "-netdev",
+        "user,id=net0",
+        "-device",
+        &nic_arg,
+        "-device",
+        &image_device,
+        "-drive",
+        &image_arg,
+        // Oxide instances receive their configuration drive as a virtio block
+        // device, which is where cloudbase-init is configured to look for it.
+        "-device",
+        &config_drive_device,
+        "-drive",
+        &config_drive_arg,
+        // OxidePrepBaseImage.ps1 and cloudbase-init log to COM1 and COM3,
+        // respectively, so give the guest three serial ports and keep the
+        // output of the first and third.
+        "-serial",
+        &com1_arg,
+        "-serial",
+        "null",
+        "-serial",
+        &com3_arg,
+        "-monitor",
+        &monitor_arg,
+    ]);
+
+    if ctx.get_var("vga_console").is_some() {
+        args.extend_from_slice(&["-vga", "std", "-display", "gtk"]);
+    } else {
+        args.extend_from_slice(&["-display", "none"]);
+    }
+
+    let mut qemu = super::qemu::qemu_command(ctx);
+    qemu.args(&args);
+    let mut logs = ui.child_logs(&qemu)?;
+    // Keep a terminal Ctrl-C from reaching QEMU, so that it's stopped through
+    // its monitor instead.
+    let mut qemu = qemu
+        .stdout(logs.stdout.try_clone()?)
+        .stderr(logs.stderr.try_clone()?)
+        .process_group(0)
+        .spawn()?;
+
+    let mut monitor = match connect_to_monitor(MONITOR_ADDR, ui) {
+        Ok(monitor) => monitor,
+        Err(e) => {
+            let _ = qemu.kill();
+            let _ = qemu.wait();
+            return Err(e);
+        }
+    };
+
+    let timeout = Duration::from_secs(
+        ctx.get_var("verify_timeout_secs").unwrap().parse()?,
+    );
+    let deadline = Instant::now() + timeout;
+    ui.set_substep("waiting for cloudbase-init to configure the guest");
+    let report = loop {
+        if interrupt_requested() {
+            stop_qemu(&mut qemu, &mut monitor, "quit", QUIT_GRACE_PERIOD)?;
+            anyhow::bail!("interrupted by user");
+        }
+
+        if let Some(status) = qemu.try_wait()? {
+            logs.record_exit_status(status)?;
+            anyhow::bail!(
+                "QEMU exited ({status}) before cloudbase-init finished \
+                configuring the guest; see {com3_log}"
+            );
+        }
+
+        let log = std::fs::read(&com3_log).unwrap_or_default();
+        let report = parse_verify_report(&String::from_utf8_lossy(&log));
+        if report.done {
+            break report;
+        }
+
+        if Instant::now() >= deadline {
+            stop_qemu(&mut qemu, &mut monitor, "quit", QUIT_GRACE_PERIOD)?;
+            anyhow::bail!(
+                "timed out after {} seconds waiting for cloudbase-init to \
+                configure the guest; see {com3_log}",
+                timeout.as_secs()
+            );
+        }
+
+        std::thread::sleep(Duration::from_secs(1));
+    };
+
+    ui.set_substep("powering off the guest");
+    if !stop_qemu(
+        &mut qemu,
+        &mut monitor,
+        "system_powerdown",
+        POWERDOWN_GRACE_PERIOD,
+    )? {
+        stop_qemu(&mut qemu, &mut monitor, "quit", QUIT_GRACE_PERIOD)?;
+    }
+
+    // The drive authorizes every key in the key file, so checking for the
+    // first one is enough to show that the keys were applied.
+    let public_keys = read_public_keys(Utf8Path::new(
+        ctx.get_var("verify_ssh_public_key").unwrap(),
+    ))?;
+    check_verify_report(
+        &report,
+        ctx.get_var("verify_hostname").unwrap(),
+        ctx.get_var("verify_user").unwrap(),
+        &public_keys[0],
+    )
+    .with_context(|| format!("verifying guest configuration (see {com3_log})"))
+}
+
+/// Sends `command` to QEMU's monitor and waits up to `grace_period` for QEMU to
+/// exit. If `command` is `quit` and QEMU doesn't exit in time, kills it.
+/// Returns whether QEMU exited.
+fn stop_qemu(
+    qemu: &mut Child,
+    monitor: &mut TcpStream,
+    command: &str,
+    grace_period: Duration,
+) -> Result<bool> {
+    // QEMU may already have closed the monitor connection if it's exiting, so
+    // failing to send the command isn't an error in itself.
+    let _ = monitor
+        .write_all(format!("{command}\n").as_bytes())
+        .and_then(|_| monitor.flush());
+
+    let deadline = Instant::now() + grace_period;
+    while Instant::now() < deadline {
+        if qemu.try_wait()?.is_some() {
+            return Ok(true);
+        }
+
+        std::thread::sleep(Duration::from_millis(250));
+    }
+
+    if command == "quit" {
+        qemu.kill()?;
+        qemu.wait()?;
+        return Ok(true);
+    }
+
+    Ok(false)
+}
+
+/// Returns a PowerShell user-data script that reports the guest's hostname,
+/// whether `user` exists, and the keys authorized for `user`.
+fn user_data_script(user: &str) -> String {
+    // The markers are assembled at run time so that the script's source can't
+    // be mistaken for its output if cloudbase-init logs it.
+    format!(
+        r#"#ps1_sysnative
+function Report($item) {{
+    Write-Output ("wimsy-" + "verify[" + $item + "]")
+}}
+
+$user = '{user}'
+Report ("hostname=" + [System.Net.Dns]::GetHostName())
+if (Get-LocalUser -Name $user -ErrorAction SilentlyContinue) {{
+    Report ("user=" + $user)
+}}
+
+$keyFiles = @(
+    (Join-Path $env:SystemDrive "Users\$user\.ssh\authorized_keys"),
+    (Join-Path $env:ProgramData "ssh\administrators_authorized_keys")
+)
+foreach ($file in $keyFiles) {{
+    Get-Content -Path $file -ErrorAction SilentlyContinue |
+        Where-Object {{ $_.Trim() }} |
+        ForEach-Object {{ Report ("key=" + $_.Trim()) }}
+}}
+
+Report "done"
+"#,
+        user = user.replace('\'', "''")
+    )
+}
+
+/// What the user-data script reported about the guest.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct VerifyReport {
+    hostname: Option<String>,
+    users: Vec<String>,
+    authorized_keys: Vec<String>,
+    done: bool,
+}
+
+/// Collects the markers the user-data script wrote from cloudbase-init's log.
+///
+/// cloudbase-init may log the script's output as an escaped string, with
+/// line breaks shown as `\r\n`, so markers are found by their delimiters
+/// rather than by line.
+fn parse_verify_report(log: &str) -> VerifyReport {
+    let mut report = VerifyReport::default();
+    let mut rest = log;
+    while let Some(start) = rest.find(MARKER_PREFIX) {
+        rest = &rest[start + MARKER_PREFIX.len()..];
+        let Some(end) = rest.find(']') else {
+            break;
+        };
+
+        let marker = &rest[..end];
+        rest = &rest[end + 1..];
+        match marker.split_once('=') {
+            Some(("hostname", hostname)) => {
+                report.hostname = Some(hostname.to_owned())
+            }
+            Some(("user", user)) => report.users.push(user.to_owned()),
+            Some(("key", key)) => report.authorized_keys.push(key.to_owned()),
+            None if marker == "done" => report.done = true,
+            _ => {}
+        }
+    }
+
+    report
+}
+
+/// Checks that `report` shows that the guest's hostname is `hostname`, that
+/// `user` exists, and that `public_key` is authorized. Keys are compared by
+/// type and key material, ignoring comments.
+fn check_verify_report(
+    report: &VerifyReport,
+    hostname: &str,
+    user: &str,
+    public_key: &str,
+) -> Result<()> {
+    let mut errors = Vec::new();
+    match &report.hostname {
+        Some(actual) if actual.eq_ignore_ascii_case(hostname) => {}
+        Some(actual) => errors.push(format!(
+            "guest hostname is {actual:?}, expected {hostname:?}"
+        )),
+        None => errors.push("guest didn't report its hostname".to_string()),
+    }
+
+    if !report.users.iter().any(|u| u.eq_ignore_ascii_case(user)) {
+        errors.push(format!("user {user:?} wasn't created"));
+    }
+
+    let key_id = |key: &str| {
+        key.split_whitespace().take(2).collect::<Vec<_>>().join(" ")
+    };
+    let expected_key = key_id(public_key);
+    if !report.authorized_keys.iter().any(|k| key_id(k) == expected_key) {
+        errors.push(format!("SSH key wasn't authorized for user {user:?}"));
+    }
+
+    if !errors.is_empty() {
+        anyhow::bail!("{}", errors.join("; "));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const KEY: &str = "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIK0 wimsy-verify";
+
+    #[test]
+    fn parse_report_from_escaped_log() {
+        let log = "\
+2026-10-18 12:00:01.000 1234 DEBUG cloudbaseinit.plugins.common.userdatautils \
+[-] User_data stdout:\n\
+b'wimsy-verify[hostname=WIMSY-VERIFY]\\r\\nwimsy-verify[user=oxide]\\r\\n\
+wimsy-verify[key=ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIK0 other]\\r\\n\
+wimsy-verify[done]\\r\\n'\n";
+
+        assert_eq!(
+            parse_verify_report(log),
+            VerifyReport {
+                hostname: Some("WIMSY-VERIFY".to_string()),
+                users: vec!["oxide".to_string()],
+                authorized_keys: vec![
+                    "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIK0 other".to_string()
+                ],
+                done: true,
+            }
+        );
+
+        // Output that hasn't finished arriving isn't complete.
+        assert!(
+            !parse_verify_report("wimsy-verify[hostname=a]\nwimsy-ver").done
+        );
+        assert!(!parse_verify_report("wimsy-verify[do").done);
+    }
+
+    #[test]
+    fn check_reports() {
+        let report = VerifyReport {
+            hostname: Some("WIMSY-VERIFY".to_string()),
+            users: vec!["Administrator".to_string(), "oxide".to_string()],
+            authorized_keys: vec![
+                "ssh-ed25519 AAAAC3NzaC1lZDI1NTE5AAAAIK0".to_string()
+            ],
+            done: true,
+        };
+        check_verify_report(&report, "wimsy-verify", "oxide", KEY).unwrap();
+
+        let err = check_verify_report(&report, "other", "admin", KEY)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("hostname"));
+        assert!(err.contains("\"admin\" wasn't created"));
+        assert!(!err.contains("SSH key"));
+
+        let err = check_verify_report(
+            &VerifyReport::default(),
+            "wimsy-verify",
+            "oxide",
+            KEY,
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(err.contains("didn't report its hostname"));
+        assert!(err.contains("SSH key wasn't authorized"));
+    }
+}
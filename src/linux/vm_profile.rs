@@ -0,0 +1,197 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Describes the virtual hardware the Linux scripts' VMs present to Windows.
+//!
+//! Windows Setup installs (and sysprep keeps) drivers for the devices it sees,
+//! so an image built on hardware that resembles an Oxide instance boots
+//! without having to enumerate new devices first. Each profile is a table of
+//! the machine type, controllers, and device placements to use; the scripts
+//! turn a profile into QEMU arguments with the functions here.
+
+/// The serial number the installation and verification VMs give their boot
+/// disk.
+const BOOT_DISK_SERIAL: &str = "01de01de";
+
+/// The virtual hardware with which to run a VM.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum VmProfile {
+    /// Mirrors an Oxide instance as closely as QEMU allows: an i440FX machine
+    /// (like Propolis's) with the NIC, boot disk, and configuration drive in
+    /// the PCI slots the rack assigns them. Setup media are attached to an
+    /// AHCI controller, since instances have no IDE controller.
+    Oxide,
+
+    /// A Q35 machine with PCIe devices and setup media on its built-in AHCI
+    /// controller.
+    Q35,
+
+    /// The i440FX machine with IDE CD-ROM drives that wimsy has always used.
+    Legacy,
+}
+
+impl std::fmt::Display for VmProfile {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VmProfile::Oxide => write!(f, "oxide"),
+            VmProfile::Q35 => write!(f, "q35"),
+            VmProfile::Legacy => write!(f, "legacy"),
+        }
+    }
+}
+
+/// The devices that make up a profile.
+struct Layout {
+    /// The machine type to pass to `-M`.
+    machine: &'static str,
+
+    /// Controllers to add before attaching devices to them.
+    controllers: &'static [&'static str],
+
+    /// Extra properties for the NIC, e.g. its PCI address.
+    nic: &'static str,
+
+    /// Extra properties for the boot disk.
+    boot_disk: &'static str,
+
+    /// Extra properties for the configuration drive the verification VM
+    /// attaches.
+    config_drive: &'static str,
+
+    /// The buses (and units) to which to attach CD-ROM drives, in order.
+    cdroms: &'static [&'static str],
+}
+
+// Propolis places an instance's NICs in PCI slots starting at 8, its disks in
+// slots starting at 16, and its cloud-init drive in slot 24.
+const OXIDE: Layout = Layout {
+    machine: "pc",
+    controllers: &["ahci,id=ahci"],
+    nic: ",addr=0x8",
+    boot_disk: ",addr=0x10",
+    config_drive: ",addr=0x18",
+    cdroms: &["bus=ahci.0", "bus=ahci.1", "bus=ahci.2"],
+};
+
+// Q35's built-in AHCI controller exposes its ports as `ide.0` through `ide.5`.
+const Q35: Layout = Layout {
+    machine: "q35",
+    controllers: &[],
+    nic: "",
+    boot_disk: "",
+    config_drive: "",
+    cdroms: &["bus=ide.0", "bus=ide.1", "bus=ide.2"],
+};
+
+const LEGACY: Layout = Layout {
+    machine: "pc",
+    controllers: &[],
+    nic: "",
+    boot_disk: "",
+    config_drive: "",
+    cdroms: &["bus=ide.0,unit=0", "bus=ide.1,unit=0", "bus=ide.0,unit=1"],
+};
+
+impl VmProfile {
+    fn layout(&self) -> &'static Layout {
+        match self {
+            VmProfile::Oxide => &OXIDE,
+            VmProfile::Q35 => &Q35,
+            VmProfile::Legacy => &LEGACY,
+        }
+    }
+
+    /// Returns the QEMU arguments that select this profile's machine type and
+    /// add its controllers.
+    pub fn machine_args(&self) -> Vec<String> {
+        let layout = self.layout();
+        let mut args = vec!["-M".to_string(), layout.machine.to_string()];
+        for controller in layout.controllers {
+            args.extend(["-device".to_string(), controller.to_string()]);
+        }
+
+        args
+    }
+
+    /// Returns the `-device` value for a virtio NIC attached to `netdev`.
+    pub fn nic(&self, netdev: &str) -> String {
+        format!("virtio-net-pci,netdev={netdev}{}", self.layout().nic)
+    }
+
+    /// Returns the `-device` value for an NVMe boot disk backed by `drive`.
+    pub fn boot_disk(&self, drive: &str) -> String {
+        format!(
+            "nvme,drive={drive},serial={BOOT_DISK_SERIAL},\
+            physical_block_size=512,logical_block_size=512,\
+            discard_granularity=512,bootindex=1{}",
+            self.layout().boot_disk
+        )
+    }
+
+    /// Returns the `-device` value for a virtio block configuration drive
+    /// backed by `drive`.
+    pub fn config_drive(&self, drive: &str) -> String {
+        format!("virtio-blk-pci,drive={drive}{}", self.layout().config_drive)
+    }
+
+    /// Returns the `-device` value for the `index`th CD-ROM drive, backed by
+    /// `drive`. The first drive is the second boot device, after the boot
+    /// disk.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the profile has fewer than `index + 1` CD-ROM drives.
+    pub fn cdrom(&self, index: usize, drive: &str) -> String {
+        let bus = self.layout().cdroms[index];
+        let bootindex = if index == 0 { ",bootindex=2" } else { "" };
+        format!("ide-cd,drive={drive},id=cd-disk{index},{bus}{bootindex}")
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn legacy_profile_matches_original_devices() {
+        let profile = VmProfile::Legacy;
+        assert_eq!(profile.machine_args(), ["-M", "pc"]);
+        assert_eq!(profile.nic("net0"), "virtio-net-pci,netdev=net0");
+        assert_eq!(
+            profile.boot_disk("drivec"),
+            "nvme,drive=drivec,serial=01de01de,physical_block_size=512,\
+            logical_block_size=512,discard_granularity=512,bootindex=1"
+        );
+        assert_eq!(
+            profile.cdrom(0, "win-disk"),
+            "ide-cd,drive=win-disk,id=cd-disk0,bus=ide.0,unit=0,bootindex=2"
+        );
+        assert_eq!(
+            profile.cdrom(2, "unattend-disk"),
+            "ide-cd,drive=unattend-disk,id=cd-disk2,bus=ide.0,unit=1"
+        );
+    }
+
+    #[test]
+    fn oxide_profile_places_devices_like_the_rack() {
+        let profile = VmProfile::Oxide;
+        assert_eq!(
+            profile.machine_args(),
+            ["-M", "pc", "-device", "ahci,id=ahci"]
+        );
+        assert!(profile.nic("net0").ends_with(",addr=0x8"));
+        assert!(profile.boot_disk("drivec").ends_with(",addr=0x10"));
+        assert!(profile.config_drive("cidata").ends_with(",addr=0x18"));
+        assert!(profile.cdrom(1, "virtio-disk").ends_with("bus=ahci.1"));
+    }
+
+    #[test]
+    fn every_profile_has_three_cdroms() {
+        for profile in [VmProfile::Oxide, VmProfile::Q35, VmProfile::Legacy] {
+            for index in 0..3 {
+                profile.cdrom(index, "cd");
+            }
+        }
+    }
+}
@@ -0,0 +1,232 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Manages the per-run directories that hold the logs a script produces.
+//!
+//! Each run of a script gets its own timestamped subdirectory of the log
+//! directory. The subdirectory holds the output of every process the script
+//! runs and a combined `run.log` that records when each step started and
+//! finished. A `latest` symlink in the log directory points to the most recent
+//! run, and older runs are deleted once there are more than a configurable
+//! number of them.
+
+use std::{
+    io::Write,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use anyhow::{Context, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+
+/// The prefix of the names of per-run log directories.
+const RUN_DIR_PREFIX: &str = "run-";
+
+/// The name of the symlink that points to the most recent run's logs.
+const LATEST_LINK: &str = "latest";
+
+/// The logs for a single run of a script.
+pub struct RunLog {
+    dir: Utf8PathBuf,
+    run_log: Mutex<std::fs::File>,
+
+    /// The sequence number to assign to the next child process's logs.
+    next_sequence: AtomicUsize,
+}
+
+impl RunLog {
+    /// Creates a new run directory under `log_root`, points the `latest`
+    /// symlink at it, and deletes all but the newest `keep` run directories
+    /// (including the new one).
+    pub fn create(log_root: &Utf8Path, keep: usize) -> Result<Self> {
+        std::fs::create_dir_all(log_root)
+            .with_context(|| format!("creating log directory {log_root}"))?;
+
+        let (date, time) = utc_date_time(SystemTime::now());
+        let name = format!(
+            "{RUN_DIR_PREFIX}{}T{}Z",
+            date.replace('-', ""),
+            time.replace(':', "")
+        );
+
+        // Runs started within the same second as an earlier run get a numeric
+        // suffix so that they sort after it, even if it has since been pruned.
+        let dir = match list_run_dirs(log_root)?.last() {
+            Some(last) if run_sort_key(last).0 == name => {
+                log_root.join(format!("{name}.{}", run_sort_key(last).1 + 1))
+            }
+            _ => log_root.join(&name),
+        };
+
+        std::fs::create_dir(&dir)
+            .with_context(|| format!("creating run log directory {dir}"))?;
+
+        let run_log = std::fs::File::create(dir.join("run.log"))
+            .with_context(|| format!("creating {dir}/run.log"))?;
+
+        update_latest_link(log_root, &dir)?;
+        prune_run_dirs(log_root, keep)?;
+
+        Ok(Self {
+            dir,
+            run_log: Mutex::new(run_log),
+            next_sequence: AtomicUsize::new(0),
+        })
+    }
+
+    /// Yields the directory containing this run's logs.
+    pub fn dir(&self) -> &Utf8Path {
+        &self.dir
+    }
+
+    /// Yields a sequence number that no other child process in this run has
+    /// been assigned.
+    pub fn next_sequence(&self) -> usize {
+        self.next_sequence.fetch_add(1, Ordering::Relaxed)
+    }
+
+    /// Appends a timestamped line containing `message` to this run's
+    /// `run.log`. Failures to write are ignored, since they shouldn't stop the
+    /// script from running.
+    pub fn log(&self, message: &str) {
+        let (date, time) = utc_date_time(SystemTime::now());
+        let mut file = self.run_log.lock().unwrap();
+        let _ = writeln!(file, "{date}T{time}Z {message}");
+    }
+}
+
+/// Points the `latest` symlink in `log_root` at `run_dir`.
+fn update_latest_link(log_root: &Utf8Path, run_dir: &Utf8Path) -> Result<()> {
+    let link = log_root.join(LATEST_LINK);
+    if link.symlink_metadata().is_ok() {
+        std::fs::remove_file(&link)
+            .with_context(|| format!("removing old link {link}"))?;
+    }
+
+    // Link relative to the log directory so the link survives moving it.
+    let target = run_dir.file_name().unwrap();
+    std::os::unix::fs::symlink(target, &link)
+        .with_context(|| format!("creating link {link}"))
+}
+
+/// Lists the run directories in `log_root`, oldest first.
+fn list_run_dirs(log_root: &Utf8Path) -> Result<Vec<Utf8PathBuf>> {
+    let mut runs = Vec::new();
+    for entry in log_root
+        .read_dir_utf8()
+        .with_context(|| format!("reading log directory {log_root}"))?
+    {
+        let entry = entry?;
+        if entry.file_name().starts_with(RUN_DIR_PREFIX)
+            && entry.file_type()?.is_dir()
+        {
+            runs.push(entry.into_path());
+        }
+    }
+
+    runs.sort_by(|a, b| run_sort_key(a).cmp(&run_sort_key(b)));
+    Ok(runs)
+}
+
+/// Deletes all but the newest `keep` run directories in `log_root`.
+fn prune_run_dirs(log_root: &Utf8Path, keep: usize) -> Result<()> {
+    let runs = list_run_dirs(log_root)?;
+    let excess = runs.len().saturating_sub(keep);
+    for run in &runs[..excess] {
+        std::fs::remove_dir_all(run)
+            .with_context(|| format!("removing old run logs {run}"))?;
+    }
+
+    Ok(())
+}
+
+/// Yields a key that orders run directories by start time, including runs that
+/// started in the same second and were given a numeric suffix.
+fn run_sort_key(path: &Utf8Path) -> (&str, u32) {
+    let name = path.file_name().unwrap_or_default();
+    match name.split_once('.') {
+        Some((base, suffix)) => (base, suffix.parse().unwrap_or(0)),
+        None => (name, 0),
+    }
+}
+
+/// Formats `time` as a UTC date (`YYYY-MM-DD`) and time (`HH:MM:SS`).
+fn utc_date_time(time: SystemTime) -> (String, String) {
+    let secs = time.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    let (days, secs_of_day) = (secs / 86400, secs % 86400);
+
+    // Convert days since the epoch to a civil date; see
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days.
+    let z = days as i64 + 719468;
+    let era = z.div_euclid(146097);
+    let doe = z.rem_euclid(146097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    (
+        format!("{year:04}-{month:02}-{day:02}"),
+        format!(
+            "{:02}:{:02}:{:02}",
+            secs_of_day / 3600,
+            secs_of_day % 3600 / 60,
+            secs_of_day % 60
+        ),
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn format_utc_times() {
+        let at = |secs| {
+            utc_date_time(UNIX_EPOCH + std::time::Duration::from_secs(secs))
+        };
+        assert_eq!(at(0), ("1970-01-01".to_string(), "00:00:00".to_string()));
+        assert_eq!(
+            at(951_825_600),
+            ("2000-02-29".to_string(), "12:00:00".to_string())
+        );
+        assert_eq!(
+            at(1_792_367_999),
+            ("2026-10-18".to_string(), "23:59:59".to_string())
+        );
+    }
+
+    #[test]
+    fn create_and_prune_runs() {
+        let root = crate::test_support::scratch_dir("logs");
+
+        let mut dirs = Vec::new();
+        for _ in 0..4 {
+            let run = RunLog::create(&root, 2).unwrap();
+            run.log("hello");
+            dirs.push(run.dir().to_owned());
+        }
+
+        // Only the two newest runs survive, and `latest` names the newest.
+        assert!(!dirs[0].exists());
+        assert!(!dirs[1].exists());
+        assert!(dirs[2].exists());
+        assert!(dirs[3].exists());
+        assert_eq!(
+            std::fs::read_to_string(root.join(LATEST_LINK).join("run.log"))
+                .unwrap(),
+            std::fs::read_to_string(dirs[3].join("run.log")).unwrap()
+        );
+        assert!(std::fs::read_to_string(dirs[3].join("run.log"))
+            .unwrap()
+            .ends_with("Z hello\n"));
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
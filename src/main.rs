@@ -4,7 +4,7 @@
 
 //! wimsy: a playful way to manipulate Windows images for use in an Oxide rack.
 
-use app::App;
+use app::{App, Command};
 use clap::Parser;
 
 pub const UNATTEND_FILES: &[&str] = &[
@@ -16,6 +16,15 @@ pub const UNATTEND_FILES: &[&str] = &[
     "specialize-unattend.xml",
 ];
 
+/// Packages that can be delivered to the guest alongside the unattend files so
+/// that OxidePrepBaseImage.ps1 doesn't have to download them, as pairs of the
+/// script context variable holding each package's path and the file name the
+/// prep script looks for.
+pub const OFFLINE_PACKAGES: &[(&str, &str)] = &[
+    ("cloudbase_init_msi", "CloudbaseInitSetup.msi"),
+    ("openssh_zip", "OpenSSH-Win64.zip"),
+];
+
 #[cfg(target_os = "illumos")]
 mod illumos;
 #[cfg(target_os = "illumos")]
@@ -31,18 +40,59 @@ compile_error!("only Linux and illumos targets are supported");
 
 pub mod app;
 pub mod autounattend;
+pub mod checksum;
+pub mod config_drive;
+pub mod gpt;
+pub mod image_metadata;
+pub mod inspect;
+pub mod logs;
+pub mod manifest;
 pub mod runner;
+pub mod sparse;
 pub mod steps;
+#[cfg(test)]
+mod test_support;
+pub mod udf;
 pub mod ui;
+pub mod upload;
 pub mod util;
+pub mod validate;
+pub mod vfat;
+pub mod wim;
 
 fn main() -> anyhow::Result<()> {
     let app = App::parse();
+    if let Command::ValidateUnattend { unattend_dir } = &app.command {
+        return validate::run_validate_unattend(unattend_dir);
+    }
+
+    if let Command::GenerateUnattend { options } = &app.command {
+        return autounattend::generate::run_generate_unattend(options);
+    }
+
+    if let Command::MakeConfigDrive { options } = &app.command {
+        return config_drive::run_make_config_drive(options);
+    }
+
+    if let Command::Inspect { image } = &app.command {
+        return inspect::run_inspect(image);
+    }
+
     let interactive = match app.interactive {
         Some(val) => val,
         None => atty::is(atty::Stream::Stdout),
     };
 
-    let script = get_script(&app);
-    runner::run_script(script, interactive, &app.work_dir)
+    let script: Box<dyn runner::Script> = match &app.command {
+        Command::Upload { options } => {
+            Box::new(upload::UploadScript::new(upload::UploadArgs {
+                work_dir: app.work_dir().to_owned(),
+                output_image: app.output_image().to_owned(),
+                options: options.clone(),
+            }))
+        }
+        _ => get_script(&app),
+    };
+    let run_log = logs::RunLog::create(&app.log_dir(), app.keep_logs as usize)?;
+    runner::run_script(script, interactive, &run_log)
 }
@@ -0,0 +1,291 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Functions for writing a build manifest that records how an output image was
+//! produced: the tool version and options, the digests of its inputs and
+//! outputs, the answer file it used, and how long each step took.
+
+use anyhow::{Context as _, Result};
+use camino::{Utf8Path, Utf8PathBuf};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    autounattend::summarize_answer_file,
+    checksum::{sha256_file, HashedInput, Sha256Digest},
+    gpt,
+    image_metadata::ImageMetadata,
+    runner::Context,
+    ui::Ui,
+};
+
+/// The version of a virtio driver included in an image.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DriverVersion {
+    /// The name of the driver's INF file, without its extension.
+    pub driver: String,
+
+    /// The versioned directory the driver came from, e.g. `2k22`.
+    pub windows_version: String,
+
+    /// The value of the `DriverVer` directive in the driver's INF file.
+    pub version: String,
+}
+
+/// Returns the value of the `DriverVer` directive in the INF file whose
+/// contents are `inf`. INF files may be encoded in UTF-16 (with a byte-order
+/// mark) or in an 8-bit encoding.
+pub fn parse_driver_version(inf: &[u8]) -> Option<String> {
+    let text = match inf {
+        [0xff, 0xfe, rest @ ..] => String::from_utf16_lossy(
+            &rest
+                .chunks_exact(2)
+                .map(|c| u16::from_le_bytes([c[0], c[1]]))
+                .collect::<Vec<_>>(),
+        ),
+        _ => String::from_utf8_lossy(inf).into_owned(),
+    };
+
+    text.lines().find_map(|line| {
+        let (key, value) = line.split_once('=')?;
+        if !key.trim().eq_ignore_ascii_case("DriverVer") {
+            return None;
+        }
+
+        // Strip any trailing comment.
+        let value = value.split(';').next().unwrap_or(value).trim();
+        Some(value.to_owned())
+    })
+}
+
+/// A partition in an image's GUID partition table.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct PartitionEntry {
+    pub number: u32,
+    pub first_sector: u64,
+    pub last_sector: u64,
+    pub type_guid: String,
+    pub unique_guid: String,
+    pub name: String,
+}
+
+/// An image's GUID partition table.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+pub struct PartitionTable {
+    pub sector_size: u64,
+    pub disk_guid: String,
+    pub partitions: Vec<PartitionEntry>,
+}
+
+impl From<gpt::PartitionTable> for PartitionTable {
+    fn from(table: gpt::PartitionTable) -> Self {
+        Self {
+            sector_size: table.sector_size,
+            disk_guid: table.disk_guid,
+            partitions: table
+                .partitions
+                .into_iter()
+                .map(|p| PartitionEntry {
+                    number: p.number,
+                    first_sector: p.first_lba,
+                    last_sector: p.last_lba,
+                    type_guid: p.type_guid,
+                    unique_guid: p.unique_guid,
+                    name: p.name,
+                })
+                .collect(),
+        }
+    }
+}
+
+/// Reads the partition table of the image at `image_path`.
+pub fn read_partition_table(image_path: &Utf8Path) -> Result<PartitionTable> {
+    let mut file = std::fs::File::open(image_path)
+        .with_context(|| format!("opening {image_path}"))?;
+    Ok(gpt::read_partition_table(&mut file)?.into())
+}
+
+#[derive(Serialize)]
+struct Host {
+    os: &'static str,
+    arch: &'static str,
+}
+
+#[derive(Serialize)]
+struct AnswerFile {
+    sha256: Sha256Digest,
+    image_index: Option<u32>,
+    windows_versions: Vec<crate::autounattend::WindowsVersion>,
+}
+
+#[derive(Serialize)]
+struct StepRecord {
+    label: &'static str,
+    seconds: f64,
+}
+
+#[derive(Serialize)]
+struct OutputImage {
+    path: Utf8PathBuf,
+    size: u64,
+    allocated_size: u64,
+    sha256: Sha256Digest,
+    partition_table: PartitionTable,
+}
+
+#[derive(Serialize)]
+struct Manifest {
+    wimsy_version: &'static str,
+    host: Host,
+    command_line: Vec<String>,
+    options: serde_json::Value,
+    inputs: Vec<HashedInput>,
+    autounattend: Option<AnswerFile>,
+    virtio_drivers: Vec<DriverVersion>,
+    image: Option<ImageMetadata>,
+    steps: Vec<StepRecord>,
+    output_image: OutputImage,
+}
+
+/// Writes a manifest describing the build to `<output_image>.manifest.json`.
+///
+/// This reads the following context variables:
+///
+/// - `output_image` (required): the path to the output image.
+/// - `manifest_options`: the script's options, as a JSON object.
+/// - `hashed_inputs`: the digests of the script's input files, as a JSON array
+///   of [`HashedInput`]s.
+/// - `unattend_dir`: the directory containing the customized answer file.
+/// - `virtio_driver_versions`: the versions of the drivers added to the image,
+///   as a JSON array of [`DriverVersion`]s.
+/// - `image_metadata`: the description of the installed Windows edition, as a
+///   JSON [`ImageMetadata`].
+pub fn write_manifest(ctx: &Context, ui: &dyn Ui) -> Result<()> {
+    let output_image = Utf8Path::new(ctx.get_var("output_image").unwrap());
+
+    let options = match ctx.get_var("manifest_options") {
+        Some(options) => serde_json::from_str(options)?,
+        None => serde_json::Value::Null,
+    };
+
+    let inputs = match ctx.get_var("hashed_inputs") {
+        Some(inputs) => serde_json::from_str(inputs)?,
+        None => Vec::new(),
+    };
+
+    let virtio_drivers = match ctx.get_var("virtio_driver_versions") {
+        Some(drivers) => serde_json::from_str(drivers)?,
+        None => Vec::new(),
+    };
+
+    let image = match ctx.get_var("image_metadata") {
+        Some(metadata) => Some(serde_json::from_str(metadata)?),
+        None => None,
+    };
+
+    let autounattend = match ctx.get_var("unattend_dir") {
+        Some(dir) if Utf8Path::new(dir).join("Autounattend.xml").exists() => {
+            let path = Utf8Path::new(dir).join("Autounattend.xml");
+            let contents = std::fs::read_to_string(&path)
+                .with_context(|| format!("reading {path}"))?;
+            let summary = summarize_answer_file(&contents)?;
+            Some(AnswerFile {
+                sha256: sha256_file(&path, ui)?,
+                image_index: summary.image_index,
+                windows_versions: summary.driver_versions,
+            })
+        }
+        _ => None,
+    };
+
+    let partition_table = read_partition_table(output_image)
+        .context("reading output image partition table")?;
+
+    let metadata = std::fs::metadata(output_image)
+        .with_context(|| format!("reading {output_image}"))?;
+    let manifest = Manifest {
+        wimsy_version: env!("CARGO_PKG_VERSION"),
+        host: Host { os: std::env::consts::OS, arch: std::env::consts::ARCH },
+        command_line: std::env::args().collect(),
+        options,
+        inputs,
+        autounattend,
+        virtio_drivers,
+        image,
+        steps: ctx
+            .step_timings()
+            .iter()
+            .map(|timing| StepRecord {
+                label: timing.label,
+                seconds: timing.duration.as_secs_f64(),
+            })
+            .collect(),
+        output_image: OutputImage {
+            path: output_image.to_owned(),
+            size: metadata.len(),
+            allocated_size: crate::sparse::allocated_size(&metadata),
+            sha256: sha256_file(output_image, ui)?,
+            partition_table,
+        },
+    };
+
+    let path = format!("{output_image}.manifest.json");
+    ui.set_substep(&format!("writing {path}"));
+    std::fs::write(&path, serde_json::to_string_pretty(&manifest)? + "\n")
+        .with_context(|| format!("writing {path}"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn read_image_partition_table() {
+        let dir = crate::test_support::scratch_dir("manifest");
+        let image = dir.join("image.img");
+        std::fs::write(
+            &image,
+            crate::gpt::test::build_disk(
+                4096,
+                &[
+                    (gpt::EFI_SYSTEM_PARTITION, 2048, 2147, "EFI system"),
+                    (gpt::BASIC_DATA, 2148, 4000, "Basic data partition"),
+                ],
+            ),
+        )
+        .unwrap();
+
+        let table = read_partition_table(&image).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(table.sector_size, 512);
+        assert_eq!(table.disk_guid, "2F0C5E3E-6C3B-4B86-9A3E-1B1D6D4E2E8B");
+        assert_eq!(table.partitions.len(), 2);
+        assert_eq!(
+            table.partitions[1],
+            PartitionEntry {
+                number: 2,
+                first_sector: 2148,
+                last_sector: 4000,
+                type_guid: gpt::BASIC_DATA.to_string(),
+                unique_guid: table.partitions[1].unique_guid.clone(),
+                name: "Basic data partition".to_string(),
+            }
+        );
+    }
+
+    #[test]
+    fn parse_inf_driver_versions() {
+        const INF: &str = "[Version]\r\n\
+            Signature=\"$WINDOWS NT$\"\r\n\
+            DriverVer = 07/19/2023,100.93.104.24000 ; built by CI\r\n";
+
+        let expected = Some("07/19/2023,100.93.104.24000".to_string());
+        assert_eq!(parse_driver_version(INF.as_bytes()), expected);
+
+        let mut utf16 = vec![0xff, 0xfe];
+        utf16.extend(INF.encode_utf16().flat_map(u16::to_le_bytes));
+        assert_eq!(parse_driver_version(&utf16), expected);
+
+        assert_eq!(parse_driver_version(b"[Version]\r\n"), None);
+    }
+}
@@ -8,15 +8,47 @@
 use std::{
     collections::HashMap,
     io::{Read, Write},
+    sync::atomic::{AtomicBool, Ordering},
 };
 
-use camino::Utf8Path;
 use colored::Colorize;
 
-use crate::ui::{Mode, Ui};
+use crate::{
+    logs::RunLog,
+    ui::{Mode, Ui},
+};
 
 type StepFn = dyn Fn(&mut Context, &dyn crate::ui::Ui) -> anyhow::Result<()>;
 
+/// A function that releases a resource a script step created, such as a
+/// mounted filesystem or a network interface.
+pub type CleanupFn = fn(&mut Context, &dyn Ui) -> anyhow::Result<()>;
+
+/// Set when the user asks to interrupt the running script.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+
+/// Returns `true` if the user has asked to interrupt the running script. Steps
+/// that wait for a long time should check this periodically and, if it is set,
+/// stop what they're doing and return an error.
+pub fn interrupt_requested() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+/// Installs a handler for SIGINT and SIGTERM that asks the running script to
+/// stop. A second signal exits immediately.
+fn install_interrupt_handler() -> anyhow::Result<()> {
+    ctrlc::set_handler(|| {
+        if INTERRUPTED.swap(true, Ordering::SeqCst) {
+            eprintln!("\nInterrupted again; exiting without cleaning up.");
+            std::process::exit(130);
+        }
+
+        eprintln!("\nInterrupting; waiting for the current step to stop...");
+    })?;
+
+    Ok(())
+}
+
 /// A step in a scripted procedure.
 pub struct ScriptStep {
     /// A descriptive label for this procedure step.
@@ -100,8 +132,9 @@ pub trait Script {
         w: Box<dyn std::io::Write>,
     ) -> std::io::Result<()>;
 
-    /// Checks that this script's prerequisites are
-    fn check_prerequisites(&self) -> MissingPrerequisites;
+    /// Checks that this script's prerequisites are satisfied, using `ui` to
+    /// report progress on any lengthy checks.
+    fn check_prerequisites(&self, ui: &dyn Ui) -> MissingPrerequisites;
 
     /// Yields a `HashMap` that contains key-value pairs that should be inserted
     /// into the script's [`Context`] prior to running it.
@@ -113,12 +146,23 @@ pub trait Script {
 pub fn run_script(
     script: Box<dyn Script>,
     interactive: bool,
-    work_dir: &Utf8Path,
+    run_log: &RunLog,
 ) -> anyhow::Result<()> {
     script.print_configuration(Box::new(std::io::stdout()))?;
     println!();
 
-    let missing = script.check_prerequisites();
+    let mode =
+        if interactive { Mode::Interactive } else { Mode::NonInteractive };
+    run_log.log("checking prerequisites");
+    let missing = script.check_prerequisites(&crate::ui::AuxiliaryUi::new(
+        run_log, &mode, "prereq",
+    ));
+    for error in missing.errors.iter() {
+        run_log.log(&format!("prerequisite error: {error}"));
+    }
+    for warning in missing.warnings.iter() {
+        run_log.log(&format!("prerequisite warning: {warning}"));
+    }
     if !missing.errors.is_empty() {
         println!("{}", "Some prerequisites were not satisfied:".bold());
         for error in missing.errors.iter() {
@@ -145,7 +189,7 @@ pub fn run_script(
         println!();
     }
 
-    println!("  Command logs will be written to {}\n", work_dir);
+    println!("  Command logs will be written to {}\n", run_log.dir());
 
     if interactive {
         println!("Press Enter to continue or CTRL-C to cancel.");
@@ -153,10 +197,9 @@ pub fn run_script(
         std::io::stdin().read_exact(&mut [0u8])?;
     }
 
-    let ctx = Context { vars: script.initial_context().clone() };
-    let mode =
-        if interactive { Mode::Interactive } else { Mode::NonInteractive };
-    crate::ui::run_script(script, ctx, work_dir, mode)
+    install_interrupt_handler()?;
+    let ctx = Context::new(script.initial_context());
+    crate::ui::run_script(script, ctx, run_log, mode)
 }
 
 /// A shared script execution context, provided to each step in a running
@@ -166,9 +209,27 @@ pub fn run_script(
 /// executes.
 pub struct Context {
     vars: HashMap<String, String>,
+
+    /// The time taken by each step that has completed successfully so far.
+    step_timings: Vec<StepTiming>,
+
+    /// The cleanup functions for resources that steps have created and that
+    /// haven't been released yet, in the order in which they were created.
+    cleanups: Vec<(&'static str, CleanupFn)>,
+}
+
+/// Records how long a script step took to run.
+pub struct StepTiming {
+    pub label: &'static str,
+    pub duration: std::time::Duration,
 }
 
 impl Context {
+    /// Creates a context whose store holds `vars`.
+    pub(crate) fn new(vars: HashMap<String, String>) -> Self {
+        Self { vars, step_timings: Vec::new(), cleanups: Vec::new() }
+    }
+
     /// Gets the value of the supplied `var`, returning `None` if the value is
     /// not in the store.
     pub fn get_var(&self, var: &str) -> Option<&str> {
@@ -180,4 +241,73 @@ impl Context {
     pub fn set_var(&mut self, var: &str, value: String) -> Option<String> {
         self.vars.insert(var.to_owned(), value)
     }
+
+    /// Yields the timings of the steps that have completed so far, in the
+    /// order in which they ran.
+    pub fn step_timings(&self) -> &[StepTiming] {
+        &self.step_timings
+    }
+
+    /// Registers `func` as the function that releases the resource named
+    /// `resource`. If the script is interrupted or a step fails before a later
+    /// step calls [`Context::release`] for the resource, the runner calls
+    /// `func` before exiting.
+    pub fn register_cleanup(
+        &mut self,
+        resource: &'static str,
+        func: CleanupFn,
+    ) {
+        self.cleanups.push((resource, func));
+    }
+
+    /// Records that the resource named `resource` has been released and no
+    /// longer needs to be cleaned up.
+    pub fn release(&mut self, resource: &'static str) {
+        self.cleanups.retain(|(r, _)| *r != resource);
+    }
+
+    /// Removes and returns the cleanup functions for all the resources that
+    /// haven't been released, most recently created first.
+    pub(crate) fn take_cleanups(&mut self) -> Vec<(&'static str, CleanupFn)> {
+        let mut cleanups = std::mem::take(&mut self.cleanups);
+        cleanups.reverse();
+        cleanups
+    }
+
+    /// Records that the step labeled `label` completed in `duration`.
+    pub(crate) fn record_step_timing(
+        &mut self,
+        label: &'static str,
+        duration: std::time::Duration,
+    ) {
+        self.step_timings.push(StepTiming { label, duration });
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn noop(_: &mut Context, _: &dyn Ui) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    #[test]
+    fn unreleased_resources_are_cleaned_up_in_reverse() {
+        let mut ctx = Context {
+            vars: HashMap::new(),
+            step_timings: Vec::new(),
+            cleanups: Vec::new(),
+        };
+
+        ctx.register_cleanup("loopback device", noop);
+        ctx.register_cleanup("WinPE partition mount", noop);
+        ctx.register_cleanup("WIM partition mount", noop);
+        ctx.release("WinPE partition mount");
+
+        let resources: Vec<_> =
+            ctx.take_cleanups().into_iter().map(|(r, _)| r).collect();
+        assert_eq!(resources, ["WIM partition mount", "loopback device"]);
+        assert!(ctx.take_cleanups().is_empty());
+    }
 }
@@ -0,0 +1,323 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Functions for working with sparse image files.
+//!
+//! Output images are created sparse, but most of a finished image is still
+//! zeroes: free space in the guest's filesystems that Windows wrote to at some
+//! point, or that later copies filled in. These functions find the regions of
+//! an image that actually hold data, turn zero-filled regions back into holes,
+//! and write a block map listing the data regions so that tools that upload
+//! images can skip the holes.
+
+use std::{
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    os::unix::{fs::MetadataExt, io::AsRawFd},
+};
+
+use anyhow::{Context as _, Result};
+use camino::Utf8Path;
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    runner::interrupt_requested,
+    ui::{ProgressUnit, Ui},
+};
+
+/// The size of the blocks that are checked for zeroes and punched out. This is
+/// the block size of most Linux filesystems; smaller holes can't be punched,
+/// and larger blocks would miss holes.
+const PUNCH_BLOCK_SIZE: usize = 4096;
+
+/// The amount of data to read from an image at once.
+const READ_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// A region of a file that contains data.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Extent {
+    pub offset: u64,
+    pub length: u64,
+}
+
+/// A list of the regions of an image that contain data. Everything outside
+/// these regions reads as zeroes.
+#[derive(Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct BlockMap {
+    /// The apparent size of the image.
+    pub image_size: u64,
+
+    /// The amount of storage the image occupies on disk.
+    pub allocated_size: u64,
+
+    pub extents: Vec<Extent>,
+}
+
+impl BlockMap {
+    /// Reads the block map of the file at `path`.
+    pub fn read(path: &Utf8Path) -> Result<Self> {
+        let file =
+            File::open(path).with_context(|| format!("opening {path}"))?;
+        let metadata = file.metadata()?;
+        Ok(Self {
+            image_size: metadata.len(),
+            allocated_size: allocated_size(&metadata),
+            extents: data_extents(&file)
+                .with_context(|| format!("finding data in {path}"))?,
+        })
+    }
+
+    /// Returns the number of bytes in the map's data extents.
+    pub fn data_size(&self) -> u64 {
+        self.extents.iter().map(|e| e.length).sum()
+    }
+}
+
+/// Returns the amount of storage a file with the supplied metadata occupies.
+pub fn allocated_size(metadata: &std::fs::Metadata) -> u64 {
+    // `st_blocks` is always in 512-byte units, whatever the filesystem's block
+    // size is.
+    metadata.blocks() * 512
+}
+
+/// Returns the regions of `file` that contain data, as reported by
+/// `SEEK_DATA` and `SEEK_HOLE`. If the filesystem doesn't support finding
+/// holes, the whole file is one region.
+pub fn data_extents(file: &File) -> Result<Vec<Extent>> {
+    let size = file.metadata()?.len();
+    let mut extents = Vec::new();
+    let mut offset = 0;
+    while offset < size {
+        let start = match seek_raw(file, offset, libc::SEEK_DATA) {
+            Ok(start) => start,
+
+            // There's no data after `offset`.
+            Err(e) if e.raw_os_error() == Some(libc::ENXIO) => break,
+            Err(e) if e.raw_os_error() == Some(libc::EINVAL) && offset == 0 => {
+                return Ok(vec![Extent { offset: 0, length: size }]);
+            }
+            Err(e) => return Err(e).context("seeking to next data region"),
+        };
+
+        let end = seek_raw(file, start, libc::SEEK_HOLE)
+            .context("seeking to next hole")?
+            .min(size);
+        if end > start {
+            extents.push(Extent { offset: start, length: end - start });
+        }
+
+        offset = end.max(start + 1);
+    }
+
+    Ok(extents)
+}
+
+/// Calls `lseek` on `file` with a `whence` value that [`Seek`] doesn't
+/// support.
+fn seek_raw(file: &File, offset: u64, whence: i32) -> std::io::Result<u64> {
+    let offset = libc::off_t::try_from(offset)
+        .map_err(|_| std::io::Error::from_raw_os_error(libc::EOVERFLOW))?;
+
+    // SAFETY: `lseek` only operates on the file descriptor, which `file` keeps
+    // open for the duration of the call.
+    let result = unsafe { libc::lseek(file.as_raw_fd(), offset, whence) };
+    if result < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    Ok(result as u64)
+}
+
+/// Deallocates the `length` bytes at `offset` in `file`, leaving a hole that
+/// reads as zeroes, without changing the file's size.
+#[cfg(target_os = "linux")]
+fn punch_hole(file: &File, offset: u64, length: u64) -> std::io::Result<()> {
+    // SAFETY: `fallocate` only operates on the file descriptor, which `file`
+    // keeps open for the duration of the call.
+    let result = unsafe {
+        libc::fallocate(
+            file.as_raw_fd(),
+            libc::FALLOC_FL_PUNCH_HOLE | libc::FALLOC_FL_KEEP_SIZE,
+            offset as libc::off_t,
+            length as libc::off_t,
+        )
+    };
+
+    if result < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Deallocates the `length` bytes at `offset` in `file`, leaving a hole that
+/// reads as zeroes, without changing the file's size.
+#[cfg(target_os = "illumos")]
+fn punch_hole(file: &File, offset: u64, length: u64) -> std::io::Result<()> {
+    // SAFETY: `flock` is a plain C struct for which all zeroes is a valid
+    // value.
+    let mut range: libc::flock = unsafe { std::mem::zeroed() };
+    range.l_whence = libc::SEEK_SET as libc::c_short;
+    range.l_start = offset as libc::off_t;
+    range.l_len = length as libc::off_t;
+
+    // SAFETY: `fcntl` only operates on the file descriptor, which `file` keeps
+    // open for the duration of the call, and reads `range`, which outlives it.
+    let result = unsafe {
+        libc::fcntl(file.as_raw_fd(), libc::F_FREESP, &range as *const _)
+    };
+
+    if result < 0 {
+        return Err(std::io::Error::last_os_error());
+    }
+
+    Ok(())
+}
+
+/// Returns the runs of zero-filled blocks in `data`, as (offset, length) pairs
+/// relative to the start of `data`. Only whole blocks are considered.
+fn zero_runs(data: &[u8]) -> Vec<(usize, usize)> {
+    let mut runs: Vec<(usize, usize)> = Vec::new();
+    for (i, block) in data.chunks_exact(PUNCH_BLOCK_SIZE).enumerate() {
+        if block.iter().any(|b| *b != 0) {
+            continue;
+        }
+
+        let offset = i * PUNCH_BLOCK_SIZE;
+        match runs.last_mut() {
+            Some((start, len)) if *start + *len == offset => {
+                *len += PUNCH_BLOCK_SIZE
+            }
+            _ => runs.push((offset, PUNCH_BLOCK_SIZE)),
+        }
+    }
+
+    runs
+}
+
+/// Replaces zero-filled blocks in the data regions of the image at `path` with
+/// holes. Returns the number of bytes that were deallocated.
+pub fn punch_zero_holes(path: &Utf8Path, ui: &dyn Ui) -> Result<u64> {
+    let mut file = File::options()
+        .read(true)
+        .write(true)
+        .open(path)
+        .with_context(|| format!("opening {path}"))?;
+
+    let extents = data_extents(&file)?;
+    let before = allocated_size(&file.metadata()?);
+    let total = extents.iter().map(|e| e.length).sum();
+    ui.start_progress(
+        "scanning for zero-filled blocks",
+        total,
+        ProgressUnit::Bytes,
+    );
+
+    let mut buf = vec![0u8; READ_CHUNK_SIZE];
+    for extent in extents {
+        // Read in chunks aligned to the start of the extent, which the
+        // filesystem aligned to one of its blocks.
+        let mut offset = extent.offset;
+        let end = extent.offset + extent.length;
+        while offset < end {
+            if interrupt_requested() {
+                ui.finish_progress();
+                anyhow::bail!("interrupted by user");
+            }
+
+            let len = (end - offset).min(READ_CHUNK_SIZE as u64) as usize;
+            file.seek(SeekFrom::Start(offset))?;
+            file.read_exact(&mut buf[..len])
+                .with_context(|| format!("reading {path}"))?;
+
+            for (start, run) in zero_runs(&buf[..len]) {
+                punch_hole(&file, offset + start as u64, run as u64)
+                    .with_context(|| format!("punching hole in {path}"))?;
+            }
+
+            offset += len as u64;
+            ui.inc_progress(len as u64);
+        }
+    }
+
+    ui.finish_progress();
+    file.sync_all()?;
+    let after = allocated_size(&file.metadata()?);
+    Ok(before.saturating_sub(after))
+}
+
+/// Writes the block map of the image at `path` to `<path>.blockmap.json` and
+/// returns it.
+pub fn write_block_map(path: &Utf8Path, ui: &dyn Ui) -> Result<BlockMap> {
+    let map = BlockMap::read(path)?;
+    let map_path = format!("{path}.blockmap.json");
+    ui.set_substep(&format!("writing {map_path}"));
+    std::fs::write(&map_path, serde_json::to_string_pretty(&map)? + "\n")
+        .with_context(|| format!("writing {map_path}"))?;
+    Ok(map)
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write;
+
+    use super::*;
+    use crate::test_support::{scratch_dir, NullUi};
+
+    #[test]
+    fn find_zero_runs() {
+        let mut data = vec![0u8; PUNCH_BLOCK_SIZE * 6 + 100];
+        data[PUNCH_BLOCK_SIZE * 2] = 1;
+        data[PUNCH_BLOCK_SIZE * 4 + 17] = 1;
+
+        // The partial block at the end is never treated as zeroes.
+        assert_eq!(
+            zero_runs(&data),
+            [
+                (0, PUNCH_BLOCK_SIZE * 2),
+                (PUNCH_BLOCK_SIZE * 3, PUNCH_BLOCK_SIZE),
+                (PUNCH_BLOCK_SIZE * 5, PUNCH_BLOCK_SIZE),
+            ]
+        );
+    }
+
+    #[test]
+    fn punch_holes_and_map_data() {
+        let dir = scratch_dir("sparse");
+        let path = dir.join("image.img");
+
+        // Write 1 MiB of data, half of which is zeroes, followed by a 1 MiB
+        // hole.
+        let mut contents = vec![0u8; 1024 * 1024];
+        contents[..512 * 1024].fill(0xa5);
+        let mut file = File::create(&path).unwrap();
+        file.write_all(&contents).unwrap();
+        file.set_len(2 * 1024 * 1024).unwrap();
+        file.sync_all().unwrap();
+        drop(file);
+
+        let before = BlockMap::read(&path).unwrap();
+        assert_eq!(before.image_size, 2 * 1024 * 1024);
+        punch_zero_holes(&path, &NullUi).unwrap();
+        let after = write_block_map(&path, &NullUi).unwrap();
+
+        // Filesystems that can't find or punch holes report all data, so only
+        // check what must hold everywhere: the data is intact and the map
+        // covers it.
+        assert_eq!(after.image_size, 2 * 1024 * 1024);
+        assert!(after.data_size() >= 512 * 1024);
+        assert!(after.data_size() <= before.data_size());
+        assert!(after.extents.iter().any(|e| e.offset == 0));
+        let data = std::fs::read(&path).unwrap();
+        assert_eq!(&data[..1024 * 1024], &contents[..]);
+
+        let map: BlockMap = serde_json::from_str(
+            &std::fs::read_to_string(format!("{path}.blockmap.json")).unwrap(),
+        )
+        .unwrap();
+        assert_eq!(map, after);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -7,18 +7,43 @@
 use std::process::Command;
 
 use crate::{
+    checksum::{format_sha256sums, HashedInput},
+    gpt,
+    image_metadata::ImageMetadata,
     ui::Ui,
     util::{grep_command_for_row_and_column, run_command_check_status},
 };
 
 use anyhow::{Context as _, Result};
+use camino::Utf8Path;
+
+/// Writes the SHA-256 digests of a script's input files, formatted as by
+/// `sha256sum`, to a file next to the output image. `hashed_inputs` is a JSON
+/// array of [`HashedInput`]s.
+pub fn record_input_checksums(
+    output_image: &str,
+    hashed_inputs: &str,
+) -> Result<()> {
+    let inputs: Vec<HashedInput> = serde_json::from_str(hashed_inputs)?;
+    let path = format!("{output_image}.inputs.sha256");
+    std::fs::write(&path, format_sha256sums(&inputs))
+        .with_context(|| format!("writing {path}"))
+}
+
+/// The size of the blank disk to which Windows is installed.
+pub const BLANK_IMAGE_SIZE: u64 = 30 * 1024 * 1024 * 1024;
 
 /// Uses `qemu-img` to create a blank output disk to which Windows can be
 /// installed.
 pub fn create_output_image(image_path: &str, ui: &dyn Ui) -> Result<()> {
     run_command_check_status(
-        Command::new("qemu-img")
-            .args(["create", "-f", "raw", image_path, "30G"]),
+        Command::new("qemu-img").args([
+            "create",
+            "-f",
+            "raw",
+            image_path,
+            &BLANK_IMAGE_SIZE.to_string(),
+        ]),
         ui,
     )
     .map(|_| ())
@@ -80,39 +105,162 @@ pub fn get_gpt_partition_information(
     })
 }
 
-/// Uses `sgdisk` to get the sector size and the offset of the last sector in an
-/// output image.
+/// Reads the partition table of an output image and returns its sector size
+/// and the last sector used by any of its partitions.
 ///
 /// # Arguments
 ///
 /// - image_path: The path to a Windows image that was produced by running the
-///   Windows installer and attendant unattend scripts. The image is presumed to
-///   have 4 partitions, the last of which is the main Windows OS partition;
-///   running Windows setup with the unattend scripts in this repository will
-///   produce an appropriately-partitioned disk.
+///   Windows installer and attendant unattend scripts. The image may have any
+///   number of partitions in any order; the unattend scripts in this
+///   repository put the main Windows OS partition last, but custom layouts
+///   might put a recovery partition after it instead.
 ///
 /// # Return value
 ///
-/// - `Ok(sector size, last sector)` if the relevant `sgdisk` commands
-///   succeeded.
-/// - `Err` if an `sgdisk` command failed or did not produce the expected
-///   output.
+/// - `Ok(sector size, last sector)` if the image has a valid GPT with at least
+///   one partition.
+/// - `Err` if the image couldn't be read or has no partitions.
 pub fn get_output_image_partition_size(
     image_path: &str,
     ui: &dyn Ui,
 ) -> Result<(String, String)> {
-    get_gpt_partition_information(image_path, 4, ui)
-        .map(|info| (info.sector_size, info.last_sector))
+    let table = read_output_image_partition_table(image_path)?;
+    let Some(last_sector) = table.last_used_lba() else {
+        anyhow::bail!("{image_path} doesn't contain any partitions");
+    };
+
+    ui.set_substep(&format!(
+        "last used sector is {last_sector} of {} ({}-byte sectors)",
+        table.backup_lba, table.sector_size
+    ));
+
+    Ok((table.sector_size.to_string(), last_sector.to_string()))
+}
+
+fn read_output_image_partition_table(
+    image_path: &str,
+) -> Result<gpt::PartitionTable> {
+    let mut file = std::fs::File::open(image_path)
+        .with_context(|| format!("opening {image_path}"))?;
+    gpt::read_partition_table(&mut file)
+        .with_context(|| format!("reading partition table from {image_path}"))
+}
+
+/// Checks that truncating a disk with partition table `table` to `new_size`
+/// bytes leaves every partition, and the secondary GPT after them, intact.
+fn check_shrink_preserves_partitions(
+    table: &gpt::PartitionTable,
+    new_size: u64,
+) -> Result<()> {
+    let new_sectors = new_size / table.sector_size;
+    let secondary_gpt_sectors = table.secondary_gpt_sectors();
+    let cut: Vec<String> = table
+        .partitions
+        .iter()
+        .filter(|p| {
+            p.last_lba.saturating_add(1 + secondary_gpt_sectors) > new_sectors
+        })
+        .map(|p| {
+            format!(
+                "partition {} (sectors {}-{})",
+                p.number, p.first_lba, p.last_lba
+            )
+        })
+        .collect();
+
+    if !cut.is_empty() {
+        anyhow::bail!(
+            "refusing to shrink image to {new_sectors} sectors: this would \
+            cut off {}",
+            cut.join(", ")
+        );
+    }
+
+    Ok(())
+}
+
+/// A size to which a shrunken output image is rounded up, e.g. so that it's a
+/// whole number of the block size or GiB granularity an importer expects.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ImageAlignment(pub u64);
+
+impl ImageAlignment {
+    /// Returns the smallest multiple of this alignment that's at least
+    /// `size`.
+    pub fn align_up(&self, size: u64) -> u64 {
+        size.div_ceil(self.0) * self.0
+    }
+}
+
+impl Default for ImageAlignment {
+    fn default() -> Self {
+        Self(1024 * 1024)
+    }
+}
+
+impl std::str::FromStr for ImageAlignment {
+    type Err = anyhow::Error;
+
+    /// Parses a size in bytes, optionally followed by a binary unit (`K`, `M`,
+    /// or `G`, with or without a trailing `iB`).
+    fn from_str(s: &str) -> Result<Self> {
+        let digits = s.trim_end_matches(|c: char| c.is_ascii_alphabetic());
+        let multiplier: u64 = match &s[digits.len()..] {
+            "" | "B" => 1,
+            "K" | "KiB" => 1024,
+            "M" | "MiB" => 1024 * 1024,
+            "G" | "GiB" => 1024 * 1024 * 1024,
+            unit => anyhow::bail!(
+                "unknown unit '{unit}' (expected K, KiB, M, MiB, G, or GiB)"
+            ),
+        };
+
+        let bytes = digits
+            .parse::<u64>()
+            .ok()
+            .and_then(|n| n.checked_mul(multiplier))
+            .filter(|n| *n > 0)
+            .ok_or_else(|| {
+                anyhow::anyhow!("'{s}' is not a positive size in bytes")
+            })?;
+
+        // Catch alignments that can't possibly work before spending an hour
+        // installing Windows; `shrink_output_image` checks the actual sector
+        // size.
+        if !bytes.is_multiple_of(512) {
+            anyhow::bail!("'{s}' is not a multiple of 512 bytes");
+        }
+
+        Ok(Self(bytes))
+    }
+}
+
+impl std::fmt::Display for ImageAlignment {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        const UNITS: [(u64, &str); 3] =
+            [(1024 * 1024 * 1024, "GiB"), (1024 * 1024, "MiB"), (1024, "KiB")];
+        for (size, unit) in UNITS {
+            if self.0.is_multiple_of(size) {
+                return write!(f, "{}{unit}", self.0 / size);
+            }
+        }
+
+        write!(f, "{}", self.0)
+    }
 }
 
 /// Given an installed Windows image at `image_path` whose sector size is
-/// `sector_size` and where the last sector of the last partition on the disk is
-/// `last_sector`, trims unused sectors from the image, leaving just enough
-/// space at the end to fit a new secondary GUID partition table.
+/// `sector_size` and where the last sector used by any partition on the disk is
+/// `last_sector`, trims unused sectors from the image, leaving enough space at
+/// the end to fit a new secondary GUID partition table and rounding the new
+/// size up to a multiple of `alignment` bytes. Fails without modifying the
+/// image if this would cut off any partition.
 pub fn shrink_output_image(
     image_path: &str,
     sector_size: &str,
     last_sector: &str,
+    alignment: &str,
     ui: &dyn Ui,
 ) -> Result<()> {
     let sector_size =
@@ -122,12 +270,43 @@ pub fn shrink_output_image(
         .parse::<u64>()
         .context("parsing last sector number as u64")?;
 
-    let os_partition_size = sector_size * last_sector;
+    let alignment = alignment
+        .parse::<ImageAlignment>()
+        .context("parsing image alignment")?;
+
+    if !alignment.0.is_multiple_of(sector_size) {
+        anyhow::bail!(
+            "image alignment {alignment} is not a multiple of the image's \
+            {sector_size}-byte sectors"
+        );
+    }
 
-    // Leave 34 sectors after the last partition for the secondary GPT. Note
-    // that this GPT won't exist in the truncated disk; the caller needs to
-    // recreate it, e.g. using `sgdisk -e`.
-    let new_disk_size = os_partition_size + (34 * sector_size);
+    // Check against the image's current partition table, not just the last
+    // sector the caller found, so that a layout change between the two steps
+    // (or a bad `last_sector`) can't cause a partition to be truncated.
+    let table = read_output_image_partition_table(image_path)?;
+    if table.sector_size != sector_size {
+        anyhow::bail!(
+            "{image_path} has {}-byte sectors, expected {sector_size}",
+            table.sector_size
+        );
+    }
+
+    // Leave room after the last partition for a secondary GPT as large as the
+    // primary one. Note that this GPT won't exist in the truncated disk; the
+    // caller needs to recreate it, e.g. using `sgdisk -e`, which puts it at
+    // the end of the padded disk.
+    let new_disk_size = last_sector
+        .checked_add(1 + table.secondary_gpt_sectors())
+        .and_then(|sectors| sectors.checked_mul(sector_size))
+        .context("last used sector is out of range")?;
+    let new_disk_size = alignment.align_up(new_disk_size);
+
+    check_shrink_preserves_partitions(&table, new_disk_size)?;
+    ui.set_substep(&format!(
+        "resizing to {} ({new_disk_size} bytes, aligned to {alignment})",
+        indicatif::HumanBytes(new_disk_size)
+    ));
     let new_disk_size = new_disk_size.to_string();
 
     // QEMU 5.10 and later require callers to pass the `--shrink` flag when
@@ -148,10 +327,10 @@ pub fn shrink_output_image(
         return Ok(());
     }
 
-    // This will overwrite the log file output from the previous invocation, but
-    // if this step fails, it's probably going to be for the same reason the
-    // previous invocation did (i.e. something else is probably wrong that
-    // isn't related to whether `--shrink` was used).
+    // If this fails too, it's probably for the same reason the previous
+    // invocation did (i.e. something else is probably wrong that isn't related
+    // to whether `--shrink` was used), but the logs from both attempts are
+    // kept in case they differ.
     assert_eq!(args.remove(1), "--shrink");
     run_command_check_status(Command::new("qemu-img").args(&args), ui)
         .map(|_| ())
@@ -164,3 +343,165 @@ pub fn repair_secondary_gpt(image_path: &str, ui: &dyn Ui) -> Result<()> {
     )
     .map(|_| ())
 }
+
+/// Deallocates the zero-filled blocks in the output image so that the image
+/// only occupies storage for the data Windows actually wrote.
+pub fn punch_holes_in_output_image(
+    image_path: &str,
+    ui: &dyn Ui,
+) -> Result<()> {
+    let freed = crate::sparse::punch_zero_holes(image_path.into(), ui)?;
+    ui.set_substep(&format!("freed {}", indicatif::HumanBytes(freed)));
+    Ok(())
+}
+
+/// Writes a map of the regions of the output image that contain data to
+/// `<output_image>.blockmap.json`.
+pub fn write_output_block_map(image_path: &str, ui: &dyn Ui) -> Result<()> {
+    crate::sparse::write_block_map(image_path.into(), ui).map(|_| ())
+}
+
+/// Writes `metadata`, a JSON [`ImageMetadata`], to the metadata file for the
+/// image at `image_path`. If there's no metadata, removes any metadata file
+/// left over from a previous image at the same path instead.
+pub fn write_output_image_metadata(
+    image_path: &str,
+    metadata: Option<&str>,
+    ui: &dyn Ui,
+) -> Result<()> {
+    let image_path = Utf8Path::new(image_path);
+    let path = ImageMetadata::path_for(image_path);
+    let Some(metadata) = metadata else {
+        ui.set_substep("no image metadata to write");
+        if path.exists() {
+            std::fs::remove_file(&path)
+                .with_context(|| format!("removing stale {path}"))?;
+        }
+
+        return Ok(());
+    };
+
+    ui.set_substep(&format!("writing {path}"));
+    let metadata: ImageMetadata = serde_json::from_str(metadata)?;
+    metadata.write_for(image_path)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn partition(number: u32, first_lba: u64, last_lba: u64) -> gpt::Partition {
+        gpt::Partition {
+            number,
+            type_guid: gpt::BASIC_DATA.to_string(),
+            unique_guid: String::new(),
+            first_lba,
+            last_lba,
+            name: String::new(),
+        }
+    }
+
+    #[test]
+    fn shrink_must_not_cut_partitions() {
+        // A layout with the recovery partition after the OS partition.
+        let table = gpt::PartitionTable {
+            sector_size: 512,
+            disk_guid: String::new(),
+            first_usable_lba: 34,
+            last_usable_lba: 62914526,
+            backup_lba: 62914559,
+            entry_count: 128,
+            entry_size: 128,
+            partitions: vec![
+                partition(1, 2048, 206847),
+                partition(2, 206848, 239615),
+                partition(3, 239616, 20000000),
+                partition(4, 20000001, 21000000),
+            ],
+        };
+
+        let last = table.last_used_lba().unwrap();
+        assert_eq!(last, 21000000);
+        assert_eq!(table.secondary_gpt_sectors(), 33);
+        check_shrink_preserves_partitions(&table, (last + 1 + 33) * 512)
+            .unwrap();
+
+        // Shrinking to the end of partition 3, as if it were the last one,
+        // would cut off partition 4.
+        let err = check_shrink_preserves_partitions(
+            &table,
+            (20000000 + 1 + 33) * 512,
+        )
+        .unwrap_err();
+        assert!(err.to_string().contains("partition 4"), "{err}");
+        assert!(!err.to_string().contains("partition 3"), "{err}");
+
+        // Partitions whose ends would overlap the secondary GPT are cut too.
+        let err = check_shrink_preserves_partitions(&table, (last + 33) * 512)
+            .unwrap_err();
+        assert!(err.to_string().contains("partition 4"), "{err}");
+    }
+
+    #[test]
+    fn secondary_gpt_size_follows_the_primary_header() {
+        // A 4Kn disk's default 16 KiB entry array fits in four sectors.
+        let table = gpt::PartitionTable {
+            sector_size: 4096,
+            disk_guid: String::new(),
+            first_usable_lba: 6,
+            last_usable_lba: 7864314,
+            backup_lba: 7864319,
+            entry_count: 128,
+            entry_size: 128,
+            partitions: vec![partition(1, 256, 2621440)],
+        };
+
+        assert_eq!(table.secondary_gpt_sectors(), 5);
+        check_shrink_preserves_partitions(&table, (2621441 + 5) * 4096)
+            .unwrap();
+        assert!(check_shrink_preserves_partitions(
+            &table,
+            (2621441 + 4) * 4096
+        )
+        .is_err());
+
+        // Tables with more entries need more room than the usual 33 sectors.
+        let table = gpt::PartitionTable {
+            sector_size: 512,
+            entry_count: 256,
+            partitions: vec![partition(1, 2048, 20000000)],
+            ..table
+        };
+
+        assert_eq!(table.secondary_gpt_sectors(), 65);
+        assert!(check_shrink_preserves_partitions(
+            &table,
+            (20000001 + 33) * 512
+        )
+        .is_err());
+        check_shrink_preserves_partitions(&table, (20000001 + 65) * 512)
+            .unwrap();
+    }
+
+    #[test]
+    fn parse_and_apply_alignments() {
+        let parse = |s: &str| s.parse::<ImageAlignment>().unwrap().0;
+        assert_eq!(parse("512"), 512);
+        assert_eq!(parse("4K"), 4096);
+        assert_eq!(parse("1MiB"), 1024 * 1024);
+        assert_eq!(parse("1G"), 1024 * 1024 * 1024);
+        assert!("0".parse::<ImageAlignment>().is_err());
+        assert!("1TB".parse::<ImageAlignment>().is_err());
+        assert!("MiB".parse::<ImageAlignment>().is_err());
+        assert!("1000".parse::<ImageAlignment>().is_err());
+
+        assert_eq!(ImageAlignment::default().to_string(), "1MiB");
+        assert_eq!(ImageAlignment(1536 * 1024).to_string(), "1536KiB");
+        assert_eq!(ImageAlignment(512).to_string(), "512");
+
+        let gib = ImageAlignment(1024 * 1024 * 1024);
+        assert_eq!(gib.align_up(1), 1024 * 1024 * 1024);
+        assert_eq!(gib.align_up(1024 * 1024 * 1024), 1024 * 1024 * 1024);
+        assert_eq!(gib.align_up(10737435136), 11 * 1024 * 1024 * 1024);
+    }
+}
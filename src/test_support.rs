@@ -0,0 +1,56 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Helpers shared by the unit tests of several modules.
+
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use camino::Utf8PathBuf;
+
+use crate::ui::{ChildLogs, Ui};
+
+/// A `Ui` that ignores everything it's told. Child processes' output goes to
+/// anonymous temporary files, so code under test can still run commands.
+pub struct NullUi;
+
+impl Ui for NullUi {
+    fn set_substep(&self, _substep: &str) {}
+
+    fn child_logs(
+        &self,
+        _: &std::process::Command,
+    ) -> anyhow::Result<ChildLogs> {
+        Ok(ChildLogs { stdout: temp_file()?, stderr: temp_file()? })
+    }
+}
+
+/// Creates a file in the system temporary directory and unlinks it, so that
+/// it disappears once the returned handle is closed.
+fn temp_file() -> std::io::Result<std::fs::File> {
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    let path = std::env::temp_dir().join(format!(
+        "wimsy-test-log-{}-{}",
+        std::process::id(),
+        NEXT.fetch_add(1, Ordering::Relaxed)
+    ));
+
+    let file = std::fs::File::options()
+        .read(true)
+        .write(true)
+        .create_new(true)
+        .open(&path)?;
+    std::fs::remove_file(&path)?;
+    Ok(file)
+}
+
+/// Creates an empty scratch directory for the test named `name`. Tests remove
+/// the directory when they're done with it.
+pub fn scratch_dir(name: &str) -> Utf8PathBuf {
+    let dir = Utf8PathBuf::from_path_buf(std::env::temp_dir())
+        .unwrap()
+        .join(format!("wimsy-{name}-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    std::fs::create_dir_all(&dir).unwrap();
+    dir
+}
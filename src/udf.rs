@@ -0,0 +1,702 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Reads files from Universal Disk Format (UDF) images.
+//!
+//! Windows setup ISOs keep their files in a UDF file system; the ISO 9660
+//! file system next to it holds only a README. This module implements just
+//! enough of UDF (ECMA-167 with the UDF 1.02 to 2.01 conventions that Windows
+//! media use) to find a file by path and read arbitrary ranges of it, which
+//! lets callers read the small pieces they need of multi-gigabyte files
+//! without extracting them.
+
+use std::io::{Read, Seek, SeekFrom};
+
+use anyhow::{Context as _, Result};
+
+/// The size of a sector on optical media, in which volume descriptors are
+/// addressed.
+const SECTOR_SIZE: u64 = 2048;
+
+/// The sector that holds the anchor volume descriptor pointer.
+const ANCHOR_SECTOR: u64 = 256;
+
+/// The most volume descriptors to read before giving up on finding a
+/// terminating descriptor.
+const MAX_VOLUME_DESCRIPTORS: u64 = 64;
+
+/// The largest directory this module will read.
+const MAX_DIRECTORY_SIZE: u64 = 16 * 1024 * 1024;
+
+// Descriptor tag identifiers.
+const TAG_ANCHOR: u16 = 2;
+const TAG_PARTITION: u16 = 5;
+const TAG_LOGICAL_VOLUME: u16 = 6;
+const TAG_TERMINATING: u16 = 8;
+const TAG_FILE_SET: u16 = 256;
+const TAG_FILE_IDENTIFIER: u16 = 257;
+const TAG_FILE_ENTRY: u16 = 261;
+const TAG_EXTENDED_FILE_ENTRY: u16 = 266;
+
+/// The file characteristic bit set for directories.
+const FID_DIRECTORY: u8 = 0x02;
+
+/// The file characteristic bit set for deleted files.
+const FID_DELETED: u8 = 0x04;
+
+/// The file characteristic bit set for a directory's parent entry.
+const FID_PARENT: u8 = 0x08;
+
+/// Reads a little-endian `u16` at `offset` in `buf`.
+fn u16_at(buf: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(buf[offset..offset + 2].try_into().unwrap())
+}
+
+/// Reads a little-endian `u32` at `offset` in `buf`.
+fn u32_at(buf: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(buf[offset..offset + 4].try_into().unwrap())
+}
+
+/// Reads a little-endian `u64` at `offset` in `buf`.
+fn u64_at(buf: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+/// Checks the descriptor tag at the start of `buf`, returning its identifier.
+fn check_tag(buf: &[u8]) -> Result<u16> {
+    if buf.len() < 16 {
+        anyhow::bail!("UDF descriptor is too short");
+    }
+
+    // The checksum is the sum of the tag's other bytes, modulo 256.
+    let checksum = buf[..16]
+        .iter()
+        .enumerate()
+        .filter(|(i, _)| *i != 4)
+        .fold(0u8, |sum, (_, b)| sum.wrapping_add(*b));
+    if checksum != buf[4] {
+        anyhow::bail!("UDF descriptor tag has a bad checksum");
+    }
+
+    Ok(u16_at(buf, 0))
+}
+
+/// The address of a block in one of a volume's partitions (an `lb_addr`).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct BlockAddress {
+    block: u32,
+    partition: u16,
+}
+
+/// The location of a long allocation descriptor (`long_ad`) at `offset` in
+/// `buf`, ignoring its length.
+fn long_ad_at(buf: &[u8], offset: usize) -> BlockAddress {
+    BlockAddress {
+        block: u32_at(buf, offset + 4),
+        partition: u16_at(buf, offset + 8),
+    }
+}
+
+/// A contiguous piece of a file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Extent {
+    /// `len` bytes stored at byte `start` of the image.
+    Recorded { start: u64, len: u64 },
+
+    /// `len` bytes that aren't stored and read as zeroes.
+    Zeroes { len: u64 },
+}
+
+impl Extent {
+    fn len(&self) -> u64 {
+        match self {
+            Extent::Recorded { len, .. } | Extent::Zeroes { len } => *len,
+        }
+    }
+}
+
+/// Where a file's contents are stored.
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Contents {
+    /// The contents are stored in the file entry itself.
+    Embedded(Vec<u8>),
+
+    /// The contents are stored in these extents, in order.
+    Extents(Vec<Extent>),
+}
+
+/// The parts of a UDF file entry this module uses.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct FileEntry {
+    size: u64,
+    contents: Contents,
+}
+
+/// A UDF file system in an image.
+pub struct Udf<R> {
+    image: R,
+
+    /// The size of a logical block.
+    block_size: u64,
+
+    /// The byte offset of the start of each partition in the volume's
+    /// partition maps, indexed by partition reference number.
+    partitions: Vec<u64>,
+
+    /// The root directory's file entry.
+    root: BlockAddress,
+}
+
+impl<R: Read + Seek> Udf<R> {
+    /// Reads the volume and file set descriptors of the UDF file system in
+    /// `image`.
+    pub fn open(mut image: R) -> Result<Self> {
+        let anchor = read_sector(&mut image, ANCHOR_SECTOR)?;
+        if check_tag(&anchor)? != TAG_ANCHOR {
+            anyhow::bail!("no UDF anchor volume descriptor at sector 256");
+        }
+
+        // Walk the main volume descriptor sequence to find the partitions and
+        // the logical volume that's built on them.
+        let sequence_start = u64::from(u32_at(&anchor, 20));
+        let sequence_sectors = u64::from(u32_at(&anchor, 16)) / SECTOR_SIZE;
+        let mut partition_starts = Vec::new();
+        let mut logical_volume = None;
+        for sector in sequence_start
+            ..sequence_start + sequence_sectors.min(MAX_VOLUME_DESCRIPTORS)
+        {
+            let desc = read_sector(&mut image, sector)?;
+            match check_tag(&desc)? {
+                TAG_PARTITION => partition_starts.push((
+                    u16_at(&desc, 22),
+                    u64::from(u32_at(&desc, 188)) * SECTOR_SIZE,
+                )),
+                TAG_LOGICAL_VOLUME => logical_volume = Some(desc),
+                TAG_TERMINATING => break,
+                _ => {}
+            }
+        }
+
+        let lvd = logical_volume.context("no UDF logical volume descriptor")?;
+        let block_size = u64::from(u32_at(&lvd, 212));
+        if block_size != SECTOR_SIZE {
+            anyhow::bail!("unsupported UDF logical block size {block_size}");
+        }
+
+        // Map partition reference numbers to the partitions they name. Only
+        // type 1 (physical) maps are supported; the metadata and sparable
+        // partitions of later UDF revisions aren't used on Windows media.
+        let map_count = u32_at(&lvd, 268) as usize;
+        let mut partitions = Vec::with_capacity(map_count);
+        let mut offset = 440;
+        for _ in 0..map_count {
+            let map = lvd
+                .get(offset..offset + 6)
+                .context("UDF partition maps overflow their descriptor")?;
+            if map[0] != 1 {
+                anyhow::bail!("unsupported UDF partition map type {}", map[0]);
+            }
+
+            let number = u16_at(map, 4);
+            let start = partition_starts
+                .iter()
+                .find(|(n, _)| *n == number)
+                .map(|(_, start)| *start)
+                .with_context(|| format!("no UDF partition {number}"))?;
+            partitions.push(start);
+            offset += usize::from(map[1]).max(6);
+        }
+
+        // The logical volume descriptor locates the file set descriptor, which
+        // in turn locates the root directory.
+        let fsd_address = long_ad_at(&lvd, 248);
+        let mut udf = Self { image, block_size, partitions, root: fsd_address };
+        let fsd = udf.read_block(fsd_address)?;
+        if check_tag(&fsd)? != TAG_FILE_SET {
+            anyhow::bail!("no UDF file set descriptor");
+        }
+
+        udf.root = long_ad_at(&fsd, 400);
+        Ok(udf)
+    }
+
+    /// Opens the file at `path`, a `/`-separated path relative to the root
+    /// directory whose components are compared case-insensitively, as Windows
+    /// does. Returns `None` if there's no such file.
+    pub fn open_file(&mut self, path: &str) -> Result<Option<UdfFile<'_, R>>> {
+        let mut entry = self.read_file_entry(self.root)?;
+        let mut is_dir = true;
+        for component in path.split('/').filter(|c| !c.is_empty()) {
+            if !is_dir {
+                return Ok(None);
+            }
+
+            let Some((address, child_is_dir)) =
+                self.find_child(&entry, component)?
+            else {
+                return Ok(None);
+            };
+
+            entry = self.read_file_entry(address)?;
+            is_dir = child_is_dir;
+        }
+
+        Ok(Some(UdfFile { image: &mut self.image, entry, pos: 0 }))
+    }
+
+    /// Returns the byte offset in the image of the block at `address`.
+    fn block_offset(&self, address: BlockAddress) -> Result<u64> {
+        let start =
+            self.partitions.get(usize::from(address.partition)).with_context(
+                || format!("no UDF partition reference {}", address.partition),
+            )?;
+        Ok(start + u64::from(address.block) * self.block_size)
+    }
+
+    fn read_block(&mut self, address: BlockAddress) -> Result<Vec<u8>> {
+        let offset = self.block_offset(address)?;
+        let mut block = vec![0u8; self.block_size as usize];
+        self.image.seek(SeekFrom::Start(offset))?;
+        self.image
+            .read_exact(&mut block)
+            .with_context(|| format!("reading UDF block at offset {offset}"))?;
+        Ok(block)
+    }
+
+    /// Reads the file entry at `address`.
+    fn read_file_entry(&mut self, address: BlockAddress) -> Result<FileEntry> {
+        let block = self.read_block(address)?;
+        let (ea_len_offset, ad_start) = match check_tag(&block)? {
+            TAG_FILE_ENTRY => (168, 176),
+            TAG_EXTENDED_FILE_ENTRY => (208, 216),
+            tag => anyhow::bail!("expected a UDF file entry, found tag {tag}"),
+        };
+
+        let size = u64_at(&block, 56);
+        let ea_len = u32_at(&block, ea_len_offset) as usize;
+        let ad_len = u32_at(&block, ea_len_offset + 4) as usize;
+        let ads = block
+            .get(ad_start + ea_len..ad_start + ea_len + ad_len)
+            .context("UDF file entry's allocation descriptors overflow it")?;
+
+        // The low bits of the ICB tag's flags say which kind of allocation
+        // descriptors follow.
+        let ad_size = match u16_at(&block, 34) & 7 {
+            0 => 8,
+            1 => 16,
+            3 => {
+                return Ok(FileEntry {
+                    size,
+                    contents: Contents::Embedded(ads.to_vec()),
+                })
+            }
+            kind => anyhow::bail!("unsupported UDF allocation type {kind}"),
+        };
+
+        let mut extents = Vec::new();
+        for ad in ads.chunks_exact(ad_size) {
+            // The top two bits of the length give the extent's type.
+            let len = u64::from(u32_at(ad, 0) & 0x3fff_ffff);
+            if len == 0 {
+                break;
+            }
+
+            extents.push(match u32_at(ad, 0) >> 30 {
+                0 => {
+                    let address = match ad_size {
+                        8 => BlockAddress {
+                            block: u32_at(ad, 4),
+                            partition: address.partition,
+                        },
+                        _ => long_ad_at(ad, 0),
+                    };
+                    Extent::Recorded { start: self.block_offset(address)?, len }
+                }
+                1 | 2 => Extent::Zeroes { len },
+                _ => anyhow::bail!(
+                    "UDF file's allocation descriptors continue elsewhere, \
+                    which isn't supported"
+                ),
+            });
+        }
+
+        Ok(FileEntry { size, contents: Contents::Extents(extents) })
+    }
+
+    /// Looks up the entry named `name` in the directory `dir`, returning the
+    /// address of its file entry and whether it's a directory.
+    fn find_child(
+        &mut self,
+        dir: &FileEntry,
+        name: &str,
+    ) -> Result<Option<(BlockAddress, bool)>> {
+        if dir.size > MAX_DIRECTORY_SIZE {
+            anyhow::bail!("UDF directory is implausibly large");
+        }
+
+        let mut data = vec![0u8; dir.size as usize];
+        UdfFile { image: &mut self.image, entry: dir.clone(), pos: 0 }
+            .read_exact(&mut data)
+            .context("reading UDF directory")?;
+
+        let mut offset = 0;
+        while offset + 38 <= data.len() {
+            let fid = &data[offset..];
+            if check_tag(fid)? != TAG_FILE_IDENTIFIER {
+                anyhow::bail!("malformed UDF directory");
+            }
+
+            let characteristics = fid[18];
+            let name_len = usize::from(fid[19]);
+            let impl_len = usize::from(u16_at(fid, 36));
+            let fid_len = (38 + impl_len + name_len).next_multiple_of(4);
+            let raw_name = fid
+                .get(38 + impl_len..38 + impl_len + name_len)
+                .context("malformed UDF file identifier")?;
+
+            if characteristics & (FID_DELETED | FID_PARENT) == 0
+                && decode_name(raw_name)?.eq_ignore_ascii_case(name)
+            {
+                let is_dir = characteristics & FID_DIRECTORY != 0;
+                return Ok(Some((long_ad_at(fid, 20), is_dir)));
+            }
+
+            offset += fid_len;
+        }
+
+        Ok(None)
+    }
+}
+
+/// Reads the sector numbered `sector`.
+fn read_sector<R: Read + Seek>(image: &mut R, sector: u64) -> Result<Vec<u8>> {
+    let mut buf = vec![0u8; SECTOR_SIZE as usize];
+    image.seek(SeekFrom::Start(sector * SECTOR_SIZE))?;
+    image
+        .read_exact(&mut buf)
+        .with_context(|| format!("reading UDF sector {sector}"))?;
+    Ok(buf)
+}
+
+/// Decodes a file name in OSTA compressed Unicode.
+fn decode_name(raw: &[u8]) -> Result<String> {
+    match raw.split_first() {
+        Some((8, name)) => Ok(name.iter().map(|&b| char::from(b)).collect()),
+        Some((16, name)) => {
+            let units: Vec<u16> = name
+                .chunks_exact(2)
+                .map(|c| u16::from_be_bytes([c[0], c[1]]))
+                .collect();
+            String::from_utf16(&units).context("invalid UDF file name")
+        }
+        _ => anyhow::bail!("unsupported UDF file name encoding"),
+    }
+}
+
+/// A file opened from a UDF image.
+pub struct UdfFile<'a, R> {
+    image: &'a mut R,
+    entry: FileEntry,
+    pos: u64,
+}
+
+impl<R> UdfFile<'_, R> {
+    /// Returns the file's size in bytes.
+    pub fn size(&self) -> u64 {
+        self.entry.size
+    }
+}
+
+impl<R: Read + Seek> Read for UdfFile<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let remaining = self.entry.size.saturating_sub(self.pos);
+        let want = (buf.len() as u64).min(remaining) as usize;
+        if want == 0 {
+            return Ok(0);
+        }
+
+        let extents = match &self.entry.contents {
+            Contents::Embedded(data) => {
+                let start = self.pos as usize;
+                let len = want.min(data.len().saturating_sub(start));
+                if len == 0 {
+                    return Err(std::io::ErrorKind::UnexpectedEof.into());
+                }
+
+                buf[..len].copy_from_slice(&data[start..start + len]);
+                self.pos += len as u64;
+                return Ok(len);
+            }
+            Contents::Extents(extents) => extents,
+        };
+
+        // Find the extent that holds the current position and read as much of
+        // it as the caller wants.
+        let mut extent_start = 0;
+        for extent in extents {
+            if self.pos < extent_start + extent.len() {
+                let within = self.pos - extent_start;
+                let len = want.min((extent.len() - within) as usize);
+                match extent {
+                    Extent::Recorded { start, .. } => {
+                        self.image.seek(SeekFrom::Start(start + within))?;
+                        self.image.read_exact(&mut buf[..len])?;
+                    }
+                    Extent::Zeroes { .. } => buf[..len].fill(0),
+                }
+
+                self.pos += len as u64;
+                return Ok(len);
+            }
+
+            extent_start += extent.len();
+        }
+
+        Err(std::io::ErrorKind::UnexpectedEof.into())
+    }
+}
+
+impl<R> Seek for UdfFile<'_, R> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let new_pos = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::End(delta) => self.entry.size.checked_add_signed(delta),
+            SeekFrom::Current(delta) => self.pos.checked_add_signed(delta),
+        };
+
+        self.pos = new_pos.ok_or_else(|| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "seek to a negative or overflowing position",
+            )
+        })?;
+        Ok(self.pos)
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+
+    /// The sector at which [`UdfBuilder`] starts its partition.
+    const PARTITION_START: usize = 300;
+
+    /// Writes the descriptor tag for a descriptor with identifier `id` at the
+    /// start of `buf`.
+    fn write_tag(buf: &mut [u8], id: u16) {
+        buf[0..2].copy_from_slice(&id.to_le_bytes());
+        buf[2..4].copy_from_slice(&2u16.to_le_bytes());
+        buf[4] = buf[..16]
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| *i != 4)
+            .fold(0u8, |sum, (_, b)| sum.wrapping_add(*b));
+    }
+
+    /// Encodes a long allocation descriptor.
+    fn long_ad(len: u32, block: u32) -> [u8; 16] {
+        let mut ad = [0u8; 16];
+        ad[0..4].copy_from_slice(&len.to_le_bytes());
+        ad[4..8].copy_from_slice(&block.to_le_bytes());
+        ad
+    }
+
+    /// Encodes a file identifier descriptor for `name`, using 16-bit name
+    /// encoding if `wide` is set.
+    fn fid(name: &str, is_dir: bool, block: u32, wide: bool) -> Vec<u8> {
+        let mut raw_name = Vec::new();
+        if wide {
+            raw_name.push(16);
+            raw_name.extend(name.encode_utf16().flat_map(u16::to_be_bytes));
+        } else {
+            raw_name.push(8);
+            raw_name.extend(name.bytes());
+        }
+
+        let mut fid = vec![0u8; (38 + raw_name.len()).next_multiple_of(4)];
+        fid[18] = if is_dir { FID_DIRECTORY } else { 0 };
+        fid[19] = raw_name.len() as u8;
+        fid[20..36].copy_from_slice(&long_ad(2048, block));
+        fid[38..38 + raw_name.len()].copy_from_slice(&raw_name);
+        write_tag(&mut fid, TAG_FILE_IDENTIFIER);
+        fid
+    }
+
+    /// Encodes the file identifier descriptor of a directory's parent.
+    fn parent_fid() -> Vec<u8> {
+        let mut fid = vec![0u8; 40];
+        fid[18] = FID_DIRECTORY | FID_PARENT;
+        write_tag(&mut fid, TAG_FILE_IDENTIFIER);
+        fid
+    }
+
+    /// Builds minimal UDF images for tests.
+    pub(crate) struct UdfBuilder {
+        image: Vec<u8>,
+    }
+
+    impl UdfBuilder {
+        /// Creates an image with the volume and file set descriptors, but no
+        /// root directory.
+        fn new() -> Self {
+            let mut builder = Self { image: Vec::new() };
+
+            let anchor = builder.sector_mut(ANCHOR_SECTOR as usize);
+            anchor[16..20].copy_from_slice(&(3 * 2048u32).to_le_bytes());
+            anchor[20..24].copy_from_slice(&32u32.to_le_bytes());
+            write_tag(anchor, TAG_ANCHOR);
+
+            let pd = builder.sector_mut(32);
+            pd[188..192]
+                .copy_from_slice(&(PARTITION_START as u32).to_le_bytes());
+            write_tag(pd, TAG_PARTITION);
+
+            let lvd = builder.sector_mut(33);
+            lvd[212..216].copy_from_slice(&2048u32.to_le_bytes());
+            lvd[248..264].copy_from_slice(&long_ad(2048, 0));
+            lvd[268..272].copy_from_slice(&1u32.to_le_bytes());
+            lvd[440..446].copy_from_slice(&[1, 6, 1, 0, 0, 0]);
+            write_tag(lvd, TAG_LOGICAL_VOLUME);
+
+            write_tag(builder.sector_mut(34), TAG_TERMINATING);
+
+            let fsd = builder.block_mut(0);
+            fsd[400..416].copy_from_slice(&long_ad(2048, 1));
+            write_tag(fsd, TAG_FILE_SET);
+            builder
+        }
+
+        fn sector_mut(&mut self, sector: usize) -> &mut [u8] {
+            let end = (sector + 1) * 2048;
+            if self.image.len() < end {
+                self.image.resize(end, 0);
+            }
+
+            &mut self.image[end - 2048..end]
+        }
+
+        fn block_mut(&mut self, block: u32) -> &mut [u8] {
+            self.sector_mut(PARTITION_START + block as usize)
+        }
+
+        /// Writes a file entry at `block` whose contents are `data`, stored
+        /// after the entry in as many blocks as needed, and returns the first
+        /// block after them.
+        fn write_file(&mut self, block: u32, data: &[u8]) -> u32 {
+            let blocks = data.len().div_ceil(2048) as u32;
+            let entry = self.block_mut(block);
+            entry[56..64].copy_from_slice(&(data.len() as u64).to_le_bytes());
+            entry[172..176].copy_from_slice(&8u32.to_le_bytes());
+            entry[176..180].copy_from_slice(&(data.len() as u32).to_le_bytes());
+            entry[180..184].copy_from_slice(&(block + 1).to_le_bytes());
+            write_tag(entry, TAG_FILE_ENTRY);
+
+            for (i, chunk) in data.chunks(2048).enumerate() {
+                self.block_mut(block + 1 + i as u32)[..chunk.len()]
+                    .copy_from_slice(chunk);
+            }
+
+            block + 1 + blocks
+        }
+
+        /// Builds an image holding just `sources/<name>`, whose contents are
+        /// `data`.
+        pub(crate) fn with_file(name: &str, data: &[u8]) -> Vec<u8> {
+            let mut builder = Self::new();
+            let mut root = parent_fid();
+            root.extend(fid("sources", true, 3, false));
+            builder.write_file(1, &root);
+
+            let mut sources = parent_fid();
+            sources.extend(fid(name, false, 5, true));
+            builder.write_file(3, &sources);
+            builder.write_file(5, data);
+            builder.image
+        }
+    }
+
+    /// Returns `len` bytes of test data that differ from block to block.
+    fn pattern(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i % 251) as u8).collect()
+    }
+
+    #[test]
+    fn read_file_by_path() {
+        let data = pattern(5000);
+        let image = UdfBuilder::with_file("install.wim", &data);
+        let mut udf = Udf::open(std::io::Cursor::new(image)).unwrap();
+
+        let mut file = udf.open_file("SOURCES/Install.wim").unwrap().unwrap();
+        assert_eq!(file.size(), 5000);
+        let mut contents = Vec::new();
+        file.read_to_end(&mut contents).unwrap();
+        assert_eq!(contents, data);
+
+        let mut buf = [0u8; 100];
+        file.seek(SeekFrom::End(-100)).unwrap();
+        file.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, data[4900..]);
+
+        assert!(udf.open_file("sources/install.esd").unwrap().is_none());
+        assert!(udf.open_file("sources/install.wim/x").unwrap().is_none());
+    }
+
+    #[test]
+    fn read_embedded_and_sparse_files() {
+        let mut builder = UdfBuilder::new();
+        let mut root = parent_fid();
+        root.extend(fid("small", false, 3, false));
+        root.extend(fid("sparse", false, 4, false));
+        builder.write_file(1, &root);
+
+        // A file whose contents are embedded in its extended file entry.
+        let entry = builder.block_mut(3);
+        entry[34] = 3;
+        entry[56..64].copy_from_slice(&5u64.to_le_bytes());
+        entry[212..216].copy_from_slice(&5u32.to_le_bytes());
+        entry[216..221].copy_from_slice(b"hello");
+        write_tag(entry, TAG_EXTENDED_FILE_ENTRY);
+
+        // A file with a recorded extent, an unrecorded one, and another
+        // recorded one in a different place, described by long_ads.
+        let entry = builder.block_mut(4);
+        entry[34] = 1;
+        entry[56..64].copy_from_slice(&4500u64.to_le_bytes());
+        entry[172..176].copy_from_slice(&48u32.to_le_bytes());
+        entry[176..192].copy_from_slice(&long_ad(3000, 5));
+        entry[192..208].copy_from_slice(&long_ad(1000 | 1 << 30, 0));
+        entry[208..224].copy_from_slice(&long_ad(500, 10));
+        write_tag(entry, TAG_FILE_ENTRY);
+        builder.block_mut(5)[..2048].fill(1);
+        builder.block_mut(6)[..952].fill(2);
+        builder.block_mut(10)[..500].fill(3);
+
+        let mut udf = Udf::open(std::io::Cursor::new(builder.image)).unwrap();
+        let mut contents = Vec::new();
+        let mut file = udf.open_file("small").unwrap().unwrap();
+        file.read_to_end(&mut contents).unwrap();
+        assert_eq!(contents, b"hello");
+
+        let mut contents = Vec::new();
+        let mut file = udf.open_file("sparse").unwrap().unwrap();
+        file.read_to_end(&mut contents).unwrap();
+        let mut expected = vec![1u8; 2048];
+        expected.extend([2u8; 952]);
+        expected.extend([0u8; 1000]);
+        expected.extend([3u8; 500]);
+        assert_eq!(contents, expected);
+    }
+
+    #[test]
+    fn reject_non_udf_images() {
+        assert!(
+            Udf::open(std::io::Cursor::new(vec![0u8; 1024 * 1024])).is_err()
+        );
+
+        // Corrupt the anchor's checksum.
+        let mut image = UdfBuilder::with_file("install.wim", b"x");
+        image[256 * 2048 + 4] ^= 1;
+        assert!(Udf::open(std::io::Cursor::new(image)).is_err());
+    }
+}
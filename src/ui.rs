@@ -2,10 +2,19 @@
 // License, v. 2.0. If a copy of the MPL was not distributed with this
 // file, You can obtain one at https://mozilla.org/MPL/2.0/.
 
-use crate::runner::{Context, Script, ScriptStep};
+use crate::{
+    logs::RunLog,
+    runner::{interrupt_requested, Context, Script, ScriptStep},
+};
 
+use std::{cell::RefCell, io::Write, process::Command};
+
+use anyhow::Context as _;
 use camino::Utf8Path;
-use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use colored::Colorize;
+use indicatif::{
+    HumanBytes, HumanDuration, MultiProgress, ProgressBar, ProgressStyle,
+};
 
 const PROGRESS_TICK_INTERVAL: std::time::Duration =
     std::time::Duration::from_millis(100);
@@ -22,15 +31,221 @@ pub trait Ui {
     /// supplied string.
     fn set_substep(&self, substep: &str);
 
-    /// Obtains a handle to the log file to which to write the `stdout` output
-    /// of a process with the supplied name.
-    fn child_stdout(&self, process_name: &str)
-        -> anyhow::Result<std::fs::File>;
+    /// Creates log files to which to write the `stdout` and `stderr` output of
+    /// an invocation of `cmd`. Every call creates new files, so running the
+    /// same command more than once keeps the logs from every attempt.
+    fn child_logs(&self, cmd: &Command) -> anyhow::Result<ChildLogs>;
+
+    /// Tells the UI that the current step is doing work described by `message`
+    /// whose size is known to be `total` `unit`s, replacing any progress that
+    /// was previously being reported.
+    fn start_progress(&self, _message: &str, _total: u64, _unit: ProgressUnit) {
+    }
+
+    /// Tells the UI that `position` units of the work passed to the most
+    /// recent call to `start_progress` are done.
+    fn set_progress(&self, _position: u64) {}
+
+    /// Tells the UI that another `delta` units of the work passed to the most
+    /// recent call to `start_progress` are done.
+    fn inc_progress(&self, _delta: u64) {}
+
+    /// Tells the UI that the work passed to the most recent call to
+    /// `start_progress` is finished.
+    fn finish_progress(&self) {}
+}
+
+/// The units in which a step reports determinate progress.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProgressUnit {
+    Bytes,
+    Percent,
+}
+
+/// The state of a unit of determinate progress that is being reported as lines
+/// of text.
+struct TextProgress {
+    message: String,
+    total: u64,
+    position: u64,
+    unit: ProgressUnit,
+    started: std::time::Instant,
+    last_reported: Option<u64>,
+}
+
+impl TextProgress {
+    /// Returns a line describing this progress if it has crossed a 10%
+    /// boundary since it was last reported.
+    fn report(&mut self) -> Option<String> {
+        let percent = (self.position.min(self.total) * 10)
+            .checked_div(self.total)
+            .unwrap_or(10)
+            * 10;
+        if self.last_reported == Some(percent) {
+            return None;
+        }
+
+        self.last_reported = Some(percent);
+        let elapsed = self.started.elapsed();
+        let mut line = format!("{}: {percent}%", self.message);
+        if self.unit == ProgressUnit::Bytes && !elapsed.is_zero() {
+            let rate = self.position as f64 / elapsed.as_secs_f64();
+            line += &format!(
+                " ({}/{}, {}/s",
+                HumanBytes(self.position),
+                HumanBytes(self.total),
+                HumanBytes(rate as u64)
+            );
+            if percent < 100 && rate > 0.0 {
+                let remaining = (self.total - self.position) as f64 / rate;
+                line += &format!(
+                    ", ETA {}",
+                    HumanDuration(std::time::Duration::from_secs_f64(
+                        remaining
+                    ))
+                );
+            }
+            line += ")";
+        }
+
+        Some(line)
+    }
+}
+
+/// Reports determinate progress on a progress bar in interactive mode or as
+/// periodic lines of text in non-interactive mode.
+struct ProgressReporter {
+    bar: Option<ProgressBar>,
+    text: RefCell<Option<TextProgress>>,
+}
+
+impl ProgressReporter {
+    fn new(bar: Option<ProgressBar>) -> Self {
+        Self { bar, text: RefCell::new(None) }
+    }
+
+    fn start(&self, message: &str, total: u64, unit: ProgressUnit) {
+        match &self.bar {
+            Some(bar) => {
+                let template = match unit {
+                    ProgressUnit::Bytes => {
+                        "{msg} [{bar:30}] {bytes}/{total_bytes} \
+                        ({binary_bytes_per_sec}, ETA {eta})"
+                    }
+                    ProgressUnit::Percent => {
+                        "{msg} [{bar:30}] {pos}% (ETA {eta})"
+                    }
+                };
+                bar.set_style(ProgressStyle::with_template(template).unwrap());
+                bar.set_message(message.to_owned());
+                bar.set_length(total);
+                bar.set_position(0);
+                bar.reset_eta();
+            }
+            None => {
+                *self.text.borrow_mut() = Some(TextProgress {
+                    message: message.to_owned(),
+                    total,
+                    position: 0,
+                    unit,
+                    started: std::time::Instant::now(),
+                    last_reported: None,
+                });
+            }
+        }
+    }
+
+    fn set(&self, position: u64) {
+        match &self.bar {
+            Some(bar) => bar.set_position(position),
+            None => {
+                if let Some(text) = self.text.borrow_mut().as_mut() {
+                    text.position = position;
+                    if let Some(line) = text.report() {
+                        println!("  {line}");
+                    }
+                }
+            }
+        }
+    }
+
+    fn inc(&self, delta: u64) {
+        match &self.bar {
+            Some(bar) => bar.inc(delta),
+            None => {
+                let position = match self.text.borrow().as_ref() {
+                    Some(text) => text.position + delta,
+                    None => return,
+                };
+                self.set(position);
+            }
+        }
+    }
+
+    /// Finishes reporting progress, restoring `message` as the bar's message.
+    fn finish(&self, message: &str) {
+        match &self.bar {
+            Some(bar) => {
+                bar.set_style(ProgressStyle::default_spinner());
+                bar.set_message(message.to_owned());
+            }
+            None => *self.text.borrow_mut() = None,
+        }
+    }
+}
+
+/// The log files for a single invocation of a child process. Each file begins
+/// with a header that records the invocation's command line.
+pub struct ChildLogs {
+    pub stdout: std::fs::File,
+    pub stderr: std::fs::File,
+}
+
+impl ChildLogs {
+    /// Appends the process's exit status to both of its log files.
+    pub fn record_exit_status(
+        &mut self,
+        status: std::process::ExitStatus,
+    ) -> anyhow::Result<()> {
+        for file in [&mut self.stdout, &mut self.stderr] {
+            writeln!(file, "\n# {status}")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Creates the log files for an invocation of `cmd`. `prefix` identifies the
+/// part of the script that ran the command; the run log supplies a sequence
+/// number that is unique across the whole run.
+fn create_child_logs(
+    run_log: &RunLog,
+    prefix: &str,
+    cmd: &Command,
+) -> anyhow::Result<ChildLogs> {
+    let sequence = run_log.next_sequence();
+    let program = Utf8Path::new(&*cmd.get_program().to_string_lossy())
+        .file_name()
+        .unwrap_or_default()
+        .to_owned();
+
+    let command_line = format!("{cmd:?}");
+    run_log.log(&format!("  command {sequence}: {command_line}"));
+
+    let create = |stream: LogStream| -> anyhow::Result<std::fs::File> {
+        let name = format!("{prefix}.{sequence:03}.{program}.{stream}.log");
+        let path = run_log.dir().join(name);
+        let mut file = std::fs::File::create(&path)
+            .with_context(|| format!("creating log file {path}"))?;
+        writeln!(file, "# command: {command_line}")?;
+        writeln!(file, "# stream: {stream}\n")?;
+        Ok(file)
+    };
 
-    /// Obtains a handle to the log file to which to write the `stderr` output
-    /// of a process with the supplied name.
-    fn child_stderr(&self, process_name: &str)
-        -> anyhow::Result<std::fs::File>;
+    Ok(ChildLogs {
+        stdout: create(LogStream::Stdout)?,
+        stderr: create(LogStream::Stderr)?,
+    })
 }
 
 /// The handler used to display updates about the status of a particular step or
@@ -80,11 +295,13 @@ struct PerStepUi<'a> {
     step_id: usize,
     step: &'a ScriptStep,
     step_handler: StepHandler<'a>,
-    log_dir: &'a Utf8Path,
+    progress: ProgressReporter,
+    run_log: &'a RunLog,
 }
 
 impl Ui for PerStepUi<'_> {
     fn set_substep(&self, substep: &str) {
+        self.run_log.log(&format!("  {substep}"));
         match self.step_handler {
             StepHandler::ProgressBar(bar) => {
                 bar.set_message(format!("{}: {}", self.step.label(), substep));
@@ -95,44 +312,104 @@ impl Ui for PerStepUi<'_> {
         }
     }
 
-    fn child_stdout(
-        &self,
-        process_name: &str,
-    ) -> anyhow::Result<std::fs::File> {
-        self.create_log_file_for_process(LogStream::Stdout, process_name)
+    fn child_logs(&self, cmd: &Command) -> anyhow::Result<ChildLogs> {
+        create_child_logs(self.run_log, &self.step_id.to_string(), cmd)
+    }
+
+    fn start_progress(&self, message: &str, total: u64, unit: ProgressUnit) {
+        self.run_log.log(&format!("  {message}"));
+        self.progress.start(
+            &format!("{}: {}", self.step.label(), message),
+            total,
+            unit,
+        );
+    }
+
+    fn set_progress(&self, position: u64) {
+        self.progress.set(position);
+    }
+
+    fn inc_progress(&self, delta: u64) {
+        self.progress.inc(delta);
+    }
+
+    fn finish_progress(&self) {
+        self.progress.finish(self.step.label());
+    }
+}
+
+/// A [`Ui`] for work a script does outside of its steps, such as checking its
+/// prerequisites or cleaning up after an interruption.
+pub struct AuxiliaryUi<'a> {
+    progress: ProgressReporter,
+    run_log: &'a RunLog,
+
+    /// The prefix of the names of the log files this UI creates.
+    log_prefix: &'static str,
+}
+
+impl<'a> AuxiliaryUi<'a> {
+    pub fn new(
+        run_log: &'a RunLog,
+        mode: &Mode,
+        log_prefix: &'static str,
+    ) -> Self {
+        let bar = match mode {
+            Mode::Interactive => {
+                let bar = ProgressBar::new_spinner();
+                bar.enable_steady_tick(PROGRESS_TICK_INTERVAL);
+                Some(bar)
+            }
+            Mode::NonInteractive => None,
+        };
+
+        Self { progress: ProgressReporter::new(bar), run_log, log_prefix }
+    }
+}
+
+impl Ui for AuxiliaryUi<'_> {
+    fn set_substep(&self, substep: &str) {
+        self.run_log.log(&format!("  {substep}"));
+        match &self.progress.bar {
+            Some(bar) => bar.set_message(substep.to_owned()),
+            None => println!("  {}", substep),
+        }
+    }
+
+    fn child_logs(&self, cmd: &Command) -> anyhow::Result<ChildLogs> {
+        create_child_logs(self.run_log, self.log_prefix, cmd)
     }
 
-    fn child_stderr(
-        &self,
-        process_name: &str,
-    ) -> anyhow::Result<std::fs::File> {
-        self.create_log_file_for_process(LogStream::Stderr, process_name)
+    fn start_progress(&self, message: &str, total: u64, unit: ProgressUnit) {
+        self.run_log.log(&format!("  {message}"));
+        self.progress.start(message, total, unit);
+    }
+
+    fn set_progress(&self, position: u64) {
+        self.progress.set(position);
+    }
+
+    fn inc_progress(&self, delta: u64) {
+        self.progress.inc(delta);
+    }
+
+    fn finish_progress(&self) {
+        self.progress.finish("");
     }
 }
 
-impl PerStepUi<'_> {
-    /// Creates a log file for the process named `process_name`.
-    ///
-    /// Note that the log file is always truncated, not appended to. The
-    /// framework adds a step ID to each log file name to disambiguate output
-    /// files generated by the same process name in different steps, but if the
-    /// same step needs to run a single process multiple times, it should tag
-    /// them with distinct `process_name`s.
-    fn create_log_file_for_process(
-        &self,
-        stream: LogStream,
-        process_name: &str,
-    ) -> anyhow::Result<std::fs::File> {
-        let mut path = self.log_dir.to_path_buf();
-        path.push(format!("{}.{}.{}.log", self.step_id, process_name, stream));
-        Ok(std::fs::File::create(&path)?)
+impl Drop for AuxiliaryUi<'_> {
+    fn drop(&mut self) {
+        if let Some(bar) = &self.progress.bar {
+            bar.finish_and_clear();
+        }
     }
 }
 
 pub fn run_script(
     script: Box<dyn Script>,
     mut ctx: Context,
-    log_dir: &Utf8Path,
+    run_log: &RunLog,
     mode: Mode,
 ) -> anyhow::Result<()> {
     let (_multi, bars) = match mode {
@@ -165,11 +442,27 @@ pub fn run_script(
     for (step_number, (step, handler)) in
         script.steps().iter().zip(substep_handlers).enumerate()
     {
+        if interrupt_requested() {
+            return handle_interrupt(
+                script.steps(),
+                step_number,
+                ctx,
+                run_log,
+                &mode,
+            );
+        }
+
+        let bar = match handler {
+            StepHandler::ProgressBar(bar) => Some(bar.clone()),
+            StepHandler::Stdout => None,
+        };
+
         let ui = PerStepUi {
             step_id: step_number,
             step,
             step_handler: handler,
-            log_dir,
+            progress: ProgressReporter::new(bar),
+            run_log,
         };
 
         if let StepHandler::ProgressBar(bar) = ui.step_handler {
@@ -177,14 +470,93 @@ pub fn run_script(
             bar.enable_steady_tick(PROGRESS_TICK_INTERVAL);
         }
 
+        run_log.log(&format!("step {step_number} started: {}", step.label()));
+        let start = std::time::Instant::now();
         let result = step.run(&mut ctx, &ui);
+        let elapsed = start.elapsed();
+        match &result {
+            Ok(()) => run_log.log(&format!(
+                "step {step_number} completed in {:.1}s",
+                elapsed.as_secs_f64()
+            )),
+            Err(e) => run_log.log(&format!(
+                "step {step_number} failed after {:.1}s: {e:#}",
+                elapsed.as_secs_f64()
+            )),
+        }
+
         ui.step_handler.apply_result(step, &result);
-        result?;
+        if result.is_err() && interrupt_requested() {
+            return handle_interrupt(
+                script.steps(),
+                step_number,
+                ctx,
+                run_log,
+                &mode,
+            );
+        }
+
+        if let Err(e) = result {
+            // Don't leave behind resources that a rerun would trip over (e.g.
+            // a half-imported disk holding the image's name).
+            release_resources(&mut ctx, run_log, &mode);
+            return Err(e);
+        }
+
+        ctx.record_step_timing(step.label(), elapsed);
     }
 
     Ok(())
 }
 
+/// Reports that the user interrupted `steps` before step `interrupted_step`
+/// completed, then releases the resources the completed steps created.
+fn handle_interrupt(
+    steps: &[ScriptStep],
+    interrupted_step: usize,
+    mut ctx: Context,
+    run_log: &RunLog,
+    mode: &Mode,
+) -> anyhow::Result<()> {
+    let label = steps[interrupted_step].label();
+    run_log.log(&format!("interrupted before completing: {label}"));
+    println!("\n{} {label}", "Interrupted:".bold());
+
+    if interrupted_step > 0 {
+        println!("These steps completed before the interruption:");
+        for step in &steps[..interrupted_step] {
+            println!("  {}", step.label());
+        }
+    }
+
+    // Steps depend on state that earlier steps left in the context, so a
+    // build can't pick up partway through.
+    println!("Rerunning the command starts over from: {}", steps[0].label());
+
+    release_resources(&mut ctx, run_log, mode);
+    anyhow::bail!("interrupted by user")
+}
+
+/// Releases the resources that completed steps created and that no later step
+/// released, most recently created first.
+fn release_resources(ctx: &mut Context, run_log: &RunLog, mode: &Mode) {
+    let cleanups = ctx.take_cleanups();
+    if cleanups.is_empty() {
+        return;
+    }
+
+    println!("Cleaning up:");
+    let ui = AuxiliaryUi::new(run_log, mode, "cleanup");
+    for (resource, cleanup) in cleanups {
+        ui.set_substep(&format!("releasing {resource}"));
+        if let Err(e) = cleanup(ctx, &ui) {
+            let message = format!("failed to release {resource}: {e:#}");
+            run_log.log(&message);
+            println!("  {message}");
+        }
+    }
+}
+
 enum LogStream {
     Stdout,
     Stderr,
@@ -196,9 +568,75 @@ impl std::fmt::Display for LogStream {
             f,
             "{}",
             match self {
-                Self::Stdout => "stdio",
+                Self::Stdout => "stdout",
                 Self::Stderr => "stderr",
             }
         )
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn text_progress_reports_every_ten_percent() {
+        let mut progress = TextProgress {
+            message: "copying".to_string(),
+            total: 1000,
+            position: 0,
+            unit: ProgressUnit::Percent,
+            started: std::time::Instant::now(),
+            last_reported: None,
+        };
+
+        let mut lines = Vec::new();
+        for position in [0, 50, 99, 100, 150, 999, 1000] {
+            progress.position = position;
+            lines.extend(progress.report());
+        }
+
+        assert_eq!(
+            lines,
+            ["copying: 0%", "copying: 10%", "copying: 90%", "copying: 100%"]
+        );
+
+        // Byte progress also includes the amount copied and the rate.
+        progress.unit = ProgressUnit::Bytes;
+        progress.position = 500;
+        progress.started -= std::time::Duration::from_secs(1);
+        let line = progress.report().unwrap();
+        assert!(line.starts_with("copying: 50% (500 B/1000 B, "), "{line}");
+        assert!(line.contains("ETA"), "{line}");
+    }
+
+    #[test]
+    fn child_logs_are_not_overwritten() {
+        let root = crate::test_support::scratch_dir("ui");
+        let run_log = RunLog::create(&root, 1).unwrap();
+
+        let mut cmd = Command::new("/usr/bin/sgdisk");
+        cmd.args(["-i", "1", "disk.img"]);
+        for _ in 0..2 {
+            let mut logs = create_child_logs(&run_log, "3", &cmd).unwrap();
+            writeln!(logs.stdout, "output").unwrap();
+        }
+
+        for sequence in ["000", "001"] {
+            let stdout = std::fs::read_to_string(
+                run_log.dir().join(format!("3.{sequence}.sgdisk.stdout.log")),
+            )
+            .unwrap();
+            assert!(stdout.starts_with(
+                "# command: \"/usr/bin/sgdisk\" \"-i\" \"1\" \"disk.img\"\n"
+            ));
+            assert!(stdout.ends_with("output\n"));
+            assert!(run_log
+                .dir()
+                .join(format!("3.{sequence}.sgdisk.stderr.log"))
+                .exists());
+        }
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,933 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Defines a script that uploads a finished image to an Oxide rack.
+//!
+//! The upload uses the Oxide API's bulk import workflow, the same one the
+//! Oxide CLI's `disk import` command uses:
+//!
+//! 1. Create a disk whose source is `importing_blocks`. The disk starts out in
+//!    the `import_ready` state.
+//! 2. Start a bulk write, which moves the disk to the
+//!    `importing_from_bulk_writes` state.
+//! 3. Write the image's contents to the disk in base64-encoded chunks of at
+//!    most 512 KiB. New disks read as zeroes, so chunks that are all zeroes
+//!    (and holes in sparse images) are skipped.
+//! 4. Stop the bulk write, returning the disk to `import_ready`.
+//! 5. Finalize the disk, taking a snapshot of it. This leaves the disk
+//!    detached and usable like any other disk.
+//! 6. Create an image from the snapshot.
+
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{Read, Seek, SeekFrom},
+    time::Duration,
+};
+
+use anyhow::{Context as _, Result};
+use base64::Engine as _;
+use camino::{Utf8Path, Utf8PathBuf};
+use colored::Colorize;
+use serde_json::json;
+
+use crate::{
+    app::UploadOptions,
+    image_metadata::ImageMetadata,
+    runner::{
+        interrupt_requested, Context, MissingPrerequisites, Script, ScriptStep,
+    },
+    sparse::data_extents,
+    steps::ImageAlignment,
+    ui::{ProgressUnit, Ui},
+    util::check_file_prerequisites,
+};
+
+/// The largest amount of data the API accepts in a single bulk write.
+const CHUNK_SIZE: u64 = 512 * 1024;
+
+/// The block size of the disks images are imported into.
+const DISK_BLOCK_SIZE: u64 = 512;
+
+/// The granularity of Oxide disk sizes. Disks created from an image must be
+/// at least as large as the image, so the image's disk is no larger than it
+/// needs to be.
+const DISK_SIZE_ALIGNMENT: ImageAlignment = ImageAlignment(1024 * 1024 * 1024);
+
+/// How long to wait for any single API request to complete.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(120);
+
+pub struct UploadArgs {
+    pub work_dir: Utf8PathBuf,
+    pub output_image: Utf8PathBuf,
+    pub options: UploadOptions,
+}
+
+pub struct UploadScript {
+    steps: Vec<ScriptStep>,
+    args: UploadArgs,
+
+    /// The OS, version, and description to give the image, or the reason they
+    /// couldn't be determined.
+    label: Result<ImageLabel, String>,
+}
+
+impl UploadScript {
+    pub fn new(script_args: UploadArgs) -> Self {
+        let label = ImageMetadata::read_for(&script_args.output_image)
+            .map_err(|e| format!("{e:#}"))
+            .and_then(|metadata| {
+                ImageLabel::new(&script_args.options, metadata.as_ref())
+                    .ok_or_else(|| {
+                        format!(
+                            "{} doesn't exist; pass --windows-version to \
+                            describe the image",
+                            ImageMetadata::path_for(&script_args.output_image)
+                        )
+                    })
+            });
+
+        Self { steps: get_script(), args: script_args, label }
+    }
+}
+
+/// The metadata with which to label an uploaded image.
+#[derive(Debug, PartialEq, Eq)]
+struct ImageLabel {
+    os: String,
+    version: String,
+    description: String,
+}
+
+impl ImageLabel {
+    /// Labels an image using the command-line `options`, falling back to the
+    /// `metadata` recorded when the image was built. Returns `None` if neither
+    /// says which version of Windows the image contains.
+    fn new(
+        options: &UploadOptions,
+        metadata: Option<&ImageMetadata>,
+    ) -> Option<Self> {
+        let (os, version) = match (options.windows_version, metadata) {
+            (Some(v), _) => {
+                (v.image_os().to_owned(), v.image_version().to_owned())
+            }
+            (None, Some(m)) => (m.os.clone(), m.version.clone()),
+            (None, None) => return None,
+        };
+
+        // Only use the recorded description if it describes the same version
+        // the image is being labeled with.
+        let description = options
+            .image_description
+            .clone()
+            .or_else(|| {
+                metadata
+                    .filter(|m| m.os == os && m.version == version)
+                    .map(|m| m.description.clone())
+            })
+            .unwrap_or_else(|| format!("{os} {version}"));
+
+        Some(Self { os, version, description })
+    }
+}
+
+impl Script for UploadScript {
+    fn steps(&self) -> &[ScriptStep] {
+        self.steps.as_slice()
+    }
+
+    fn print_configuration(
+        &self,
+        mut w: Box<dyn std::io::Write>,
+    ) -> std::io::Result<()> {
+        writeln!(w, "Uploading a Windows image with these options:\n")?;
+
+        let args = &self.args;
+        let options = &args.options;
+        writeln!(w, "  {}: {}", "Working directory".bold(), args.work_dir)?;
+        writeln!(w, "  {}: {}", "Image".bold(), args.output_image)?;
+        writeln!(w, "  {}: {}", "Oxide API".bold(), options.oxide_host)?;
+        writeln!(w, "  {}: {}", "Project".bold(), options.project)?;
+        writeln!(w)?;
+        writeln!(w, "  Image name: {}", options.image_name)?;
+        match &self.label {
+            Ok(label) => {
+                writeln!(w, "  Image description: {}", label.description)?;
+                writeln!(w, "  Image OS: {} {}", label.os, label.version)?;
+            }
+            Err(_) => writeln!(w, "  Image OS: unknown")?,
+        }
+
+        Ok(())
+    }
+
+    fn check_prerequisites(&self, _ui: &dyn Ui) -> MissingPrerequisites {
+        let options = &self.args.options;
+        let mut errors = check_file_prerequisites(std::slice::from_ref(
+            &self.args.output_image,
+        ));
+
+        if !options.oxide_host.starts_with("http://")
+            && !options.oxide_host.starts_with("https://")
+        {
+            errors.push(format!(
+                "Oxide API URL {} must start with http:// or https://",
+                options.oxide_host
+            ));
+        }
+
+        errors.extend(check_resource_name(&options.image_name));
+        if let Err(e) = &self.label {
+            errors.push(e.clone());
+        }
+
+        MissingPrerequisites::from_messages(errors, Vec::new())
+    }
+
+    fn initial_context(&self) -> HashMap<String, String> {
+        let args = &self.args;
+        let options = &args.options;
+        [
+            ("output_image", args.output_image.to_string()),
+            ("oxide_host", options.oxide_host.clone()),
+            ("oxide_token", options.oxide_token.clone()),
+            ("oxide_project", options.project.clone()),
+            ("image_name", options.image_name.clone()),
+        ]
+        .into_iter()
+        .chain(self.label.iter().flat_map(|label| {
+            [
+                ("image_description", label.description.clone()),
+                ("image_os", label.os.clone()),
+                ("image_version", label.version.clone()),
+            ]
+        }))
+        .map(|(k, v)| (k.to_string(), v))
+        .collect()
+    }
+}
+
+/// Checks that `name` is a valid name for an Oxide resource: 1 to 63
+/// characters, starting with a lowercase letter, containing only lowercase
+/// letters, digits, and hyphens, and not ending with a hyphen.
+fn check_resource_name(name: &str) -> Option<String> {
+    let valid = (1..=63).contains(&name.len())
+        && name.starts_with(|c: char| c.is_ascii_lowercase())
+        && !name.ends_with('-')
+        && name
+            .chars()
+            .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '-');
+
+    (!valid).then(|| {
+        format!(
+            "'{name}' is not a valid Oxide resource name (names must start \
+            with a lowercase letter, contain only lowercase letters, digits, \
+            and hyphens, not end with a hyphen, and be at most 63 characters \
+            long)"
+        )
+    })
+}
+
+/// A client for the parts of the Oxide API that import disks and create
+/// images in a single project.
+pub struct OxideClient {
+    host: String,
+    token: String,
+    project: String,
+    agent: ureq::Agent,
+}
+
+impl OxideClient {
+    pub fn new(host: &str, token: &str, project: &str) -> Self {
+        Self {
+            host: host.trim_end_matches('/').to_string(),
+            token: token.to_string(),
+            project: project.to_string(),
+            agent: ureq::AgentBuilder::new().timeout(REQUEST_TIMEOUT).build(),
+        }
+    }
+
+    fn from_context(ctx: &Context) -> Self {
+        Self::new(
+            ctx.get_var("oxide_host").unwrap(),
+            ctx.get_var("oxide_token").unwrap(),
+            ctx.get_var("oxide_project").unwrap(),
+        )
+    }
+
+    /// Sends a request to the API endpoint at `path`, scoped to the client's
+    /// project, and returns the response body (or `null` if it was empty).
+    fn request(
+        &self,
+        method: &str,
+        path: &str,
+        body: Option<serde_json::Value>,
+    ) -> Result<serde_json::Value> {
+        let request = self
+            .agent
+            .request(method, &format!("{}{path}", self.host))
+            .query("project", &self.project)
+            .set("Authorization", &format!("Bearer {}", self.token));
+
+        let result = match body {
+            Some(body) => request.send_json(body),
+            None => request.call(),
+        };
+
+        let response = match result {
+            Ok(response) => response,
+            Err(ureq::Error::Status(status, response)) => {
+                // Oxide API errors have a JSON body with a human-readable
+                // message; fall back to the raw body if this one doesn't.
+                let body = response.into_string().unwrap_or_default();
+                let message = serde_json::from_str::<serde_json::Value>(&body)
+                    .ok()
+                    .and_then(|v| v["message"].as_str().map(str::to_owned))
+                    .unwrap_or(body);
+                anyhow::bail!("{method} {path} failed ({status}): {message}");
+            }
+            Err(e) => {
+                return Err(e).with_context(|| format!("{method} {path}"))
+            }
+        };
+
+        let body = response
+            .into_string()
+            .with_context(|| format!("reading response to {method} {path}"))?;
+        if body.trim().is_empty() {
+            return Ok(serde_json::Value::Null);
+        }
+
+        serde_json::from_str(&body)
+            .with_context(|| format!("parsing response to {method} {path}"))
+    }
+
+    /// Creates a disk named `name` of `size` bytes that's ready to have blocks
+    /// imported into it.
+    pub fn create_importing_disk(
+        &self,
+        name: &str,
+        description: &str,
+        size: u64,
+    ) -> Result<()> {
+        self.request(
+            "POST",
+            "/v1/disks",
+            Some(json!({
+                "name": name,
+                "description": description,
+                "size": size,
+                "disk_source": {
+                    "type": "importing_blocks",
+                    "block_size": DISK_BLOCK_SIZE,
+                },
+            })),
+        )
+        .map(|_| ())
+    }
+
+    pub fn start_bulk_write(&self, disk: &str) -> Result<()> {
+        self.request(
+            "POST",
+            &format!("/v1/disks/{disk}/bulk-write-start"),
+            None,
+        )
+        .map(|_| ())
+    }
+
+    /// Writes `data`, which must be a whole number of blocks no larger than
+    /// 512 KiB, to `disk` at byte `offset`.
+    pub fn bulk_write(
+        &self,
+        disk: &str,
+        offset: u64,
+        data: &[u8],
+    ) -> Result<()> {
+        let encoded = base64::engine::general_purpose::STANDARD.encode(data);
+        self.request(
+            "POST",
+            &format!("/v1/disks/{disk}/bulk-write"),
+            Some(json!({
+                "offset": offset,
+                "base64_encoded_data": encoded,
+            })),
+        )
+        .map(|_| ())
+    }
+
+    pub fn stop_bulk_write(&self, disk: &str) -> Result<()> {
+        self.request("POST", &format!("/v1/disks/{disk}/bulk-write-stop"), None)
+            .map(|_| ())
+    }
+
+    /// Finalizes an imported disk, taking a snapshot of it named
+    /// `snapshot_name`.
+    pub fn finalize(&self, disk: &str, snapshot_name: &str) -> Result<()> {
+        self.request(
+            "POST",
+            &format!("/v1/disks/{disk}/finalize"),
+            Some(json!({ "snapshot_name": snapshot_name })),
+        )
+        .map(|_| ())
+    }
+
+    /// Returns the ID of the snapshot named `name`.
+    pub fn snapshot_id(&self, name: &str) -> Result<String> {
+        let snapshot =
+            self.request("GET", &format!("/v1/snapshots/{name}"), None)?;
+        snapshot["id"]
+            .as_str()
+            .map(str::to_owned)
+            .ok_or_else(|| anyhow::anyhow!("snapshot {name} has no ID"))
+    }
+
+    /// Creates an image from the snapshot with ID `snapshot_id` and returns the
+    /// new image's ID.
+    pub fn create_image(
+        &self,
+        name: &str,
+        description: &str,
+        os: &str,
+        version: &str,
+        snapshot_id: &str,
+    ) -> Result<String> {
+        let image = self.request(
+            "POST",
+            "/v1/images",
+            Some(json!({
+                "name": name,
+                "description": description,
+                "os": os,
+                "version": version,
+                "source": { "type": "snapshot", "id": snapshot_id },
+            })),
+        )?;
+
+        Ok(image["id"].as_str().unwrap_or_default().to_owned())
+    }
+
+    pub fn delete_disk(&self, disk: &str) -> Result<()> {
+        self.request("DELETE", &format!("/v1/disks/{disk}"), None).map(|_| ())
+    }
+}
+
+/// The amounts of an image's data that were and weren't sent to the rack.
+#[derive(Debug, PartialEq, Eq)]
+pub struct WriteSummary {
+    pub written: u64,
+    pub skipped: u64,
+}
+
+/// Returns the offsets of the chunks of a file that overlap the file's data
+/// extents, in order.
+fn chunks_to_read(file: &File) -> Result<Vec<u64>> {
+    let mut chunks: Vec<u64> = Vec::new();
+    for extent in data_extents(file)? {
+        let mut offset = extent.offset / CHUNK_SIZE * CHUNK_SIZE;
+        while offset < extent.offset + extent.length {
+            if chunks.last() != Some(&offset) {
+                chunks.push(offset);
+            }
+
+            offset += CHUNK_SIZE;
+        }
+    }
+
+    Ok(chunks)
+}
+
+/// Writes the contents of the image at `path` to `disk`, which must be
+/// accepting bulk writes, skipping chunks that are all zeroes.
+pub fn write_image_blocks(
+    client: &OxideClient,
+    disk: &str,
+    path: &Utf8Path,
+    ui: &dyn Ui,
+) -> Result<WriteSummary> {
+    let mut file =
+        File::open(path).with_context(|| format!("opening {path}"))?;
+    let size = file.metadata()?.len();
+    let chunks = chunks_to_read(&file)
+        .with_context(|| format!("finding data in {path}"))?;
+
+    let total = chunks.iter().map(|offset| CHUNK_SIZE.min(size - offset)).sum();
+    ui.start_progress("writing image data", total, ProgressUnit::Bytes);
+
+    let mut buf = vec![0u8; CHUNK_SIZE as usize];
+    let mut written = 0;
+    for offset in chunks {
+        if interrupt_requested() {
+            ui.finish_progress();
+            anyhow::bail!("interrupted by user");
+        }
+
+        let len = CHUNK_SIZE.min(size - offset);
+        let chunk = &mut buf[..len as usize];
+        file.seek(SeekFrom::Start(offset))?;
+        file.read_exact(chunk).with_context(|| format!("reading {path}"))?;
+
+        if chunk.iter().any(|b| *b != 0) {
+            client.bulk_write(disk, offset, chunk).with_context(|| {
+                format!("writing {len} bytes at offset {offset}")
+            })?;
+            written += len;
+        }
+
+        ui.inc_progress(len);
+    }
+
+    ui.finish_progress();
+    Ok(WriteSummary { written, skipped: size - written })
+}
+
+fn create_importing_disk(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
+    let image = Utf8Path::new(ctx.get_var("output_image").unwrap());
+    let image_size = std::fs::metadata(image)
+        .with_context(|| format!("reading {image}"))?
+        .len();
+
+    if !image_size.is_multiple_of(DISK_BLOCK_SIZE) {
+        anyhow::bail!(
+            "{image} is {image_size} bytes, which isn't a whole number of \
+            {DISK_BLOCK_SIZE}-byte blocks"
+        );
+    }
+
+    let disk_size = DISK_SIZE_ALIGNMENT.align_up(image_size);
+    let name = ctx.get_var("image_name").unwrap();
+    ui.set_substep(&format!(
+        "creating {} disk {name}",
+        indicatif::HumanBytes(disk_size)
+    ));
+
+    OxideClient::from_context(ctx).create_importing_disk(
+        name,
+        ctx.get_var("image_description").unwrap(),
+        disk_size,
+    )?;
+
+    ctx.register_cleanup("importing disk", delete_importing_disk);
+    Ok(())
+}
+
+fn start_bulk_write(ctx: &mut Context, _ui: &dyn Ui) -> Result<()> {
+    OxideClient::from_context(ctx)
+        .start_bulk_write(ctx.get_var("image_name").unwrap())
+}
+
+fn write_blocks(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
+    let summary = write_image_blocks(
+        &OxideClient::from_context(ctx),
+        ctx.get_var("image_name").unwrap(),
+        Utf8Path::new(ctx.get_var("output_image").unwrap()),
+        ui,
+    )?;
+
+    ui.set_substep(&format!(
+        "wrote {}, skipped {} of zeroes",
+        indicatif::HumanBytes(summary.written),
+        indicatif::HumanBytes(summary.skipped)
+    ));
+    Ok(())
+}
+
+fn stop_bulk_write(ctx: &mut Context, _ui: &dyn Ui) -> Result<()> {
+    OxideClient::from_context(ctx)
+        .stop_bulk_write(ctx.get_var("image_name").unwrap())
+}
+
+fn finalize_disk(ctx: &mut Context, _ui: &dyn Ui) -> Result<()> {
+    let name = ctx.get_var("image_name").unwrap();
+    OxideClient::from_context(ctx).finalize(name, name)?;
+    ctx.release("importing disk");
+    Ok(())
+}
+
+fn create_image(ctx: &mut Context, ui: &dyn Ui) -> Result<()> {
+    let client = OxideClient::from_context(ctx);
+    let name = ctx.get_var("image_name").unwrap();
+    let snapshot_id = client.snapshot_id(name)?;
+    let image_id = client.create_image(
+        name,
+        ctx.get_var("image_description").unwrap(),
+        ctx.get_var("image_os").unwrap(),
+        ctx.get_var("image_version").unwrap(),
+        &snapshot_id,
+    )?;
+
+    ui.set_substep(&format!("created image {name} ({image_id})"));
+    Ok(())
+}
+
+/// Deletes a disk that was created for an import that didn't finish. The disk
+/// can only be deleted once it's no longer accepting bulk writes.
+fn delete_importing_disk(ctx: &mut Context, _ui: &dyn Ui) -> Result<()> {
+    let client = OxideClient::from_context(ctx);
+    let name = ctx.get_var("image_name").unwrap();
+
+    // This fails if the bulk write was never started or was already stopped,
+    // either of which is fine.
+    let _ = client.stop_bulk_write(name);
+    client.delete_disk(name)?;
+    ctx.release("importing disk");
+    Ok(())
+}
+
+fn get_script() -> Vec<ScriptStep> {
+    vec![
+        ScriptStep::new("create importing disk", create_importing_disk),
+        ScriptStep::new("start bulk import", start_bulk_write),
+        ScriptStep::new("write image data to disk", write_blocks),
+        ScriptStep::new("stop bulk import", stop_bulk_write),
+        ScriptStep::new("finalize disk and take snapshot", finalize_disk),
+        ScriptStep::new("create image from snapshot", create_image),
+    ]
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        io::{BufRead, BufReader, Write},
+        net::TcpListener,
+        sync::{Arc, Mutex},
+    };
+
+    use super::*;
+    use crate::test_support::{scratch_dir, NullUi};
+
+    /// A request received by [`MockOxide`].
+    #[derive(Debug)]
+    struct Request {
+        method: String,
+        path: String,
+        authorization: String,
+        body: serde_json::Value,
+    }
+
+    /// A minimal HTTP server that records the requests it receives and
+    /// responds to them as the Oxide API would to a successful import.
+    struct MockOxide {
+        url: String,
+        requests: Arc<Mutex<Vec<Request>>>,
+    }
+
+    impl MockOxide {
+        fn start() -> Self {
+            Self::start_failing(None)
+        }
+
+        /// Starts a server that responds with an internal error to requests
+        /// whose paths start with `fail_path`.
+        fn start_failing(fail_path: Option<&'static str>) -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let url = format!("http://{}", listener.local_addr().unwrap());
+            let requests = Arc::new(Mutex::new(Vec::new()));
+            let recorded = requests.clone();
+            std::thread::spawn(move || {
+                for stream in listener.incoming() {
+                    let stream = stream.unwrap();
+                    let recorded = recorded.clone();
+                    std::thread::spawn(move || {
+                        serve(stream, &recorded, fail_path)
+                    });
+                }
+            });
+
+            Self { url, requests }
+        }
+
+        fn take_requests(&self) -> Vec<Request> {
+            std::mem::take(&mut *self.requests.lock().unwrap())
+        }
+    }
+
+    /// Serves requests on `stream` until the client closes it.
+    fn serve(
+        stream: std::net::TcpStream,
+        recorded: &Mutex<Vec<Request>>,
+        fail_path: Option<&str>,
+    ) {
+        let mut reader = BufReader::new(stream.try_clone().unwrap());
+        let mut writer = stream;
+        loop {
+            let mut request_line = String::new();
+            if reader.read_line(&mut request_line).unwrap_or(0) == 0 {
+                return;
+            }
+
+            let mut parts = request_line.split_whitespace();
+            let method = parts.next().unwrap().to_string();
+            let path = parts.next().unwrap().to_string();
+
+            let mut content_length = 0;
+            let mut authorization = String::new();
+            loop {
+                let mut header = String::new();
+                reader.read_line(&mut header).unwrap();
+                let header = header.trim_end();
+                if header.is_empty() {
+                    break;
+                }
+
+                let (name, value) = header.split_once(": ").unwrap();
+                match name.to_ascii_lowercase().as_str() {
+                    "content-length" => content_length = value.parse().unwrap(),
+                    "authorization" => authorization = value.to_string(),
+                    _ => {}
+                }
+            }
+
+            let mut body = vec![0u8; content_length];
+            reader.read_exact(&mut body).unwrap();
+            let body = if body.is_empty() {
+                serde_json::Value::Null
+            } else {
+                serde_json::from_slice(&body).unwrap()
+            };
+
+            let (status, response) =
+                if fail_path.is_some_and(|fail| path.starts_with(fail)) {
+                    (500, json!({ "message": "Internal Server Error" }))
+                } else if path.starts_with("/v1/disks/missing") {
+                    (404, json!({ "message": "not found: disk \"missing\"" }))
+                } else if path.starts_with("/v1/snapshots/") {
+                    (200, json!({ "id": "snapshot-id" }))
+                } else if path.starts_with("/v1/images") {
+                    (201, json!({ "id": "image-id" }))
+                } else {
+                    (204, serde_json::Value::Null)
+                };
+
+            recorded.lock().unwrap().push(Request {
+                method,
+                path,
+                authorization,
+                body,
+            });
+
+            let response = if response.is_null() {
+                String::new()
+            } else {
+                response.to_string()
+            };
+
+            write!(
+                writer,
+                "HTTP/1.1 {status} Mock\r\nContent-Type: application/json\r\n\
+                Content-Length: {}\r\n\r\n{response}",
+                response.len()
+            )
+            .unwrap();
+        }
+    }
+
+    #[test]
+    fn validate_resource_names() {
+        assert!(check_resource_name("windows-server-2022").is_none());
+        assert!(check_resource_name("w2k22-v1").is_none());
+        assert!(check_resource_name("").is_some());
+        assert!(check_resource_name("2022").is_some());
+        assert!(check_resource_name("Windows").is_some());
+        assert!(check_resource_name("windows-").is_some());
+        assert!(check_resource_name("windows_2022").is_some());
+        assert!(check_resource_name(&"a".repeat(64)).is_some());
+    }
+
+    #[test]
+    fn label_from_options_and_metadata() {
+        use crate::autounattend::WindowsVersion;
+
+        let mut options = UploadOptions {
+            oxide_host: "https://oxide.example.com".to_string(),
+            oxide_token: "tok".to_string(),
+            project: "images".to_string(),
+            image_name: "windows-server-2022".to_string(),
+            image_description: None,
+            windows_version: None,
+        };
+
+        let metadata = ImageMetadata {
+            os: "Windows Server".to_string(),
+            version: "2022".to_string(),
+            description: "Windows Server 2022 Standard (Desktop Experience)"
+                .to_string(),
+            image_index: Some(2),
+            edition: None,
+        };
+
+        // Without a version, only recorded metadata can label the image.
+        assert_eq!(ImageLabel::new(&options, None), None);
+        let label = ImageLabel::new(&options, Some(&metadata)).unwrap();
+        assert_eq!(label.os, "Windows Server");
+        assert_eq!(label.version, "2022");
+        assert_eq!(label.description, metadata.description);
+
+        // A version that agrees with the metadata keeps its description; one
+        // that doesn't replaces it.
+        options.windows_version = Some(WindowsVersion::Server2022);
+        let label = ImageLabel::new(&options, Some(&metadata)).unwrap();
+        assert_eq!(label.description, metadata.description);
+        options.windows_version = Some(WindowsVersion::Server2025);
+        let label = ImageLabel::new(&options, Some(&metadata)).unwrap();
+        assert_eq!(label.version, "2025");
+        assert_eq!(label.description, "Windows Server 2025");
+
+        options.image_description = Some("golden image".to_string());
+        let label = ImageLabel::new(&options, Some(&metadata)).unwrap();
+        assert_eq!(label.description, "golden image");
+    }
+
+    #[test]
+    fn upload_skips_zeroes_and_follows_import_workflow() {
+        let server = MockOxide::start();
+        let client = OxideClient::new(&format!("{}/", server.url), "tok", "p");
+
+        // Lay out an image with a chunk of data, a chunk of explicit zeroes, a
+        // hole, and a short chunk of data at the end.
+        let dir = scratch_dir("upload");
+        let path = dir.join("image.img");
+        let size = 5 * CHUNK_SIZE / 2;
+        let mut file = File::create(&path).unwrap();
+        file.write_all(&vec![0x5a; CHUNK_SIZE as usize]).unwrap();
+        file.write_all(&vec![0; CHUNK_SIZE as usize]).unwrap();
+        file.seek(SeekFrom::Start(2 * CHUNK_SIZE + 4096)).unwrap();
+        file.write_all(b"tail").unwrap();
+        file.set_len(size).unwrap();
+        drop(file);
+
+        client
+            .create_importing_disk("img", "desc", DISK_SIZE_ALIGNMENT.0)
+            .unwrap();
+        client.start_bulk_write("img").unwrap();
+        let summary =
+            write_image_blocks(&client, "img", &path, &NullUi).unwrap();
+        client.stop_bulk_write("img").unwrap();
+        client.finalize("img", "img").unwrap();
+        let snapshot_id = client.snapshot_id("img").unwrap();
+        let image_id = client
+            .create_image("img", "desc", "Windows Server", "2022", &snapshot_id)
+            .unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(image_id, "image-id");
+        assert_eq!(
+            summary,
+            WriteSummary { written: CHUNK_SIZE * 3 / 2, skipped: CHUNK_SIZE }
+        );
+
+        let requests = server.take_requests();
+        let calls: Vec<String> = requests
+            .iter()
+            .map(|r| format!("{} {}", r.method, r.path))
+            .collect();
+        assert_eq!(
+            calls,
+            [
+                "POST /v1/disks?project=p",
+                "POST /v1/disks/img/bulk-write-start?project=p",
+                "POST /v1/disks/img/bulk-write?project=p",
+                "POST /v1/disks/img/bulk-write?project=p",
+                "POST /v1/disks/img/bulk-write-stop?project=p",
+                "POST /v1/disks/img/finalize?project=p",
+                "GET /v1/snapshots/img?project=p",
+                "POST /v1/images?project=p",
+            ]
+        );
+
+        assert!(requests.iter().all(|r| r.authorization == "Bearer tok"));
+        assert_eq!(
+            requests[0].body["disk_source"],
+            json!({ "type": "importing_blocks", "block_size": 512 })
+        );
+        assert_eq!(requests[0].body["size"], DISK_SIZE_ALIGNMENT.0);
+
+        let decode = |r: &Request| {
+            base64::engine::general_purpose::STANDARD
+                .decode(r.body["base64_encoded_data"].as_str().unwrap())
+                .unwrap()
+        };
+        assert_eq!(requests[2].body["offset"], 0);
+        assert_eq!(decode(&requests[2]), vec![0x5a; CHUNK_SIZE as usize]);
+        assert_eq!(requests[3].body["offset"], 2 * CHUNK_SIZE);
+        let tail = decode(&requests[3]);
+        assert_eq!(tail.len() as u64, CHUNK_SIZE / 2);
+        assert_eq!(&tail[4096..4100], b"tail");
+
+        assert_eq!(requests[5].body, json!({ "snapshot_name": "img" }));
+        assert_eq!(
+            requests[7].body,
+            json!({
+                "name": "img",
+                "description": "desc",
+                "os": "Windows Server",
+                "version": "2022",
+                "source": { "type": "snapshot", "id": "snapshot-id" },
+            })
+        );
+    }
+
+    #[test]
+    fn failed_upload_deletes_importing_disk() {
+        use crate::autounattend::WindowsVersion;
+
+        let server =
+            MockOxide::start_failing(Some("/v1/disks/img/bulk-write?"));
+        let dir = scratch_dir("upload-fail");
+        let image = dir.join("image.raw");
+        std::fs::write(&image, vec![0x5a; CHUNK_SIZE as usize]).unwrap();
+
+        let script = UploadScript::new(UploadArgs {
+            work_dir: dir.clone(),
+            output_image: image,
+            options: UploadOptions {
+                oxide_host: server.url.clone(),
+                oxide_token: "tok".to_string(),
+                project: "p".to_string(),
+                image_name: "img".to_string(),
+                image_description: None,
+                windows_version: Some(WindowsVersion::Server2022),
+            },
+        });
+
+        let ctx = Context::new(script.initial_context());
+        let run_log =
+            crate::logs::RunLog::create(&dir.join("logs"), 1).unwrap();
+        let err = crate::ui::run_script(
+            Box::new(script),
+            ctx,
+            &run_log,
+            crate::ui::Mode::NonInteractive,
+        )
+        .unwrap_err();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(format!("{err:#}").contains("(500)"), "{err:#}");
+
+        let calls: Vec<String> = server
+            .take_requests()
+            .iter()
+            .map(|r| format!("{} {}", r.method, r.path))
+            .collect();
+        assert_eq!(
+            calls,
+            [
+                "POST /v1/disks?project=p",
+                "POST /v1/disks/img/bulk-write-start?project=p",
+                "POST /v1/disks/img/bulk-write?project=p",
+                "POST /v1/disks/img/bulk-write-stop?project=p",
+                "DELETE /v1/disks/img?project=p",
+            ]
+        );
+    }
+
+    #[test]
+    fn api_errors_include_message() {
+        let server = MockOxide::start();
+        let client = OxideClient::new(&server.url, "tok", "p");
+        let err = client.delete_disk("missing").unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "DELETE /v1/disks/missing failed (404): not found: disk \"missing\""
+        );
+    }
+}
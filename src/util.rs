@@ -7,43 +7,256 @@
 
 use std::{
     collections::BTreeSet,
-    io::Write,
-    process::{Command, Output},
+    io::{Read, Write},
+    process::{Child, Command, ExitStatus, Output, Stdio},
+    time::Duration,
 };
 
-use camino::Utf8PathBuf;
+use anyhow::Context as _;
+use camino::{Utf8Path, Utf8PathBuf};
 
-use crate::{runner::ScriptStep, ui::Ui};
+use crate::{
+    runner::{interrupt_requested, ScriptStep},
+    ui::{ProgressUnit, Ui},
+};
+
+/// How often to check whether a long-running child process has exited.
+const CHILD_POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// The size of the buffer used to copy files while reporting progress.
+const COPY_BUFFER_SIZE: usize = 1024 * 1024;
 
-/// Runs a `Command` and returns its output. Returns `Err` if the command's exit
-/// status indicates that it failed.
+/// The number of bytes at the end of a failed command's stderr to include in
+/// the error that reports the failure.
+const STDERR_TAIL_LEN: usize = 4096;
+
+/// Runs a `Command` and returns its exit status. Returns `Err` if the exit
+/// status indicates that the command failed.
 ///
-/// The process's stdout and stderr contents will be written to log files whose
-/// names are generated by the supplied `ui`.
+/// As with [`Command::output`], the process's stdin is closed. Its stdout and
+/// stderr contents and its exit status are written to log files created by the
+/// supplied `ui` as the output arrives; the output isn't otherwise kept. Use
+/// [`run_command_capture_output`] to get the output as well.
 pub fn run_command_check_status(
     cmd: &mut Command,
     ui: &dyn Ui,
+) -> anyhow::Result<ExitStatus> {
+    run_and_stream_output(cmd, Stdio::null(), ui, false, |_| {})
+        .map(|output| output.status)
+}
+
+/// Like [`run_command_check_status`], but also returns everything the process
+/// wrote to stdout and stderr. Only use this for commands whose output the
+/// caller needs, since the output is held in memory.
+pub fn run_command_capture_output(
+    cmd: &mut Command,
+    ui: &dyn Ui,
+) -> anyhow::Result<Output> {
+    run_and_stream_output(cmd, Stdio::null(), ui, true, |_| {})
+}
+
+/// Like [`run_command_check_status`], but also calls `on_line` with each line
+/// the process writes to stdout as soon as the line is complete. Carriage
+/// returns and backspaces also end lines so that callers can follow progress
+/// indicators that redraw a single line.
+pub fn run_command_with_line_callback(
+    cmd: &mut Command,
+    ui: &dyn Ui,
+    on_line: impl FnMut(&str),
+) -> anyhow::Result<ExitStatus> {
+    run_and_stream_output(cmd, Stdio::null(), ui, false, on_line)
+        .map(|output| output.status)
+}
+
+/// Like [`run_command_check_status`], but connects the process's stdin to
+/// `stdin` instead of closing it.
+pub fn run_command_with_stdin(
+    cmd: &mut Command,
+    stdin: Stdio,
+    ui: &dyn Ui,
+) -> anyhow::Result<ExitStatus> {
+    run_and_stream_output(cmd, stdin, ui, false, |_| {})
+        .map(|output| output.status)
+}
+
+/// Runs `cmd`, copying its output to log files as it arrives. If `capture` is
+/// set, the returned `Output` holds everything the process wrote; otherwise
+/// its `stdout` and `stderr` are empty.
+fn run_and_stream_output(
+    cmd: &mut Command,
+    stdin: Stdio,
+    ui: &dyn Ui,
+    capture: bool,
+    mut on_line: impl FnMut(&str),
 ) -> anyhow::Result<Output> {
     ui.set_substep(&format!("{} {:?}", "executing: ", cmd));
-    let output = cmd.output()?;
+    let mut logs = ui.child_logs(cmd)?;
+    let mut child = cmd
+        .stdin(stdin)
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .with_context(|| {
+            format!("spawning '{}'", cmd.get_program().to_string_lossy())
+        })?;
 
-    let process_name = cmd.get_program();
-    ui.child_stdout(&process_name.to_string_lossy())?
-        .write_all(&output.stdout)?;
-    ui.child_stderr(&process_name.to_string_lossy())?
-        .write_all(&output.stderr)?;
+    // Drain stderr on another thread so that neither pipe can fill up and
+    // block the process while this thread waits on the other one. Even when
+    // the output isn't captured, keep the end of stderr so that a failure can
+    // be reported with the process's last words.
+    let (stdout_limit, stderr_limit) =
+        if capture { (None, None) } else { (Some(0), Some(STDERR_TAIL_LEN)) };
+    let mut child_stdout = child.stdout.take().unwrap();
+    let mut child_stderr = child.stderr.take().unwrap();
+    let (stdout, stderr) = std::thread::scope(|s| {
+        let stderr_log = &mut logs.stderr;
+        let stderr = s.spawn(move || {
+            copy_output(&mut child_stderr, stderr_log, stderr_limit, |_| {})
+        });
+
+        let stdout = copy_output(
+            &mut child_stdout,
+            &mut logs.stdout,
+            stdout_limit,
+            &mut on_line,
+        );
+        (stdout, stderr.join().unwrap())
+    });
+
+    let status = child.wait()?;
+    logs.record_exit_status(status)?;
+    let (stdout, stderr) = (stdout?, stderr?);
+    if !status.success() {
+        let stderr = String::from_utf8_lossy(&stderr);
+        let mut tail = stderr.len().saturating_sub(STDERR_TAIL_LEN);
+        while !stderr.is_char_boundary(tail) {
+            tail += 1;
+        }
 
-    if !output.status.success() {
         anyhow::bail!(
-            "'{}' returned non-success exit code: {:?}",
+            "'{}' returned non-success exit code: {status}; stderr: {:?}",
             cmd.get_program().to_string_lossy(),
-            output
+            &stderr[tail..]
         );
     }
 
+    Ok(Output { status, stdout, stderr })
+}
+
+/// Waits for `child`, a long-running process such as a hypervisor, to exit and
+/// returns its exit status.
+///
+/// If the user interrupts the script while waiting, calls `stop` to ask the
+/// process to stop cleanly, waits up to `grace_period` for it to exit, kills it
+/// if it's still running, and returns an error. Errors from `stop` only matter
+/// if the process doesn't exit, since they're expected if it was already
+/// exiting. Spawn the process in its own process group so that a Ctrl-C in
+/// the terminal doesn't signal it before `stop` gets a chance to.
+pub fn wait_for_child_or_interrupt(
+    child: &mut Child,
+    grace_period: Duration,
+    stop: impl FnOnce(&mut Child) -> anyhow::Result<()>,
+) -> anyhow::Result<ExitStatus> {
+    while !interrupt_requested() {
+        if let Some(status) = child.try_wait()? {
+            return Ok(status);
+        }
+
+        std::thread::sleep(CHILD_POLL_INTERVAL);
+    }
+
+    let stopped = stop(child);
+    let deadline = std::time::Instant::now() + grace_period;
+    while std::time::Instant::now() < deadline {
+        if child.try_wait()?.is_some() {
+            anyhow::bail!("interrupted by user");
+        }
+
+        std::thread::sleep(CHILD_POLL_INTERVAL);
+    }
+
+    child.kill()?;
+    child.wait()?;
+    match stopped {
+        Ok(()) => anyhow::bail!(
+            "interrupted by user; killed process that didn't stop"
+        ),
+        Err(e) => anyhow::bail!(
+            "interrupted by user; killed process after failing to stop it: \
+            {e:#}"
+        ),
+    }
+}
+
+/// Copies everything `src` produces to `log` as it arrives, calling `on_line`
+/// with each complete line. Returns what was copied, or only its last `limit`
+/// bytes if there's a limit.
+fn copy_output(
+    src: &mut impl Read,
+    log: &mut std::fs::File,
+    limit: Option<usize>,
+    mut on_line: impl FnMut(&str),
+) -> std::io::Result<Vec<u8>> {
+    let mut output = Vec::new();
+    let mut line = Vec::new();
+    let mut buf = [0u8; 8192];
+    loop {
+        let read = src.read(&mut buf)?;
+        if read == 0 {
+            break;
+        }
+
+        log.write_all(&buf[..read])?;
+        output.extend_from_slice(&buf[..read]);
+        if let Some(limit) = limit {
+            let excess = output.len().saturating_sub(limit);
+            output.drain(..excess);
+        }
+
+        for &byte in &buf[..read] {
+            if matches!(byte, b'\n' | b'\r' | 0x08) {
+                if !line.is_empty() {
+                    on_line(&String::from_utf8_lossy(&line));
+                    line.clear();
+                }
+            } else {
+                line.push(byte);
+            }
+        }
+    }
+
+    if !line.is_empty() {
+        on_line(&String::from_utf8_lossy(&line));
+    }
+
     Ok(output)
 }
 
+/// Parses a progress line that 7-Zip prints when run with `-bsp1`, such as
+/// `" 42% 3 - sources/boot.wim"`, and returns the percentage it reports.
+pub fn parse_7z_progress(line: &str) -> Option<u32> {
+    let (percent, _) = line.trim_start().split_once('%')?;
+    percent.parse().ok()
+}
+
+/// Runs `cmd`, a 7-Zip command, with `-bsp1` added to its arguments and
+/// reports the progress it prints through `ui` as the progress of `action`.
+pub fn run_7z_with_progress(
+    cmd: &mut Command,
+    ui: &dyn Ui,
+    action: &str,
+) -> anyhow::Result<ExitStatus> {
+    ui.start_progress(action, 100, ProgressUnit::Percent);
+    let result = run_command_with_line_callback(cmd.arg("-bsp1"), ui, |line| {
+        if let Some(percent) = parse_7z_progress(line) {
+            ui.set_progress(percent.into());
+        }
+    });
+
+    ui.finish_progress();
+    result
+}
+
 /// Runs the supplied `cmd` and searches its `stdout` for the first line
 /// containing `row_contains`, then splits it by whitespace and returns the
 /// `column`th zero-indexed word from that line.
@@ -55,7 +268,7 @@ pub fn grep_command_for_row_and_column(
     column: usize,
     ui: &dyn Ui,
 ) -> anyhow::Result<String> {
-    let output = run_command_check_status(cmd, ui)?.stdout;
+    let output = run_command_capture_output(cmd, ui)?.stdout;
     let output = String::from_utf8_lossy(&output);
     for line in output.lines() {
         if !line.contains(row_contains) {
@@ -94,6 +307,155 @@ pub fn check_file_prerequisites(files: &[Utf8PathBuf]) -> Vec<String> {
     errors
 }
 
+/// Checks that `dir`, a directory of extra files to deliver to the guest, is a
+/// directory and that none of its top-level entries have the same name as one
+/// of the files in `reserved`. Names are compared case-insensitively because
+/// the guest sees them on a case-insensitive filesystem. Returns a `Vec` of
+/// strings describing any problems, or an empty `Vec` if there are none.
+pub fn check_extra_files_prerequisites(
+    dir: &Utf8Path,
+    reserved: &[&str],
+) -> Vec<String> {
+    if !dir.is_dir() {
+        return vec![format!("extra files directory '{dir}' not found")];
+    }
+
+    let entries = match dir.read_dir_utf8() {
+        Ok(entries) => entries,
+        Err(e) => return vec![format!("reading '{dir}': {e}")],
+    };
+
+    let mut errors = Vec::new();
+    for entry in entries {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                errors.push(format!("reading '{dir}': {e}"));
+                continue;
+            }
+        };
+
+        if reserved.iter().any(|r| r.eq_ignore_ascii_case(entry.file_name())) {
+            errors.push(format!(
+                "'{}' in the extra files directory would replace a file \
+                wimsy supplies to the guest",
+                entry.file_name()
+            ));
+        }
+    }
+
+    errors
+}
+
+/// Recursively copies the contents of the directory `src` into `dst`, creating
+/// `dst` and any subdirectories as needed. Fails instead of replacing any file
+/// that already exists in `dst`.
+pub fn copy_dir_contents(
+    src: &Utf8Path,
+    dst: &Utf8Path,
+    ui: &dyn Ui,
+) -> anyhow::Result<()> {
+    ui.start_progress(
+        &format!("copying files from {src}"),
+        dir_size(src)?,
+        ProgressUnit::Bytes,
+    );
+    let result = copy_dir_contents_inner(src, dst, ui);
+    ui.finish_progress();
+    result
+}
+
+/// Returns the total size of the files in `dir` and its subdirectories.
+fn dir_size(dir: &Utf8Path) -> anyhow::Result<u64> {
+    let mut size = 0;
+    for entry in
+        dir.read_dir_utf8().with_context(|| format!("reading '{dir}'"))?
+    {
+        let entry = entry.with_context(|| format!("reading '{dir}'"))?;
+        if entry.file_type()?.is_dir() {
+            size += dir_size(entry.path())?;
+        } else {
+            size += entry.metadata()?.len();
+        }
+    }
+
+    Ok(size)
+}
+
+fn copy_dir_contents_inner(
+    src: &Utf8Path,
+    dst: &Utf8Path,
+    ui: &dyn Ui,
+) -> anyhow::Result<()> {
+    std::fs::create_dir_all(dst)
+        .with_context(|| format!("creating directory '{dst}'"))?;
+
+    for entry in
+        src.read_dir_utf8().with_context(|| format!("reading '{src}'"))?
+    {
+        let entry = entry.with_context(|| format!("reading '{src}'"))?;
+        let src_path = entry.path();
+        let dst_path = dst.join(entry.file_name());
+        if entry.file_type()?.is_dir() {
+            copy_dir_contents_inner(src_path, &dst_path, ui)?;
+            continue;
+        }
+
+        if dst_path.exists() {
+            anyhow::bail!(
+                "copying '{src_path}' would replace existing file '{dst_path}'"
+            );
+        }
+
+        copy_file_contents(src_path, &dst_path, ui)
+            .with_context(|| format!("copying '{src_path}' to '{dst_path}'"))?;
+    }
+
+    Ok(())
+}
+
+/// Copies the file at `src` to `dst`, reporting the copy's progress through
+/// `ui`.
+pub fn copy_file_with_progress(
+    src: &Utf8Path,
+    dst: &Utf8Path,
+    ui: &dyn Ui,
+) -> anyhow::Result<()> {
+    let len = std::fs::metadata(src)
+        .with_context(|| format!("reading '{src}'"))?
+        .len();
+    ui.start_progress(&format!("copying {src}"), len, ProgressUnit::Bytes);
+    let result = copy_file_contents(src, dst, ui)
+        .with_context(|| format!("copying '{src}' to '{dst}'"));
+    ui.finish_progress();
+    result
+}
+
+/// Copies the file at `src` to `dst` in chunks, adding the size of each chunk
+/// to the progress `ui` is reporting.
+fn copy_file_contents(
+    src: &Utf8Path,
+    dst: &Utf8Path,
+    ui: &dyn Ui,
+) -> anyhow::Result<()> {
+    let mut src_file = std::fs::File::open(src)?;
+    let mut dst_file = std::fs::File::create(dst)?;
+    let mut buf = vec![0u8; COPY_BUFFER_SIZE];
+    loop {
+        let read = src_file.read(&mut buf)?;
+        if read == 0 {
+            break;
+        }
+
+        dst_file.write_all(&buf[..read])?;
+        ui.inc_progress(read as u64);
+    }
+
+    // Match `std::fs::copy`, which also copies permissions.
+    dst_file.set_permissions(src_file.metadata()?.permissions())?;
+    Ok(())
+}
+
 pub fn check_executable_prerequisites(steps: &[ScriptStep]) -> Vec<String> {
     let mut errors = Vec::new();
     let mut executables = BTreeSet::new();
@@ -114,3 +476,122 @@ pub fn check_executable_prerequisites(steps: &[ScriptStep]) -> Vec<String> {
 
     errors
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::test_support::{scratch_dir, NullUi};
+    use itertools::Itertools;
+
+    /// A `Ui` that writes child process logs to a directory.
+    struct DirUi(Utf8PathBuf);
+
+    impl Ui for DirUi {
+        fn set_substep(&self, _substep: &str) {}
+
+        fn child_logs(
+            &self,
+            _: &std::process::Command,
+        ) -> anyhow::Result<crate::ui::ChildLogs> {
+            Ok(crate::ui::ChildLogs {
+                stdout: std::fs::File::create(self.0.join("stdout.log"))?,
+                stderr: std::fs::File::create(self.0.join("stderr.log"))?,
+            })
+        }
+    }
+
+    #[test]
+    fn stream_command_output() {
+        let dir = scratch_dir("stream-output");
+        let ui = DirUi(dir.clone());
+
+        let mut lines = Vec::new();
+        run_command_with_line_callback(
+            Command::new("sh").args([
+                "-c",
+                "printf ' 5%% 1\\r 50%% 2\\nlast'; echo oops >&2",
+            ]),
+            &ui,
+            |line| lines.push(line.to_owned()),
+        )
+        .unwrap();
+
+        assert_eq!(lines, [" 5% 1", " 50% 2", "last"]);
+        assert_eq!(
+            lines.iter().filter_map(|l| parse_7z_progress(l)).collect_vec(),
+            [5, 50]
+        );
+
+        let stderr = std::fs::read_to_string(dir.join("stderr.log")).unwrap();
+        assert!(stderr.starts_with("oops\n"));
+        assert!(stderr.contains("# exit status: 0"));
+
+        let output = run_command_capture_output(
+            Command::new("sh").args(["-c", "echo out; echo oops >&2"]),
+            &ui,
+        )
+        .unwrap();
+        assert_eq!(output.stdout, b"out\n");
+        assert_eq!(output.stderr, b"oops\n");
+
+        // Failures report the end of stderr even when output isn't captured.
+        let err = run_command_check_status(
+            Command::new("sh").args([
+                "-c",
+                "head -c 10000 /dev/zero | tr '\\0' x >&2; echo fatal >&2; \
+                exit 3",
+            ]),
+            &ui,
+        )
+        .unwrap_err()
+        .to_string();
+        assert!(err.contains("fatal"), "{err}");
+        assert!(err.len() < 2 * STDERR_TAIL_LEN, "{err}");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn copy_extra_files() {
+        let dir = scratch_dir("copy-extra-files");
+        let src = dir.join("src");
+        std::fs::create_dir_all(src.join("hooks")).unwrap();
+        std::fs::write(src.join("agent.msi"), "agent").unwrap();
+        std::fs::write(src.join("hooks/10-agent.ps1"), "hook").unwrap();
+
+        let dst = dir.join("dst");
+        copy_dir_contents(&src, &dst, &NullUi).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(dst.join("agent.msi")).unwrap(),
+            "agent"
+        );
+        assert_eq!(
+            std::fs::read_to_string(dst.join("hooks/10-agent.ps1")).unwrap(),
+            "hook"
+        );
+
+        // Copying again would replace the files that are now in `dst`.
+        assert!(copy_dir_contents(&src, &dst, &NullUi).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn extra_files_cant_replace_reserved_files() {
+        let dir = scratch_dir("reserved-extra-files");
+        assert!(check_extra_files_prerequisites(&dir, &["prep.cmd"]).is_empty());
+
+        std::fs::write(dir.join("PREP.CMD"), "").unwrap();
+        assert_eq!(
+            check_extra_files_prerequisites(&dir, &["prep.cmd"]).len(),
+            1
+        );
+
+        assert_eq!(
+            check_extra_files_prerequisites(&dir.join("missing"), &[]).len(),
+            1
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,737 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Functions for checking answer files for mistakes that Windows Setup would
+//! otherwise only report once it's running in the installation VM.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use anyhow::{Context, Result};
+use camino::Utf8Path;
+use xml::common::Position;
+
+use crate::autounattend::{new_reader, UNATTEND_NAMESPACE, WCM_NAMESPACE};
+
+/// The answer files in an unattend directory that this module knows how to
+/// validate.
+pub const VALIDATED_FILES: &[&str] =
+    &["Autounattend.xml", "specialize-unattend.xml"];
+
+/// The configuration passes Windows Setup runs, in the order in which it runs
+/// them.
+const PASSES: &[&str] = &[
+    "windowsPE",
+    "offlineServicing",
+    "generalize",
+    "specialize",
+    "auditSystem",
+    "auditUser",
+    "oobeSystem",
+];
+
+/// The components this tool knows about and the passes in which each of them
+/// can be configured. Windows has many more components than these, so other
+/// component names are only reported as warnings.
+const COMPONENTS: &[(&str, &[&str])] = &[
+    (
+        "Microsoft-Windows-Deployment",
+        &["generalize", "specialize", "auditSystem", "auditUser", "oobeSystem"],
+    ),
+    ("Microsoft-Windows-DNS-Client", &["windowsPE", "specialize"]),
+    ("Microsoft-Windows-IE-ESC", &["specialize"]),
+    (
+        "Microsoft-Windows-International-Core",
+        &["specialize", "auditSystem", "auditUser", "oobeSystem"],
+    ),
+    ("Microsoft-Windows-International-Core-WinPE", &["windowsPE"]),
+    ("Microsoft-Windows-LUA-Settings", &["offlineServicing"]),
+    ("Microsoft-Windows-Networking-MPSSVC-Svc", &["specialize"]),
+    (
+        "Microsoft-Windows-PnpCustomizationsNonWinPE",
+        &["offlineServicing", "auditSystem"],
+    ),
+    ("Microsoft-Windows-PnpCustomizationsWinPE", &["windowsPE"]),
+    ("Microsoft-Windows-PnpSysprep", &["generalize"]),
+    ("Microsoft-Windows-Security-SPP", &["generalize", "specialize"]),
+    ("Microsoft-Windows-Security-SPP-UX", &["specialize"]),
+    ("Microsoft-Windows-ServerManager-SvrMgrNc", &["specialize"]),
+    ("Microsoft-Windows-Setup", &["windowsPE"]),
+    (
+        "Microsoft-Windows-Shell-Setup",
+        &[
+            "offlineServicing",
+            "specialize",
+            "auditSystem",
+            "auditUser",
+            "oobeSystem",
+        ],
+    ),
+    ("Microsoft-Windows-TCPIP", &["windowsPE", "specialize"]),
+    (
+        "Microsoft-Windows-TerminalServices-LocalSessionManager",
+        &["offlineServicing", "generalize", "specialize"],
+    ),
+    (
+        "Microsoft-Windows-TerminalServices-RDP-WinStationExtensions",
+        &["generalize", "specialize"],
+    ),
+    ("Microsoft-Windows-UnattendedJoin", &["specialize"]),
+];
+
+/// The attributes every `component` element must have.
+const COMPONENT_ATTRIBUTES: &[&str] =
+    &["processorArchitecture", "publicKeyToken", "language", "versionScope"];
+
+/// The problems found in one or more answer files.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Problems {
+    /// Mistakes that would make Windows Setup fail.
+    pub errors: Vec<String>,
+
+    /// Possible mistakes that might also be intended, e.g. components this
+    /// module doesn't know about.
+    pub warnings: Vec<String>,
+}
+
+impl Problems {
+    fn extend(&mut self, other: Problems) {
+        self.errors.extend(other.errors);
+        self.warnings.extend(other.warnings);
+    }
+}
+
+/// An element in a parsed answer file.
+struct Element {
+    name: xml::name::OwnedName,
+    attributes: Vec<xml::attribute::OwnedAttribute>,
+    children: Vec<Element>,
+    text: String,
+    line: u64,
+}
+
+impl Element {
+    /// Returns the value of the attribute with the supplied local name.
+    fn attr(&self, name: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|a| a.name.local_name == name)
+            .map(|a| a.value.as_str())
+    }
+
+    fn children_named<'a>(
+        &'a self,
+        name: &'a str,
+    ) -> impl Iterator<Item = &'a Element> + 'a {
+        self.children.iter().filter(move |c| c.name.local_name == name)
+    }
+
+    fn child(&self, name: &str) -> Option<&Element> {
+        self.children.iter().find(|c| c.name.local_name == name)
+    }
+
+    /// Returns the trimmed text of the first child with the supplied name.
+    fn child_text(&self, name: &str) -> Option<&str> {
+        self.child(name).map(|c| c.text.trim())
+    }
+
+    /// Visits this element and all of its descendants in document order.
+    fn walk<'a>(&'a self, f: &mut impl FnMut(&'a Element)) {
+        f(self);
+        for child in &self.children {
+            child.walk(f);
+        }
+    }
+}
+
+/// Parses an answer file into a tree of elements.
+fn parse(contents: &str) -> Result<Element> {
+    use xml::reader::XmlEvent;
+
+    let mut reader = new_reader(contents.as_bytes());
+    let mut open: Vec<Element> = Vec::new();
+    loop {
+        let event = reader.next()?;
+        let line = reader.position().row + 1;
+        match event {
+            XmlEvent::StartElement { name, attributes, .. } => {
+                open.push(Element {
+                    name,
+                    attributes,
+                    children: Vec::new(),
+                    text: String::new(),
+                    line,
+                });
+            }
+            XmlEvent::EndElement { .. } => {
+                let element = open.pop().expect("parser balances elements");
+                match open.last_mut() {
+                    Some(parent) => parent.children.push(element),
+                    None => return Ok(element),
+                }
+            }
+            XmlEvent::Characters(data) | XmlEvent::CData(data) => {
+                if let Some(element) = open.last_mut() {
+                    element.text.push_str(&data);
+                }
+            }
+            XmlEvent::EndDocument => anyhow::bail!("document has no elements"),
+            _ => {}
+        }
+    }
+}
+
+/// Checks the answer file at `path` for problems, returning descriptions of
+/// the problems found, each prefixed by the file's name.
+pub fn validate_answer_file(path: &Utf8Path) -> Problems {
+    let file_name = path.file_name().unwrap_or(path.as_str());
+    let problems = match std::fs::read_to_string(path)
+        .with_context(|| format!("reading {path}"))
+    {
+        Ok(contents) => validate_answer_file_contents(&contents),
+        Err(e) => {
+            Problems { errors: vec![format!("{e:#}")], ..Default::default() }
+        }
+    };
+
+    let prefix = |problems: Vec<String>| -> Vec<String> {
+        problems.into_iter().map(|p| format!("{file_name}: {p}")).collect()
+    };
+
+    Problems {
+        errors: prefix(problems.errors),
+        warnings: prefix(problems.warnings),
+    }
+}
+
+/// Checks the answer file whose text is `contents` for problems, returning
+/// descriptions of the problems found.
+pub fn validate_answer_file_contents(contents: &str) -> Problems {
+    let root = match parse(contents) {
+        Ok(root) => root,
+        Err(e) => {
+            return Problems {
+                errors: vec![format!("not a well-formed XML file: {e:#}")],
+                ..Default::default()
+            }
+        }
+    };
+
+    let mut problems = Problems::default();
+    check_structure(&root, &mut problems.errors, &mut problems.warnings);
+    check_wcm_attributes(&root, &mut problems.errors);
+    check_list_keys(&root, &mut problems.errors);
+    check_disk_configuration(&root, &mut problems.errors);
+    problems
+}
+
+/// Checks that the document has an `unattend` root whose children are
+/// `settings` elements for known passes, and that each known component in a
+/// pass can be configured in that pass. Unknown components are reported in
+/// `warnings`.
+fn check_structure(
+    root: &Element,
+    problems: &mut Vec<String>,
+    warnings: &mut Vec<String>,
+) {
+    if root.name.local_name != "unattend"
+        || root.name.namespace.as_deref() != Some(UNATTEND_NAMESPACE)
+    {
+        problems.push(format!(
+            "line {}: root element must be 'unattend' in namespace '{}'",
+            root.line, UNATTEND_NAMESPACE
+        ));
+        return;
+    }
+
+    let mut passes = BTreeSet::new();
+    for settings in &root.children {
+        if settings.name.local_name != "settings" {
+            problems.push(format!(
+                "line {}: unexpected element '{}' (only 'settings' elements \
+                may appear under 'unattend')",
+                settings.line, settings.name.local_name
+            ));
+            continue;
+        }
+
+        let Some(pass) = settings.attr("pass") else {
+            problems.push(format!(
+                "line {}: 'settings' element has no 'pass' attribute",
+                settings.line
+            ));
+            continue;
+        };
+
+        if !PASSES.contains(&pass) {
+            problems.push(format!(
+                "line {}: unknown configuration pass '{pass}' (expected one \
+                of {})",
+                settings.line,
+                PASSES.join(", ")
+            ));
+            continue;
+        }
+
+        if !passes.insert(pass) {
+            problems.push(format!(
+                "line {}: configuration pass '{pass}' appears more than once",
+                settings.line
+            ));
+        }
+
+        let mut components = BTreeSet::new();
+        for component in &settings.children {
+            if component.name.local_name != "component" {
+                problems.push(format!(
+                    "line {}: unexpected element '{}' in pass '{pass}' (only \
+                    'component' elements may appear under 'settings')",
+                    component.line, component.name.local_name
+                ));
+                continue;
+            }
+
+            let Some(name) = component.attr("name") else {
+                problems.push(format!(
+                    "line {}: 'component' element has no 'name' attribute",
+                    component.line
+                ));
+                continue;
+            };
+
+            for attr in COMPONENT_ATTRIBUTES {
+                if component.attr(attr).is_none() {
+                    problems.push(format!(
+                        "line {}: component '{name}' has no '{attr}' \
+                        attribute",
+                        component.line
+                    ));
+                }
+            }
+
+            if !components.insert(name) {
+                problems.push(format!(
+                    "line {}: component '{name}' appears more than once in \
+                    pass '{pass}'",
+                    component.line
+                ));
+            }
+
+            match COMPONENTS.iter().find(|(known, _)| *known == name) {
+                None => warnings.push(format!(
+                    "line {}: unknown component '{name}' (is it misspelled?)",
+                    component.line
+                )),
+                Some((_, valid_passes)) if !valid_passes.contains(&pass) => {
+                    problems.push(format!(
+                        "line {}: component '{name}' can't be configured in \
+                        pass '{pass}' (valid passes: {})",
+                        component.line,
+                        valid_passes.join(", ")
+                    ))
+                }
+                Some(_) => {}
+            }
+        }
+    }
+}
+
+/// Checks that every list-manipulation attribute (`action` and `keyValue`) is
+/// in the `wcm` namespace and that every `action` is one Setup understands.
+fn check_wcm_attributes(root: &Element, problems: &mut Vec<String>) {
+    root.walk(&mut |element| {
+        for attr in &element.attributes {
+            let name = attr.name.local_name.as_str();
+            if !matches!(name, "action" | "keyValue") {
+                continue;
+            }
+
+            if attr.name.namespace.as_deref() != Some(WCM_NAMESPACE) {
+                problems.push(format!(
+                    "line {}: attribute '{name}' on '{}' must be in the \
+                    '{WCM_NAMESPACE}' namespace (e.g. 'wcm:{name}', with \
+                    xmlns:wcm declared on an enclosing element)",
+                    element.line, element.name.local_name
+                ));
+            }
+
+            if name == "action"
+                && !matches!(attr.value.as_str(), "add" | "modify" | "remove")
+            {
+                problems.push(format!(
+                    "line {}: unknown action '{}' on '{}' (expected add, \
+                    modify, or remove)",
+                    element.line, attr.value, element.name.local_name
+                ));
+            }
+        }
+    });
+}
+
+/// Checks that the items in each list are uniquely identified: every `Order`
+/// among sibling list items must be a distinct positive integer, and every
+/// `wcm:keyValue` among siblings must be distinct. Entries in `DriverPaths`
+/// must also have a `keyValue`.
+fn check_list_keys(root: &Element, problems: &mut Vec<String>) {
+    root.walk(&mut |element| {
+        let mut orders = BTreeMap::new();
+        let mut keys = BTreeMap::new();
+        for child in &element.children {
+            if let Some(order) = child.child_text("Order") {
+                match order.parse::<u32>() {
+                    Ok(0) | Err(_) => problems.push(format!(
+                        "line {}: '{}' has invalid Order '{order}' (must be a \
+                        positive integer)",
+                        child.line, child.name.local_name
+                    )),
+                    Ok(order) => {
+                        if let Some(first) = orders.insert(order, child.line) {
+                            problems.push(format!(
+                                "line {}: Order {order} in '{}' duplicates \
+                                the Order on line {first}",
+                                child.line, element.name.local_name
+                            ));
+                        }
+                    }
+                }
+            }
+
+            match child.attr("keyValue") {
+                Some(key) => {
+                    if let Some(first) = keys.insert(key, child.line) {
+                        problems.push(format!(
+                            "line {}: keyValue '{key}' in '{}' duplicates the \
+                            keyValue on line {first}",
+                            child.line, element.name.local_name
+                        ));
+                    }
+                }
+                None if element.name.local_name == "DriverPaths" => {
+                    problems.push(format!(
+                        "line {}: '{}' in DriverPaths has no keyValue",
+                        child.line, child.name.local_name
+                    ));
+                }
+                None => {}
+            }
+        }
+    });
+}
+
+/// Checks that the partitions Setup is told to create, modify, and install to
+/// are consistent with one another.
+fn check_disk_configuration(root: &Element, problems: &mut Vec<String>) {
+    let Some(setup) = root
+        .children_named("settings")
+        .filter(|s| s.attr("pass") == Some("windowsPE"))
+        .flat_map(|s| s.children_named("component"))
+        .find(|c| c.attr("name") == Some("Microsoft-Windows-Setup"))
+    else {
+        return;
+    };
+
+    // Maps from disk IDs to the number of partitions created on each disk.
+    let mut disks = BTreeMap::new();
+    for disk in setup
+        .children_named("DiskConfiguration")
+        .flat_map(|d| d.children_named("Disk"))
+    {
+        let Some(disk_id) = disk.child_text("DiskID") else {
+            problems.push(format!("line {}: Disk has no DiskID", disk.line));
+            continue;
+        };
+
+        let mut partitions: Vec<&Element> = disk
+            .children_named("CreatePartitions")
+            .flat_map(|c| c.children_named("CreatePartition"))
+            .collect();
+
+        partitions.sort_by_key(|p| {
+            p.child_text("Order").and_then(|o| o.parse::<u32>().ok())
+        });
+
+        for (index, partition) in partitions.iter().enumerate() {
+            let extend = partition
+                .child_text("Extend")
+                .is_some_and(|e| e.eq_ignore_ascii_case("true"));
+            let size = partition.child_text("Size");
+            if extend && size.is_some() {
+                problems.push(format!(
+                    "line {}: partition specifies both Size and Extend",
+                    partition.line
+                ));
+            } else if !extend && size.is_none() {
+                problems.push(format!(
+                    "line {}: partition specifies neither Size nor Extend",
+                    partition.line
+                ));
+            } else if extend && index != partitions.len() - 1 {
+                problems.push(format!(
+                    "line {}: only the last partition on a disk can have \
+                    Extend set",
+                    partition.line
+                ));
+            }
+        }
+
+        let count = partitions.len();
+        for modify in disk
+            .children_named("ModifyPartitions")
+            .flat_map(|m| m.children_named("ModifyPartition"))
+        {
+            check_partition_id(
+                modify,
+                disk_id,
+                count,
+                "ModifyPartition",
+                problems,
+            );
+        }
+
+        disks.insert(disk_id, count);
+    }
+
+    for install_to in setup
+        .children_named("ImageInstall")
+        .flat_map(|i| i.children_named("OSImage"))
+        .flat_map(|o| o.children_named("InstallTo"))
+    {
+        let Some(disk_id) = install_to.child_text("DiskID") else {
+            problems.push(format!(
+                "line {}: InstallTo has no DiskID",
+                install_to.line
+            ));
+            continue;
+        };
+
+        // Setup can install to a disk that this answer file doesn't
+        // partition, so only check installations to disks that it does.
+        if let Some(count) = disks.get(disk_id) {
+            check_partition_id(
+                install_to,
+                disk_id,
+                *count,
+                "InstallTo",
+                problems,
+            );
+        }
+    }
+}
+
+/// Checks that `element`'s `PartitionID` refers to one of the `count`
+/// partitions created on disk `disk_id`.
+fn check_partition_id(
+    element: &Element,
+    disk_id: &str,
+    count: usize,
+    what: &str,
+    problems: &mut Vec<String>,
+) {
+    let Some(id) = element.child_text("PartitionID") else {
+        problems
+            .push(format!("line {}: {what} has no PartitionID", element.line));
+        return;
+    };
+
+    match id.parse::<usize>() {
+        Ok(id) if (1..=count).contains(&id) => {}
+        _ => problems.push(format!(
+            "line {}: {what} refers to PartitionID {id}, but disk {disk_id} \
+            only has {count} partition(s) in CreatePartitions",
+            element.line
+        )),
+    }
+}
+
+/// Validates each of the [`VALIDATED_FILES`] that exists in `unattend_dir`,
+/// returning descriptions of any problems found. Files that don't exist are
+/// skipped.
+pub fn validate_unattend_dir(unattend_dir: &Utf8Path) -> Problems {
+    let mut problems = Problems::default();
+    for path in VALIDATED_FILES.iter().map(|file| unattend_dir.join(file)) {
+        if path.exists() {
+            problems.extend(validate_answer_file(&path));
+        }
+    }
+
+    problems
+}
+
+/// Implements the `validate-unattend` command: validates the answer files in
+/// `unattend_dir` and prints the results.
+pub fn run_validate_unattend(unattend_dir: &Utf8Path) -> Result<()> {
+    use colored::Colorize;
+
+    let files: Vec<_> = VALIDATED_FILES
+        .iter()
+        .filter(|file| unattend_dir.join(file).exists())
+        .collect();
+
+    if files.is_empty() {
+        anyhow::bail!(
+            "none of {} found in {unattend_dir}",
+            VALIDATED_FILES.join(", ")
+        );
+    }
+
+    let Problems { errors, warnings } = validate_unattend_dir(unattend_dir);
+    if !warnings.is_empty() {
+        println!("{}", "Warnings:".yellow().bold());
+        for warning in &warnings {
+            println!("  {}", warning);
+        }
+
+        println!();
+    }
+
+    if errors.is_empty() {
+        for file in files {
+            println!("{}: {}", file, "OK".green());
+        }
+
+        return Ok(());
+    }
+
+    println!("{}", "Some answer files have problems:".bold());
+    for problem in &errors {
+        println!("  {}", problem);
+    }
+
+    println!();
+    anyhow::bail!("found {} problem(s) in answer files", errors.len());
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const ILLUMOS_UNATTEND: &str = include_str!("../illumos/Autounattend.xml");
+    const LINUX_UNATTEND: &str = include_str!("../unattend/Autounattend.xml");
+    const SPECIALIZE_UNATTEND: &str =
+        include_str!("../unattend/specialize-unattend.xml");
+
+    /// Validates `doc`, asserting that exactly one error and no warnings are
+    /// found and that the error's description contains `expected`.
+    fn assert_one_problem(doc: &str, expected: &str) {
+        let problems = validate_answer_file_contents(doc);
+        assert!(problems.warnings.is_empty(), "warnings: {problems:#?}");
+        let errors = problems.errors;
+        assert_eq!(errors.len(), 1, "unexpected errors: {errors:#?}");
+        assert!(
+            errors[0].contains(expected),
+            "'{}' doesn't mention '{expected}'",
+            errors[0]
+        );
+    }
+
+    #[test]
+    fn shipped_answer_files_are_valid() {
+        for doc in [ILLUMOS_UNATTEND, LINUX_UNATTEND, SPECIALIZE_UNATTEND] {
+            assert_eq!(validate_answer_file_contents(doc), Problems::default());
+        }
+    }
+
+    #[test]
+    fn misspelled_names() {
+        assert_one_problem(
+            &LINUX_UNATTEND.replace("\"oobeSystem\"", "\"oobe\""),
+            "unknown configuration pass 'oobe'",
+        );
+
+        assert_one_problem(
+            &SPECIALIZE_UNATTEND.replace("\"specialize\"", "\"windowsPE\""),
+            "can't be configured in pass 'windowsPE'",
+        );
+    }
+
+    #[test]
+    fn unknown_components_are_warnings() {
+        let problems = validate_answer_file_contents(&LINUX_UNATTEND.replace(
+            "Microsoft-Windows-PnpCustomizationsNonWinPE",
+            "Microsoft-Windows-PnPCustomizationsNonWinPE",
+        ));
+
+        assert!(problems.errors.is_empty(), "errors: {problems:#?}");
+        assert_eq!(problems.warnings.len(), 1);
+        assert!(problems.warnings[0].contains("unknown component"));
+
+        // Components this module doesn't know about are allowed in any pass.
+        let problems = validate_answer_file_contents(&LINUX_UNATTEND.replace(
+            "Microsoft-Windows-PnpCustomizationsNonWinPE",
+            "Microsoft-Windows-SystemRestore-Main",
+        ));
+
+        assert!(problems.errors.is_empty(), "errors: {problems:#?}");
+        assert_eq!(problems.warnings.len(), 1);
+    }
+
+    #[test]
+    fn missing_wcm_namespace() {
+        let problems = validate_answer_file_contents(&LINUX_UNATTEND.replace(
+            "xmlns:wcm=\"http://schemas.microsoft.com/WMIConfig/2002/State\"",
+            "",
+        ));
+
+        assert_eq!(problems.errors.len(), 1);
+        assert!(problems.errors[0].contains("not a well-formed XML file"));
+
+        assert_one_problem(
+            &LINUX_UNATTEND
+                .replace("<Disk wcm:action=\"add\">", "<Disk action=\"add\">"),
+            "must be in the",
+        );
+    }
+
+    #[test]
+    fn duplicate_list_keys() {
+        assert_one_problem(
+            &SPECIALIZE_UNATTEND
+                .replace("<Order>3</Order>", "<Order>1</Order>"),
+            "Order 1 in 'RunSynchronous' duplicates the Order on line 15",
+        );
+
+        assert_one_problem(
+            &LINUX_UNATTEND.replace("keyValue=\"5\"", "keyValue=\"2\""),
+            "keyValue '2' in 'DriverPaths' duplicates",
+        );
+
+        assert_one_problem(
+            &LINUX_UNATTEND.replace(" wcm:keyValue=\"5\"", ""),
+            "in DriverPaths has no keyValue",
+        );
+    }
+
+    #[test]
+    fn inconsistent_partitions() {
+        let bad_install_to = LINUX_UNATTEND.replace(
+            "<DiskID>0</DiskID>\n                        \
+            <PartitionID>4</PartitionID>",
+            "<DiskID>0</DiskID>\n                        \
+            <PartitionID>5</PartitionID>",
+        );
+
+        assert_ne!(bad_install_to, LINUX_UNATTEND);
+        assert_one_problem(
+            &bad_install_to,
+            "InstallTo refers to PartitionID 5, but disk 0 only has 4",
+        );
+
+        assert_one_problem(
+            &LINUX_UNATTEND.replace(
+                "<PartitionID>3</PartitionID>",
+                "<PartitionID>7</PartitionID>",
+            ),
+            "ModifyPartition refers to PartitionID 7",
+        );
+
+        assert_one_problem(
+            &LINUX_UNATTEND.replace(
+                "<Order>4</Order>\n                            \
+                <Extend>true</Extend>",
+                "<Order>4</Order>\n                            \
+                <Extend>true</Extend><Size>100</Size>",
+            ),
+            "both Size and Extend",
+        );
+    }
+}
@@ -0,0 +1,913 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Writes small FAT12 filesystem images with VFAT long file names, and looks
+//! up files in existing FAT filesystems.
+//!
+//! This is just enough of a FAT implementation to produce configuration drives:
+//! every file goes in the root directory, is stored in contiguous clusters, and
+//! gets a long file name entry if its name isn't a valid 8.3 name. Timestamps
+//! are fixed so that the same files always produce the same image.
+//!
+//! The reader handles FAT12, FAT16, and FAT32, which is enough to check the
+//! contents of the EFI system partition in a Windows image.
+
+use std::io::{Read, Seek, SeekFrom};
+
+use anyhow::{Context as _, Result};
+
+const SECTOR_SIZE: usize = 512;
+const DIR_ENTRY_SIZE: usize = 32;
+const ROOT_DIR_ENTRIES: usize = 512;
+const RESERVED_SECTORS: usize = 1;
+const NUM_FATS: usize = 2;
+const MEDIA_DESCRIPTOR: u8 = 0xf8;
+
+/// The largest number of clusters a FAT12 filesystem may have.
+const MAX_FAT12_CLUSTERS: usize = 4084;
+
+/// The smallest image to produce. Some tools refuse to mount FAT filesystems
+/// that are much smaller than a floppy disk.
+const MIN_IMAGE_SECTORS: usize = 2048;
+
+/// The DOS date 1980-01-01, the earliest date a directory entry can hold.
+const FIXED_DATE: u16 = (1 << 5) | 1;
+
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+const ATTR_LONG_NAME: u8 = 0x0f;
+
+/// The number of UCS-2 characters in each long file name entry.
+const LFN_CHARS_PER_ENTRY: usize = 13;
+
+/// A file to store in the root directory of a FAT image.
+pub struct FatFile<'a> {
+    pub name: &'a str,
+    pub contents: &'a [u8],
+}
+
+/// The layout of a FAT12 filesystem.
+#[derive(Debug, PartialEq, Eq)]
+struct Layout {
+    total_sectors: usize,
+    sectors_per_cluster: usize,
+    fat_sectors: usize,
+    clusters: usize,
+}
+
+impl Layout {
+    fn cluster_size(&self) -> usize {
+        self.sectors_per_cluster * SECTOR_SIZE
+    }
+
+    fn root_dir_offset(&self) -> usize {
+        (RESERVED_SECTORS + NUM_FATS * self.fat_sectors) * SECTOR_SIZE
+    }
+
+    fn data_offset(&self) -> usize {
+        self.root_dir_offset() + ROOT_DIR_ENTRIES * DIR_ENTRY_SIZE
+    }
+
+    /// Returns the smallest layout (of at least [`MIN_IMAGE_SECTORS`]) whose
+    /// data area can hold `data_clusters` clusters of `sectors_per_cluster`
+    /// sectors each, or `None` if FAT12 can't address that many clusters.
+    fn for_data(
+        data_clusters: usize,
+        sectors_per_cluster: usize,
+    ) -> Option<Self> {
+        let root_dir_sectors = ROOT_DIR_ENTRIES * DIR_ENTRY_SIZE / SECTOR_SIZE;
+        let mut fat_sectors = 1;
+        loop {
+            let total_sectors = (RESERVED_SECTORS
+                + NUM_FATS * fat_sectors
+                + root_dir_sectors
+                + data_clusters * sectors_per_cluster)
+                .max(MIN_IMAGE_SECTORS);
+
+            let clusters = (total_sectors
+                - RESERVED_SECTORS
+                - NUM_FATS * fat_sectors
+                - root_dir_sectors)
+                / sectors_per_cluster;
+
+            if clusters > MAX_FAT12_CLUSTERS {
+                return None;
+            }
+
+            // Each FAT12 entry takes a byte and a half, and the first two
+            // entries are reserved.
+            let needed = ((clusters + 2) * 3).div_ceil(2).div_ceil(SECTOR_SIZE);
+            if needed <= fat_sectors {
+                return Some(Self {
+                    total_sectors,
+                    sectors_per_cluster,
+                    fat_sectors,
+                    clusters,
+                });
+            }
+
+            fat_sectors = needed;
+        }
+    }
+}
+
+/// Builds a FAT12 image with the volume label `label` whose root directory
+/// contains `files`. `serial` is the volume serial number.
+pub fn build_image(
+    label: &str,
+    serial: u32,
+    files: &[FatFile],
+) -> Result<Vec<u8>> {
+    let label = short_label(label)?;
+    let names = short_names(files)?;
+
+    let entries: usize = 1 + files
+        .iter()
+        .zip(&names)
+        .map(|(file, short)| 1 + lfn_entry_count(file.name, short))
+        .sum::<usize>();
+    if entries > ROOT_DIR_ENTRIES {
+        anyhow::bail!("too many files for a FAT12 root directory");
+    }
+
+    let layout = [1, 2, 4, 8, 16, 32, 64, 128]
+        .into_iter()
+        .find_map(|sectors_per_cluster| {
+            let cluster_size = sectors_per_cluster * SECTOR_SIZE;
+            let clusters = files
+                .iter()
+                .map(|f| f.contents.len().div_ceil(cluster_size))
+                .sum();
+            Layout::for_data(clusters, sectors_per_cluster)
+        })
+        .ok_or_else(|| anyhow::anyhow!("files are too large for FAT12"))?;
+
+    let mut image = vec![0u8; layout.total_sectors * SECTOR_SIZE];
+    write_boot_sector(&mut image, &layout, &label, serial);
+
+    let mut fat = vec![0u8; layout.fat_sectors * SECTOR_SIZE];
+    set_fat12_entry(&mut fat, 0, 0xf00 | u16::from(MEDIA_DESCRIPTOR));
+    set_fat12_entry(&mut fat, 1, 0xfff);
+
+    let mut dir = Vec::with_capacity(entries * DIR_ENTRY_SIZE);
+    dir.extend(dir_entry(&label, ATTR_VOLUME_ID, 0, 0));
+
+    let mut next_cluster = 2;
+    for (file, short) in files.iter().zip(&names) {
+        let clusters = file.contents.len().div_ceil(layout.cluster_size());
+        let first_cluster = if clusters == 0 { 0 } else { next_cluster };
+        for i in 0..clusters {
+            let cluster = next_cluster + i;
+            let next = if i + 1 == clusters { 0xfff } else { cluster + 1 };
+            set_fat12_entry(&mut fat, cluster, next as u16);
+        }
+
+        let offset =
+            layout.data_offset() + (next_cluster - 2) * layout.cluster_size();
+        image[offset..offset + file.contents.len()]
+            .copy_from_slice(file.contents);
+        next_cluster += clusters;
+
+        if lfn_entry_count(file.name, short) > 0 {
+            dir.extend(lfn_entries(file.name, short));
+        }
+
+        dir.extend(dir_entry(
+            short,
+            ATTR_ARCHIVE,
+            first_cluster as u16,
+            file.contents.len() as u32,
+        ));
+    }
+
+    for i in 0..NUM_FATS {
+        let offset = (RESERVED_SECTORS + i * layout.fat_sectors) * SECTOR_SIZE;
+        image[offset..offset + fat.len()].copy_from_slice(&fat);
+    }
+
+    let offset = layout.root_dir_offset();
+    image[offset..offset + dir.len()].copy_from_slice(&dir);
+    Ok(image)
+}
+
+fn write_boot_sector(
+    image: &mut [u8],
+    layout: &Layout,
+    label: &[u8; 11],
+    serial: u32,
+) {
+    let boot = &mut image[..SECTOR_SIZE];
+
+    // A jump over the BPB to an infinite loop, in case anything tries to boot
+    // from the image.
+    boot[0..3].copy_from_slice(&[0xeb, 0x3c, 0x90]);
+    boot[3..11].copy_from_slice(b"WIMSY   ");
+    boot[11..13].copy_from_slice(&(SECTOR_SIZE as u16).to_le_bytes());
+    boot[13] = layout.sectors_per_cluster as u8;
+    boot[14..16].copy_from_slice(&(RESERVED_SECTORS as u16).to_le_bytes());
+    boot[16] = NUM_FATS as u8;
+    boot[17..19].copy_from_slice(&(ROOT_DIR_ENTRIES as u16).to_le_bytes());
+    if layout.total_sectors < 0x10000 {
+        boot[19..21]
+            .copy_from_slice(&(layout.total_sectors as u16).to_le_bytes());
+    } else {
+        boot[32..36]
+            .copy_from_slice(&(layout.total_sectors as u32).to_le_bytes());
+    }
+    boot[21] = MEDIA_DESCRIPTOR;
+    boot[22..24].copy_from_slice(&(layout.fat_sectors as u16).to_le_bytes());
+    boot[24..26].copy_from_slice(&32u16.to_le_bytes()); // sectors per track
+    boot[26..28].copy_from_slice(&64u16.to_le_bytes()); // heads
+
+    // Extended BPB.
+    boot[36] = 0x80; // drive number
+    boot[38] = 0x29; // extended boot signature
+    boot[39..43].copy_from_slice(&serial.to_le_bytes());
+    boot[43..54].copy_from_slice(label);
+    boot[54..62].copy_from_slice(b"FAT12   ");
+    boot[62..64].copy_from_slice(&[0xeb, 0xfe]);
+    boot[510..512].copy_from_slice(&[0x55, 0xaa]);
+}
+
+fn set_fat12_entry(fat: &mut [u8], cluster: usize, value: u16) {
+    let offset = cluster * 3 / 2;
+    if cluster.is_multiple_of(2) {
+        fat[offset] = value as u8;
+        fat[offset + 1] =
+            (fat[offset + 1] & 0xf0) | ((value >> 8) as u8 & 0x0f);
+    } else {
+        fat[offset] = (fat[offset] & 0x0f) | ((value << 4) as u8);
+        fat[offset + 1] = (value >> 4) as u8;
+    }
+}
+
+fn dir_entry(
+    name: &[u8; 11],
+    attributes: u8,
+    first_cluster: u16,
+    size: u32,
+) -> [u8; DIR_ENTRY_SIZE] {
+    let mut entry = [0u8; DIR_ENTRY_SIZE];
+    entry[0..11].copy_from_slice(name);
+    entry[11] = attributes;
+    if attributes != ATTR_VOLUME_ID {
+        entry[16..18].copy_from_slice(&FIXED_DATE.to_le_bytes()); // created
+        entry[18..20].copy_from_slice(&FIXED_DATE.to_le_bytes()); // accessed
+    }
+    entry[24..26].copy_from_slice(&FIXED_DATE.to_le_bytes()); // modified
+    entry[26..28].copy_from_slice(&first_cluster.to_le_bytes());
+    entry[28..32].copy_from_slice(&size.to_le_bytes());
+    entry
+}
+
+/// Returns whether `c` may appear in a short (8.3) name.
+fn is_short_name_char(c: char) -> bool {
+    c.is_ascii_uppercase()
+        || c.is_ascii_digit()
+        || "!#$%&'()-@^_`{}~".contains(c)
+}
+
+/// Converts a volume label to the padded form stored on disk. Labels are
+/// stored as given (including lowercase letters, which `mkfs.vfat -n` also
+/// preserves) so that tools looking for a specific label find it.
+fn short_label(label: &str) -> Result<[u8; 11]> {
+    if label.is_empty()
+        || label.len() > 11
+        || !label
+            .chars()
+            .all(|c| is_short_name_char(c.to_ascii_uppercase()) || c == ' ')
+    {
+        anyhow::bail!("invalid FAT volume label {label:?}");
+    }
+
+    let mut padded = [b' '; 11];
+    padded[..label.len()].copy_from_slice(label.as_bytes());
+    Ok(padded)
+}
+
+/// Returns `name` in on-disk 8.3 form if it's already a valid short name.
+fn exact_short_name(name: &str) -> Option<[u8; 11]> {
+    let (base, ext) = name.split_once('.').unwrap_or((name, ""));
+    if base.is_empty()
+        || base.len() > 8
+        || ext.len() > 3
+        || !base.chars().chain(ext.chars()).all(is_short_name_char)
+    {
+        return None;
+    }
+
+    let mut short = [b' '; 11];
+    short[..base.len()].copy_from_slice(base.as_bytes());
+    short[8..8 + ext.len()].copy_from_slice(ext.as_bytes());
+    Some(short)
+}
+
+/// Chooses a unique short name for each file, using the file's own name where
+/// possible and a `~N` alias (as Windows does) otherwise.
+fn short_names(files: &[FatFile]) -> Result<Vec<[u8; 11]>> {
+    let mut names: Vec<[u8; 11]> = Vec::with_capacity(files.len());
+    for file in files {
+        if file.name.is_empty()
+            || file.name.encode_utf16().count() > 255
+            || file
+                .name
+                .contains(['/', '\\', ':', '*', '?', '"', '<', '>', '|'])
+        {
+            anyhow::bail!("invalid FAT file name {:?}", file.name);
+        }
+
+        if let Some(short) = exact_short_name(file.name) {
+            if names.contains(&short) {
+                anyhow::bail!("duplicate FAT file name {:?}", file.name);
+            }
+
+            names.push(short);
+            continue;
+        }
+
+        let sanitize = |s: &str| -> Vec<u8> {
+            s.chars()
+                .filter(|c| *c != ' ' && *c != '.')
+                .map(|c| c.to_ascii_uppercase())
+                .map(|c| if is_short_name_char(c) { c as u8 } else { b'_' })
+                .collect()
+        };
+
+        let (base, ext) = match file.name.rsplit_once('.') {
+            Some((base, ext)) if !base.is_empty() => (base, ext),
+            _ => (file.name, ""),
+        };
+        let base = sanitize(base);
+        let ext = sanitize(ext);
+
+        let short = (1..=999_999)
+            .map(|n| {
+                let suffix = format!("~{n}");
+                let keep = base.len().min(8 - suffix.len());
+                let mut short = [b' '; 11];
+                short[..keep].copy_from_slice(&base[..keep]);
+                short[keep..keep + suffix.len()]
+                    .copy_from_slice(suffix.as_bytes());
+                let ext_len = ext.len().min(3);
+                short[8..8 + ext_len].copy_from_slice(&ext[..ext_len]);
+                short
+            })
+            .find(|short| !names.contains(short))
+            .unwrap();
+        names.push(short);
+    }
+
+    Ok(names)
+}
+
+/// Returns the number of long file name entries `name` needs, which is zero if
+/// its short name is the name itself.
+fn lfn_entry_count(name: &str, short: &[u8; 11]) -> usize {
+    if exact_short_name(name).as_ref() == Some(short) {
+        0
+    } else {
+        name.encode_utf16().count().div_ceil(LFN_CHARS_PER_ENTRY)
+    }
+}
+
+/// Returns the checksum of a short name that ties long file name entries to
+/// the short entry that follows them.
+fn short_name_checksum(short: &[u8; 11]) -> u8 {
+    short.iter().fold(0u8, |sum, &c| sum.rotate_right(1).wrapping_add(c))
+}
+
+/// Returns the long file name entries for `name`, in the order in which they
+/// appear on disk (i.e. last part first).
+fn lfn_entries(name: &str, short: &[u8; 11]) -> Vec<u8> {
+    let mut chars: Vec<u16> = name.encode_utf16().collect();
+    let count = chars.len().div_ceil(LFN_CHARS_PER_ENTRY);
+
+    // Names that don't fill their last entry are terminated with a null and
+    // padded with 0xFFFF.
+    if !chars.len().is_multiple_of(LFN_CHARS_PER_ENTRY) {
+        chars.push(0);
+    }
+    chars.resize(count * LFN_CHARS_PER_ENTRY, 0xffff);
+
+    let checksum = short_name_checksum(short);
+    let mut entries = Vec::with_capacity(count * DIR_ENTRY_SIZE);
+    for seq in (1..=count).rev() {
+        let part = &chars[(seq - 1) * LFN_CHARS_PER_ENTRY..][..13];
+        let mut entry = [0u8; DIR_ENTRY_SIZE];
+        entry[0] = seq as u8 | if seq == count { 0x40 } else { 0 };
+        entry[11] = ATTR_LONG_NAME;
+        entry[13] = checksum;
+
+        let offsets = (1..11).step_by(2).chain((14..26).step_by(2));
+        for (offset, c) in offsets.chain((28..32).step_by(2)).zip(part) {
+            entry[offset..offset + 2].copy_from_slice(&c.to_le_bytes());
+        }
+
+        entries.extend(entry);
+    }
+
+    entries
+}
+
+/// A file or directory found in a FAT filesystem.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FatEntry {
+    /// The entry's long file name, or its short name if it doesn't have one.
+    pub name: String,
+    pub is_dir: bool,
+    pub size: u32,
+    first_cluster: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum FatType {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+/// A FAT filesystem stored at some offset in a disk image.
+pub struct FatVolume<'a, R> {
+    disk: &'a mut R,
+    offset: u64,
+    fat_type: FatType,
+    cluster_size: u64,
+    fat_offset: u64,
+    root_dir_offset: u64,
+    root_dir_entries: u64,
+    root_cluster: u32,
+    data_offset: u64,
+    clusters: u32,
+}
+
+impl<'a, R: Read + Seek> FatVolume<'a, R> {
+    /// Reads the boot sector of the FAT filesystem that starts `offset` bytes
+    /// into `disk`.
+    pub fn open(disk: &'a mut R, offset: u64) -> Result<Self> {
+        let mut boot = [0u8; SECTOR_SIZE];
+        disk.seek(SeekFrom::Start(offset))?;
+        disk.read_exact(&mut boot).context("reading FAT boot sector")?;
+
+        let u16_at =
+            |o: usize| u64::from(u16::from_le_bytes([boot[o], boot[o + 1]]));
+        let u32_at = |o: usize| {
+            u64::from(u32::from_le_bytes(boot[o..o + 4].try_into().unwrap()))
+        };
+
+        let bytes_per_sector = u16_at(11);
+        let sectors_per_cluster = u64::from(boot[13]);
+        let reserved_sectors = u16_at(14);
+        let num_fats = u64::from(boot[16]);
+        let root_dir_entries = u16_at(17);
+        let total_sectors = match u16_at(19) {
+            0 => u32_at(32),
+            n => n,
+        };
+        let fat_sectors = match u16_at(22) {
+            0 => u32_at(36),
+            n => n,
+        };
+
+        if boot[510..512] != [0x55, 0xaa]
+            || ![512, 1024, 2048, 4096].contains(&bytes_per_sector)
+            || !sectors_per_cluster.is_power_of_two()
+            || reserved_sectors == 0
+            || num_fats == 0
+            || fat_sectors == 0
+        {
+            anyhow::bail!("partition doesn't contain a FAT filesystem");
+        }
+
+        let root_dir_sectors = (root_dir_entries * DIR_ENTRY_SIZE as u64)
+            .div_ceil(bytes_per_sector);
+        let data_sector =
+            reserved_sectors + num_fats * fat_sectors + root_dir_sectors;
+        let clusters = total_sectors
+            .checked_sub(data_sector)
+            .context("FAT filesystem is smaller than its metadata")?
+            / sectors_per_cluster;
+
+        // The number of clusters alone determines a FAT filesystem's type.
+        let fat_type = match clusters {
+            0..=4084 => FatType::Fat12,
+            4085..=65524 => FatType::Fat16,
+            _ => FatType::Fat32,
+        };
+
+        Ok(Self {
+            disk,
+            offset,
+            fat_type,
+            cluster_size: bytes_per_sector * sectors_per_cluster,
+            fat_offset: reserved_sectors * bytes_per_sector,
+            root_dir_offset: (reserved_sectors + num_fats * fat_sectors)
+                * bytes_per_sector,
+            root_dir_entries,
+            root_cluster: u32_at(44) as u32,
+            data_offset: data_sector * bytes_per_sector,
+            clusters: clusters as u32,
+        })
+    }
+
+    /// Looks up the entry at `path`, a list of names (e.g. `["EFI", "Boot",
+    /// "bootx64.efi"]`), starting from the root directory. Names are compared
+    /// without regard to case, as Windows and UEFI firmware do.
+    pub fn find(&mut self, path: &[&str]) -> Result<Option<FatEntry>> {
+        let mut dir = None;
+        let mut found = None;
+        for (i, name) in path.iter().enumerate() {
+            let entries = self.read_dir(dir.as_ref())?;
+            let Some(entry) =
+                entries.into_iter().find(|e| e.name.eq_ignore_ascii_case(name))
+            else {
+                return Ok(None);
+            };
+
+            if i + 1 < path.len() && !entry.is_dir {
+                return Ok(None);
+            }
+
+            dir = Some(entry.clone());
+            found = Some(entry);
+        }
+
+        Ok(found)
+    }
+
+    /// Reads the entries in `dir`, or in the root directory if `dir` is
+    /// `None`.
+    fn read_dir(&mut self, dir: Option<&FatEntry>) -> Result<Vec<FatEntry>> {
+        let data = match dir {
+            None if self.fat_type != FatType::Fat32 => {
+                let mut data =
+                    vec![0u8; self.root_dir_entries as usize * DIR_ENTRY_SIZE];
+                self.disk.seek(SeekFrom::Start(
+                    self.offset + self.root_dir_offset,
+                ))?;
+                self.disk.read_exact(&mut data)?;
+                data
+            }
+            None => self.read_chain(self.root_cluster)?,
+            Some(dir) => self.read_chain(dir.first_cluster)?,
+        };
+
+        Ok(parse_dir_entries(&data))
+    }
+
+    /// Reads the contents of every cluster in the chain that starts at
+    /// `cluster`.
+    fn read_chain(&mut self, mut cluster: u32) -> Result<Vec<u8>> {
+        let mut data = Vec::new();
+        let mut visited = 0;
+        while (2..self.clusters + 2).contains(&cluster) {
+            // A chain can't be longer than the filesystem; a longer one is a
+            // loop.
+            visited += 1;
+            if visited > self.clusters {
+                anyhow::bail!("FAT cluster chain contains a loop");
+            }
+
+            let mut buf = vec![0u8; self.cluster_size as usize];
+            self.disk.seek(SeekFrom::Start(
+                self.offset
+                    + self.data_offset
+                    + u64::from(cluster - 2) * self.cluster_size,
+            ))?;
+            self.disk.read_exact(&mut buf)?;
+            data.extend(buf);
+            cluster = self.next_cluster(cluster)?;
+        }
+
+        Ok(data)
+    }
+
+    /// Returns the FAT entry for `cluster`, i.e. the next cluster in its chain.
+    fn next_cluster(&mut self, cluster: u32) -> Result<u32> {
+        let (offset, len) = match self.fat_type {
+            FatType::Fat12 => (u64::from(cluster) * 3 / 2, 2),
+            FatType::Fat16 => (u64::from(cluster) * 2, 2),
+            FatType::Fat32 => (u64::from(cluster) * 4, 4),
+        };
+
+        let mut buf = [0u8; 4];
+        self.disk
+            .seek(SeekFrom::Start(self.offset + self.fat_offset + offset))?;
+        self.disk.read_exact(&mut buf[..len])?;
+        let value = u32::from_le_bytes(buf);
+        Ok(match self.fat_type {
+            FatType::Fat12 if cluster.is_multiple_of(2) => value & 0xfff,
+            FatType::Fat12 => value >> 4,
+            FatType::Fat16 => value,
+            FatType::Fat32 => value & 0x0fff_ffff,
+        })
+    }
+}
+
+/// Parses the entries in a directory's contents, reassembling long file names.
+fn parse_dir_entries(data: &[u8]) -> Vec<FatEntry> {
+    let mut entries = Vec::new();
+    let mut long_name: Vec<u16> = Vec::new();
+    let mut long_name_checksum = None;
+    for entry in data.chunks_exact(DIR_ENTRY_SIZE) {
+        match entry[0] {
+            0x00 => break,
+            0xe5 => {
+                long_name.clear();
+                continue;
+            }
+            _ => {}
+        }
+
+        if entry[11] == ATTR_LONG_NAME {
+            // Long name entries appear last part first, so each one's
+            // characters go in front of the ones already seen.
+            let mut part: Vec<u16> = (1..11)
+                .step_by(2)
+                .chain((14..26).step_by(2))
+                .chain((28..32).step_by(2))
+                .map(|o| u16::from_le_bytes([entry[o], entry[o + 1]]))
+                .collect();
+            if entry[0] & 0x40 != 0 {
+                long_name.clear();
+            }
+            part.append(&mut long_name);
+            long_name = part;
+            long_name_checksum = Some(entry[13]);
+            continue;
+        }
+
+        let short: [u8; 11] = entry[0..11].try_into().unwrap();
+        let long_name = std::mem::take(&mut long_name);
+        if entry[11] & ATTR_VOLUME_ID != 0 {
+            continue;
+        }
+
+        let name = if !long_name.is_empty()
+            && long_name_checksum == Some(short_name_checksum(&short))
+        {
+            let end = long_name
+                .iter()
+                .position(|c| *c == 0 || *c == 0xffff)
+                .unwrap_or(long_name.len());
+            String::from_utf16_lossy(&long_name[..end])
+        } else {
+            let base = String::from_utf8_lossy(&short[..8]);
+            let ext = String::from_utf8_lossy(&short[8..]);
+            match ext.trim_end() {
+                "" => base.trim_end().to_string(),
+                ext => format!("{}.{ext}", base.trim_end()),
+            }
+        };
+
+        let cluster_high = u16::from_le_bytes([entry[20], entry[21]]);
+        let cluster_low = u16::from_le_bytes([entry[26], entry[27]]);
+        entries.push(FatEntry {
+            name,
+            is_dir: entry[11] & ATTR_DIRECTORY != 0,
+            size: u32::from_le_bytes(entry[28..32].try_into().unwrap()),
+            first_cluster: (u32::from(cluster_high) << 16)
+                | u32::from(cluster_low),
+        });
+    }
+
+    entries
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+
+    fn u16_at(image: &[u8], offset: usize) -> usize {
+        u16::from_le_bytes([image[offset], image[offset + 1]]) as usize
+    }
+
+    fn fat12_entry(fat: &[u8], cluster: usize) -> usize {
+        let value = u16_at(fat, cluster * 3 / 2);
+        if cluster.is_multiple_of(2) {
+            value & 0xfff
+        } else {
+            value >> 4
+        }
+    }
+
+    /// Reads the files in the root directory of a FAT12 image, reassembling
+    /// long file names and following cluster chains.
+    fn read_image(image: &[u8]) -> (String, Vec<(String, Vec<u8>)>) {
+        assert_eq!(&image[510..512], &[0x55, 0xaa]);
+        let sector_size = u16_at(image, 11);
+        let cluster_size = sector_size * image[13] as usize;
+        let reserved = u16_at(image, 14);
+        let fat_sectors = u16_at(image, 22);
+        let root_entries = u16_at(image, 17);
+        let fat = &image[reserved * sector_size..];
+        let root = (reserved + image[16] as usize * fat_sectors) * sector_size;
+        let data = root + root_entries * DIR_ENTRY_SIZE;
+
+        let mut label = String::new();
+        let mut files = Vec::new();
+        let mut long_name: Vec<u16> = Vec::new();
+        for entry in image[root..data].chunks(DIR_ENTRY_SIZE) {
+            if entry[0] == 0 {
+                break;
+            }
+
+            match entry[11] {
+                ATTR_LONG_NAME => {
+                    let mut part = Vec::new();
+                    for offset in (1..11)
+                        .step_by(2)
+                        .chain((14..26).step_by(2))
+                        .chain((28..32).step_by(2))
+                    {
+                        part.push(u16_at(entry, offset) as u16);
+                    }
+                    part.extend(long_name);
+                    long_name = part;
+                }
+                ATTR_VOLUME_ID => {
+                    label = String::from_utf8_lossy(&entry[0..11])
+                        .trim_end()
+                        .to_string();
+                }
+                _ => {
+                    let name = if long_name.is_empty() {
+                        let base = String::from_utf8_lossy(&entry[0..8]);
+                        let ext = String::from_utf8_lossy(&entry[8..11]);
+                        if ext.trim().is_empty() {
+                            base.trim_end().to_string()
+                        } else {
+                            format!("{}.{}", base.trim_end(), ext.trim_end())
+                        }
+                    } else {
+                        let end = long_name
+                            .iter()
+                            .position(|&c| c == 0 || c == 0xffff)
+                            .unwrap_or(long_name.len());
+                        String::from_utf16(&long_name[..end]).unwrap()
+                    };
+                    long_name = Vec::new();
+
+                    let size =
+                        u32::from_le_bytes(entry[28..32].try_into().unwrap())
+                            as usize;
+                    let mut contents = Vec::new();
+                    let mut cluster = u16_at(entry, 26);
+                    while (2..0xff8).contains(&cluster) {
+                        let offset = data + (cluster - 2) * cluster_size;
+                        contents.extend(&image[offset..offset + cluster_size]);
+                        cluster = fat12_entry(fat, cluster);
+                    }
+                    contents.truncate(size);
+                    files.push((name, contents));
+                }
+            }
+        }
+
+        (label, files)
+    }
+
+    #[test]
+    fn round_trip_files() {
+        let big: Vec<u8> = (0..5000u32).map(|i| i as u8).collect();
+        let image = build_image(
+            "cidata",
+            0x1234_5678,
+            &[
+                FatFile { name: "meta-data", contents: b"instance-id: a\n" },
+                FatFile { name: "user-data", contents: &big },
+                FatFile { name: "EMPTY.TXT", contents: b"" },
+                FatFile {
+                    name: "a rather long name.with-extension",
+                    contents: b"x",
+                },
+            ],
+        )
+        .unwrap();
+
+        assert_eq!(image.len(), MIN_IMAGE_SECTORS * SECTOR_SIZE);
+        assert_eq!(&image[43..54], b"cidata     ");
+        assert_eq!(&image[54..62], b"FAT12   ");
+
+        let (label, files) = read_image(&image);
+        assert_eq!(label, "cidata");
+        assert_eq!(
+            files,
+            vec![
+                ("meta-data".to_string(), b"instance-id: a\n".to_vec()),
+                ("user-data".to_string(), big),
+                ("EMPTY.TXT".to_string(), Vec::new()),
+                (
+                    "a rather long name.with-extension".to_string(),
+                    b"x".to_vec()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn large_files_use_larger_clusters() {
+        let big = vec![0xa5u8; 3 * 1024 * 1024];
+        let image = build_image(
+            "cidata",
+            0,
+            &[FatFile { name: "user-data", contents: &big }],
+        )
+        .unwrap();
+
+        assert!(image[13] > 1);
+        let (_, files) = read_image(&image);
+        assert_eq!(files[0].1, big);
+    }
+
+    #[test]
+    fn generate_short_names() {
+        let files = [
+            FatFile { name: "meta-data", contents: b"" },
+            FatFile { name: "meta-data.json", contents: b"" },
+            FatFile { name: "meta-data-2", contents: b"" },
+            FatFile { name: "README.TXT", contents: b"" },
+        ];
+
+        let names = short_names(&files).unwrap();
+        assert_eq!(&names[0], b"META-D~1   ");
+        assert_eq!(&names[1], b"META-D~1JSO");
+        assert_eq!(&names[2], b"META-D~2   ");
+        assert_eq!(&names[3], b"README  TXT");
+
+        assert!(short_names(&[FatFile { name: "a/b", contents: b"" }]).is_err());
+        assert!(build_image("not a valid label", 0, &[]).is_err());
+    }
+
+    #[test]
+    fn fat_layouts() {
+        assert_eq!(
+            Layout::for_data(0, 1),
+            Some(Layout {
+                total_sectors: 2048,
+                sectors_per_cluster: 1,
+                fat_sectors: 6,
+                clusters: 2003,
+            })
+        );
+        assert_eq!(Layout::for_data(5000, 1), None);
+        assert!(Layout::for_data(4000, 2).unwrap().clusters >= 4000);
+    }
+
+    /// Builds an image containing `\\EFI\\BOOT\\bootx64.efi`.
+    ///
+    /// The writer only creates files in the root directory, so this builds an
+    /// image whose first two files hold directory listings and then marks
+    /// them as directories. Files are stored in order starting at cluster 2,
+    /// so `EFI` is in cluster 2, `BOOT` in 3, and the boot loader in 4.
+    pub(crate) fn build_boot_image() -> Vec<u8> {
+        let mut efi = dir_entry(b"BOOT       ", ATTR_DIRECTORY, 3, 0).to_vec();
+        efi.extend([0u8; DIR_ENTRY_SIZE]);
+        let loader_name = *b"BOOTX64 EFI";
+        let mut boot = lfn_entries("bootx64.efi", &loader_name);
+        boot.extend(dir_entry(&loader_name, ATTR_ARCHIVE, 4, 5));
+        let mut image = build_image(
+            "ESP",
+            0,
+            &[
+                FatFile { name: "EFI", contents: &efi },
+                FatFile { name: "BOOT", contents: &boot },
+                FatFile { name: "BOOTX64.EFI", contents: b"hello" },
+            ],
+        )
+        .unwrap();
+
+        let fat_sectors = u16_at(&image, 22);
+        let root = (RESERVED_SECTORS + NUM_FATS * fat_sectors) * SECTOR_SIZE;
+        for i in [1, 2] {
+            image[root + i * DIR_ENTRY_SIZE + 11] = ATTR_DIRECTORY;
+        }
+
+        image
+    }
+
+    #[test]
+    fn find_files_in_subdirectories() {
+        let image = build_boot_image();
+        let mut disk = std::io::Cursor::new(image);
+        let mut volume = FatVolume::open(&mut disk, 0).unwrap();
+        let found = volume.find(&["efi", "Boot", "BOOTX64.efi"]).unwrap();
+        assert_eq!(
+            found,
+            Some(FatEntry {
+                name: "bootx64.efi".to_string(),
+                is_dir: false,
+                size: 5,
+                first_cluster: 4,
+            })
+        );
+
+        assert!(volume.find(&["EFI", "BOOT"]).unwrap().unwrap().is_dir);
+        assert_eq!(volume.find(&["EFI", "Microsoft"]).unwrap(), None);
+        assert_eq!(volume.find(&["BOOTX64.EFI", "x"]).unwrap(), None);
+
+        let mut not_fat = std::io::Cursor::new(vec![0u8; 4096]);
+        assert!(FatVolume::open(&mut not_fat, 0).is_err());
+    }
+}
@@ -0,0 +1,354 @@
+// This Source Code Form is subject to the terms of the Mozilla Public
+// License, v. 2.0. If a copy of the MPL was not distributed with this
+// file, You can obtain one at https://mozilla.org/MPL/2.0/.
+
+//! Reads the descriptions of the images in a Windows Imaging (WIM) file.
+//!
+//! A WIM file begins with a fixed-size header that, among other things, gives
+//! the location of an XML document describing each image in the file: its
+//! name, edition, installation type, build number, and languages. wimsy reads
+//! this document from the `install.wim` on a Windows setup ISO to describe the
+//! edition it installed, seeking to it through the ISO's UDF file system so
+//! that the rest of the multi-gigabyte file is never read. Media that ship an
+//! `install.esd` instead use the same header and an uncompressed XML
+//! document, so it's read the same way.
+
+use std::io::{Read, Seek, SeekFrom};
+
+use anyhow::{Context as _, Result};
+use camino::Utf8Path;
+use serde::{Deserialize, Serialize};
+
+use crate::udf::Udf;
+
+/// The size of a WIM file's header.
+const HEADER_SIZE: usize = 208;
+
+/// The magic number at the start of a WIM file.
+const WIM_MAGIC: &[u8; 8] = b"MSWIM\0\0\0";
+
+/// The offset in the header of the resource header that locates the XML
+/// document.
+const XML_RESOURCE_OFFSET: usize = 72;
+
+/// The largest XML document this module will read. Real documents are a few
+/// KiB per image.
+const MAX_XML_SIZE: u64 = 16 * 1024 * 1024;
+
+/// The paths at which a Windows setup ISO can hold its installable images, in
+/// order of preference.
+const INSTALL_IMAGE_PATHS: [&str; 2] =
+    ["sources/install.wim", "sources/install.esd"];
+
+/// The description of one image in a WIM file.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct WimImage {
+    /// The image's 1-based index, as used in `/IMAGE/INDEX`.
+    pub index: u32,
+
+    /// The image's name, e.g. "Windows Server 2022 SERVERSTANDARD".
+    pub name: String,
+
+    /// The image's user-facing name, e.g. "Windows Server 2022 Standard
+    /// (Desktop Experience)". Older media don't include one.
+    pub display_name: Option<String>,
+
+    /// The edition ID, e.g. "ServerStandard" or "Professional".
+    pub edition_id: Option<String>,
+
+    /// The installation type, e.g. "Server", "Server Core", or "Client".
+    pub installation_type: Option<String>,
+
+    /// The build number, e.g. "10.0.20348.587".
+    pub build: Option<String>,
+
+    /// The image's languages, e.g. "en-US".
+    pub languages: Vec<String>,
+}
+
+impl WimImage {
+    /// Returns the most descriptive name the image has.
+    pub fn best_name(&self) -> &str {
+        self.display_name.as_deref().unwrap_or(&self.name)
+    }
+}
+
+/// The location of a resource in a WIM file.
+#[derive(Debug, PartialEq, Eq)]
+struct Resource {
+    offset: u64,
+    size: u64,
+}
+
+/// Reads the location of the XML document from a WIM file's header.
+fn parse_header(header: &[u8]) -> Result<Resource> {
+    if header.len() < HEADER_SIZE || &header[..8] != WIM_MAGIC {
+        anyhow::bail!("not a WIM file");
+    }
+
+    let u64_at = |offset: usize| {
+        u64::from_le_bytes(header[offset..offset + 8].try_into().unwrap())
+    };
+
+    // The first eight bytes of a resource header hold its size in the low
+    // seven bytes and its flags in the high byte.
+    let size = u64_at(XML_RESOURCE_OFFSET) & 0x00ff_ffff_ffff_ffff;
+    let offset = u64_at(XML_RESOURCE_OFFSET + 8);
+    if size == 0 || size > MAX_XML_SIZE || offset < HEADER_SIZE as u64 {
+        anyhow::bail!(
+            "WIM header has implausible XML data location ({size} bytes at \
+            offset {offset})"
+        );
+    }
+
+    Ok(Resource { offset, size })
+}
+
+/// Decodes a WIM XML document, which is UTF-16LE with a byte order mark.
+fn decode_xml(data: &[u8]) -> Result<String> {
+    let data = data.strip_prefix(&[0xff, 0xfe]).unwrap_or(data);
+    let units: Vec<u16> = data
+        .chunks_exact(2)
+        .map(|c| u16::from_le_bytes([c[0], c[1]]))
+        .collect();
+    String::from_utf16(&units).context("WIM XML data isn't valid UTF-16")
+}
+
+/// Parses the image descriptions in a WIM XML document.
+fn parse_xml(xml: &str) -> Result<Vec<WimImage>> {
+    use xml::reader::XmlEvent;
+
+    let mut images = Vec::new();
+    let mut current: Option<WimImage> = None;
+    let mut stack: Vec<String> = Vec::new();
+    let mut version: [Option<String>; 4] = Default::default();
+    for event in xml::reader::EventReader::new(xml.as_bytes()) {
+        match event.context("parsing WIM XML data")? {
+            XmlEvent::StartElement { name, attributes, .. } => {
+                if name.local_name == "IMAGE" && stack.len() == 1 {
+                    let index = attributes
+                        .iter()
+                        .find(|a| a.name.local_name == "INDEX")
+                        .and_then(|a| a.value.parse().ok())
+                        .context("WIM image has no INDEX attribute")?;
+                    current = Some(WimImage { index, ..Default::default() });
+                    version = Default::default();
+                }
+                stack.push(name.local_name);
+            }
+            XmlEvent::EndElement { name } => {
+                stack.pop();
+                if name.local_name == "IMAGE" && stack.len() == 1 {
+                    let mut image = current.take().unwrap();
+                    if let [Some(major), Some(minor), Some(build), sp] =
+                        &version
+                    {
+                        image.build = Some(match sp {
+                            Some(sp) => format!("{major}.{minor}.{build}.{sp}"),
+                            None => format!("{major}.{minor}.{build}"),
+                        });
+                    }
+                    images.push(image);
+                }
+            }
+            XmlEvent::Characters(text) => {
+                let Some(image) = current.as_mut() else {
+                    continue;
+                };
+
+                let path: Vec<&str> =
+                    stack.iter().skip(2).map(String::as_str).collect();
+                let text = text.trim().to_owned();
+                match path.as_slice() {
+                    ["NAME"] => image.name = text,
+                    ["DISPLAYNAME"] => image.display_name = Some(text),
+                    ["WINDOWS", "EDITIONID"] => image.edition_id = Some(text),
+                    ["WINDOWS", "INSTALLATIONTYPE"] => {
+                        image.installation_type = Some(text)
+                    }
+                    ["WINDOWS", "LANGUAGES", "LANGUAGE"] => {
+                        image.languages.push(text)
+                    }
+                    ["WINDOWS", "VERSION", part] => {
+                        let slot = match *part {
+                            "MAJOR" => 0,
+                            "MINOR" => 1,
+                            "BUILD" => 2,
+                            "SPBUILD" => 3,
+                            _ => continue,
+                        };
+                        version[slot] = Some(text);
+                    }
+                    _ => {}
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok(images)
+}
+
+/// Reads the image descriptions from the WIM file `reader`, seeking directly
+/// to the XML document (which is usually at the end of the file).
+pub fn read_images<R: Read + Seek>(reader: &mut R) -> Result<Vec<WimImage>> {
+    let mut header = [0u8; HEADER_SIZE];
+    reader.read_exact(&mut header).context("reading WIM header")?;
+    let xml = parse_header(&header)?;
+
+    reader
+        .seek(SeekFrom::Start(xml.offset))
+        .context("seeking to WIM XML data")?;
+    let mut data = vec![0u8; xml.size as usize];
+    reader.read_exact(&mut data).context("reading WIM XML data")?;
+    parse_xml(&decode_xml(&data)?)
+}
+
+/// Reads the image descriptions from the WIM file at `path`.
+pub fn read_file_images(path: &Utf8Path) -> Result<Vec<WimImage>> {
+    let mut file =
+        std::fs::File::open(path).with_context(|| format!("opening {path}"))?;
+    read_images(&mut file).with_context(|| format!("reading {path}"))
+}
+
+/// Reads the image descriptions from the first of [`INSTALL_IMAGE_PATHS`] in
+/// the UDF file system in `image`.
+fn read_udf_images<R: Read + Seek>(image: R) -> Result<Vec<WimImage>> {
+    let mut udf = Udf::open(image)?;
+    for path in INSTALL_IMAGE_PATHS {
+        if let Some(mut file) = udf.open_file(path)? {
+            return read_images(&mut file)
+                .with_context(|| format!("reading {path}"));
+        }
+    }
+
+    anyhow::bail!("found neither {}", INSTALL_IMAGE_PATHS.join(" nor "))
+}
+
+/// Reads the image descriptions from `sources/install.wim` (or, if there's no
+/// such file, `sources/install.esd`) on the Windows setup ISO at `iso`. Only
+/// the file's header and XML document are read from the ISO.
+pub fn read_iso_images(iso: &Utf8Path) -> Result<Vec<WimImage>> {
+    let file =
+        std::fs::File::open(iso).with_context(|| format!("opening {iso}"))?;
+    read_udf_images(std::io::BufReader::new(file))
+        .with_context(|| format!("reading install image from {iso}"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::udf::test::UdfBuilder;
+
+    const SERVER_2022_XML: &str = r#"<WIM>
+<TOTALBYTES>5000000000</TOTALBYTES>
+<IMAGE INDEX="1">
+  <NAME>Windows Server 2022 SERVERSTANDARDCORE</NAME>
+  <DISPLAYNAME>Windows Server 2022 Standard</DISPLAYNAME>
+  <WINDOWS>
+    <EDITIONID>ServerStandard</EDITIONID>
+    <INSTALLATIONTYPE>Server Core</INSTALLATIONTYPE>
+    <VERSION><MAJOR>10</MAJOR><MINOR>0</MINOR><BUILD>20348</BUILD></VERSION>
+  </WINDOWS>
+</IMAGE>
+<IMAGE INDEX="2">
+  <NAME>Windows Server 2022 SERVERSTANDARD</NAME>
+  <DISPLAYNAME>Windows Server 2022 Standard (Desktop Experience)</DISPLAYNAME>
+  <WINDOWS>
+    <EDITIONID>ServerStandard</EDITIONID>
+    <INSTALLATIONTYPE>Server</INSTALLATIONTYPE>
+    <VERSION>
+      <MAJOR>10</MAJOR><MINOR>0</MINOR><BUILD>20348</BUILD>
+      <SPBUILD>587</SPBUILD>
+    </VERSION>
+    <LANGUAGES><LANGUAGE>en-US</LANGUAGE><DEFAULT>en-US</DEFAULT></LANGUAGES>
+  </WINDOWS>
+</IMAGE>
+</WIM>"#;
+
+    /// Builds a WIM file whose XML document is `xml`, preceded by `padding`
+    /// bytes of resource data.
+    fn build_wim(xml: &str, padding: usize) -> Vec<u8> {
+        let mut encoded = vec![0xff, 0xfe];
+        encoded.extend(xml.encode_utf16().flat_map(u16::to_le_bytes));
+
+        let mut wim = vec![0u8; HEADER_SIZE + padding];
+        wim[..8].copy_from_slice(WIM_MAGIC);
+        let size = encoded.len() as u64 | (0x02 << 56);
+        wim[72..80].copy_from_slice(&size.to_le_bytes());
+        let offset = wim.len() as u64;
+        wim[80..88].copy_from_slice(&offset.to_le_bytes());
+        wim.extend(encoded);
+        wim
+    }
+
+    #[test]
+    fn read_image_descriptions() {
+        let wim = build_wim(SERVER_2022_XML, 3 * 1024 * 1024 + 17);
+        let images = read_images(&mut std::io::Cursor::new(&wim)).unwrap();
+        assert_eq!(
+            images,
+            [
+                WimImage {
+                    index: 1,
+                    name: "Windows Server 2022 SERVERSTANDARDCORE".to_string(),
+                    display_name: Some(
+                        "Windows Server 2022 Standard".to_string()
+                    ),
+                    edition_id: Some("ServerStandard".to_string()),
+                    installation_type: Some("Server Core".to_string()),
+                    build: Some("10.0.20348".to_string()),
+                    languages: vec![],
+                },
+                WimImage {
+                    index: 2,
+                    name: "Windows Server 2022 SERVERSTANDARD".to_string(),
+                    display_name: Some(
+                        "Windows Server 2022 Standard (Desktop Experience)"
+                            .to_string()
+                    ),
+                    edition_id: Some("ServerStandard".to_string()),
+                    installation_type: Some("Server".to_string()),
+                    build: Some("10.0.20348.587".to_string()),
+                    languages: vec!["en-US".to_string()],
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn read_esd_image_descriptions() {
+        // ESD files compress their resources with LZMS, which the header's
+        // flags record, but leave the XML document uncompressed.
+        let mut esd = build_wim(SERVER_2022_XML, 4096);
+        esd[16..20].copy_from_slice(&0x0002_0002u32.to_le_bytes());
+        let images = read_images(&mut std::io::Cursor::new(&esd)).unwrap();
+        assert_eq!(images.len(), 2);
+        assert_eq!(images[1].build.as_deref(), Some("10.0.20348.587"));
+    }
+
+    #[test]
+    fn read_iso_image_descriptions() {
+        let wim = build_wim(SERVER_2022_XML, 64 * 1024);
+        for name in ["install.wim", "INSTALL.ESD"] {
+            let iso = UdfBuilder::with_file(name, &wim);
+            let images = read_udf_images(std::io::Cursor::new(iso)).unwrap();
+            assert_eq!(images.len(), 2);
+            assert_eq!(images[0].index, 1);
+        }
+
+        let iso = UdfBuilder::with_file("boot.wim", &wim);
+        assert!(read_udf_images(std::io::Cursor::new(iso)).is_err());
+    }
+
+    #[test]
+    fn reject_bad_headers() {
+        let mut wim = build_wim(SERVER_2022_XML, 0);
+        wim[0] = b'X';
+        assert!(read_images(&mut std::io::Cursor::new(&wim)).is_err());
+
+        let mut wim = build_wim(SERVER_2022_XML, 0);
+        wim[80..88].copy_from_slice(&0u64.to_le_bytes());
+        assert!(read_images(&mut std::io::Cursor::new(&wim)).is_err());
+    }
+}
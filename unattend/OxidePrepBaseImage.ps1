@@ -5,6 +5,14 @@ param (
 
 $ErrorActionPreference = 'stop'
 
+# Packages wimsy can deliver alongside this script (see its
+# --cloudbase-init-msi and --openssh-zip options). If present, these are
+# installed instead of downloading the packages from the internet.
+$localCloudbaseInitMsi = "$ConfigDir\CloudbaseInitSetup.msi"
+$localSshArchive = "$ConfigDir\OpenSSH-Win64.zip"
+$haveCloudbaseInitMsi = Test-Path -Path $localCloudbaseInitMsi -PathType Leaf
+$haveSshArchive = Test-Path -Path $localSshArchive -PathType Leaf
+
 function RetryWithBackoff {
     param (
         [Parameter(Mandatory=$True)]
@@ -93,25 +101,29 @@ Enable-NetFirewallRule -DisplayGroup "Remote Desktop"
 #endregion
 
 #region Wait for internet access
-$timeout = New-TimeSpan -Seconds 30
-$stopwatch = [System.Diagnostics.Stopwatch]::StartNew()
-$connected = $false
-
-do {
-    $ping = Test-NetConnection -ComputerName "www.oxide.computer" -Port 443
-    if ($ping.TcpTestSucceeded) {
-        $connected = $true
-        break
-    }
+if ($haveCloudbaseInitMsi -and $haveSshArchive) {
+    Write-Host "All packages supplied locally, skipping internet connectivity check"
+} else {
+    $timeout = New-TimeSpan -Seconds 30
+    $stopwatch = [System.Diagnostics.Stopwatch]::StartNew()
+    $connected = $false
 
-    Start-Sleep -Seconds 1
-} while ($stopwatch.Elapsed -lt $timeout)
+    do {
+        $ping = Test-NetConnection -ComputerName "www.oxide.computer" -Port 443
+        if ($ping.TcpTestSucceeded) {
+            $connected = $true
+            break
+        }
 
-if (-not $connected) {
-    Write-Host "No internet connectivity"
-    exit 1
-} else {
-    Write-Host "Internet connection established"
+        Start-Sleep -Seconds 1
+    } while ($stopwatch.Elapsed -lt $timeout)
+
+    if (-not $connected) {
+        Write-Host "No internet connectivity"
+        exit 1
+    } else {
+        Write-Host "Internet connection established"
+    }
 }
 #endregion
 
@@ -119,24 +131,33 @@ if (-not $connected) {
 #region Enable SSH
 Write-Host "Enabling SSH"
 
-# The easiest way to install OpenSSH is to install the relevant Windows
-# capability, but this only exists in-box on Windows Server 2019 and later.
-# Server 2016 recognizes the capability name, but enabling it doesn't actually
-# install the sshd service. Try to detect both of these cases.
-Add-WindowsCapability -Online -Name OpenSSH.Server~~~~0.0.1.0 -ErrorAction SilentlyContinue
-if ($?) {
-    $sshCap = Get-Service -Name sshd -ErrorAction SilentlyContinue
-}
-
-# If either of the last two commands produced an error, fall back to trying to
-# pull the latest release down from GitHub and trying to install it manually.
-if ($?) {
-    Write-Host "SSH service installed via Add-WindowsCapability"
-} else {
-    Write-Host "SSH capability not present in image, will download from GitHub"
+# If a release archive was supplied, install it directly; the Windows
+# capability may need to be downloaded from Windows Update.
+if ($haveSshArchive) {
+    Write-Host "Installing SSH from local archive"
     $sshPath = "C:\Windows\Temp\OpenSSH-Win64.zip"
-    RetryWithBackoff -ScriptBlock { DownloadLatestSshArchive -ArchivePath $sshPath }
+    Copy-Item $localSshArchive -Destination $sshPath
     InstallSshFromArchive -ArchivePath $sshPath
+} else {
+    # The easiest way to install OpenSSH is to install the relevant Windows
+    # capability, but this only exists in-box on Windows Server 2019 and later.
+    # Server 2016 recognizes the capability name, but enabling it doesn't actually
+    # install the sshd service. Try to detect both of these cases.
+    Add-WindowsCapability -Online -Name OpenSSH.Server~~~~0.0.1.0 -ErrorAction SilentlyContinue
+    if ($?) {
+        $sshCap = Get-Service -Name sshd -ErrorAction SilentlyContinue
+    }
+
+    # If either of the last two commands produced an error, fall back to trying to
+    # pull the latest release down from GitHub and trying to install it manually.
+    if ($?) {
+        Write-Host "SSH service installed via Add-WindowsCapability"
+    } else {
+        Write-Host "SSH capability not present in image, will download from GitHub"
+        $sshPath = "C:\Windows\Temp\OpenSSH-Win64.zip"
+        RetryWithBackoff -ScriptBlock { DownloadLatestSshArchive -ArchivePath $sshPath }
+        InstallSshFromArchive -ArchivePath $sshPath
+    }
 }
 
 Set-Service -Name sshd -StartupType Automatic
@@ -148,7 +169,11 @@ $content = [System.IO.File]::ReadAllText("C:\ProgramData\ssh\sshd_config").Repla
 
 #region Install Cloudbase-init (built from https://github.com/luqmana/cloudbase-init/tree/oxide w/ https://github.com/luqmana/cloudbase-init-installer/tree/oxide)
 Write-Host "Installing cloudbase-init"
-RetryWithBackoff -ScriptBlock { Invoke-WebRequest -Uri https://oxide-omicron-build.s3.amazonaws.com/CloudbaseInitSetup.msi -OutFile C:\Windows\Temp\CloudbaseInitSetup.msi | Out-Null }
+if ($haveCloudbaseInitMsi) {
+    Copy-Item $localCloudbaseInitMsi -Destination C:\Windows\Temp\CloudbaseInitSetup.msi
+} else {
+    RetryWithBackoff -ScriptBlock { Invoke-WebRequest -Uri https://oxide-omicron-build.s3.amazonaws.com/CloudbaseInitSetup.msi -OutFile C:\Windows\Temp\CloudbaseInitSetup.msi | Out-Null }
+}
 Start-Process msiexec.exe -ArgumentList "/i C:\Windows\Temp\CloudbaseInitSetup.msi /qn /norestart RUN_SERVICE_AS_LOCAL_SYSTEM=1" -Wait
 del C:\Windows\Temp\CloudbaseInitSetup.msi
 
@@ -163,6 +188,19 @@ Remove-Item "$confPath\Unattend.xml"
 Set-Service -Name cloudbase-init -StartupType Disabled
 #endregion
 
+#region Run hooks
+# Run any scripts supplied in the hooks directory of wimsy's --extra-files, in
+# name order. Hooks receive the same -ConfigDir as this script so they can find
+# the other extra files.
+$hooksDir = "$ConfigDir\hooks"
+if (Test-Path -Path $hooksDir -PathType Container) {
+    Get-ChildItem -Path $hooksDir -Filter *.ps1 -File | Sort-Object -Property Name | ForEach-Object {
+        Write-Host "Running hook" $_.Name
+        & $_.FullName -ConfigDir $ConfigDir
+    }
+}
+#endregion
+
 #region Cleanup and defrag/TRIM disk
 Write-Host "Cleaning up disk"
 Dism.exe /online /Cleanup-Image /StartComponentCleanup /ResetBase